@@ -1,5 +1,5 @@
 use bevy::prelude::*;
-use bevy_voxel::{BevyVoxelResource, Center};
+use bevy_voxel::{BevyVoxelResource, Center, physics::character::{CharacterController, CharacterInput}};
 use rapier3d::{na::Vector3, prelude::{RigidBodyHandle, ColliderHandle}};
 use crate::data::{GameResource, GameState};
 
@@ -21,15 +21,10 @@ fn start(
   // // let pos = [0.0, 5.0, 0.0];
   let pos = Vec3::new(0.0, 0.4, 0.0);
 
-  let (body, collider) = bevy_voxel_res.physics.spawn_character(1.0, 0.5, pos);
-  let k = bevy_voxel_res.get_key(pos);
+  let (player, center, controller) = spawn_player(&mut bevy_voxel_res, pos);
 
-  println!("start player key {:?}", k);
-  commands
-    .spawn(
-      (Player::new(body, collider, k),
-      Center {prev_key: k, key: k } )
-    );
+  println!("start player key {:?}", player.key);
+  commands.spawn((player, center, controller));
 }
 
 fn init(
@@ -39,41 +34,74 @@ fn init(
 ) {
   let p = game_res.data.status.position;
   let pos = Vec3::new(p[0], p[1], p[2]);
-  let (body, collider) = bevy_voxel_res.physics.spawn_character(
-    1.0, 0.5, pos
-  );
-  let k = bevy_voxel_res.get_key(pos);
-  commands
-    .spawn(
-      (Player::new(body, collider, k),
-      Center {prev_key: k, key: k } )
-    );
+  commands.spawn(spawn_player(&mut bevy_voxel_res, pos));
 
   // info!("player init() {:?}", pos);
 }
 
-pub fn create_new_player(mut bevy_voxel_res: ResMut<BevyVoxelResource>, game_res: &Res<GameResource>) -> (Player, Center) {
+pub fn create_new_player(
+  mut bevy_voxel_res: ResMut<BevyVoxelResource>, game_res: &Res<GameResource>
+) -> (Player, Center, CharacterController) {
 
   let p = game_res.data.status.position;
   let pos = Vec3::new(p[0], p[1], p[2]);
-  let (body, collider) = bevy_voxel_res.physics.spawn_character(
-    1.0, 0.5, pos
-  );
+  spawn_player(&mut bevy_voxel_res, pos)
+}
+
+/// Player with its kinematic character at the position
+fn spawn_player(
+  bevy_voxel_res: &mut BevyVoxelResource, pos: Vec3
+) -> (Player, Center, CharacterController) {
+  let mut controller = CharacterController::default();
+  bevy_voxel_res.physics.spawn_kinematic_character(&mut controller, pos);
   let k = bevy_voxel_res.get_key(pos);
   (
-    Player::new(body, collider, k),
-    Center {prev_key: k, key: k } 
+    Player::new(controller.body, controller.collider, k),
+    Center {prev_key: k, key: k },
+    controller,
   )
 }
 
+/**
+  Flying moves the player with the camera. Walking takes the horizontal
+  movement of the camera as the walk of the character, Space jumps and C
+  crouches, F switches between both.
+*/
 fn update(
-  mut query: Query<(&Transform, &mut Center, &mut Player)>,
+  mut query: Query<(&mut Transform, &mut Center, &mut Player, Option<&mut CharacterController>)>,
   mut bevy_voxel_res: ResMut<BevyVoxelResource>,
+  key_input: Res<Input<KeyCode>>,
+  time: Res<Time>,
 ) {
-  for (trans, mut center, mut player) in &mut query {
-    let p = trans.translation;
-    let rigid_body = &mut bevy_voxel_res.physics.rigid_body_set[player.body];
-    rigid_body.set_position(Vector3::new(p.x, p.y, p.z).into(), false);
+  for (mut trans, mut center, mut player, controller) in &mut query {
+    if key_input.just_pressed(KeyCode::F) {
+      player.walking = !player.walking;
+    }
+
+    let p = match controller {
+      Some(mut controller) if player.walking => {
+        let physics = &mut bevy_voxel_res.physics;
+        let prev = match physics.rigid_body_set.get(player.body) {
+          Some(b) => b.next_position().translation.vector,
+          None => continue,
+        };
+        let prev = Vec3::new(prev.x, prev.y, prev.z);
+        let input = CharacterInput {
+          translation: trans.translation - prev,
+          jump: key_input.pressed(KeyCode::Space),
+          crouch: key_input.pressed(KeyCode::C),
+        };
+        let p = physics.move_character(&mut controller, prev, &input, time.delta_seconds());
+        trans.translation = p;
+        p
+      }
+      _ => {
+        let p = trans.translation;
+        let rigid_body = &mut bevy_voxel_res.physics.rigid_body_set[player.body];
+        rigid_body.set_next_kinematic_translation(Vector3::new(p.x, p.y, p.z));
+        p
+      }
+    };
 
     let k = bevy_voxel_res.get_key(p);
     if player.key != k {
@@ -92,6 +120,8 @@ pub struct Player {
   pub collider: ColliderHandle,
   pub prev_key: [i64; 3],
   pub key: [i64; 3],
+  /// Moved by its CharacterController instead of the camera
+  pub walking: bool,
 }
 
 impl Player {
@@ -101,7 +131,8 @@ impl Player {
       body: b,
       collider: c,
       prev_key: k.clone(),
-      key: k
+      key: k,
+      walking: false,
     }
   }
 }
\ No newline at end of file
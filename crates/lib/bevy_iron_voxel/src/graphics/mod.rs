@@ -1,9 +1,9 @@
 use std::f32::consts::PI;
 
-use bevy::{prelude::*, pbr::CascadeShadowConfigBuilder};
-use bevy_voxel::{MeshComponent, BevyVoxelResource, Center};
+use bevy::{prelude::*, pbr::CascadeShadowConfigBuilder, render::{render_resource::PrimitiveTopology, mesh::Indices}};
+use bevy_voxel::BevyVoxelResource;
 use rapier3d::prelude::ColliderHandle;
-use utils::Utils;
+use voxels::data::{voxel_octree::MeshData, transparent::{MaterialKinds, MaterialKind}};
 
 pub mod chunk_preview;
 mod player;
@@ -84,35 +84,90 @@ fn remove(
   mut commands: Commands,
 
   chunk_graphics: Query<(Entity, &ChunkGraphics)>,
-  mesh_comps: Query<(&Center, &MeshComponent)>,
-  bevy_voxel_res: Res<BevyVoxelResource>,
+  mut bevy_voxel_res: ResMut<BevyVoxelResource>,
 ) {
-  let max_lod = bevy_voxel_res.chunk_manager.depth as usize;
-  for (center, _mesh_comp) in &mesh_comps {
+  // Unloaded by the lod manager, once no center needs them
+  for key in std::mem::take(&mut bevy_voxel_res.unloaded).iter() {
     for (entity, graphics) in &chunk_graphics {
-      for lod in 0..max_lod {
-        if bevy_voxel_res.in_range_by_lod(&center.key, &graphics.key, lod) {
-          if graphics.lod != lod {
-            for (_, g2) in &chunk_graphics {
-              if bevy_voxel_res.in_range_by_lod(&center.key, &g2.key, lod) {
-                if g2.lod == lod && graphics.key == g2.key {
-                  commands.entity(entity).despawn_recursive();
-                }
-              }
-            }
-          }
-        }
+      if graphics.key == *key {
+        commands.entity(entity).despawn_recursive();
+        bevy_voxel_res.physics.remove_collider(graphics.collider);
       }
+    }
+  }
 
-      let r = bevy_voxel_res.ranges.clone();
-      let max_range = r[r.len() - 1] as i64;
-      if !Utils::in_range(&center.key, &graphics.key, max_range) {
-        commands.entity(entity).despawn_recursive();
+  // Graphics of the previous lod, once the one of the current lod is spawned
+  for (entity, graphics) in &chunk_graphics {
+    let lod = match bevy_voxel_res.clipmap.lod(&graphics.key) {
+      Some(l) => l,
+      None => continue,
+    };
+    if graphics.lod != lod {
+      for (_, g2) in &chunk_graphics {
+        if g2.lod == lod && graphics.key == g2.key {
+          commands.entity(entity).despawn_recursive();
+        }
       }
     }
   }
 }
 
+/**
+  Spawns the transparent and liquid faces of the chunk with alpha blending.
+  It has no collider, it is despawned with the other graphics of the key.
+*/
+pub fn spawn_transparent(
+  commands: &mut Commands,
+  meshes: &mut Assets<Mesh>,
+  materials: &mut Assets<StandardMaterial>,
+  data: &MeshData,
+  pos: Vec3,
+  kinds: &MaterialKinds,
+) {
+  let transparent = match &data.transparent {
+    Some(t) => t,
+    None => return,
+  };
+
+  // StandardMaterial multiplies the base color with the vertex color
+  let colors: Vec<[f32; 4]> = transparent.colors
+    .iter()
+    .zip(transparent.types_1.iter())
+    .map(|(c, t)| {
+      let alpha = match kinds.get(t[0] as u8) {
+        MaterialKind::Liquid => 0.6,
+        _ => 0.35,
+      };
+      [c[0], c[1], c[2], alpha]
+    })
+    .collect();
+
+  let mut render_mesh = Mesh::new(PrimitiveTopology::TriangleList);
+  render_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, transparent.positions.clone());
+  render_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, transparent.normals.clone());
+  render_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+  render_mesh.set_indices(Some(Indices::U32(transparent.indices.clone())));
+
+  let material = materials.add(StandardMaterial {
+    base_color: Color::WHITE,
+    alpha_mode: AlphaMode::Blend,
+    perceptual_roughness: 0.1,
+    ..default()
+  });
+  commands
+    .spawn(MaterialMeshBundle {
+      mesh: meshes.add(render_mesh),
+      material: material,
+      transform: Transform::from_translation(pos),
+      ..default()
+    })
+    .insert(ChunkGraphics {
+      key: data.key,
+      lod: data.lod as usize,
+      collider: ColliderHandle::invalid(),
+    });
+}
+
 #[derive(Resource)]
 pub struct GraphicsResource {
   pub show_preview: bool,
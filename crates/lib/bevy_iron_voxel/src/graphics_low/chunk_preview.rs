@@ -16,7 +16,7 @@ fn update(
   mut commands: Commands,
   mut meshes: ResMut<Assets<Mesh>>,
   mut materials: ResMut<Assets<StandardMaterial>>,
-  bevy_voxel_res: Res<BevyVoxelResource>,
+  mut bevy_voxel_res: ResMut<BevyVoxelResource>,
 
   previews: Query<&Preview, Changed<Preview>>,
   preview_graphics: Query<Entity, With<PreviewGraphics>>,
@@ -1,7 +1,7 @@
 use bevy::{prelude::*, render::{render_resource::PrimitiveTopology, mesh::Indices}};
 use bevy_voxel::{BevyVoxelResource, Chunks, MeshComponent, Center};
 use utils::Utils;
-use crate::graphics::ChunkGraphics;
+use crate::graphics::{ChunkGraphics, spawn_transparent};
 
 pub struct CustomPlugin;
 impl Plugin for CustomPlugin {
@@ -40,9 +40,16 @@ fn add(
       render_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, data.positions.clone());
       render_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, data.normals.clone());
       render_mesh.set_indices(Some(Indices::U32(data.indices.clone())));
+      if data.ao.len() == data.positions.len() {
+        // StandardMaterial multiplies the base color with the vertex color
+        let ao: Vec<[f32; 4]> = data.ao.iter().map(|a| [*a, *a, *a, 1.0]).collect();
+        render_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, ao);
+      }
 
       let mesh_handle = meshes.add(render_mesh);
       let mut pos = bevy_voxel_res.get_pos(data.key);
+      let kinds = bevy_voxel_res.chunk_manager.mesh_options.kinds;
+      spawn_transparent(&mut commands, &mut meshes, &mut materials, data, pos, &kinds);
 
       let mut color = Color::rgba(0.7, 0.7, 0.7, 0.5);
       if data.lod == 1 {
@@ -93,7 +100,7 @@ impl Default for LocalResource {
 mod tests {
   use bevy_voxel::BevyVoxelResource;
   use rapier3d::prelude::ColliderHandle;
-  use crate::graphics::ChunkGraphics;
+  use crate::graphics::{ChunkGraphics, spawn_transparent};
 
 
   #[test]
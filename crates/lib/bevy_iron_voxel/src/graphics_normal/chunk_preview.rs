@@ -31,7 +31,7 @@ fn edit_remove(edit_state: Res<State<EditState>>,) -> bool {
 fn update(
   mut commands: Commands,
   mut meshes: ResMut<Assets<Mesh>>,
-  bevy_voxel_res: Res<BevyVoxelResource>,
+  mut bevy_voxel_res: ResMut<BevyVoxelResource>,
 
   previews: Query<&Preview, Changed<Preview>>,
   preview_graphics: Query<Entity, With<PreviewGraphics>>,
@@ -57,7 +57,7 @@ fn update(
     render_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, data.positions.clone());
     render_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, data.normals.clone());
     render_mesh.set_indices(Some(Indices::U32(data.indices.clone())));
-    render_mesh.insert_attribute(VOXEL_COLOR, data.colors.clone());
+    render_mesh.insert_attribute(VOXEL_COLOR, data.shaded_colors());
 
     let mesh_handle = meshes.add(render_mesh);
     let material_handle = custom_materials.add(CustomMaterial {
@@ -80,7 +80,7 @@ fn update_remove(
   mut commands: Commands,
   mut meshes: ResMut<Assets<Mesh>>,
   mut materials: ResMut<Assets<StandardMaterial>>,
-  bevy_voxel_res: Res<BevyVoxelResource>,
+  mut bevy_voxel_res: ResMut<BevyVoxelResource>,
 
   previews: Query<&Preview, Changed<Preview>>,
   preview_graphics: Query<Entity, With<PreviewGraphics>>,
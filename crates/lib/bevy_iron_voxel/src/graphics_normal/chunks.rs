@@ -1,6 +1,6 @@
 use bevy::{prelude::*, render::{mesh::{MeshVertexAttribute, MeshVertexBufferLayout, Indices}, render_resource::{VertexFormat, AsBindGroup, ShaderRef, SpecializedMeshPipelineError, RenderPipelineDescriptor, PrimitiveTopology}}, reflect::TypeUuid, pbr::{MaterialPipeline, MaterialPipelineKey}};
 use bevy_voxel::{BevyVoxelResource, MeshComponent};
-use crate::graphics::ChunkGraphics;
+use crate::graphics::{ChunkGraphics, spawn_transparent};
 
 pub struct CustomPlugin;
 impl Plugin for CustomPlugin {
@@ -22,6 +22,7 @@ fn add(
   mut commands: Commands,
   mut meshes: ResMut<Assets<Mesh>>,
   mut custom_materials: ResMut<Assets<CustomMaterial>>,
+  mut materials: ResMut<Assets<StandardMaterial>>,
   mut _images: ResMut<Assets<Image>>,
 
   chunk_graphics: Query<(Entity, &ChunkGraphics)>,
@@ -43,18 +44,21 @@ fn add(
         }
       }
 
+      let pos = bevy_voxel_res.get_pos(data.key);
+      let kinds = bevy_voxel_res.chunk_manager.mesh_options.kinds;
+      spawn_transparent(&mut commands, &mut meshes, &mut materials, data, pos, &kinds);
+
       let mut render_mesh = Mesh::new(PrimitiveTopology::TriangleList);
       render_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, data.positions.clone());
       render_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, data.normals.clone());
       render_mesh.set_indices(Some(Indices::U32(data.indices.clone())));
-      render_mesh.insert_attribute(VOXEL_COLOR, data.colors.clone());
+      render_mesh.insert_attribute(VOXEL_COLOR, data.shaded_colors());
 
       let mesh_handle = meshes.add(render_mesh);
       let material_handle = custom_materials.add(CustomMaterial {
         base_color: Color::rgb(1.0, 1.0, 1.0),
       });
 
-      let pos = bevy_voxel_res.get_pos(data.key);
       commands
         .spawn(MaterialMeshBundle {
           mesh: mesh_handle,
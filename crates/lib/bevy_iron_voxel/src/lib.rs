@@ -55,17 +55,29 @@ impl Plugin for VoxelWorldPlugin {
   
     cfg_if! {
       if #[cfg(feature = "core")] {
+        use voxels::chunk::{cache::ChunkBudget, bounds::WorldBounds};
+
         let range = 2;
+        let mut voxel_res = BevyVoxelResource::new(
+          4, 
+          // 0.5,
+          1.0, 
+          range, 
+          DEFAULT_COLOR_PALETTE.to_vec(),
+          vec![0, range as u32, 4, 6, 8],
+        );
+        // Unmodified chunks beyond the budget are evicted and regenerated when needed
+        let budget_mb = if cfg!(target_arch = "wasm32") { 64 } else { 512 };
+        voxel_res.chunk_manager.set_budget(ChunkBudget::Bytes(budget_mb * 1024 * 1024));
+        // Terrain is within 16 voxels of y 0, no need to generate empty chunks far above or below
+        voxel_res.chunk_manager.bounds = WorldBounds::default().with_y(-64, 191);
+        voxel_res.chunk_manager.mesh_options.ao = true;
+        // Far rings are decimated more, lod 0 stays full for edits
+        voxel_res.chunk_manager.mesh_options.lod_max_errors = [0.0, 0.02, 0.1, 0.3, 0.3, 0.3, 0.3, 0.3];
+
         app
           .add_plugins(BevyVoxelPlugin)
-          .insert_resource(BevyVoxelResource::new(
-            4, 
-            // 0.5,
-            1.0, 
-            range, 
-            DEFAULT_COLOR_PALETTE.to_vec(),
-            vec![0, range as u32, 4, 6, 8],
-          ))
+          .insert_resource(voxel_res)
           .add_plugins(data::CustomPlugin)
           // .add_plugins(physics::CustomPlugin)
           .add_plugins(components::CustomPlugin)
@@ -25,7 +25,7 @@ fn enter(
 
   cameras: Query<Entity, With<FlyCam>>,
 ) {
-  game_res.chunk_manager.chunks.clear();
+  game_res.chunk_manager.clear();
   *physics = Physics::default();
   
   for (entity, _) in &player_query {
@@ -61,6 +61,11 @@ fn enter(
     }
   }
 
+  // Older saves can have diverged copies of the shared border voxels
+  let repaired = game_res.chunk_manager.repair_borders();
+  if repaired > 0 {
+    warn!("Repaired {} mismatched border voxels", repaired);
+  }
 
 
   ui_state_next.set(UIState::Default);
@@ -19,7 +19,7 @@ fn enter(
 
   chunk_graphics: Query<Entity, With<ChunkGraphics>>,
 ) {
-  game_res.chunk_manager.chunks.clear();
+  game_res.chunk_manager.clear();
   *physics = Physics::default();
   
   for (entity, _) in &player_query {
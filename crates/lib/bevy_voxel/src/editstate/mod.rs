@@ -1,4 +1,5 @@
 use bevy::prelude::*;
+use voxels::chunk::islands::Island;
 
 use crate::{BevyVoxelResource, Preview, Chunks, MeshComponent};
 
@@ -18,6 +19,8 @@ impl Plugin for CustomPlugin {
   fn build(&self, app: &mut App) {
     app
       .add_event::<EditEvents>()
+      .add_event::<FloatingIslands>()
+      .add_event::<EditRejected>()
       .add_plugins(add_normal::CustomPlugin)
       .add_plugins(add_dist::CustomPlugin)
       .add_plugins(add_snap::CustomPlugin)
@@ -28,7 +31,8 @@ impl Plugin for CustomPlugin {
 
       .add_plugins(normal_common::CustomPlugin)
       .add_plugins(dist_common::CustomPlugin)
-      .add_systems(Update, modify_voxels);
+      .add_systems(Update, modify_voxels)
+      .add_systems(Update, send_rejected_edits.after(modify_voxels));
   }
 }
 
@@ -38,6 +42,7 @@ fn modify_voxels(
   mut chunks: Query<(&Preview, &mut Chunks, &mut MeshComponent)>,
 
   mut edit_event_reader: EventReader<EditEvents>,
+  mut islands_writer: EventWriter<FloatingIslands>,
 ) {
   for e in edit_event_reader.iter() {
     if e.event == EditEvent::RemoveCube {
@@ -47,7 +52,20 @@ fn modify_voxels(
         }
 
         let p = preview.pos.unwrap();
-        let res = bevy_voxel_res.set_voxel_cube_default(p, preview.size, 0);
+        let mut res = bevy_voxel_res.set_voxel_cube_default(p, preview.size, 0);
+
+        let v = bevy_voxel_res.to_voxel_pos(p).0;
+        let s = preview.size as i64;
+        let max = (s / 2) + 1;
+        let min = max - s;
+        let islands = bevy_voxel_res.floating_islands(
+          [v[0] + min, v[1] + min, v[2] + min],
+          [v[0] + max - 1, v[1] + max - 1, v[2] + max - 1],
+          &mut res,
+        );
+        if !islands.is_empty() {
+          islands_writer.send(FloatingIslands { islands: islands });
+        }
 
         let mut all_chunks = Vec::new();
         for (key, chunk) in res.iter() {
@@ -71,7 +89,18 @@ fn modify_voxels(
         }
 
         let p = preview.pos.unwrap();
-        let res = bevy_voxel_res.set_voxel_sphere_default(p, preview.sphere_size, 0);
+        let mut res = bevy_voxel_res.set_voxel_sphere_default(p, preview.sphere_size, 0);
+
+        let v = bevy_voxel_res.to_voxel_pos(p).0;
+        let r = preview.sphere_size as i64;
+        let islands = bevy_voxel_res.floating_islands(
+          [v[0] - r, v[1] - r, v[2] - r],
+          [v[0] + r, v[1] + r, v[2] + r],
+          &mut res,
+        );
+        if !islands.is_empty() {
+          islands_writer.send(FloatingIslands { islands: islands });
+        }
 
         let mut all_chunks = Vec::new();
         for (key, chunk) in res.iter() {
@@ -96,6 +125,15 @@ pub struct EditEvents {
   pub event: EditEvent
 }
 
+/**
+  Islands left floating by a remove edit, already set to air with
+  IslandPolicy::Remove. A game can turn them into rigid bodies or debris.
+*/
+#[derive(Event, Debug, Clone)]
+pub struct FloatingIslands {
+  pub islands: Vec<Island>,
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum EditEvent {
   AddCube,
@@ -104,4 +142,19 @@ pub enum EditEvent {
   RemoveSphere,
 }
 
+/// Voxels of an edit outside of the world bounds, they were left unchanged
+#[derive(Event, Debug, Clone)]
+pub struct EditRejected {
+  pub voxels: Vec<[i64; 3]>,
+}
 
+fn send_rejected_edits(
+  mut bevy_voxel_res: ResMut<BevyVoxelResource>,
+  mut rejected_writer: EventWriter<EditRejected>,
+) {
+  if bevy_voxel_res.rejected_edits.is_empty() {
+    return;
+  }
+  let voxels = std::mem::take(&mut bevy_voxel_res.rejected_edits);
+  rejected_writer.send(EditRejected { voxels: voxels });
+}
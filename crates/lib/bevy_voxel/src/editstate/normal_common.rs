@@ -21,7 +21,7 @@ fn preview_position(
   bevy_voxel_res: Res<BevyVoxelResource>,
 ) {
   for (cam_trans, mut preview) in &mut cam {
-    let hit = bevy_voxel_res.get_raycast_hit(cam_trans);
+    let hit = bevy_voxel_res.get_voxel_hit(cam_trans);
 
     if hit.is_none() {
       if preview.pos.is_some() {
@@ -29,8 +29,7 @@ fn preview_position(
       }
       continue;
     }
-    let point = hit.unwrap();
-    let pos = bevy_voxel_res.get_nearest_voxel_air(point);
+    let pos = hit.map(|h| bevy_voxel_res.get_voxel_world_pos(h.prev));
     if pos.is_none() && preview.pos.is_some() {
       preview.pos = pos;
     }
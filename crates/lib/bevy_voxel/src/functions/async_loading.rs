@@ -1,108 +1,477 @@
-use bevy::{prelude::*, tasks::{AsyncComputeTaskPool, Task}};
-use voxels::{chunk::chunk_manager::{ChunkManager, Chunk}, data::{voxel_octree::{VoxelMode, MeshData}, surface_nets::VoxelReuse}};
+use std::collections::VecDeque;
+use bevy::{prelude::*, tasks::{AsyncComputeTaskPool, Task}, utils::HashMap};
+use rapier3d::prelude::SharedShape;
+use voxels::{chunk::chunk_manager::{ChunkManager, Chunk}, data::{voxel_octree::{VoxelMode, MeshData}, surface_nets::VoxelReuse, skirts::add_skirts, mesh_cache::MeshCacheKey}};
+use voxels::data::transparent::collider_mesh;
+use voxels::{utils::job_queue::JobQueue, chunk::{clipmap::Clipmap, load_priority::LoadPriority}};
+use voxels::chunk::prefetch::{Prefetch, PrefetchConfig, PREFETCH_SCORE};
 use futures_lite::future;
-use crate::BevyVoxelResource;
+use crate::{BevyVoxelResource, Center, physics::trimesh_shape};
 
+/// Jobs running on the pool at once, including the finished ones not sent yet
+pub const MAX_JOBS_IN_FLIGHT: usize = 16;
+/// Finished chunks and meshes sent back to the systems per frame
+pub const JOBS_BATCH_LEN: usize = 8;
+/// Chunk generations started per frame
+pub const JOBS_GENERATE_LEN: usize = 8;
+/// Weight of the last frame in the velocity of the centers
+pub const VELOCITY_SMOOTHING: f32 = 0.2;
 
 pub struct CustomPlugin;
 impl Plugin for CustomPlugin {
   fn build(&self, app: &mut App) {
     app
-      .add_systems(Update, recv_keys)
-      .add_systems(Update, recv_chunk)
-      .add_systems(Update, recv_process_mesh)
-      .add_systems(Update, recv_mesh);
+      .insert_resource(ChunkJobs::default())
+      .add_systems(Update, (
+        queue_jobs,
+        prefetch_jobs,
+        cancel_jobs,
+        spawn_jobs,
+        recv_jobs,
+      ).chain());
   }
 }
 
+pub enum ChunkJob {
+  /// Generates the chunk at the lod
+  Load(usize),
+  Mesh(Chunk),
+  /// Generates the chunk ahead of a moving center and keeps it in the ChunkManager
+  Prefetch,
+}
 
-fn recv_keys(
-  mut commands: Commands,
-  bevy_voxel_res: Res<BevyVoxelResource>,
-) {
-  let thread_pool = AsyncComputeTaskPool::get();
+impl ChunkJob {
+  fn lod(&self) -> usize {
+    match self {
+      ChunkJob::Load(lod) => *lod,
+      ChunkJob::Mesh(chunk) => chunk.lod,
+      ChunkJob::Prefetch => 0,
+    }
+  }
+}
 
-  let depth = bevy_voxel_res.chunk_manager.depth as u8;
-  let noise = bevy_voxel_res.chunk_manager.noise;
+enum JobOutput {
+  Chunk(Chunk),
+  Prefetch(Chunk),
+  /// Mesh with skirts and the collider of a lod 0 mesh
+  Mesh(MeshData, Option<MainMesh>),
+}
 
-  for (key, lod) in bevy_voxel_res.recv_key.drain() {
-    let key = key.clone();
-    let task = thread_pool.spawn(async move {
-      let chunk = ChunkManager::new_chunk(&key, depth, lod, noise);
-      chunk
-    });
-  
-    // Spawn new entity and add our new task as a component
-    commands.spawn(LoadChunk(task));
+/// Collider of a lod 0 mesh, with the voxels it was meshed from
+struct MainMesh {
+  shape: Option<SharedShape>,
+  voxels: Vec<u8>,
+}
+
+struct RunningJob {
+  id: u64,
+  lod: usize,
+  prefetch: bool,
+  task: Task<JobOutput>,
+}
+
+struct DoneJob {
+  key: [i64; 3],
+  id: u64,
+  lod: usize,
+  prefetch: bool,
+  output: JobOutput,
+}
+
+/// Smoothed velocity of a center and the keys prefetched along it
+struct Observer {
+  prefetch: Prefetch,
+  pos: Vec3,
+  velocity: Vec3,
+}
+
+/**
+  Chunk generation and meshing requested through send_key and
+  send_process_mesh, run on the AsyncComputeTaskPool by the LoadPriority
+  score, so the near chunks in view of the centers come first, lod 0 ones
+  before the coarser rings at the same distance. Jobs of chunks the clipmap
+  no longer has at their lod are cancelled, finished ones are sent to
+  send_chunk and send_mesh at most batch_len per frame. The chunks along
+  the paths of the moving centers are prefetched after every other job, and
+  cancelled when the centers turn.
+*/
+#[derive(Resource)]
+pub struct ChunkJobs {
+  pub queue: JobQueue<[i64; 3], ChunkJob>,
+  pub priority: LoadPriority,
+  /// Finished jobs sent per frame
+  pub batch_len: usize,
+  /// Load and prefetch jobs started per frame
+  pub generate_len: usize,
+  pub prefetch: PrefetchConfig,
+  running: HashMap<[i64; 3], RunningJob>,
+  done: VecDeque<DoneJob>,
+  observers: HashMap<Entity, Observer>,
+}
+
+impl Default for ChunkJobs {
+  fn default() -> Self {
+    Self {
+      queue: JobQueue::new(MAX_JOBS_IN_FLIGHT),
+      priority: LoadPriority::default(),
+      batch_len: JOBS_BATCH_LEN,
+      generate_len: JOBS_GENERATE_LEN,
+      prefetch: PrefetchConfig::default(),
+      running: HashMap::new(),
+      done: VecDeque::new(),
+      observers: HashMap::new(),
+    }
+  }
+}
+
+/// Keys of the centers and the directions they look at
+type Centers = Vec<([i64; 3], Option<[f32; 3]>)>;
+
+fn centers(query: &Query<(&Center, Option<&GlobalTransform>)>) -> Centers {
+  query
+    .iter()
+    .map(|(c, t)| (c.key, t.map(|t| t.forward().to_array())))
+    .collect()
+}
+
+/// Best score among the centers, None if the clipmap has the key at another lod
+fn job_priority(
+  key: &[i64; 3],
+  lod: usize,
+  centers: &Centers,
+  clipmap: &Clipmap,
+  priority: &LoadPriority,
+) -> Option<u64> {
+  if clipmap.lod(key) != Some(lod) {
+    return None;
+  }
+  Some(priority.min_score(key, lod, centers))
+}
+
+fn is_prefetched(observers: &HashMap<Entity, Observer>, key: &[i64; 3]) -> bool {
+  observers.values().any(|o| o.prefetch.contains(key))
+}
+
+/// Lowest priority of the prefetch, None if it is no longer on a path or already loaded
+fn prefetch_priority(
+  key: &[i64; 3],
+  centers: &Centers,
+  clipmap: &Clipmap,
+  observers: &HashMap<Entity, Observer>,
+  priority: &LoadPriority,
+) -> Option<u64> {
+  if clipmap.lod(key).is_some() || !is_prefetched(observers, key) {
+    return None;
   }
-  
+  Some(PREFETCH_SCORE + priority.min_score(key, 0, centers))
 }
 
-fn recv_chunk(
-  mut commands: Commands,
-  bevy_voxel_res: Res<BevyVoxelResource>,
-  mut tasks: Query<(Entity, &mut LoadChunk)>,
+fn queue_jobs(
+  res: Res<BevyVoxelResource>,
+  mut jobs: ResMut<ChunkJobs>,
+  centers_query: Query<(&Center, Option<&GlobalTransform>)>,
 ) {
-  for (entity, mut task) in &mut tasks {
-    if let Some(chunk) = future::block_on(future::poll_once(&mut task.0)) {
-      let _ = bevy_voxel_res.send_chunk.send(chunk);
+  let centers = centers(&centers_query);
+  let priority = jobs.priority;
 
-      // Task is complete, so remove task component from entity
-      commands.entity(entity).remove::<LoadChunk>();
+  for (key, lod) in res.recv_key.drain() {
+    if let Some(priority) = job_priority(&key, lod, &centers, &res.clipmap, &priority) {
+      jobs.queue.push(key, priority, ChunkJob::Load(lod));
     }
   }
-}
 
+  for chunk in res.recv_process_mesh.drain() {
+    if let Some(priority) = job_priority(&chunk.key, chunk.lod, &centers, &res.clipmap, &priority) {
+      jobs.queue.push(chunk.key, priority, ChunkJob::Mesh(chunk));
+    }
+  }
+}
 
-fn recv_process_mesh(
-  mut commands: Commands,
-  bevy_voxel_res: Res<BevyVoxelResource>,
+/// Queues the chunks on the paths of the moving centers, cancels the ones off their paths
+fn prefetch_jobs(
+  res: Res<BevyVoxelResource>,
+  mut jobs: ResMut<ChunkJobs>,
+  time: Res<Time>,
+  centers_query: Query<(Entity, &Center, &GlobalTransform)>,
 ) {
-  let thread_pool = AsyncComputeTaskPool::get();
+  let dt = time.delta_seconds();
+  if dt <= 0.0 {
+    return;
+  }
+  let chunk_len = res.chunk_manager.seamless_size() as f32 * res.chunk_manager.voxel_scale;
+  let jobs = &mut *jobs;
+  let config = jobs.prefetch;
 
-  let depth = bevy_voxel_res.chunk_manager.depth;
-  let scale = bevy_voxel_res.chunk_manager.voxel_scale;
-
-  for chunk in bevy_voxel_res.recv_process_mesh.drain() {
-    let colors = bevy_voxel_res.chunk_manager.colors.clone();
-    let task = thread_pool.spawn(async move {
-      chunk.octree.compute_mesh(
-        VoxelMode::SurfaceNets, 
-        &mut VoxelReuse::new(depth, 3), 
-        &colors, 
-        scale, 
-        chunk.key,
-        chunk.lod
-      )
+  // Despawned centers stop their prefetches
+  let mut cancelled = Vec::new();
+  jobs.observers.retain(|entity, o| {
+    let alive = centers_query.contains(*entity);
+    if !alive {
+      cancelled.append(&mut o.prefetch.clear());
+    }
+    alive
+  });
+
+  let mut added = Vec::new();
+  let mut centers = Vec::new();
+  for (entity, center, trans) in &centers_query {
+    let pos = trans.translation();
+    centers.push((center.key, Some(trans.forward().to_array())));
+    let o = jobs.observers.entry(entity).or_insert_with(|| Observer {
+      prefetch: Prefetch::new(config),
+      pos: pos,
+      velocity: Vec3::ZERO,
     });
-  
-    // Spawn new entity and add our new task as a component
-    commands.spawn(LoadMeshData(task));
+    o.prefetch.config = config;
+    o.velocity = o.velocity.lerp((pos - o.pos) / dt, VELOCITY_SMOOTHING);
+    o.pos = pos;
+
+    let update = o.prefetch.update(&center.key, &(o.velocity / chunk_len).to_array());
+    cancelled.extend(update.cancelled);
+    added.extend(update.added);
+  }
+
+  // Keys on the path of another center or loaded meanwhile keep their jobs
+  for key in cancelled.iter() {
+    if res.clipmap.lod(key).is_some() || is_prefetched(&jobs.observers, key) {
+      continue;
+    }
+    if jobs.queue.cancel(key) {
+      jobs.running.remove(key);
+    }
+  }
+
+  for key in added.iter() {
+    let manager = &res.chunk_manager;
+    if manager.get_chunk(key).is_some() || !manager.in_bounds(key) || jobs.running.contains_key(key) {
+      continue;
+    }
+    let priority = prefetch_priority(key, &centers, &res.clipmap, &jobs.observers, &jobs.priority);
+    if let Some(priority) = priority {
+      jobs.queue.push(*key, priority, ChunkJob::Prefetch);
+    }
   }
-  
 }
 
-fn recv_mesh(
-  mut commands: Commands,
-  bevy_voxel_res: Res<BevyVoxelResource>,
-  mut tasks: Query<(Entity, &mut LoadMeshData)>,
+/// Reorders the jobs after the centers moved or turned and stops the stale ones
+fn cancel_jobs(
+  res: Res<BevyVoxelResource>,
+  mut jobs: ResMut<ChunkJobs>,
+  changed: Query<(), (With<Center>, Or<(Changed<Center>, Changed<GlobalTransform>)>)>,
+  centers_query: Query<(&Center, Option<&GlobalTransform>)>,
 ) {
-  for (entity, mut task) in &mut tasks {
-    if let Some(data) = future::block_on(future::poll_once(&mut task.0)) {
-      let _ = bevy_voxel_res.send_mesh.send(data);
+  if changed.is_empty() {
+    return;
+  }
+  let centers = centers(&centers_query);
+  let clipmap = &res.clipmap;
 
-      // Task is complete, so remove task component from entity
-      commands.entity(entity).remove::<LoadMeshData>();
+  let jobs = &mut *jobs;
+  let priority = jobs.priority;
+  let observers = &jobs.observers;
+  jobs.queue.reprioritize(|key, job| match job {
+    ChunkJob::Prefetch => prefetch_priority(key, &centers, clipmap, observers, &priority),
+    _ => job_priority(key, job.lod(), &centers, clipmap, &priority),
+  });
+
+  let running = &jobs.running;
+  let done = &jobs.done;
+  let cancelled = jobs.queue.retain_in_flight(|key| {
+    let (lod, prefetch) = match running.get(key) {
+      Some(r) => (r.lod, r.prefetch),
+      None => done.iter().find(|d| d.key == *key).map_or((0, false), |d| (d.lod, d.prefetch)),
+    };
+    // Prefetched chunks are still of use once loaded
+    if prefetch {
+      return is_prefetched(observers, key) || clipmap.lod(key).is_some();
     }
+    clipmap.lod(key) == Some(lod)
+  });
+
+  // Dropping the task cancels it, finished outputs are dropped by recv_jobs()
+  for key in cancelled.iter() {
+    jobs.running.remove(key);
   }
 }
 
+fn spawn_jobs(
+  mut res: ResMut<BevyVoxelResource>,
+  mut jobs: ResMut<ChunkJobs>,
+) {
+  let res = &mut *res;
+  let thread_pool = AsyncComputeTaskPool::get();
+
+  let depth = res.chunk_manager.depth;
+  let noise = res.chunk_manager.noise;
+  let policy = res.chunk_manager.lod_policy;
+  let bounds = res.chunk_manager.bounds;
+  let scale = res.chunk_manager.voxel_scale;
+  let options = res.chunk_manager.mesh_options();
+  let chunk_size = res.chunk_manager.chunk_size;
+  let seams = &res.lod_seams;
 
+  let mut generated = 0;
+  loop {
+    // The queue keeps its order, generations wait for the next frame past the limit
+    if let Some((_, ChunkJob::Load(_) | ChunkJob::Prefetch)) = jobs.queue.peek() {
+      if generated >= jobs.generate_len {
+        break;
+      }
+      generated += 1;
+    }
+    let job = match jobs.queue.pop() {
+      Some(j) => j,
+      None => break,
+    };
+    let key = job.key;
+    let lod = job.job.lod();
+    let prefetch = matches!(job.job, ChunkJob::Prefetch);
+    let task = match job.job {
+      ChunkJob::Load(lod) => {
+        // Prefetched and edited chunks only need their lod
+        if let Some(c) = res.chunk_manager.get_chunk(&key) {
+          let mut chunk = c.clone();
+          chunk.lod = lod;
+          let output = JobOutput::Chunk(chunk);
+          jobs.done.push_back(DoneJob { key: key, id: job.id, lod: lod, prefetch: false, output: output });
+          continue;
+        }
+        thread_pool.spawn(async move {
+          let chunk = ChunkManager::new_chunk_in_bounds(
+            &key, depth as u8, lod, noise, policy, &bounds
+          );
+          JobOutput::Chunk(chunk)
+        })
+      }
+      ChunkJob::Prefetch => {
+        thread_pool.spawn(async move {
+          let chunk = ChunkManager::new_chunk_in_bounds(
+            &key, depth as u8, 0, noise, policy, &bounds
+          );
+          JobOutput::Prefetch(chunk)
+        })
+      }
+      ChunkJob::Mesh(chunk) => {
+        let colors = res.chunk_manager.colors.clone();
+        let sides = seams.sides(&chunk.key, chunk.lod);
+        let skirt_depth = scale * (1 << seams.max_lod(&chunk.key, chunk.lod)) as f32;
 
+        // Lod 0 meshes have colliders and shade their border with the neighbour voxels
+        if chunk.lod == 0 {
+          let ao_border = if options.ao {
+            Some(res.chunk_manager.ao_border(&chunk.key))
+          } else {
+            None
+          };
+          thread_pool.spawn(async move {
+            let mut data = chunk.octree.compute_mesh_with_border(
+              VoxelMode::SurfaceNets,
+              &mut VoxelReuse::new(depth, 3),
+              &colors,
+              scale,
+              chunk.key,
+              chunk.lod,
+              &options,
+              ao_border.as_ref(),
+            );
+            // Skirts only cover the cracks on screen, they stay out of the collider
+            let (positions, indices) = collider_mesh(&data, &options.kinds);
+            let shape = trimesh_shape(&positions, &indices);
+            add_skirts(&mut data, &sides, chunk_size, scale, skirt_depth);
+            let main = MainMesh { shape: shape, voxels: chunk.octree.data };
+            JobOutput::Mesh(data, Some(main))
+          })
+        } else {
+          // Cached meshes only need their skirts, meshes of cancelled jobs are cached too
+          let mesh_cache = res.mesh_cache.clone();
+          thread_pool.spawn(async move {
+            let voxels = chunk.octree.to_dense();
+            let cache_key = MeshCacheKey::from_voxels(
+              chunk.octree.get_size(), &voxels, VoxelMode::SurfaceNets,
+              &colors, scale, chunk.key, chunk.lod, &options
+            );
+            let cached = mesh_cache.lock().unwrap().get(&cache_key, &voxels, chunk.key);
+            let mut data = match cached {
+              Some(data) => data,
+              None => {
+                let data = chunk.octree.compute_mesh_with_options(
+                  VoxelMode::SurfaceNets,
+                  &mut VoxelReuse::new(depth, 3),
+                  &colors,
+                  scale,
+                  chunk.key,
+                  chunk.lod,
+                  &options
+                );
+                mesh_cache.lock().unwrap().insert(cache_key, voxels, &data)
+              }
+            };
+            add_skirts(&mut data, &sides, chunk_size, scale, skirt_depth);
+            JobOutput::Mesh(data, None)
+          })
+        }
+      }
+    };
+    jobs.running.insert(key, RunningJob { id: job.id, lod: lod, prefetch: prefetch, task: task });
+  }
+}
 
-#[derive(Component)]
-struct LoadChunk(Task<Chunk>);
+/// Sends the finished jobs, the rest stays in flight until the next frames
+fn recv_jobs(
+  mut res: ResMut<BevyVoxelResource>,
+  mut jobs: ResMut<ChunkJobs>,
+) {
+  let jobs = &mut *jobs;
+  let mut finished = Vec::new();
+  for (key, running) in jobs.running.iter_mut() {
+    if let Some(output) = future::block_on(future::poll_once(&mut running.task)) {
+      finished.push(*key);
+      let prefetch = running.prefetch;
+      jobs.done.push_back(DoneJob { key: *key, id: running.id, lod: running.lod, prefetch: prefetch, output: output });
+    }
+  }
+  for key in finished.iter() {
+    jobs.running.remove(key);
+  }
 
-#[derive(Component)]
-struct LoadMeshData(Task<MeshData>);
+  let mut sent = 0;
+  while sent < jobs.batch_len {
+    let done = match jobs.done.pop_front() {
+      Some(d) => d,
+      None => break,
+    };
+    if !jobs.queue.finish(&done.key, done.id) {
+      continue;
+    }
 
+    match done.output {
+      JobOutput::Chunk(chunk) => {
+        // Lod 0 chunks are kept for the edits, the loaded ones may be edited already
+        if chunk.lod == 0 && res.chunk_manager.get_chunk(&chunk.key).is_none() {
+          res.chunk_manager.set_chunk(&chunk.key, &chunk);
+        }
+        let _ = res.send_chunk.send(chunk);
+      }
+      JobOutput::Prefetch(chunk) => {
+        if res.chunk_manager.get_chunk(&chunk.key).is_none() {
+          res.chunk_manager.set_chunk(&chunk.key, &chunk);
+        }
+      }
+      JobOutput::Mesh(data, main) => {
+        let shape = match main {
+          Some(main) => {
+            // Edited meanwhile, the edit meshed it again already
+            let current = res.chunk_manager.get_chunk(&done.key);
+            if current.map_or(true, |c| c.octree.data != main.voxels) {
+              continue;
+            }
+            main.shape
+          }
+          None => None,
+        };
+        let _ = res.send_mesh.send((data, shape));
+      }
+    }
+    sent += 1;
+  }
+}
@@ -3,7 +3,7 @@ mod cube;
 
 use bevy::prelude::*;
 use rapier3d::prelude::ColliderHandle;
-use crate::{BevyVoxelResource, Selected, Preview, Chunks, Center, ShapeState, EditState, MeshComponent};
+use crate::{BevyVoxelResource, Selected, Preview, Center, ShapeState, EditState, MeshComponent, mesh_sink};
 
 use cfg_if::cfg_if;
 
@@ -26,13 +26,7 @@ impl Plugin for CustomPlugin {
       .add_systems(Update, detect_selected_voxel_position)
       .add_systems(Update, receive_chunks)
       .add_systems(Update, receive_mesh)
-      .add_systems(Update, shape_state_changed)
-      .add_systems(Update, (
-        load_main_octrees,
-        load_main_delta_octrees,
-        load_lod_chunks,
-        load_lod_delta_octrees
-      ));
+      .add_systems(Update, shape_state_changed);
 
     cfg_if! {
       if #[cfg(not(target_arch = "wasm32"))] {
@@ -64,7 +58,7 @@ fn detect_selected_voxel_position(
   bevy_voxel_res: Res<BevyVoxelResource>,
 ) {
   for (cam_trans, mut selected) in &mut cam {
-    let hit = bevy_voxel_res.get_raycast_hit(cam_trans);
+    let hit = bevy_voxel_res.get_voxel_hit(cam_trans);
     if hit.is_none() {
       if selected.pos.is_some() {
         selected.pos = None;
@@ -72,7 +66,7 @@ fn detect_selected_voxel_position(
       continue;
     }
 
-    let pos = bevy_voxel_res.get_hit_voxel_pos(hit.unwrap());
+    let pos = hit.map(|h| bevy_voxel_res.get_voxel_world_pos(h.pos));
     if pos.is_none() && selected.pos.is_some() {
       selected.pos = pos;
     }
@@ -93,93 +87,6 @@ fn detect_selected_voxel_position(
   }
 }
 
-fn load_main_octrees(
-  mut res: ResMut<BevyVoxelResource>,
-  mut chunks: Query<(&Center, &mut Chunks, &mut MeshComponent), Added<Chunks>>
-) {
-  for (center, mut chunks, mut mesh_comp) in &mut chunks {
-    let lod = 0;
-    let keys = res.get_keys_by_lod(center.key, lod);
-
-    let tmp_c = res.load_chunks(&keys, &chunks.data, lod);
-    for c in tmp_c.iter() {
-      chunks.data.insert(c.key, c.clone());
-    }
-    chunks.added_keys.append(&mut keys.clone());
-
-
-    let data = res.load_mesh_data(&tmp_c);
-    for (d, handle) in data.iter() {
-      mesh_comp.data.insert(d.key, d.clone());
-      mesh_comp.added.push((d.clone(), *handle));
-    }
-  }
-}
-
-fn load_lod_chunks(
-  mut res: ResMut<BevyVoxelResource>,
-  chunks: Query<&Center, Added<Chunks>>
-) {
-  for center in &chunks {
-    for lod in 1..res.ranges.len() - 1 {
-      let keys = res.get_keys_by_lod(center.key, lod);
-      request_load_chunk(&keys, &mut res, lod);
-    }
-  }
-}
-
-fn load_main_delta_octrees(
-  mut res: ResMut<BevyVoxelResource>,
-  mut centers: Query<(&Center, &mut Chunks, &mut MeshComponent), Changed<Center>>
-) {
-  for (center, mut chunks, mut mesh_comp) in &mut centers {
-    let lod = 0;
-    let keys = res.get_delta_keys_by_lod(
-      &center.prev_key, &center.key, lod
-    );
-
-    let tmp_c = res.load_chunks(&keys, &chunks.data, lod);
-    for c in tmp_c.iter() {
-      chunks.data.insert(c.key, c.clone());
-    }
-    chunks.added_keys.clear();
-    chunks.added_keys.append(&mut keys.clone());
-
-    mesh_comp.added.clear();
-    let data = res.load_mesh_data(&tmp_c);
-    for (d, handle) in data.iter() {
-      mesh_comp.data.insert(d.key, d.clone());
-      mesh_comp.added.push((d.clone(), *handle));
-    }
-  }
-}
-
-fn load_lod_delta_octrees(
-  res: Res<BevyVoxelResource>,
-  centers: Query<(&Center, &Chunks), Changed<Center>>
-) {
-  
-  for (center, chunks) in &centers {
-    for lod in 1..res.ranges.len() - 1 {
-      let keys = res.get_delta_keys_by_lod(
-        &center.prev_key, &center.key, lod
-      );
-
-      for key in keys.iter() {
-        let d = chunks.data.get(key);
-        if d.is_none() {
-          let _ = res.send_key.send((*key, lod));
-        }
-        if d.is_some() {
-          let mut data = d.unwrap().clone();
-          data.lod = lod;
-          let _ = res.send_process_mesh.send(data);
-        }
-      }
-    }
-  }
-}
-
 fn shape_state_changed(
   shape_state: Res<State<ShapeState>>,
   mut local: Local<ShapeState>,
@@ -204,16 +111,6 @@ fn shape_state_changed(
   
 }
 
-fn request_load_chunk(
-  keys: &Vec<[i64; 3]>,
-  bevy_voxel_res: &mut BevyVoxelResource,
-  lod: usize
-) {
-  for key in keys.iter() {
-    let _ = bevy_voxel_res.send_key.send((*key, lod));
-  }
-}
-
 fn receive_chunks(
   res: Res<BevyVoxelResource>,
 ) {
@@ -222,22 +119,27 @@ fn receive_chunks(
   }
 }
 
-fn receive_mesh(
-  res: Res<BevyVoxelResource>,
-  mut queries: Query<(&Center, &mut MeshComponent)>
+/// Meshes of the async loading, added once to the mesh_sink() for every center
+pub fn receive_mesh(
+  mut res: ResMut<BevyVoxelResource>,
+  mut sinks: Query<(Entity, &mut MeshComponent), With<Center>>
 ) {
-  for data in res.recv_mesh.drain() {
-    for (center, mut mesh_comp) in &mut queries {
-      if res.in_range_by_lod(&center.key, &data.key, data.lod) {
-        if data.lod == 0 {
-          // println!("Error: Lod 0 should not be loaded async");
-        }
-
-        if data.indices.len() > 0 {
-          mesh_comp.added.push((data.clone(), ColliderHandle::invalid()));
-        }
-      }
+  let res = &mut *res;
+  let sink = match mesh_sink(sinks.iter().map(|(e, _)| e)) {
+    Some(e) => e,
+    None => return,
+  };
+  let (_, mut mesh_comp) = sinks.get_mut(sink).unwrap();
+  for (data, shape) in res.recv_mesh.drain() {
+    // Dropped if the chunk was unloaded or changed lod meanwhile
+    if res.clipmap.lod(&data.key) != Some(data.lod) || data.is_empty() {
+      continue;
     }
+    let pos = res.get_pos(data.key);
+    let handle = match shape {
+      Some(s) => res.physics.add_shape_collider([pos.x, pos.y, pos.z], s),
+      None => ColliderHandle::invalid(),
+    };
+    mesh_comp.added.push((data, handle));
   }
 }
-
@@ -1,9 +1,12 @@
 use bevy::{prelude::*, utils::HashMap};
-use rapier3d::{prelude::{Vector, ColliderHandle, Ray, QueryFilter}, na::Point3};
+use rapier3d::{prelude::{Vector, ColliderHandle, Ray, QueryFilter, SharedShape}, na::Point3};
 use utils::{RayUtils, Utils};
-use voxels::{chunk::{chunk_manager::{ChunkManager, Chunk}, adjacent_keys}, data::{voxel_octree::{VoxelMode, MeshData}, surface_nets::VoxelReuse}};
-use voxels::utils::key_to_world_coord_f32;
-use crate::{BevyVoxelResource, physics::Physics, Preview, ShapeState, EditState, ChunkMesh};
+use voxels::{chunk::{chunk_manager::{ChunkManager, Chunk}, adjacent_keys, subscription::{ChunkChange, ChunkChangeKind}}, data::{voxel_octree::{VoxelMode, MeshData}, surface_nets::{VoxelReuse, AoBorder}}};
+use voxels::chunk::{coords::{ChunkKey, WorldVoxelPos, WorldPosF32}, raycast::VoxelHit, store::ChunkStore};
+use voxels::data::{skirts::add_skirts, block_mesher::BlockMesher, transparent::collider_mesh};
+use voxels::chunk::islands::Island;
+use voxels::chunk::bounds::EditError;
+use crate::{BevyVoxelResource, physics::{Physics, trimesh_shape}, Preview, ShapeState, EditState, IslandPolicy};
 use crate::util::*;
 
 use cfg_if::cfg_if;
@@ -14,6 +17,26 @@ cfg_if! {
   }
 }
 
+/// Grids per axis of the blocks the edited chunks are meshed again by
+pub const MESHER_BLOCK_SIZE: u32 = 8;
+/// Edited chunks keeping their mesher
+pub const MESHERS_LEN: usize = 32;
+
+/// Mesher of an edited chunk with the collider part of each of its blocks
+pub(crate) struct CachedMesher {
+  mesher: BlockMesher,
+  parts: Vec<Option<SharedShape>>,
+}
+
+impl CachedMesher {
+  /// Builds the collider parts of the blocks meshed by the last update
+  fn patch_parts(&mut self) {
+    for index in self.mesher.updated_blocks().iter() {
+      let block = &self.mesher.blocks()[*index];
+      self.parts[*index] = trimesh_shape(&block.positions, &block.indices);
+    }
+  }
+}
 
 impl BevyVoxelResource {
 
@@ -49,11 +72,12 @@ impl BevyVoxelResource {
   }
 
   /// Get all chunks adjacent to the player based on
-  /// Depth, range and voxel scale
+  /// Depth, range and voxel scale, skipping the chunks outside of the world bounds
   pub fn load_adj_chunks(&mut self, key: [i64; 3]) -> Vec<Chunk> {
     let mut chunks = Vec::new();
 
     let keys = adjacent_keys(&key, self.chunk_manager.range as i64, true);
+    let keys = self.chunk_manager.bounded_keys(&keys);
     for key in keys.iter() {
       chunks.push(load_chunk(self, *key, 0));
       
@@ -62,33 +86,221 @@ impl BevyVoxelResource {
     chunks
   }
 
-  /// Return mesh data needed for collision or rendering
-  pub fn compute_mesh(&self, mode: VoxelMode, chunk: &Chunk) -> MeshData {
-    chunk
-      .octree
-      .compute_mesh(
-        mode, 
+  /**
+    Mesh of the chunk, reused from mesh_cache if it has the same voxels.
+    Lod 0 meshes with AO shade their border with the neighbours' voxels, the
+    cache does not key them so they are always meshed.
+  */
+  pub fn compute_mesh(&mut self, mode: VoxelMode, chunk: &Chunk) -> MeshData {
+    let options = self.chunk_manager.mesh_options();
+    if chunk.lod == 0 && options.ao {
+      return chunk.octree.compute_mesh_with_border(
+        mode,
+        &mut VoxelReuse::new(self.chunk_manager.depth, 3),
+        &self.chunk_manager.colors,
+        self.chunk_manager.voxel_scale,
+        chunk.key,
+        chunk.lod,
+        &options,
+        Some(&self.chunk_manager.ao_border(&chunk.key)),
+      );
+    }
+
+    self.mesh_cache.lock().unwrap().compute_mesh(
+      &chunk.octree,
+      mode, 
+      &mut VoxelReuse::new(self.chunk_manager.depth, 3),
+      &self.chunk_manager.colors,
+      self.chunk_manager.voxel_scale,
+      chunk.key,
+      chunk.lod,
+      &options
+    )
+  }
+
+  /**
+    Meshes the chunk, with a cached mesher if it was edited: only the blocks
+    around the voxels of the edits in `changes` are meshed again. Keeps the
+    meshers of the last MESHERS_LEN edited chunks.
+  */
+  pub fn compute_mesh_incremental(
+    &mut self,
+    chunk: &Chunk,
+    changes: &HashMap<[i64; 3], ChunkChange>,
+  ) -> MeshData {
+    let options = self.chunk_manager.mesh_options();
+    // Lod 0 neighbours mesh the same voxels, so the vertices they share shade the same
+    let ao_border = if chunk.lod == 0 && options.ao {
+      Some(self.chunk_manager.ao_border(&chunk.key))
+    } else {
+      None
+    };
+
+    if !self.sync_mesher(chunk, changes.get(&chunk.key), ao_border.clone()) {
+      return chunk.octree.compute_mesh_with_border(
+        VoxelMode::SurfaceNets,
         &mut VoxelReuse::new(self.chunk_manager.depth, 3),
         &self.chunk_manager.colors,
         self.chunk_manager.voxel_scale,
         chunk.key,
-        chunk.lod
-      )
+        chunk.lod,
+        &options,
+        ao_border.as_ref(),
+      );
+    }
+
+    let mut data = self.meshers[&chunk.key].mesher.mesh_data();
+    data.transparent = chunk.octree.compute_transparent_mesh(
+      &self.chunk_manager.colors,
+      self.chunk_manager.voxel_scale,
+      chunk.key,
+      chunk.lod,
+      &options,
+    );
+    data
+  }
+
+  /**
+    Brings the cached mesher of the chunk up to date, creating it for an
+    edited chunk. Returns false if the chunk has no mesher.
+  */
+  fn sync_mesher(
+    &mut self,
+    chunk: &Chunk,
+    change: Option<&ChunkChange>,
+    ao_border: Option<AoBorder>,
+  ) -> bool {
+    let options = self.chunk_manager.mesh_options();
+    let scale = self.chunk_manager.voxel_scale;
+    let colors = &self.chunk_manager.colors;
+    let valid = self.meshers.get(&chunk.key).map_or(false, |c| {
+      let m = &c.mesher;
+      m.lod == chunk.lod
+        && m.size() == chunk.octree.get_size()
+        && m.colors == *colors
+        && m.scale() == scale
+        && *m.options() == options
+    });
+    let edited = change.map_or(false, |c| c.kind == ChunkChangeKind::Edited);
+
+    if valid {
+      let cached = self.meshers.get_mut(&chunk.key).unwrap();
+      match change {
+        Some(c) if c.kind == ChunkChangeKind::Edited => {
+          cached.mesher.sync_region(&chunk.octree, c.min, c.max);
+        }
+        Some(_) => {
+          cached.mesher.sync(&chunk.octree);
+        }
+        None => {}
+      }
+      if let Some(border) = ao_border {
+        cached.mesher.set_ao_border(border);
+      }
+      cached.mesher.update();
+      cached.patch_parts();
+    } else if edited {
+      let mesher = BlockMesher::new_with_border(
+        &chunk.octree,
+        colors,
+        scale,
+        chunk.key,
+        chunk.lod,
+        &options,
+        MESHER_BLOCK_SIZE,
+        ao_border,
+      );
+      let mut cached = CachedMesher { parts: vec![None; mesher.blocks().len()], mesher: mesher };
+      cached.patch_parts();
+      self.meshers.insert(chunk.key, cached);
+    } else {
+      self.meshers.remove(&chunk.key);
+      self.mesher_keys.retain(|k| *k != chunk.key);
+      return false;
+    }
+
+    // Least recently used first
+    self.mesher_keys.retain(|k| *k != chunk.key);
+    self.mesher_keys.push(chunk.key);
+    if self.mesher_keys.len() > MESHERS_LEN {
+      let key = self.mesher_keys.remove(0);
+      self.meshers.remove(&key);
+    }
+    true
+  }
+
+  /**
+    Edits since the last call by chunk key, for the cached meshers. The
+    meshers of the edited chunks not in `chunks` are dropped, their edits
+    are taken now so those chunks are meshed from scratch later.
+  */
+  fn take_mesher_changes(&mut self, chunks: &Vec<Chunk>) -> HashMap<[i64; 3], ChunkChange> {
+    let subscriptions = &mut self.chunk_manager.subscriptions;
+    match self.mesher_changes.and_then(|id| subscriptions.take_changes(id)) {
+      Some(changes) => {
+        let changes: HashMap<[i64; 3], ChunkChange> =
+          changes.into_iter().map(|c| (c.key, c)).collect();
+        for key in changes.keys() {
+          if !chunks.iter().any(|c| c.key == *key) {
+            self.meshers.remove(key);
+            self.mesher_keys.retain(|k| k != key);
+          }
+        }
+        changes
+      }
+      None => {
+        // Not subscribed to this chunk manager yet, the meshers may not match it
+        self.meshers.clear();
+        self.mesher_keys.clear();
+        self.mesher_changes = Some(subscriptions.subscribe([i64::MIN; 3], [i64::MAX; 3]));
+        HashMap::new()
+      }
+    }
+  }
+
+  /**
+    Collider of the mesh, the one of a chunk with a cached mesher is made of
+    the block parts so an edit only builds the trimesh of the changed blocks
+  */
+  fn add_mesh_collider(&mut self, pos: Vec3, data: &MeshData) -> ColliderHandle {
+    let cached = match self.meshers.get(&data.key) {
+      Some(c) => c,
+      None => return self.add_collider(pos, data),
+    };
+    let mut parts: Vec<SharedShape> = cached.parts.iter().flatten().cloned().collect();
+
+    // The transparent faces are meshed again with every edit
+    let kinds = &self.chunk_manager.mesh_options.kinds;
+    let transparent = MeshData { transparent: data.transparent.clone(), ..Default::default() };
+    let (positions, indices) = collider_mesh(&transparent, kinds);
+    if let Some(shape) = trimesh_shape(&positions, &indices) {
+      parts.push(shape);
+    }
+    self.physics.add_compound_collider([pos.x, pos.y, pos.z], &parts)
+  }
+
+  /// Covers the cracks against neighbouring chunks meshed at another lod
+  pub fn add_lod_skirts(&self, data: &mut MeshData) {
+    let sides = self.lod_seams.sides(&data.key, data.lod);
+    let max_lod = self.lod_seams.max_lod(&data.key, data.lod);
+    let scale = self.chunk_manager.voxel_scale;
+    let depth = scale * (1 << max_lod) as f32;
+    add_skirts(data, &sides, self.chunk_manager.chunk_size, scale, depth);
   }
 
   /// Return a world position based on chunk size(depth) and voxel scale
   pub fn get_pos(&self, key: [i64; 3]) -> Vec3 {
     let seamless = self.chunk_manager.seamless_size();
     let scale = self.chunk_manager.voxel_scale;
-    let mut pos = key_to_world_coord_f32(&key, seamless);
-
-    pos[0] *= scale;
-    pos[1] *= scale;
-    pos[2] *= scale;
-    
+    let pos = ChunkKey(key).to_world_f32(seamless, scale).0;
     Vec3::new(pos[0], pos[1], pos[2])
   }
 
+  /// Voxel of a world position based on voxel scale, truncated like the edits always did
+  pub fn to_voxel_pos(&self, pos: Vec3) -> WorldVoxelPos {
+    WorldPosF32([pos.x, pos.y, pos.z]).to_voxel_pos_truncated(self.chunk_manager.voxel_scale)
+  }
+
   pub fn get_raycast_hit(&self, trans: &Transform) -> Option<Vec3> {
     let start_pos = trans.translation;
     let dir = trans.forward();
@@ -115,9 +327,28 @@ impl BevyVoxelResource {
     point
   }
 
+  /// Voxel hit by the forward ray of the transform within the loaded range.
+  /// Traverses the voxel grid, so it doesn't need the colliders
+  pub fn get_voxel_hit(&self, trans: &Transform) -> Option<VoxelHit> {
+    let start = trans.translation;
+    let dir = trans.forward();
+    let seamless = self.chunk_manager.seamless_size() as f32;
+    let range = self.chunk_manager.range as f32 + 1.0;
+    let max_dist = range * seamless * self.chunk_manager.voxel_scale;
+
+    self.chunk_manager.raycast(
+      [start.x, start.y, start.z], [dir.x, dir.y, dir.z], max_dist
+    )
+  }
+
+  /// World position of a voxel based on voxel scale
+  pub fn get_voxel_world_pos(&self, pos: [i64; 3]) -> Vec3 {
+    let p = WorldVoxelPos(pos).to_world_f32(self.chunk_manager.voxel_scale).0;
+    Vec3::new(p[0], p[1], p[2])
+  }
+
   pub fn get_hit_voxel_pos(&self, point: Vec3) -> Option<Vec3> {
     let voxel_scale = self.chunk_manager.voxel_scale;
-    let mul = 1.0 / voxel_scale;
     let mut nearest_dist = f32::MAX;
 
     let mut pos = None;
@@ -129,11 +360,7 @@ impl BevyVoxelResource {
     let near_pos = get_near_positions(tmp_point, voxel_scale);
     for n in near_pos.iter() {
       let dist = point.distance(*n);
-      let tmp_pos = [
-        (n[0] * mul) as i64, 
-        (n[1] * mul) as i64, 
-        (n[2] * mul) as i64, 
-      ];
+      let tmp_pos = WorldPosF32([n.x, n.y, n.z]).to_voxel_pos_truncated(voxel_scale).0;
 
       let res = self.chunk_manager.get_voxel_safe(&tmp_pos);
       if res.is_some() && res.unwrap() != 0 {
@@ -150,7 +377,6 @@ impl BevyVoxelResource {
   pub fn get_nearest_voxel_air(&self, point: Vec3) -> Option<Vec3> {
     let voxel_scale = self.chunk_manager.voxel_scale;
 
-    let mul = 1.0 / voxel_scale;
     let mut nearest_dist = f32::MAX;
 
     let mut pos = None;
@@ -163,11 +389,7 @@ impl BevyVoxelResource {
     
     for n in near_pos.iter() {
       let dist = point.distance(*n);
-      let tmp_pos = [
-        (n[0] * mul) as i64, 
-        (n[1] * mul) as i64, 
-        (n[2] * mul) as i64, 
-      ];
+      let tmp_pos = WorldPosF32([n.x, n.y, n.z]).to_voxel_pos_truncated(voxel_scale).0;
 
       let res = self.chunk_manager.get_voxel_safe(&tmp_pos);
       if res.is_some() && res.unwrap() == 0 {
@@ -183,7 +405,6 @@ impl BevyVoxelResource {
   }
 
   pub fn get_nearest_voxel_by_unit(&self, point: Vec3, unit: f32) -> Option<Vec3> {
-    let mul = 1.0 / unit;
     let mut nearest_dist = f32::MAX;
 
     let mut pos = None;
@@ -195,11 +416,7 @@ impl BevyVoxelResource {
     let near_pos = get_near_positions(tmp_point, unit);
     for n in near_pos.iter() {
       let dist = point.distance(*n);
-      let tmp_pos = [
-        (n[0] * mul) as i64, 
-        (n[1] * mul) as i64, 
-        (n[2] * mul) as i64, 
-      ];
+      let tmp_pos = WorldPosF32([n.x, n.y, n.z]).to_voxel_pos_truncated(unit).0;
 
       let res = self.chunk_manager.get_voxel_safe(&tmp_pos);
       if res.is_some() && res.unwrap() > 0 {
@@ -299,53 +516,65 @@ impl BevyVoxelResource {
 
 
   pub fn set_voxel(&mut self, pos: Vec3, voxel: u8) {
-    let mul = 1.0 / self.chunk_manager.voxel_scale;
-    let p = [
-      (pos.x * mul) as i64,
-      (pos.y * mul) as i64,
-      (pos.z * mul) as i64,
-    ];
-
-    self.chunk_manager.set_voxel2(&p, voxel);
+    let p = self.to_voxel_pos(pos).0;
+    let _ = self.set_voxel_default(p, voxel);
   }
 
+  /// Modified chunks, the voxels outside of the bounds are added to rejected_edits
   pub fn set_voxel_default(
     &mut self, coord: [i64; 3], voxel: u8
-  ) -> Vec<([i64; 3], Chunk)> {
-    self.chunk_manager.set_voxel2(&coord, voxel)
+  ) -> Result<Vec<([i64; 3], Chunk)>, EditError> {
+    let res = self.chunk_manager.try_set_voxel(&coord, voxel);
+    if let Err(EditError::OutOfBounds(pos)) = res {
+      self.rejected_edits.push(pos);
+    }
+    res
+  }
+
+  /**
+    Adds the lod 0 chunks shading their border with the edited voxel, so they
+    are meshed again with the edited ones and their seam keeps the same AO
+  */
+  fn add_ao_border_chunks(&self, pos: &[i64; 3], res: &mut HashMap<[i64; 3], Chunk>) {
+    if !self.chunk_manager.mesh_options().ao {
+      return;
+    }
+    for key in self.chunk_manager.ao_border_keys(pos).iter() {
+      if self.clipmap.lod(key) != Some(0) || res.contains_key(key) {
+        continue;
+      }
+      if let Some(chunk) = self.chunk_manager.get_chunk(key) {
+        res.insert(*key, chunk.clone());
+      }
+    }
   }
 
   pub fn set_voxel_cube(
     &mut self, pos: Vec3, preview: &Preview
   ) -> HashMap<[i64; 3], Chunk> {
     let mut res = HashMap::new();
-    let scale = self.chunk_manager.voxel_scale;
 
     let s = preview.size as i64;
     let max = (s / 2) + 1;
     let min = max - s;
     
-    let mul = 1.0 / scale;
-    let p = [
-      pos[0] * mul,
-      pos[1] * mul,
-      pos[2] * mul,
-    ];
+    let p = self.to_voxel_pos(pos).0;
     for x in min..max {
       for y in min..max {
         for z in min..max {
 
           let tmp = [
-            p[0] as i64 + x,
-            p[1] as i64 + y,
-            p[2] as i64 + z,
+            p[0] + x,
+            p[1] + y,
+            p[2] + z,
           ];
 
-          let chunks = self.set_voxel_default(tmp, preview.voxel);
+          let chunks = self.set_voxel_default(tmp, preview.voxel).unwrap_or_default();
 
           for (key, chunk) in chunks.iter() {
             res.insert(*key, chunk.clone());
           }
+          self.add_ao_border_chunks(&tmp, &mut res);
         }
       }
     }
@@ -359,34 +588,29 @@ impl BevyVoxelResource {
     voxel: u8,
   ) -> HashMap<[i64; 3], Chunk> {
     let mut res = HashMap::new();
-    let scale = self.chunk_manager.voxel_scale;
 
     let s = size as i64;
     let max = (s / 2) + 1;
     let min = max - s;
     
-    let mul = 1.0 / scale;
-    let p = [
-      pos[0] * mul,
-      pos[1] * mul,
-      pos[2] * mul,
-    ];
+    let p = self.to_voxel_pos(pos).0;
 
     for x in min..max {
       for y in min..max {
         for z in min..max {
 
           let tmp = [
-            p[0] as i64 + x,
-            p[1] as i64 + y,
-            p[2] as i64 + z,
+            p[0] + x,
+            p[1] + y,
+            p[2] + z,
           ];
 
-          let chunks = self.set_voxel_default(tmp, voxel);
+          let chunks = self.set_voxel_default(tmp, voxel).unwrap_or_default();
 
           for (key, chunk) in chunks.iter() {
             res.insert(*key, chunk.clone());
           }
+          self.add_ao_border_chunks(&tmp, &mut res);
         }
       }
     }
@@ -400,27 +624,22 @@ impl BevyVoxelResource {
     voxel: u8,
   ) -> HashMap<[i64; 3], Chunk> {
     let mut res = HashMap::new();
-    let scale = self.chunk_manager.voxel_scale;
-    let mul = 1.0 / scale;
-    let p = [
-      pos.x * mul,
-      pos.y * mul,
-      pos.z * mul,
-    ];
+    let p = self.to_voxel_pos(pos).0;
 
     let coords = get_sphere_coords(size);
     for c in coords.iter() {
       let tmp = [
-        p[0] as i64 + c[0],
-        p[1] as i64 + c[1],
-        p[2] as i64 + c[2],
+        p[0] + c[0],
+        p[1] + c[1],
+        p[2] + c[2],
       ];
       
-      let chunks = self.set_voxel_default(tmp, voxel);
+      let chunks = self.set_voxel_default(tmp, voxel).unwrap_or_default();
 
       for (key, chunk) in chunks.iter() {
         res.insert(*key, chunk.clone());
       }
+      self.add_ao_border_chunks(&tmp, &mut res);
     }
     res
   }
@@ -429,33 +648,52 @@ impl BevyVoxelResource {
     &mut self, pos: Vec3, preview: &Preview
   ) -> HashMap<[i64; 3], Chunk> {
     let mut res = HashMap::new();
-    let scale = self.chunk_manager.voxel_scale;
-    let mul = 1.0 / scale;
-    let p = [
-      pos.x * mul,
-      pos.y * mul,
-      pos.z * mul,
-    ];
+    let p = self.to_voxel_pos(pos).0;
 
     let size = preview.sphere_size;
     let coords = get_sphere_coords(size);
     for c in coords.iter() {
       let tmp = [
-        p[0] as i64 + c[0],
-        p[1] as i64 + c[1],
-        p[2] as i64 + c[2],
+        p[0] + c[0],
+        p[1] + c[1],
+        p[2] + c[2],
       ];
       
-      let chunks = self.set_voxel_default(tmp, preview.voxel);
+      let chunks = self.set_voxel_default(tmp, preview.voxel).unwrap_or_default();
 
       for (key, chunk) in chunks.iter() {
         res.insert(*key, chunk.clone());
       }
+      self.add_ao_border_chunks(&tmp, &mut res);
     }
     res
   }
 
 
+  /**
+    Islands left floating by an edit of the voxels in [min, max], handled by
+    the island_policy. The chunks of the removed islands are added to `chunks`.
+  */
+  pub fn floating_islands(
+    &mut self,
+    min: [i64; 3],
+    max: [i64; 3],
+    chunks: &mut HashMap<[i64; 3], Chunk>,
+  ) -> Vec<Island> {
+    if self.island_policy == IslandPolicy::Ignore {
+      return Vec::new();
+    }
+    let islands = self.chunk_manager.find_islands(&min, &max, &self.islands);
+    if self.island_policy == IslandPolicy::Remove {
+      for island in islands.iter() {
+        for (key, chunk) in self.chunk_manager.remove_island(island) {
+          chunks.insert(key, chunk);
+        }
+      }
+    }
+    islands
+  }
+
   /// Load Chunks, MeshData then create Collider for MeshData
   pub fn load_adj_mesh_data(&mut self, key: [i64; 3]) -> Vec<([i64; 3], MeshData)> {
     let mut mesh_data = Vec::new();
@@ -470,7 +708,7 @@ impl BevyVoxelResource {
 
     for chunk in chunks.iter() {
       let data = self.compute_mesh(VoxelMode::SurfaceNets, chunk);
-      if data.positions.len() == 0 {
+      if data.is_empty() {
         continue;
       }
 
@@ -495,7 +733,7 @@ impl BevyVoxelResource {
 
     for chunk in chunks.iter() {
       let data = self.compute_mesh(VoxelMode::SurfaceNets, chunk);
-      if data.positions.len() == 0 {
+      if data.is_empty() {
         continue;
       }
 
@@ -513,9 +751,12 @@ impl BevyVoxelResource {
     pos: Vec3, 
     data: &MeshData
   ) -> ColliderHandle {
-    self.physics.add_collider(
-      [pos.x, pos.y, pos.z], &data.positions, &data.indices
-    )
+    let kinds = &self.chunk_manager.mesh_options.kinds;
+    let (positions, indices) = collider_mesh(data, kinds);
+    if indices.is_empty() {
+      return ColliderHandle::invalid();
+    }
+    self.physics.add_collider([pos.x, pos.y, pos.z], &positions, &indices)
   }
 
   pub fn remove_collider(&mut self, handle: ColliderHandle) {
@@ -523,6 +764,13 @@ impl BevyVoxelResource {
   }
 
 
+  /// Copy of the chunks around the voxel the previews are edited on
+  fn preview_store(&self, p: [i64; 3], preview_size: i64) -> ChunkStore {
+    let min = [p[0] - preview_size, p[1] - preview_size, p[2] - preview_size];
+    let max = [p[0] + preview_size, p[1] + preview_size, p[2] + preview_size];
+    self.chunk_manager.chunk_store(&min, &max)
+  }
+
   /// - calc_pos should be the calculated position based on edit mode
   /// - Add voxel mode(TODO): Probably be a separate function
   /// - Remove voxel mode(TODO): Probably be a separate function
@@ -533,17 +781,12 @@ impl BevyVoxelResource {
     // println!("voxel {}", voxel);
     let size = preview.size;
 
-    let scale = self.chunk_manager.voxel_scale;
-    let mul = 1.0 / scale;
-    let p = [
-      calc_pos.x * mul,
-      calc_pos.y * mul,
-      calc_pos.z * mul,
-    ];
-
-    let mut tmp_manager = self.chunk_manager.clone();
+    let p = self.to_voxel_pos(calc_pos).0;
 
     let s = size as i64;
+    let preview_size = s + 2;
+    let tmp_store = self.preview_store(p, preview_size);
+
     let max = (s / 2) + 1;
     let min = max - s;
 
@@ -552,12 +795,12 @@ impl BevyVoxelResource {
         for z in min..max {
 
           let tmp = [
-            p[0] as i64 + x,
-            p[1] as i64 + y,
-            p[2] as i64 + z
+            p[0] + x,
+            p[1] + y,
+            p[2] + z
           ];
           
-          set_voxel_default(&mut tmp_manager, tmp, voxel);
+          tmp_store.set_voxel(&tmp, voxel);
         }
       }
     }
@@ -565,7 +808,6 @@ impl BevyVoxelResource {
     let mut chunk = Chunk::default();
     let mid_pos = (chunk.octree.get_size() / 2) as i64;
 
-    let preview_size = s + 2;
     let min = -preview_size;
     let max = preview_size;
     for x in min..max {
@@ -576,11 +818,11 @@ impl BevyVoxelResource {
           let local_z = (mid_pos + z) as u32;
 
           let tmp_pos = [
-            p[0] as i64 + x,
-            p[1] as i64 + y,
-            p[2] as i64 + z,
+            p[0] + x,
+            p[1] + y,
+            p[2] + z,
           ];
-          let v = tmp_manager.get_voxel(&tmp_pos);
+          let v = tmp_store.get_voxel(&tmp_pos);
           chunk.octree.set_voxel(local_x, local_y, local_z, v);
         }
       }
@@ -593,31 +835,25 @@ impl BevyVoxelResource {
   pub fn get_preview_sphere(
     &self, pos: Vec3, preview: &Preview
   ) -> Chunk {
-    let scale = self.chunk_manager.voxel_scale;
-    let mul = 1.0 / scale;
-    let p = [
-      pos.x * mul,
-      pos.y * mul,
-      pos.z * mul,
-    ];
-
-    let mut tmp_manager = self.chunk_manager.clone();
+    let p = self.to_voxel_pos(pos).0;
+
     let size = preview.sphere_size;
+    let preview_size = (size as i64) + 2;
+    let tmp_store = self.preview_store(p, preview_size);
     let coords = get_sphere_coords(size);
     for c in coords.iter() {
       let tmp = [
-        p[0] as i64 + c[0],
-        p[1] as i64 + c[1],
-        p[2] as i64 + c[2],
+        p[0] + c[0],
+        p[1] + c[1],
+        p[2] + c[2],
       ];
       
-      set_voxel_default(&mut tmp_manager, tmp, preview.voxel);
+      tmp_store.set_voxel(&tmp, preview.voxel);
     }
 
     let mut chunk = Chunk::default();
     let mid_pos = (chunk.octree.get_size() / 2) as i64;
 
-    let preview_size = (size as i64) + 2;
     let min = -preview_size;
     let max = preview_size;
     for x in min..max {
@@ -628,11 +864,11 @@ impl BevyVoxelResource {
           let local_z = (mid_pos + z) as u32;
 
           let tmp_pos = [
-            p[0] as i64 + x,
-            p[1] as i64 + y,
-            p[2] as i64 + z,
+            p[0] + x,
+            p[1] + y,
+            p[2] + z,
           ];
-          let v = tmp_manager.get_voxel(&tmp_pos);
+          let v = tmp_store.get_voxel(&tmp_pos);
           chunk.octree.set_voxel(local_x, local_y, local_z, v);
         }
       }
@@ -643,25 +879,9 @@ impl BevyVoxelResource {
 
 
 
-  pub fn load_lod_meshes(&mut self, key: [i64; 3], lod: usize) -> Vec<ChunkMesh> {
-    let mut chunk_meshes = Vec::new();
-    let keys = self.get_keys_by_lod(key, lod);
-    for k in keys.iter() {
-      let chunk = load_chunk_with_lod(self, *k, lod);
-      let data = self.compute_mesh(VoxelMode::SurfaceNets, &chunk);
-      if data.positions.len() == 0 {
-        continue;
-      }
-
-      
-      chunk_meshes.push(ChunkMesh { key: *k, mesh: data });
-    }
-
-    chunk_meshes
-  }
-
   pub fn get_keys_by_lod(&self, key: [i64; 3], lod: usize) -> Vec<[i64; 3]> {
-    Utils::get_keys_by_lod(&self.ranges, &key, lod)
+    let keys = Utils::get_keys_by_lod(&self.ranges, &key, lod);
+    self.chunk_manager.bounded_keys(&keys)
   }
 
   pub fn load_chunks(
@@ -698,15 +918,18 @@ impl BevyVoxelResource {
     // }
     // self.colliders_cache.clear();
 
+    let changes = self.take_mesher_changes(chunks);
     for chunk in chunks.iter() {
-      let data = self.compute_mesh(VoxelMode::SurfaceNets, chunk);
-      if data.positions.len() == 0 {
+      let mut data = self.compute_mesh_incremental(chunk, &changes);
+      if data.is_empty() {
         continue;
       }
 
+      // Skirts only cover the cracks on screen, they stay out of the collider
       let pos = self.get_pos(chunk.key);
-      let c = self.add_collider(pos, &data);
+      let c = self.add_mesh_collider(pos, &data);
       // self.colliders_cache.push(c);
+      self.add_lod_skirts(&mut data);
       mesh_data.push((data, c));
     }
 
@@ -717,9 +940,10 @@ impl BevyVoxelResource {
   pub fn get_delta_keys_by_lod(
     &self, prev_key: &[i64; 3], key: &[i64; 3], lod: usize
   ) -> Vec<[i64; 3]> {
-    Utils::get_delta_keys_by_lod(
+    let keys = Utils::get_delta_keys_by_lod(
       &self.ranges, prev_key, key, lod
-    )
+    );
+    self.chunk_manager.bounded_keys(&keys)
   }
 
 
@@ -1,4 +1,4 @@
-mod physics;
+pub mod physics;
 mod util;
 mod functions;
 mod implement;
@@ -6,11 +6,14 @@ pub mod editstate;
 mod lod;
 
 
+use std::sync::{Arc, Mutex};
 use bevy::{prelude::*, utils::HashMap};
 use flume::{Sender, Receiver};
 use physics::Physics;
-use rapier3d::prelude::ColliderHandle;
-use voxels::{chunk::chunk_manager::{ChunkManager, Chunk}, data::voxel_octree::MeshData};
+use rapier3d::prelude::{ColliderHandle, SharedShape};
+use voxels::{chunk::{chunk_manager::{ChunkManager, Chunk}, seams::LodSeams, clipmap::{Clipmap, LOD_HYSTERESIS}, subscription::SubscriptionId}, data::{voxel_octree::MeshData, mesh_cache::MeshCache}};
+use implement::CachedMesher;
+use voxels::chunk::islands::IslandConfig;
 
 use cfg_if::cfg_if;
 
@@ -57,13 +60,32 @@ pub struct BevyVoxelResource {
   pub send_process_mesh: Sender<Chunk>,
   pub recv_process_mesh: Receiver<Chunk>,
 
-  pub send_mesh: Sender<MeshData>,
-  pub recv_mesh: Receiver<MeshData>,
+  /// Meshes with the collider shape of the lod 0 ones
+  pub send_mesh: Sender<(MeshData, Option<SharedShape>)>,
+  pub recv_mesh: Receiver<(MeshData, Option<SharedShape>)>,
 
   colliders_cache: Vec<ColliderHandle>,
   shape_state: ShapeState,
   edit_state: EditState,
   pub ranges: Vec<u32>,
+  /// Lod each chunk is meshed at, for the skirts between rings
+  pub lod_seams: LodSeams,
+  /// Lods of the chunks around the centers, by `ranges` or their LoadRanges
+  pub clipmap: Clipmap,
+  /// Meshers of the recently edited chunks, least recently used key first
+  meshers: HashMap<[i64; 3], CachedMesher>,
+  mesher_keys: Vec<[i64; 3]>,
+  /// Subscription to the chunk manager with the edits the meshers did not sync yet
+  mesher_changes: Option<SubscriptionId>,
+  /// Meshes by octree content, used by compute_mesh() and the mesh jobs
+  pub mesh_cache: Arc<Mutex<MeshCache>>,
+  /// Keys of the unloaded chunks, their graphics and colliders are to be removed
+  pub unloaded: Vec<[i64; 3]>,
+  /// Voxels of the edits outside of the world bounds, sent as EditRejected events
+  pub rejected_edits: Vec<[i64; 3]>,
+  /// Floating voxels left by the remove edits
+  pub islands: IslandConfig,
+  pub island_policy: IslandPolicy,
 }
 
 impl Default for BevyVoxelResource {
@@ -73,13 +95,24 @@ impl Default for BevyVoxelResource {
     let (send_process_mesh, recv_process_mesh) = flume::unbounded();
     let (send_mesh, recv_mesh) = flume::unbounded();
 
+    let ranges = vec![0, 1, 3, 5, 7];
     Self {
       chunk_manager: ChunkManager::default(),
       physics: Physics::default(),
       colliders_cache: Vec::new(),
       shape_state: ShapeState::Cube,
       edit_state: EditState::AddNormal,
-      ranges: vec![0, 1, 3, 5, 7],
+      clipmap: Clipmap::new(ranges.clone(), LOD_HYSTERESIS),
+      ranges: ranges,
+      lod_seams: LodSeams::default(),
+      meshers: HashMap::new(),
+      mesher_keys: Vec::new(),
+      mesher_changes: None,
+      mesh_cache: Arc::new(Mutex::new(MeshCache::default())),
+      unloaded: Vec::new(),
+      rejected_edits: Vec::new(),
+      islands: IslandConfig::default(),
+      island_policy: IslandPolicy::default(),
 
       send_key: send_key,
       recv_key: recv_key,
@@ -112,6 +145,17 @@ pub enum ShapeState {
   Sphere,
 }
 
+/// What is done with the islands left floating by the remove edits
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IslandPolicy {
+  Ignore,
+  /// Sent as FloatingIslands events
+  #[default]
+  Report,
+  /// Set to air, then sent as FloatingIslands events
+  Remove,
+}
+
 #[derive(Component, Clone)]
 pub struct Selected {
   pub pos: Option<Vec3>,
@@ -172,6 +216,14 @@ pub struct MeshComponent {
   pub added: Vec<(MeshData, ColliderHandle)>,
 }
 
+/**
+  Entity with the MeshComponent the loaded meshes of every center go to, the
+  lowest one so it stays the same while the centers spawn and despawn
+*/
+pub fn mesh_sink(entities: impl Iterator<Item = Entity>) -> Option<Entity> {
+  entities.min()
+}
+
 #[derive(Component, Debug, Clone)]
 pub struct Chunks {
   pub data: HashMap<[i64; 3], Chunk>,
@@ -202,6 +254,15 @@ impl Default for Center {
   }
 }
 
+/**
+  Rings of the chunks loaded around the Center of the entity, instead of
+  BevyVoxelResource::ranges. Chunks stay loaded while any center needs them.
+*/
+#[derive(Component, Debug, Clone)]
+pub struct LoadRanges {
+  pub ranges: Vec<u32>,
+}
+
 
 pub struct ChunkMesh {
   pub key: [i64; 3],
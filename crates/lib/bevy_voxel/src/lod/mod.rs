@@ -1,7 +1,213 @@
+use std::collections::BTreeMap;
 use bevy::prelude::*;
+use voxels::chunk::clipmap::LodObserver;
+use crate::{BevyVoxelResource, Center, Chunks, MeshComponent, LoadRanges, mesh_sink};
 
+/**
+  Loads, meshes again and unloads the chunks as the centers move, by the
+  lod transitions of BevyVoxelResource::clipmap. Every entity with a Center
+  is an observer, a chunk is unloaded once no center needs it. The unloaded
+  keys go to BevyVoxelResource::unloaded, the meshes to the mesh_sink().
+  Every lod is sent to the async loading by priority, lod 0 meshes with their
+  colliders. Without threads on wasm the lod 0 chunks are meshed at once.
+*/
 pub struct CustomPlugin;
 impl Plugin for CustomPlugin {
-  fn build(&self, _app: &mut App) {
+  fn build(&self, app: &mut App) {
+    app
+      .add_systems(Update, update_lods);
   }
-}
\ No newline at end of file
+}
+
+fn update_lods(
+  mut res: ResMut<BevyVoxelResource>,
+  changed: Query<(), Or<(Changed<Center>, Changed<LoadRanges>, Added<Chunks>)>>,
+  mut removed_centers: RemovedComponents<Center>,
+  centers: Query<(&Center, Option<&LoadRanges>)>,
+  mut sinks: Query<(Entity, &mut Chunks, &mut MeshComponent), With<Center>>,
+) {
+  // Despawned centers release their chunks
+  let removed = removed_centers.iter().count() > 0;
+  if changed.is_empty() && !removed {
+    return;
+  }
+  let res = &mut *res;
+  if res.clipmap.ranges != res.ranges {
+    res.clipmap.ranges = res.ranges.clone();
+  }
+  let observers: Vec<LodObserver> = centers
+    .iter()
+    .map(|(c, ranges)| match ranges {
+      Some(r) => LodObserver::with_ranges(c.key, r.ranges.clone()),
+      None => LodObserver::new(c.key),
+    })
+    .collect();
+  let transitions = res.clipmap.update(&observers);
+
+  // Lod 0 chunks have colliders, they stay loaded whatever the chunk budget
+  for t in transitions.iter() {
+    match t.lod() {
+      Some(0) => res.chunk_manager.hold_chunk(&t.key()),
+      _ => res.chunk_manager.release_chunk(&t.key()),
+    }
+    // Unloaded chunks no longer stitch their neighbours, even without any center left
+    if t.lod().is_none() {
+      res.lod_seams.remove(&t.key());
+      res.unloaded.push(t.key());
+    }
+  }
+
+  // The graphics are by key, so the meshes of all the centers go to a single sink
+  let sink = match mesh_sink(sinks.iter().map(|(e, ..)| e)) {
+    Some(e) => e,
+    None => return,
+  };
+  let (_, mut chunks, mut mesh_comp) = sinks.get_mut(sink).unwrap();
+
+  let mut keys_by_lod: BTreeMap<usize, Vec<[i64; 3]>> = BTreeMap::new();
+  for t in transitions.iter() {
+    match t.lod() {
+      Some(lod) => keys_by_lod.entry(lod).or_default().push(t.key()),
+      None => {
+        mesh_comp.data.remove(&t.key());
+      }
+    }
+  }
+
+  for (lod, keys) in keys_by_lod.into_iter() {
+    let keys = res.chunk_manager.bounded_keys(&keys);
+    if keys.is_empty() {
+      continue;
+    }
+    let restitch = res.lod_seams.set_lods(&keys, lod);
+    if lod == 0 && cfg!(target_arch = "wasm32") {
+      load_main_chunks(res, &keys, &mut chunks, &mut mesh_comp);
+    } else {
+      request_lod_chunks(res, &keys, lod);
+    }
+    restitch_chunks(res, &restitch, &mut mesh_comp);
+  }
+}
+
+/// Loads and meshes the lod 0 chunks with their colliders
+fn load_main_chunks(
+  res: &mut BevyVoxelResource,
+  keys: &Vec<[i64; 3]>,
+  chunks: &mut Chunks,
+  mesh_comp: &mut MeshComponent,
+) {
+  let tmp_c = res.load_chunks(keys, &chunks.data, 0);
+  for c in tmp_c.iter() {
+    chunks.data.insert(c.key, c.clone());
+  }
+  chunks.added_keys.clear();
+  chunks.added_keys.append(&mut keys.clone());
+
+  let data = res.load_mesh_data(&tmp_c);
+  for (d, handle) in data.iter() {
+    mesh_comp.data.insert(d.key, d.clone());
+    mesh_comp.added.push((d.clone(), *handle));
+  }
+}
+
+/// Meshes the loaded chunks at the lod, generates the others first
+fn request_lod_chunks(
+  res: &mut BevyVoxelResource,
+  keys: &Vec<[i64; 3]>,
+  lod: usize,
+) {
+  for key in keys.iter() {
+    // Lod 0 chunks stay in the chunk manager with their edits
+    match res.chunk_manager.get_chunk(key) {
+      Some(c) => {
+        let mut data = c.clone();
+        data.lod = lod;
+        let _ = res.send_process_mesh.send(data);
+      }
+      None => {
+        let _ = res.send_key.send((*key, lod));
+      }
+    }
+  }
+}
+
+/// Meshes the chunks again, so their skirts match the new lods of their neighbours
+fn restitch_chunks(
+  res: &mut BevyVoxelResource,
+  keys: &Vec<[i64; 3]>,
+  mesh_comp: &mut MeshComponent,
+) {
+  for key in keys.iter() {
+    let lod = match res.lod_seams.lod(key) {
+      Some(l) => l,
+      None => continue,
+    };
+    if lod == 0 && cfg!(target_arch = "wasm32") {
+      if let Some(c) = res.chunk_manager.get_chunk(key) {
+        let data = res.load_mesh_data(&vec![c.clone()]);
+        for (d, handle) in data.iter() {
+          mesh_comp.data.insert(d.key, d.clone());
+          mesh_comp.added.push((d.clone(), *handle));
+        }
+      }
+      continue;
+    }
+    request_lod_chunks(res, &vec![*key], lod);
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use bevy::prelude::*;
+  use voxels::data::voxel_octree::MeshData;
+  use crate::{BevyVoxelResource, Center, Chunks, MeshComponent, functions::receive_mesh};
+  use super::update_lods;
+
+  fn spawn_observer(app: &mut App) -> Entity {
+    app.world.spawn((Center::default(), Chunks::default(), MeshComponent::default())).id()
+  }
+
+  fn added_len(app: &App, entity: Entity) -> usize {
+    app.world.get::<MeshComponent>(entity).map_or(0, |m| m.added.len())
+  }
+
+  #[test]
+  fn test_two_observers() -> Result<(), String> {
+    let mut app = App::new();
+    app
+      .insert_resource(BevyVoxelResource::default())
+      .add_systems(Update, (update_lods, receive_mesh).chain());
+    let first = spawn_observer(&mut app);
+    let second = spawn_observer(&mut app);
+    app.update();
+
+    let res = app.world.resource::<BevyVoxelResource>();
+    assert_eq!(res.clipmap.lod(&[0, 0, 0]), Some(0));
+    let loaded = res.clipmap.len();
+    let _ = res.recv_key.drain();
+
+    // Meshes of the shared chunks are added once, to the lowest entity
+    let mut data = MeshData::default();
+    data.positions = vec![[0.0; 3]; 3];
+    data.indices = vec![0, 1, 2];
+    let _ = res.send_mesh.send((data, None));
+    app.update();
+    assert_eq!((added_len(&app, first), added_len(&app, second)), (1, 0));
+
+    // The chunks stay loaded for the other center, even without the sink
+    app.world.despawn(first);
+    app.update();
+    let res = app.world.resource::<BevyVoxelResource>();
+    assert!(res.unloaded.is_empty());
+    assert_eq!(res.clipmap.len(), loaded);
+
+    // Without any center every chunk is unloaded
+    app.world.despawn(second);
+    app.update();
+    let res = app.world.resource::<BevyVoxelResource>();
+    assert_eq!(res.unloaded.len(), loaded);
+    assert_eq!(res.clipmap.len(), 0);
+    Ok(())
+  }
+}
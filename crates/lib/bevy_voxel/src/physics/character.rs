@@ -0,0 +1,113 @@
+use bevy::prelude::*;
+use rapier3d::control::{KinematicCharacterController, CharacterAutostep, CharacterLength};
+use rapier3d::prelude::{RigidBodyHandle, ColliderHandle, Capsule, QueryFilter, InteractionGroups, Group, Isometry, Vector};
+
+/**
+  Parameters and state of a kinematic capsule moved by
+  Physics::move_character() against the chunk colliders. Lengths are in world
+  units, speeds per second and angles in radians. The position of the
+  character is the center of its capsule.
+*/
+#[derive(Component, Debug, Clone)]
+pub struct CharacterController {
+  /// Standing height, caps included
+  pub height: f32,
+  pub crouch_height: f32,
+  pub radius: f32,
+  /// Highest ledge walked onto without jumping
+  pub step_height: f32,
+  /// Steepest slope walked up, the character slides down the steeper ones
+  pub max_slope: f32,
+  /// Distance kept to the ground when walking down slopes and steps
+  pub snap_distance: f32,
+  pub jump_speed: f32,
+  pub gravity: f32,
+  /// Multiplies the walk translation while crouching
+  pub crouch_speed: f32,
+
+  pub vertical_speed: f32,
+  pub grounded: bool,
+  pub crouching: bool,
+  pub body: RigidBodyHandle,
+  pub collider: ColliderHandle,
+}
+
+impl Default for CharacterController {
+  fn default() -> Self {
+    Self {
+      height: 1.8,
+      crouch_height: 1.2,
+      radius: 0.4,
+      step_height: 0.5,
+      max_slope: 50_f32.to_radians(),
+      snap_distance: 0.3,
+      jump_speed: 6.0,
+      gravity: 20.0,
+      crouch_speed: 0.5,
+
+      vertical_speed: 0.0,
+      grounded: false,
+      crouching: false,
+      body: RigidBodyHandle::invalid(),
+      collider: ColliderHandle::invalid(),
+    }
+  }
+}
+
+impl CharacterController {
+  pub fn current_height(&self) -> f32 {
+    if self.crouching { self.crouch_height } else { self.height }
+  }
+
+  pub fn shape(&self) -> Capsule {
+    Self::capsule(self.current_height(), self.radius)
+  }
+
+  pub fn capsule(height: f32, radius: f32) -> Capsule {
+    let half_height = (height * 0.5 - radius).max(0.0);
+    Capsule::new_y(half_height, radius)
+  }
+
+  /// Collides with the chunks only, not with the character itself
+  pub fn filter(&self) -> QueryFilter {
+    QueryFilter::new()
+      .groups(InteractionGroups::new(Group::GROUP_2, Group::GROUP_1))
+      .exclude_rigid_body(self.body)
+  }
+
+  pub fn kinematic_controller(&self) -> KinematicCharacterController {
+    let autostep = if self.step_height > 0.0 {
+      Some(CharacterAutostep {
+        max_height: CharacterLength::Absolute(self.step_height),
+        min_width: CharacterLength::Absolute(self.radius * 0.5),
+        include_dynamic_bodies: false,
+      })
+    } else {
+      None
+    };
+
+    KinematicCharacterController {
+      up: Vector::y_axis(),
+      offset: CharacterLength::Absolute(0.01),
+      slide: true,
+      autostep: autostep,
+      max_slope_climb_angle: self.max_slope,
+      min_slope_slide_angle: self.max_slope,
+      snap_to_ground: Some(CharacterLength::Absolute(self.snap_distance)),
+      ..Default::default()
+    }
+  }
+}
+
+/// Movement asked for a frame
+#[derive(Debug, Clone, Default)]
+pub struct CharacterInput {
+  /// Walk translation of the frame, only the horizontal part is used
+  pub translation: Vec3,
+  pub jump: bool,
+  pub crouch: bool,
+}
+
+pub fn isometry(pos: Vec3) -> Isometry<f32> {
+  Isometry::translation(pos.x, pos.y, pos.z)
+}
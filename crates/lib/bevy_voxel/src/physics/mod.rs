@@ -1,5 +1,8 @@
+pub mod character;
+
 use bevy::prelude::*;
-use rapier3d::{prelude::{RigidBodySet, ColliderSet, PhysicsPipeline, ColliderBuilder, RigidBodyBuilder, Real, Vector, IntegrationParameters, IslandManager, MultibodyJointSet, ImpulseJointSet, NarrowPhase, BroadPhase, CCDSolver, RigidBodyHandle, Collider, ColliderHandle, InteractionGroups, QueryPipeline, Group, Point, Isometry}, na::Vector3};
+use rapier3d::{prelude::{RigidBodySet, ColliderSet, PhysicsPipeline, ColliderBuilder, RigidBodyBuilder, Real, Vector, IntegrationParameters, IslandManager, MultibodyJointSet, ImpulseJointSet, NarrowPhase, BroadPhase, CCDSolver, RigidBodyHandle, Collider, ColliderHandle, InteractionGroups, QueryPipeline, Group, Point, Isometry, SharedShape}, na::Vector3};
+use character::{CharacterController, CharacterInput, isometry};
 
 pub struct Physics {
   pub pipeline: PhysicsPipeline,
@@ -82,6 +85,96 @@ impl Physics {
     (body_handle, collider_handle)
   }
 
+  /// Adds the kinematic body and the capsule of the character, sets their handles
+  pub fn spawn_kinematic_character(&mut self, character: &mut CharacterController, pos: Vec3) {
+    let shape = character.shape();
+    let collider = ColliderBuilder::new(SharedShape::new(shape))
+      .collision_groups(InteractionGroups::new(Group::GROUP_2, Group::GROUP_1))
+      .build();
+    let rigid_body = RigidBodyBuilder::kinematic_position_based()
+      .translation(Vector3::from([pos.x, pos.y, pos.z]))
+      .build();
+    character.body = self.rigid_body_set.insert(rigid_body);
+    character.collider = self.insert_with_parent(collider, character.body);
+  }
+
+  /**
+    Moves the character by the input for dt seconds, with gravity, jumps,
+    steps, slopes and snapping to the ground. Returns the new position.
+    Standing up waits until there is room above the crouched capsule.
+  */
+  pub fn move_character(
+    &mut self,
+    character: &mut CharacterController,
+    pos: Vec3,
+    input: &CharacterInput,
+    dt: f32,
+  ) -> Vec3 {
+    let mut pos = pos;
+    if input.crouch != character.crouching {
+      let offset = (character.height - character.crouch_height) * 0.5;
+      if input.crouch {
+        character.crouching = true;
+        pos.y -= offset;
+      } else {
+        let standing = pos + Vec3::Y * offset;
+        let shape = CharacterController::capsule(character.height, character.radius);
+        let blocked = self.query_pipeline.intersection_with_shape(
+          &self.rigid_body_set, &self.collider_set, &isometry(standing), &shape, character.filter()
+        );
+        if blocked.is_none() {
+          character.crouching = false;
+          pos = standing;
+        }
+      }
+      if let Some(collider) = self.collider_set.get_mut(character.collider) {
+        collider.set_shape(SharedShape::new(character.shape()));
+      }
+    }
+
+    if character.grounded {
+      character.vertical_speed = character.vertical_speed.max(0.0);
+      if input.jump && !character.crouching {
+        character.vertical_speed = character.jump_speed;
+      }
+    }
+    character.vertical_speed -= character.gravity * dt;
+
+    let mut walk = Vec3::new(input.translation.x, 0.0, input.translation.z);
+    if character.crouching {
+      walk *= character.crouch_speed;
+    }
+    let desired = walk + Vec3::Y * character.vertical_speed * dt;
+
+    let movement = character.kinematic_controller().move_shape(
+      dt,
+      &self.rigid_body_set,
+      &self.collider_set,
+      &self.query_pipeline,
+      &character.shape(),
+      &isometry(pos),
+      Vector::new(desired.x, desired.y, desired.z),
+      character.filter(),
+      |_| {},
+    );
+    let t = movement.translation;
+    pos += Vec3::new(t.x, t.y, t.z);
+
+    // Landing or hitting a ceiling stops the vertical speed
+    character.grounded = movement.grounded;
+    if character.grounded && character.vertical_speed < 0.0 {
+      character.vertical_speed = 0.0;
+    }
+    if desired.y > 0.0 && t.y <= 0.0 {
+      character.vertical_speed = 0.0;
+    }
+
+    if let Some(body) = self.rigid_body_set.get_mut(character.body) {
+      body.set_next_kinematic_translation(Vector3::new(pos.x, pos.y, pos.z));
+    }
+    pos
+  }
+
   #[allow(dead_code)]
   pub fn insert_with_parent(&mut self, collider: Collider, handle: RigidBodyHandle) -> ColliderHandle {
     self
@@ -118,4 +211,198 @@ impl Physics {
 
     self.collider_set.insert(collider)
   }
+
+  /**
+    Collider of the parts sharing the local space of one mesh, so patching a
+    part of it only builds that part's trimesh again
+  */
+  pub fn add_compound_collider(&mut self, pos: [f32; 3], parts: &Vec<SharedShape>) -> ColliderHandle {
+    if parts.is_empty() {
+      return ColliderHandle::invalid();
+    }
+    let shapes = parts.iter().map(|p| (Isometry::identity(), p.clone())).collect();
+    self.add_shape_collider(pos, SharedShape::compound(shapes))
+  }
+
+  /// Collider of a chunk shape built off the main thread, e.g. by trimesh_shape()
+  pub fn add_shape_collider(&mut self, pos: [f32; 3], shape: SharedShape) -> ColliderHandle {
+    let mut collider = ColliderBuilder::new(shape)
+      .collision_groups(InteractionGroups::new(Group::GROUP_1, Group::GROUP_2))
+      .build();
+    collider.set_position(Isometry::from(pos));
+
+    self.collider_set.insert(collider)
+  }
+}
+
+/// Trimesh of the mesh, None without triangles
+pub fn trimesh_shape(mesh_pos: &Vec<[f32; 3]>, mesh_indices: &Vec<u32>) -> Option<SharedShape> {
+  if mesh_indices.len() < 3 {
+    return None;
+  }
+  let points = mesh_pos.iter().map(|d| Point::from([d[0], d[1], d[2]])).collect();
+  let indices = mesh_indices.chunks_exact(3).map(|i| [i[0], i[1], i[2]]).collect();
+  Some(SharedShape::trimesh(points, indices))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use bevy::prelude::Vec3;
+  use rapier3d::prelude::ColliderHandle;
+  use super::Physics;
+  use super::character::{CharacterController, CharacterInput};
+
+  const DT: f32 = 1.0 / 30.0;
+  const HALF: f32 = 20.0;
+
+  /// Two triangles of the corners, facing up when they turn counter clockwise seen from above
+  fn add_quad(physics: &mut Physics, corners: [[f32; 3]; 4]) -> ColliderHandle {
+    physics.add_collider([0.0; 3], &corners.to_vec(), &vec![0, 2, 1, 0, 3, 2])
+  }
+
+  /// Ground at y = 0 up to `x`
+  fn add_ground(physics: &mut Physics, x: f32) {
+    add_quad(physics, [[-HALF, 0.0, -HALF], [x, 0.0, -HALF], [x, 0.0, HALF], [-HALF, 0.0, HALF]]);
+  }
+
+  /// Ledge of the height from x = 2 on
+  fn add_step(physics: &mut Physics, height: f32) {
+    add_ground(physics, 2.0);
+    add_quad(physics, [[2.0, 0.0, -HALF], [2.0, height, -HALF], [2.0, height, HALF], [2.0, 0.0, HALF]]);
+    add_quad(physics, [[2.0, height, -HALF], [HALF, height, -HALF], [HALF, height, HALF], [2.0, height, HALF]]);
+  }
+
+  /// Slope of the angle in degrees from x = 1 on
+  fn add_ramp(physics: &mut Physics, angle: f32) {
+    add_ground(physics, 1.0);
+    let top = 10.0 * angle.to_radians().tan();
+    add_quad(physics, [[1.0, 0.0, -HALF], [11.0, top, -HALF], [11.0, top, HALF], [1.0, 0.0, HALF]]);
+  }
+
+  fn run(
+    physics: &mut Physics,
+    character: &mut CharacterController,
+    pos: Vec3,
+    input: &CharacterInput,
+    frames: usize,
+  ) -> Vec3 {
+    let mut pos = pos;
+    for _ in 0..frames {
+      pos = physics.move_character(character, pos, input, DT);
+      physics.step();
+    }
+    pos
+  }
+
+  /// Character dropped at the origin, once it landed
+  fn spawn(physics: &mut Physics) -> (CharacterController, Vec3) {
+    let mut character = CharacterController::default();
+    let pos = Vec3::new(0.0, character.height * 0.5 + 0.2, 0.0);
+    physics.spawn_kinematic_character(&mut character, pos);
+    physics.step();
+    let pos = run(physics, &mut character, pos, &CharacterInput::default(), 30);
+    (character, pos)
+  }
+
+  fn walk(x: f32) -> CharacterInput {
+    CharacterInput { translation: Vec3::new(x, 0.0, 0.0), ..Default::default() }
+  }
+
+  #[test]
+  fn test_grounded_and_jump() -> Result<(), String> {
+    let mut physics = Physics::default();
+    add_ground(&mut physics, HALF);
+    let (mut character, pos) = spawn(&mut physics);
+    assert!(character.grounded);
+    assert!((pos.y - 0.9).abs() < 0.05, "{:?}", pos);
+
+    let jump = CharacterInput { jump: true, ..Default::default() };
+    let mut pos = run(&mut physics, &mut character, pos, &jump, 1);
+    assert!(!character.grounded);
+    assert!(character.vertical_speed > 0.0);
+
+    // About jump_speed² / 2 gravity high, then back on the ground
+    let mut top = pos.y;
+    for _ in 0..60 {
+      pos = run(&mut physics, &mut character, pos, &CharacterInput::default(), 1);
+      top = top.max(pos.y);
+    }
+    assert!(top > 0.9 + 0.7 && top < 0.9 + 1.1, "{}", top);
+    assert!(character.grounded);
+    assert!((pos.y - 0.9).abs() < 0.05, "{:?}", pos);
+    Ok(())
+  }
+
+  #[test]
+  fn test_step_height() -> Result<(), String> {
+    // Steps below step_height are walked onto
+    let mut physics = Physics::default();
+    add_step(&mut physics, 0.3);
+    let (mut character, pos) = spawn(&mut physics);
+    let pos = run(&mut physics, &mut character, pos, &walk(0.05), 90);
+    assert!(pos.x > 2.5, "{:?}", pos);
+    assert!((pos.y - 1.2).abs() < 0.1, "{:?}", pos);
+    assert!(character.grounded);
+
+    // Higher ones stop the character
+    let mut physics = Physics::default();
+    add_step(&mut physics, 1.0);
+    let (mut character, pos) = spawn(&mut physics);
+    let pos = run(&mut physics, &mut character, pos, &walk(0.05), 90);
+    assert!(pos.x < 2.0, "{:?}", pos);
+    assert!(pos.y < 1.0, "{:?}", pos);
+    Ok(())
+  }
+
+  #[test]
+  fn test_max_slope() -> Result<(), String> {
+    let mut physics = Physics::default();
+    add_ramp(&mut physics, 30.0);
+    let (mut character, pos) = spawn(&mut physics);
+    let pos = run(&mut physics, &mut character, pos, &walk(0.05), 90);
+    assert!(pos.x > 2.5, "{:?}", pos);
+    assert!(pos.y > 0.9 + 0.8, "{:?}", pos);
+
+    // Steeper than max_slope
+    let mut physics = Physics::default();
+    add_ramp(&mut physics, 70.0);
+    let (mut character, pos) = spawn(&mut physics);
+    let pos = run(&mut physics, &mut character, pos, &walk(0.05), 90);
+    assert!(pos.x < 1.5, "{:?}", pos);
+    assert!(pos.y < 0.9 + 0.6, "{:?}", pos);
+    Ok(())
+  }
+
+  #[test]
+  fn test_crouch() -> Result<(), String> {
+    let mut physics = Physics::default();
+    add_ground(&mut physics, HALF);
+    let (mut character, pos) = spawn(&mut physics);
+
+    let crouch = CharacterInput { crouch: true, ..Default::default() };
+    let pos = run(&mut physics, &mut character, pos, &crouch, 10);
+    assert!(character.crouching);
+    assert!((pos.y - 0.6).abs() < 0.05, "{:?}", pos);
+
+    // Slower, and no jumps while crouching
+    let input = CharacterInput { translation: Vec3::new(0.05, 0.0, 0.0), jump: true, crouch: true };
+    let moved = run(&mut physics, &mut character, pos, &input, 10);
+    assert!((moved.x - pos.x - 0.25).abs() < 0.02, "{:?} {:?}", pos, moved);
+    assert!((moved.y - pos.y).abs() < 0.01, "{:?} {:?}", pos, moved);
+
+    // Standing up waits for room under the ceiling
+    let ceiling = add_quad(&mut physics, [[-HALF, 1.5, -HALF], [HALF, 1.5, -HALF], [HALF, 1.5, HALF], [-HALF, 1.5, HALF]]);
+    physics.step();
+    let pos = run(&mut physics, &mut character, moved, &CharacterInput::default(), 5);
+    assert!(character.crouching);
+    assert!((pos.y - 0.6).abs() < 0.05, "{:?}", pos);
+
+    physics.remove_collider(ceiling);
+    physics.step();
+    let pos = run(&mut physics, &mut character, pos, &CharacterInput::default(), 5);
+    assert!(!character.crouching);
+    assert!((pos.y - 0.9).abs() < 0.05, "{:?}", pos);
+    Ok(())
+  }
 }
@@ -1,5 +1,5 @@
 use bevy::prelude::*;
-use voxels::chunk::chunk_manager::{ChunkManager, Chunk};
+use voxels::chunk::{chunk_manager::{ChunkManager, Chunk}, coords::WorldPosF32};
 use crate::BevyVoxelResource;
 
 pub fn set_voxel_default(
@@ -17,18 +17,7 @@ pub fn load_chunk(
   key: [i64; 3],
   lod: usize,
 ) -> Chunk {
-  let res = resource.chunk_manager.get_chunk(&key);
-  if res.is_none() {
-    let chunk = ChunkManager::new_chunk(
-      &key, resource.chunk_manager.depth as u8,
-      lod,
-      resource.chunk_manager.noise,
-    );
-    resource.chunk_manager.set_chunk(&key, &chunk);
-    return chunk;
-  }
-
-  res.unwrap().clone()
+  resource.chunk_manager.load_chunk(&key, lod)
 }
 
 pub fn load_chunk_with_lod(
@@ -36,9 +25,7 @@ pub fn load_chunk_with_lod(
   key: [i64; 3], 
   lod: usize,
 ) -> Chunk {
-  ChunkManager::new_chunk(
-    &key, resource.chunk_manager.depth as u8, lod, resource.chunk_manager.noise
-  )
+  resource.chunk_manager.generate_chunk(&key, lod)
 }
 
 
@@ -63,8 +50,7 @@ pub fn get_near_positions(pos: Vec3, unit: f32) -> Vec<Vec3> {
 
 
 pub fn get_key(pos: Vec3, voxel_scale: f32, seamless_size: u32) -> [i64; 3] {
-  let s1 = (seamless_size as f32) / (1.0 / voxel_scale);
-  pos_to_key(pos, s1)
+  WorldPosF32([pos.x, pos.y, pos.z]).to_chunk_key(seamless_size, voxel_scale).0
 }
 
 
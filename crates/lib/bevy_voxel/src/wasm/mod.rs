@@ -51,6 +51,6 @@ fn load_mesh(
   bevy_voxel_res: ResMut<BevyVoxelResource>,
 ) {
   for data in plugin_res.recv_mesh.drain() {
-    let _ = bevy_voxel_res.send_mesh.send(data);
+    let _ = bevy_voxel_res.send_mesh.send((data, None));
   }
 }
\ No newline at end of file
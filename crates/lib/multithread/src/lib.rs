@@ -6,7 +6,7 @@ use wasm_mt_pool::prelude::*;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::spawn_local;
 use wasm_mt::utils::{console_ln, fetch_as_arraybuffer};
-use voxels::{chunk::chunk_manager::*, data::{voxel_octree::{MeshData, VoxelMode}, surface_nets::VoxelReuse}};
+use voxels::{chunk::chunk_manager::*, data::{voxel_octree::{MeshData, VoxelMode}, surface_nets::VoxelReuse, quantized_mesh::QuantizedMesh}};
 use flume::{Sender, Receiver};
 use web_sys::{CustomEvent, HtmlInputElement, CustomEventInit};
 
@@ -182,9 +182,11 @@ async fn load_data_from_wasm(
       };
   
       pool_exec!(pool, move || {
+        let size = chunk.octree.get_size();
         let mesh = compute_mesh(chunk, &colors);
 
-        let r = bincode::serialize(&mesh);
+        // Quantized to keep the message small, decoded by plugin::receive_mesh()
+        let r = bincode::serialize(&QuantizedMesh::new(&mesh, size, 1.0));
         if r.is_err() {
           console_ln!("Error encoding");
         }
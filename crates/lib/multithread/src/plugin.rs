@@ -2,7 +2,7 @@ use bevy::prelude::*;
 use flume;
 use flume::{Sender, Receiver};
 use voxels::chunk::chunk_manager::Chunk;
-use voxels::data::voxel_octree::MeshData;
+use voxels::data::{voxel_octree::MeshData, quantized_mesh::QuantizedMesh};
 use web_sys::{CustomEvent, CustomEventInit};
 use wasm_bindgen::prelude::*;
 
@@ -47,8 +47,8 @@ pub fn receive_mesh(send: Sender<MeshData>) {
 
     let data = event.detail().as_string().unwrap();
     let bytes = array_bytes::hex2bytes(data).unwrap();
-    let mesh: MeshData = bincode::deserialize(&bytes).unwrap();
-    let _ = send.send(mesh);
+    let mesh: QuantizedMesh = bincode::deserialize(&bytes).unwrap();
+    let _ = send.send(mesh.to_mesh_data());
   }) as Box<dyn FnMut(CustomEvent)>);
 
   let window = web_sys::window().unwrap();
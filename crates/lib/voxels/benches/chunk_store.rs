@@ -0,0 +1,194 @@
+use std::sync::RwLock;
+use hashbrown::HashMap;
+use voxels::{chunk::{chunk_manager::{ChunkManager, Chunk}, store::ChunkStore}, utils::grid_hashmap::{GridHashMap, ShardedGridHashMap}};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, BatchSize};
+
+const THREADS: usize = 4;
+
+fn keys(range: i64) -> Vec<[i64; 3]> {
+  let mut keys = Vec::new();
+  for x in -range..range {
+    for y in -range..range {
+      for z in -range..range {
+        keys.push([x, y, z]);
+      }
+    }
+  }
+  keys
+}
+
+pub fn bench_insert(c: &mut Criterion) {
+  let keys = keys(8);
+  let chunk = Chunk::default();
+
+  c.bench_function("hashmap_insert", |b| {
+    b.iter(|| {
+      let mut map = HashMap::new();
+      for key in keys.iter() {
+        map.insert(*key, chunk.clone());
+      }
+      black_box(map.len());
+    })
+  });
+
+  c.bench_function("grid_hashmap_insert", |b| {
+    b.iter(|| {
+      let mut map = GridHashMap::<Chunk, [i64; 3]>::new(8);
+      for key in keys.iter() {
+        map.insert(*key, chunk.clone());
+      }
+      black_box(map.len());
+    })
+  });
+
+  c.bench_function("sharded_grid_hashmap_insert", |b| {
+    b.iter(|| {
+      let map = ShardedGridHashMap::<Chunk, [i64; 3]>::default();
+      for key in keys.iter() {
+        map.insert(*key, chunk.clone());
+      }
+      black_box(map.len());
+    })
+  });
+}
+
+pub fn bench_get(c: &mut Criterion) {
+  let keys = keys(8);
+  let mut map = HashMap::new();
+  let mut grid = GridHashMap::<u8, [i64; 3]>::new(8);
+  let sharded = ShardedGridHashMap::<u8, [i64; 3]>::default();
+  for (i, key) in keys.iter().enumerate() {
+    map.insert(*key, i as u8);
+    grid.insert(*key, i as u8);
+    sharded.insert(*key, i as u8);
+  }
+
+  c.bench_function("hashmap_get", |b| {
+    b.iter(|| {
+      for key in keys.iter() {
+        black_box(map.get(key));
+      }
+    })
+  });
+
+  c.bench_function("grid_hashmap_get", |b| {
+    b.iter(|| {
+      for key in keys.iter() {
+        black_box(grid.get(key));
+      }
+    })
+  });
+
+  c.bench_function("sharded_grid_hashmap_get", |b| {
+    b.iter(|| {
+      for key in keys.iter() {
+        black_box(sharded.get(key));
+      }
+    })
+  });
+}
+
+/// Each thread reads every key and writes a quarter of them
+pub fn bench_parallel(c: &mut Criterion) {
+  let keys = keys(8);
+  let locked = RwLock::new(HashMap::new());
+  let sharded = ShardedGridHashMap::<u8, [i64; 3]>::default();
+  for key in keys.iter() {
+    locked.write().unwrap().insert(*key, 0_u8);
+    sharded.insert(*key, 0);
+  }
+
+  c.bench_function("rwlock_hashmap_parallel", |b| {
+    b.iter(|| {
+      std::thread::scope(|scope| {
+        for t in 0..THREADS {
+          let (locked, keys) = (&locked, &keys);
+          scope.spawn(move || {
+            for (i, key) in keys.iter().enumerate() {
+              if i % THREADS == t {
+                locked.write().unwrap().insert(*key, t as u8);
+              } else {
+                black_box(locked.read().unwrap().get(key).cloned());
+              }
+            }
+          });
+        }
+      });
+    })
+  });
+
+  c.bench_function("sharded_grid_hashmap_parallel", |b| {
+    b.iter(|| {
+      std::thread::scope(|scope| {
+        for t in 0..THREADS {
+          let (sharded, keys) = (&sharded, &keys);
+          scope.spawn(move || {
+            for (i, key) in keys.iter().enumerate() {
+              if i % THREADS == t {
+                sharded.write(key, |v| *v = t as u8);
+              } else {
+                black_box(sharded.get(key));
+              }
+            }
+          });
+        }
+      });
+    })
+  });
+}
+
+/// Generating chunks in parallel, cloning the manager per task vs sharing a store
+pub fn bench_chunk_generation(c: &mut Criterion) {
+  let mut manager = ChunkManager::default();
+  for key in keys(6).iter() {
+    manager.load_chunk(key, 0);
+  }
+  let keys = keys(2);
+
+  c.bench_function("chunk_manager_clone_generation", |b| {
+    b.iter(|| {
+      std::thread::scope(|scope| {
+        for t in 0..THREADS {
+          let mut tmp_manager = manager.clone();
+          let keys = &keys;
+          scope.spawn(move || {
+            for key in keys.iter().skip(t).step_by(THREADS) {
+              let offset = [key[0] + 100, key[1], key[2]];
+              black_box(tmp_manager.load_chunk(&offset, 0));
+            }
+          });
+        }
+      });
+    })
+  });
+
+  // A new empty store per iteration, so every key is generated again
+  c.bench_function("chunk_store_generation", |b| {
+    b.iter_batched(
+      || ChunkStore::new(&manager),
+      |store| {
+        std::thread::scope(|scope| {
+          for t in 0..THREADS {
+            let (store, keys) = (&store, &keys);
+            scope.spawn(move || {
+              for key in keys.iter().skip(t).step_by(THREADS) {
+                let offset = [key[0] + 100, key[1], key[2]];
+                black_box(store.load_chunk(&offset, 0));
+              }
+            });
+          }
+        });
+      },
+      BatchSize::SmallInput,
+    )
+  });
+}
+
+criterion_group!(
+  benches,
+  bench_insert,
+  bench_get,
+  bench_parallel,
+  bench_chunk_generation
+);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+use voxels::data::{
+  block_mesher::BlockMesher,
+  surface_nets::VoxelReuse,
+  voxel_octree::{VoxelOctree, VoxelMode, MeshOptions},
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const DEPTH: u8 = 5;
+
+fn terrain() -> VoxelOctree {
+  let mut octree = VoxelOctree::new(0, DEPTH);
+  let size = octree.get_size();
+  for x in 0..size {
+    for z in 0..size {
+      let height = 12 + ((x as f32 * 0.4).sin() * 4.0 + (z as f32 * 0.3).cos() * 4.0) as u32;
+      for y in 0..height {
+        octree.set_voxel(x, y, z, 1 + (x + z) as u8 % 3);
+      }
+    }
+  }
+  octree
+}
+
+/// Digs and fills the same voxel, so every iteration changes the mesh
+pub fn bench_single_voxel_edit(c: &mut Criterion) {
+  let colors = vec![[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+  let options = MeshOptions { ao: true, ..Default::default() };
+  let (x, y, z) = (15, 10, 16);
+
+  let mut octree = terrain();
+  let mut voxel_reuse = VoxelReuse::new(DEPTH as u32, 3);
+  let mut voxel = 0;
+  c.bench_function("full_remesh_single_voxel", |b| {
+    b.iter(|| {
+      octree.set_voxel(x, y, z, voxel);
+      voxel = if voxel == 0 { 1 } else { 0 };
+      let data = octree.compute_mesh_with_options(
+        VoxelMode::SurfaceNets, &mut voxel_reuse, &colors, 1.0, [0, 0, 0], 0, &options
+      );
+      black_box(data.indices.len());
+    })
+  });
+
+  let octree = terrain();
+  let mut mesher = BlockMesher::new(&octree, &colors, 1.0, [0, 0, 0], 0, &options, 8);
+  let mut voxel = 0;
+  c.bench_function("block_remesh_single_voxel", |b| {
+    b.iter(|| {
+      mesher.set_voxel(x, y, z, voxel);
+      voxel = if voxel == 0 { 1 } else { 0 };
+      mesher.update();
+      let data = mesher.mesh_data();
+      black_box(data.indices.len());
+    })
+  });
+}
+
+criterion_group!(benches, bench_single_voxel_edit);
+criterion_main!(benches);
@@ -0,0 +1,248 @@
+use hashbrown::HashSet;
+use super::chunk_manager::ChunkManager;
+use super::coords::{WorldVoxelPos, ChunkKey, LocalVoxelPos};
+use super::subscription::ChunkChangeKind;
+
+/**
+  Shared border voxel of `key` that differs from the chunk owning it.
+  Chunks overlap by offset voxels, so every border voxel is stored in the
+  owner and in up to 7 neighbouring chunks.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BorderMismatch {
+  /// World voxel position
+  pub pos: [i64; 3],
+  pub owner: [i64; 3],
+  pub voxel: u8,
+  pub key: [i64; 3],
+  pub copy: u8,
+}
+
+impl ChunkManager {
+  /// Compares the border voxels of every loaded chunk with the loaded chunks owning them
+  pub fn check_borders(&self) -> Vec<BorderMismatch> {
+    let mut keys: Vec<[i64; 3]> = self.chunks.keys().cloned().collect();
+    keys.sort();
+
+    let mut mismatches = Vec::new();
+    for key in keys.iter() {
+      mismatches.append(&mut self.check_chunk_borders(key));
+    }
+    mismatches
+  }
+
+  /// Same as check_borders() for the copies stored in one chunk
+  pub fn check_chunk_borders(&self, key: &[i64; 3]) -> Vec<BorderMismatch> {
+    let mut mismatches = Vec::new();
+    let chunk = match self.get_chunk(key) {
+      Some(c) => c,
+      None => return mismatches,
+    };
+
+    let chunk_size = self.chunk_size;
+    let seamless_size = self.seamless_size();
+    let origin = ChunkKey(*key).origin(seamless_size).0;
+    for x in 0..chunk_size {
+      for y in 0..chunk_size {
+        for z in 0..chunk_size {
+          // Voxels below seamless_size in every axis are owned by this chunk
+          if x < seamless_size && y < seamless_size && z < seamless_size {
+            continue;
+          }
+
+          let pos = WorldVoxelPos([
+            origin[0] + x as i64, origin[1] + y as i64, origin[2] + z as i64
+          ]);
+          let owner = pos.to_chunk_key(seamless_size);
+          let owner_chunk = match self.get_chunk(&owner.0) {
+            Some(c) => c,
+            None => continue,
+          };
+
+          let local = match pos.to_local(&owner, chunk_size, seamless_size) {
+            Some(l) => l.0,
+            None => continue,
+          };
+          let voxel = owner_chunk.octree.get_voxel(local[0], local[1], local[2]);
+          let copy = chunk.octree.get_voxel(x, y, z);
+          if voxel != copy {
+            mismatches.push(BorderMismatch {
+              pos: pos.0,
+              owner: owner.0,
+              voxel: voxel,
+              key: *key,
+              copy: copy,
+            });
+          }
+        }
+      }
+    }
+    mismatches
+  }
+
+  /**
+    Writes the owner's voxel to the diverged copies. The copy is kept instead
+    if it was modified while the owner was not, e.g. the owner was generated
+    again after an edit on the border.
+    Returns the number of repaired voxel positions.
+  */
+  pub fn repair_borders(&mut self) -> usize {
+    let mut repaired = HashSet::new();
+    for m in self.check_borders().iter() {
+      if repaired.contains(&m.pos) {
+        continue;
+      }
+
+      let owner_default = self.chunks.get(&m.owner).map_or(true, |c| c.is_default);
+      let copy_default = self.chunks.get(&m.key).map_or(true, |c| c.is_default);
+      let voxel = if owner_default && !copy_default { m.copy } else { m.voxel };
+      self.sync_border_voxel(&m.pos, voxel);
+      repaired.insert(m.pos);
+    }
+    repaired.len()
+  }
+
+  /**
+    Writes every border voxel of the chunk to the loaded neighbours sharing
+    it, so the chunk is authoritative over its whole octree.
+    Returns the number of repaired voxel positions.
+  */
+  pub fn repair_chunk_borders(&mut self, key: &[i64; 3]) -> usize {
+    let chunk = match self.get_chunk(key) {
+      Some(c) => c.clone(),
+      None => return 0,
+    };
+
+    let offset = self.offset;
+    let seamless_size = self.seamless_size();
+    let origin = ChunkKey(*key).origin(seamless_size).0;
+    let mut repaired = 0;
+    for x in 0..self.chunk_size {
+      for y in 0..self.chunk_size {
+        for z in 0..self.chunk_size {
+          let shared = [x, y, z].iter().any(|l| *l < offset || *l >= seamless_size);
+          if !shared {
+            continue;
+          }
+
+          let pos = [origin[0] + x as i64, origin[1] + y as i64, origin[2] + z as i64];
+          let voxel = chunk.octree.get_voxel(x, y, z);
+          if self.sync_border_voxel(&pos, voxel) > 0 {
+            repaired += 1;
+          }
+        }
+      }
+    }
+    repaired
+  }
+
+  /// Sets the voxel in the loaded chunks containing it, returns the number of chunks changed
+  fn sync_border_voxel(&mut self, pos: &[i64; 3], voxel: u8) -> usize {
+    let mut changed = 0;
+    let coords = WorldVoxelPos(*pos).chunk_coords(self.chunk_size, self.seamless_size());
+    for (ChunkKey(key), LocalVoxelPos(local)) in coords.iter() {
+      let chunk = match self.chunks.get_mut(key) {
+        Some(c) => c,
+        None => continue,
+      };
+      if chunk.octree.get_voxel(local[0], local[1], local[2]) == voxel {
+        continue;
+      }
+
+      chunk.octree.set_voxel(local[0], local[1], local[2], voxel);
+      chunk.is_default = false;
+      self.cache.pin(key);
+      self.notify(key, ChunkChangeKind::Edited, *local, *local);
+      changed += 1;
+    }
+    changed
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::adjacent_keys;
+
+  fn test_manager() -> ChunkManager {
+    let mut manager = ChunkManager::default();
+    for key in adjacent_keys(&[0, 0, 0], 1, true).iter() {
+      manager.load_chunk(key, 0);
+    }
+    for x in -5..20 {
+      manager.set_voxel2(&[x, 13, -1], 4);
+    }
+    manager
+  }
+
+  #[test]
+  fn test_check_borders() -> Result<(), String> {
+    let mut manager = test_manager();
+    assert_eq!(manager.check_borders(), vec![]);
+
+    // [14, 3, 3] is owned by [1, 0, 0], [0, 0, 0] has a copy at local [14, 3, 3]
+    let owner_voxel = manager.get_voxel(&[14, 3, 3]);
+    manager.get_chunk_mut(&[0, 0, 0]).unwrap().octree.set_voxel(14, 3, 3, 7);
+    let expected = BorderMismatch {
+      pos: [14, 3, 3],
+      owner: [1, 0, 0],
+      voxel: owner_voxel,
+      key: [0, 0, 0],
+      copy: 7,
+    };
+    assert_eq!(manager.check_borders(), vec![expected]);
+    assert_eq!(manager.check_chunk_borders(&[0, 0, 0]), vec![expected]);
+    assert_eq!(manager.check_chunk_borders(&[1, 0, 0]), vec![]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_repair_borders() -> Result<(), String> {
+    let mut manager = test_manager();
+
+    // Both unmodified, the owner wins
+    let owner_voxel = manager.get_voxel(&[14, 3, 3]);
+    manager.get_chunk_mut(&[0, 0, 0]).unwrap().octree.set_voxel(14, 3, 3, 7);
+    assert_eq!(manager.repair_borders(), 1);
+    assert_eq!(manager.check_borders(), vec![]);
+    assert_eq!(manager.get_chunk(&[0, 0, 0]).unwrap().octree.get_voxel(14, 3, 3), owner_voxel);
+
+    // Corner shared by 8 chunks, the modified copy wins over the unmodified owner [1, 1, 1]
+    let chunk = manager.get_chunk_mut(&[0, 0, 0]).unwrap();
+    chunk.octree.set_voxel(15, 15, 15, 9);
+    chunk.is_default = false;
+    assert!(manager.get_chunk(&[1, 1, 1]).unwrap().is_default);
+
+    assert_eq!(manager.repair_borders(), 1);
+    assert_eq!(manager.check_borders(), vec![]);
+    for (key, local) in WorldVoxelPos([15, 15, 15]).chunk_coords(16, 14).iter() {
+      let chunk = manager.get_chunk(&key.0).unwrap();
+      assert_eq!(chunk.octree.get_voxel(local.0[0], local.0[1], local.0[2]), 9);
+    }
+    assert_eq!(manager.repair_borders(), 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_repair_chunk_borders() -> Result<(), String> {
+    let mut manager = test_manager();
+    let id = manager.subscribe([-1, -1, -1], [1, 1, 1]);
+
+    let chunk = manager.get_chunk_mut(&[0, 0, 0]).unwrap();
+    chunk.octree.set_voxel(14, 3, 3, 7);
+    chunk.octree.set_voxel(0, 0, 5, 8);
+    chunk.octree.set_voxel(5, 5, 5, 6);
+
+    assert_eq!(manager.repair_chunk_borders(&[0, 0, 0]), 2);
+    assert_eq!(manager.check_borders(), vec![]);
+    assert_eq!(manager.get_voxel(&[14, 3, 3]), 7);
+    assert_eq!(manager.get_voxel(&[0, 0, 5]), 8);
+    assert_eq!(manager.get_chunk(&[-1, -1, 0]).unwrap().octree.get_voxel(14, 14, 5), 8);
+
+    let mut keys: Vec<[i64; 3]> = manager.take_changes(id).iter().map(|c| c.key).collect();
+    keys.sort();
+    assert_eq!(keys, vec![[-1, -1, 0], [-1, 0, 0], [0, -1, 0], [1, 0, 0]]);
+    Ok(())
+  }
+}
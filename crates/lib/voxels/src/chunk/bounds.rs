@@ -0,0 +1,141 @@
+/**
+  Limits of the world in world voxel positions(inclusive). Unbounded by
+  default, voxels outside are always air and can't be edited.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorldBounds {
+  pub min: [i64; 3],
+  pub max: [i64; 3],
+  /// Voxel filling the lowest layer(min y), it can't be edited
+  pub bedrock: Option<u8>,
+}
+
+impl Default for WorldBounds {
+  fn default() -> Self {
+    WorldBounds {
+      min: [i64::MIN; 3],
+      max: [i64::MAX; 3],
+      bedrock: None,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditError {
+  /// Position outside of the bounds or on the bedrock layer
+  OutOfBounds([i64; 3]),
+}
+
+impl WorldBounds {
+  pub fn with_xz(mut self, min: [i64; 2], max: [i64; 2]) -> Self {
+    self.min[0] = min[0];
+    self.min[2] = min[1];
+    self.max[0] = max[0];
+    self.max[2] = max[1];
+    self
+  }
+
+  pub fn with_y(mut self, min: i64, max: i64) -> Self {
+    self.min[1] = min;
+    self.max[1] = max;
+    self
+  }
+
+  /// Ignored if min y is unbounded
+  pub fn with_bedrock(mut self, voxel: u8) -> Self {
+    self.bedrock = Some(voxel);
+    self
+  }
+
+  pub fn contains(&self, pos: &[i64; 3]) -> bool {
+    (0..3).all(|i| pos[i] >= self.min[i] && pos[i] <= self.max[i])
+  }
+
+  pub fn is_bedrock(&self, pos: &[i64; 3]) -> bool {
+    self.bedrock.is_some() && self.min[1] != i64::MIN && pos[1] == self.min[1]
+  }
+
+  pub fn is_editable(&self, pos: &[i64; 3]) -> bool {
+    self.contains(pos) && !self.is_bedrock(pos)
+  }
+
+  /// Same as is_editable() for every position of the inclusive box
+  pub fn is_editable_box(&self, min: &[i64; 3], max: &[i64; 3]) -> bool {
+    self.contains(min) && self.contains(max) && !self.is_bedrock(min)
+  }
+
+  /// Generated voxel clamped to the bounds
+  pub fn voxel(&self, pos: &[i64; 3], voxel: u8) -> u8 {
+    if !self.contains(pos) {
+      return 0;
+    }
+    match self.bedrock {
+      Some(bedrock) if self.is_bedrock(pos) => bedrock,
+      _ => voxel,
+    }
+  }
+
+  /// True if any voxel of the chunk is inside the bounds
+  pub fn contains_key(&self, key: &[i64; 3], chunk_size: u32, seamless_size: u32) -> bool {
+    (0..3).all(|i| {
+      let min = key[i].saturating_mul(seamless_size as i64);
+      let max = min.saturating_add(chunk_size as i64 - 1);
+      max >= self.min[i] && min <= self.max[i]
+    })
+  }
+
+  pub fn filter_keys(
+    &self, keys: &Vec<[i64; 3]>, chunk_size: u32, seamless_size: u32
+  ) -> Vec<[i64; 3]> {
+    keys
+      .iter()
+      .filter(|k| self.contains_key(k, chunk_size, seamless_size))
+      .cloned()
+      .collect()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_world_bounds() -> Result<(), String> {
+    let bounds = WorldBounds::default();
+    assert!(bounds.contains(&[i64::MAX, i64::MIN, 0]));
+    assert!(bounds.contains_key(&[i64::MAX / 14, -5, 0], 16, 14));
+    assert_eq!(bounds.voxel(&[0, i64::MIN, 0], 1), 1);
+
+    let bounds = WorldBounds::default()
+      .with_xz([-20, -10], [19, 9])
+      .with_y(-16, 31)
+      .with_bedrock(5);
+    assert!(bounds.contains(&[-20, -16, 9]));
+    assert!(!bounds.contains(&[20, 0, 0]));
+    assert!(!bounds.contains(&[0, 32, 0]));
+    assert!(!bounds.is_editable(&[0, -16, 0]));
+    assert!(bounds.is_editable(&[0, -15, 0]));
+    assert!(bounds.is_editable_box(&[-20, -15, -10], &[19, 31, 9]));
+    assert!(!bounds.is_editable_box(&[-21, -15, -10], &[19, 31, 9]));
+    assert!(!bounds.is_editable_box(&[0, -16, 0], &[1, 1, 1]));
+
+    assert_eq!(bounds.voxel(&[0, -16, 0], 1), 5);
+    assert_eq!(bounds.voxel(&[0, -17, 0], 1), 0);
+    assert_eq!(bounds.voxel(&[0, 0, 10], 1), 0);
+    assert_eq!(bounds.voxel(&[0, 0, 0], 1), 1);
+
+    // Key 1 covers 14..29 and key -2 covers -28..-13
+    assert!(bounds.contains_key(&[1, 0, 0], 16, 14));
+    assert!(!bounds.contains_key(&[2, 0, 0], 16, 14));
+    assert!(bounds.contains_key(&[-2, 0, 0], 16, 14));
+    assert!(!bounds.contains_key(&[-3, 0, 0], 16, 14));
+    assert!(!bounds.contains_key(&[0, 3, 0], 16, 14));
+    assert!(bounds.contains_key(&[0, -2, 0], 16, 14));
+    assert!(!bounds.contains_key(&[0, -3, 0], 16, 14));
+
+    let keys = vec![[0, 0, 0], [0, 5, 0], [-3, 0, 0]];
+    assert_eq!(bounds.filter_keys(&keys, 16, 14), vec![[0, 0, 0]]);
+    Ok(())
+  }
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use hashbrown::{HashMap, HashSet};
+
+/**
+  Limit of the chunks kept by the ChunkManager before the least recently
+  used unmodified chunks are evicted. Modified chunks are never evicted, so
+  they can go beyond the budget.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ChunkBudget {
+  #[default]
+  Unlimited,
+  /// Max number of chunks
+  Count(usize),
+  /// Max estimated memory of the chunks in bytes
+  Bytes(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct ChunkCacheStats {
+  /// Requested chunks that were already loaded
+  pub hits: u64,
+  /// Requested chunks that had to be generated
+  pub misses: u64,
+  pub evictions: u64,
+  /// Modified chunks excluded from the eviction
+  pub pinned: usize,
+  /// Chunks in use excluded from the eviction, see ChunkCache::hold()
+  pub held: usize,
+  pub len: usize,
+  /// Estimated memory of the chunks in bytes
+  pub bytes: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+  tick: u64,
+  bytes: usize,
+  pinned: bool,
+}
+
+/**
+  Keeps the usage order of the chunks, the chunks themselves are still owned
+  by ChunkManager::chunks. Pinned and held entries are counted for the memory
+  but are not part of the usage order.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct ChunkCache {
+  pub budget: ChunkBudget,
+  tick: u64,
+  order: BTreeMap<u64, [i64; 3]>,
+  entries: HashMap<[i64; 3], Entry>,
+  /// Keys in use, can be held before their chunk is loaded
+  held: HashSet<[i64; 3]>,
+  bytes: usize,
+  hits: u64,
+  misses: u64,
+  evictions: u64,
+}
+
+impl ChunkCache {
+  pub fn new(budget: ChunkBudget) -> Self {
+    ChunkCache { budget: budget, ..Default::default() }
+  }
+
+  /// Marks the chunk as the most recently used, updating its memory estimate
+  pub fn touch(&mut self, key: &[i64; 3], bytes: usize, pinned: bool) {
+    self.tick += 1;
+    if let Some(entry) = self.entries.get(key) {
+      self.order.remove(&entry.tick);
+      self.bytes -= entry.bytes;
+    }
+
+    let entry = Entry { tick: self.tick, bytes: bytes, pinned: pinned };
+    if !pinned && !self.held.contains(key) {
+      self.order.insert(self.tick, *key);
+    }
+    self.entries.insert(*key, entry);
+    self.bytes += bytes;
+  }
+
+  /// Excludes the chunk from the eviction
+  pub fn pin(&mut self, key: &[i64; 3]) {
+    if let Some(entry) = self.entries.get_mut(key) {
+      if !entry.pinned {
+        self.order.remove(&entry.tick);
+        entry.pinned = true;
+      }
+    }
+  }
+
+  /**
+    Excludes the chunk from the eviction until released, e.g. while it is
+    meshed with a collider. Unlike pin(), it is kept when removed.
+  */
+  pub fn hold(&mut self, key: &[i64; 3]) {
+    self.held.insert(*key);
+    if let Some(entry) = self.entries.get(key) {
+      self.order.remove(&entry.tick);
+    }
+  }
+
+  /// Makes a held chunk evictable again, as used when it was last touched
+  pub fn release(&mut self, key: &[i64; 3]) {
+    if !self.held.remove(key) {
+      return;
+    }
+    if let Some(entry) = self.entries.get(key) {
+      if !entry.pinned {
+        self.order.insert(entry.tick, *key);
+      }
+    }
+  }
+
+  pub fn is_held(&self, key: &[i64; 3]) -> bool {
+    self.held.contains(key)
+  }
+
+  pub fn remove(&mut self, key: &[i64; 3]) {
+    if let Some(entry) = self.entries.remove(key) {
+      self.order.remove(&entry.tick);
+      self.bytes -= entry.bytes;
+    }
+  }
+
+  /// Least recently used chunk that is not pinned
+  pub fn oldest(&self) -> Option<[i64; 3]> {
+    self.order.values().next().cloned()
+  }
+
+  pub fn over_budget(&self) -> bool {
+    match self.budget {
+      ChunkBudget::Unlimited => false,
+      ChunkBudget::Count(count) => self.entries.len() > count,
+      ChunkBudget::Bytes(bytes) => self.bytes > bytes,
+    }
+  }
+
+  pub fn hit(&mut self) {
+    self.hits += 1;
+  }
+
+  pub fn miss(&mut self) {
+    self.misses += 1;
+  }
+
+  pub fn evicted(&mut self) {
+    self.evictions += 1;
+  }
+
+  /// Forgets every chunk, the stats are kept
+  pub fn clear(&mut self) {
+    self.order.clear();
+    self.entries.clear();
+    self.bytes = 0;
+  }
+
+  pub fn stats(&self) -> ChunkCacheStats {
+    ChunkCacheStats {
+      hits: self.hits,
+      misses: self.misses,
+      evictions: self.evictions,
+      pinned: self.entries.values().filter(|e| e.pinned).count(),
+      held: self.entries.keys().filter(|k| self.held.contains(*k)).count(),
+      len: self.entries.len(),
+      bytes: self.bytes,
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cache_order() -> Result<(), String> {
+    let mut cache = ChunkCache::new(ChunkBudget::Count(2));
+    cache.touch(&[0, 0, 0], 10, false);
+    cache.touch(&[1, 0, 0], 10, false);
+    cache.touch(&[2, 0, 0], 10, true);
+    assert!(cache.over_budget());
+    assert_eq!(cache.oldest(), Some([0, 0, 0]));
+
+    cache.touch(&[0, 0, 0], 20, false);
+    assert_eq!(cache.oldest(), Some([1, 0, 0]));
+
+    cache.pin(&[1, 0, 0]);
+    assert_eq!(cache.oldest(), Some([0, 0, 0]));
+
+    cache.remove(&[0, 0, 0]);
+    assert_eq!(cache.oldest(), None);
+    assert!(!cache.over_budget());
+
+    let stats = cache.stats();
+    assert_eq!(stats.len, 2);
+    assert_eq!(stats.pinned, 2);
+    assert_eq!(stats.bytes, 20);
+    Ok(())
+  }
+
+  #[test]
+  fn test_cache_hold() -> Result<(), String> {
+    let mut cache = ChunkCache::new(ChunkBudget::Count(1));
+    cache.hold(&[1, 0, 0]);
+    cache.touch(&[0, 0, 0], 10, false);
+    cache.touch(&[1, 0, 0], 10, false);
+    cache.hold(&[0, 0, 0]);
+    assert!(cache.over_budget());
+    assert_eq!(cache.oldest(), None);
+    assert_eq!(cache.stats().held, 2);
+
+    // Released in the order they were used
+    cache.release(&[1, 0, 0]);
+    cache.release(&[0, 0, 0]);
+    assert_eq!(cache.oldest(), Some([0, 0, 0]));
+    cache.release(&[0, 0, 0]);
+    assert_eq!(cache.oldest(), Some([0, 0, 0]));
+    cache.remove(&[0, 0, 0]);
+    assert_eq!(cache.oldest(), Some([1, 0, 0]));
+
+    // Pinned stays out of the order when released
+    cache.hold(&[1, 0, 0]);
+    cache.pin(&[1, 0, 0]);
+    cache.release(&[1, 0, 0]);
+    assert_eq!(cache.oldest(), None);
+    assert_eq!(cache.stats().pinned, 1);
+    Ok(())
+  }
+}
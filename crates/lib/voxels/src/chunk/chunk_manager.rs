@@ -1,4 +1,8 @@
-use crate::{data::voxel_octree::{VoxelOctree, ParentValueType}, utils::get_chunk_coords};
+use crate::data::{voxel_octree::{VoxelOctree, ParentValueType, LodPolicy, MeshOptions}, csg::CsgOp, surface_nets::AoBorder};
+use super::coords::{WorldVoxelPos, ChunkKey, LocalVoxelPos};
+use super::cache::{ChunkCache, ChunkBudget, ChunkCacheStats};
+use super::bounds::{WorldBounds, EditError};
+use super::subscription::{ChunkSubscriptions, ChunkChange, ChunkChangeKind, SubscriptionId};
 use super::*;
 use hashbrown::HashMap;
 use noise::*;
@@ -39,18 +43,6 @@ pub const DEFAULT_COLOR_PALETTE: [[f32; 3]; 255] = [
   [0.13, 0.00, 0.93], [0.00, 0.00, 0.80], [0.13, 0.00, 0.67], [0.00, 0.00, 0.53], [0.13, 0.00, 0.40], [0.00, 0.00, 0.27], [0.13, 0.00, 0.13]
 ];
 
-#[derive(Default)]
-pub struct LoadedChunk {
-  pub key: [u32; 3],
-  pub ttl: f32,
-}
-
-#[derive(Default)]
-pub struct SubscribeData {
-  pub chunks: HashMap<[u32; 3], VoxelOctree>,
-  pub rays: Vec<[f32; 3]>,
-}
-
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum ChunkMode {
   None,
@@ -75,6 +67,13 @@ pub struct Chunk {
   pub is_default: bool,
 }
 
+impl Chunk {
+  /// Estimated memory used by the chunk in bytes
+  pub fn memory_size(&self) -> usize {
+    std::mem::size_of::<Chunk>() + self.octree.heap_size()
+  }
+}
+
 impl Default for Chunk {
   fn default() -> Chunk {
     Chunk {
@@ -100,6 +99,11 @@ pub struct ChunkManager {
   pub voxel_scale: f32,
   pub range: u8,
   pub colors: Vec<[f32; 3]>,
+  pub lod_policy: LodPolicy,
+  pub cache: ChunkCache,
+  pub subscriptions: ChunkSubscriptions,
+  pub bounds: WorldBounds,
+  pub mesh_options: MeshOptions,
 }
 
 impl Default for ChunkManager {
@@ -109,7 +113,7 @@ impl Default for ChunkManager {
     // let voxel_reuse = VoxelReuse::new(depth, loop_count);
     
     let noise = OpenSimplex::new().set_seed(1234);
-    let offset = 2;
+    let offset = CHUNK_OFFSET;
     let chunk_size = 2_i32.pow(depth) as u32;
 
     ChunkManager {
@@ -123,6 +127,11 @@ impl Default for ChunkManager {
       voxel_scale: 1.0,
       range: 1,
       colors: DEFAULT_COLOR_PALETTE.to_vec(),
+      lod_policy: LodPolicy::default(),
+      cache: ChunkCache::default(),
+      subscriptions: ChunkSubscriptions::default(),
+      bounds: WorldBounds::default(),
+      mesh_options: MeshOptions::default(),
     }
   }
 }
@@ -136,7 +145,7 @@ impl ChunkManager {
     colors: Vec<[f32; 3]>,  
   ) -> Self {
     let noise = OpenSimplex::new().set_seed(1234);
-    let offset = 2;
+    let offset = CHUNK_OFFSET;
     let chunk_size = 2_i32.pow(depth) as u32;
 
     ChunkManager {
@@ -150,6 +159,11 @@ impl ChunkManager {
       voxel_scale: voxel_scale,
       range: range,
       colors: colors,
+      lod_policy: LodPolicy::default(),
+      cache: ChunkCache::default(),
+      subscriptions: ChunkSubscriptions::default(),
+      bounds: WorldBounds::default(),
+      mesh_options: MeshOptions::default(),
     }
   }
 /* 
@@ -217,54 +231,125 @@ impl ChunkManager {
     return keys;
   }
  */
+  /// Same as try_set_voxel(), nothing is modified if outside of the bounds
   pub fn set_voxel2(&mut self, pos: &[i64; 3], voxel: u8) -> Vec<([i64; 3], Chunk)> {
+    self.try_set_voxel(pos, voxel).unwrap_or_default()
+  }
+
+  /**
+    Sets the voxel in every chunk containing the position(including the
+    shared borders), missing chunks are generated first.
+    Returns the modified chunks, or an error if outside of the bounds.
+  */
+  pub fn try_set_voxel(
+    &mut self, pos: &[i64; 3], voxel: u8
+  ) -> Result<Vec<([i64; 3], Chunk)>, EditError> {
+    if !self.bounds.is_editable(pos) {
+      return Err(EditError::OutOfBounds(*pos));
+    }
+
     let mut chunks = Vec::new();
     let chunk_size = self.chunk_size;
     let seamless_size = self.seamless_size();
 
-    let coords = get_chunk_coords(pos, chunk_size, seamless_size);
-    for coord in coords.iter() {
-      let key = &coord.key;
-      let local = &coord.local;
-
+    let coords = WorldVoxelPos(*pos).chunk_coords(chunk_size, seamless_size);
+    for (ChunkKey(key), LocalVoxelPos(local)) in coords.iter() {
       // Refactor: Chunk already have keys, remove mapping here later
 
       if let Some(chunk) = self.get_chunk_mut(key) {
         chunk.octree.set_voxel(local[0], local[1], local[2], voxel);
+        chunk.is_default = false;
         chunks.push((key.clone(), chunk.clone()));
+        self.cache.pin(key);
       } else {
-        let mut chunk = ChunkManager::new_chunk(
-          &key, self.depth as u8, 0, self.noise
-        );
+        let mut chunk = self.generate_chunk(key, 0);
         chunk.octree.set_voxel(local[0], local[1], local[2], voxel);
+        chunk.is_default = false;
         self.set_chunk(key, &chunk);
         chunks.push((key.clone(), chunk.clone()));
       }
+      self.notify(key, ChunkChangeKind::Edited, *local, *local);
     }
-    chunks
+    Ok(chunks)
   }
 
   /**
-    Returns 0 if the chunk is not loaded containing the coordinate
+    Applies a boolean operation of the stamp with its origin at world voxel
+    position `pos`. Every chunk overlapping the stamp is edited(including the
+    shared borders), missing chunks are generated first.
+    Returns only the chunks that were modified, or an error if any part
+    of the stamp is outside of the bounds.
    */
-  pub fn get_voxel(&self, pos: &[i64; 3]) -> u8 {
-    let seamless_size = self.seamless_size();
-    let key = voxel_pos_to_key(pos, seamless_size);
-    // let key = world_pos_to_key(pos, seamless_size);
-    
-    let octree = match self.get_octree(&pos) {
-      Some(o) => o,
-      None => return 0
-    };
+  pub fn apply_csg(
+    &mut self, stamp: &VoxelOctree, pos: &[i64; 3], op: CsgOp
+  ) -> Result<Vec<([i64; 3], Chunk)>, EditError> {
+    let mut chunks = Vec::new();
+    let seamless_size = self.seamless_size() as i64;
+    let chunk_size = self.chunk_size as i64;
+    let stamp_size = stamp.get_size() as i64;
+    let policy = self.lod_policy;
+
+    let end = [pos[0] + stamp_size - 1, pos[1] + stamp_size - 1, pos[2] + stamp_size - 1];
+    if !self.bounds.is_editable_box(pos, &end) {
+      return Err(EditError::OutOfBounds(*pos));
+    }
 
-    let sizei64 = seamless_size as i64;
-    let local_x = pos[0] - (key[0] * sizei64);
-    let local_y = pos[1] - (key[1] * sizei64);
-    let local_z = pos[2] - (key[2] * sizei64);
+    let mut min = [0; 3];
+    let mut max = [0; 3];
+    for i in 0..3 {
+      min[i] = (pos[i] - chunk_size).div_euclid(seamless_size) + 1;
+      max[i] = (pos[i] + stamp_size - 1).div_euclid(seamless_size);
+    }
 
-    // println!("key1 {:?} local {} {} {}", key, local_x, local_y, local_z);
+    for x in min[0]..max[0] + 1 {
+      for y in min[1]..max[1] + 1 {
+        for z in min[2]..max[2] + 1 {
+          let key = [x, y, z];
+          let offset = [
+            pos[0] - x * seamless_size,
+            pos[1] - y * seamless_size,
+            pos[2] - z * seamless_size,
+          ];
+
+          let modified = if let Some(chunk) = self.get_chunk_mut(&key) {
+            let modified = chunk.octree.apply_csg(stamp, offset, op, policy);
+            if modified {
+              chunk.is_default = false;
+              chunks.push((key, chunk.clone()));
+              self.cache.pin(&key);
+            }
+            modified
+          } else {
+            let mut chunk = self.generate_chunk(&key, 0);
+            let modified = chunk.octree.apply_csg(stamp, offset, op, policy);
+            if modified {
+              chunk.is_default = false;
+              self.set_chunk(&key, &chunk);
+              chunks.push((key, chunk));
+            }
+            modified
+          };
+
+          if modified {
+            let mut start = [0; 3];
+            let mut end = [0; 3];
+            for i in 0..3 {
+              start[i] = offset[i].max(0) as u32;
+              end[i] = (offset[i] + stamp_size - 1).min(chunk_size - 1) as u32;
+            }
+            self.notify(&key, ChunkChangeKind::Edited, start, end);
+          }
+        }
+      }
+    }
+    Ok(chunks)
+  }
 
-    octree.get_voxel(local_x as u32, local_y as u32, local_z as u32)
+  /**
+    Returns 0 if the chunk is not loaded containing the coordinate
+   */
+  pub fn get_voxel(&self, pos: &[i64; 3]) -> u8 {
+    self.get_voxel_safe(pos).unwrap_or(0)
   }
 
   /**
@@ -272,57 +357,105 @@ impl ChunkManager {
    */
   pub fn get_voxel_safe(&self, pos: &[i64; 3]) -> Option<u8> {
     let seamless_size = self.seamless_size();
-    let key = voxel_pos_to_key(pos, seamless_size);
-    
-    let octree = match self.get_octree(&pos) {
-      Some(o) => o,
-      None => return None
-    };
+    let pos = WorldVoxelPos(*pos);
+    let key = pos.to_chunk_key(seamless_size);
 
-    let sizei64 = seamless_size as i64;
-    let local_x = pos[0] - (key[0] * sizei64);
-    let local_y = pos[1] - (key[1] * sizei64);
-    let local_z = pos[2] - (key[2] * sizei64);
-
-    // println!("key1 {:?} local {} {} {}", key, local_x, local_y, local_z);
+    let chunk = self.get_chunk(&key.0)?;
+    let local = pos.to_local(&key, self.chunk_size, seamless_size)?.0;
+    Some(chunk.octree.get_voxel(local[0], local[1], local[2]))
+  }
 
-    Some(octree.get_voxel(local_x as u32, local_y as u32, local_z as u32))
+  pub fn seamless_size(&self) -> u32 {
+    self.chunk_size - self.offset
   }
 
-  fn get_octree(&self, pos: &[i64; 3]) -> Option<&VoxelOctree> {
-    let seamless_size = self.seamless_size();
-    let key = &voxel_pos_to_key(pos, seamless_size);
-    // let key = &world_pos_to_key(pos, seamless_size);
-    // println!("get_octree() Key {:?}", key);
-    let chunk = match self.get_chunk(key) {
-      Some(o) => o,
-      None => return None,
-    };
-    Some(&chunk.octree)
+  /// Mesh options with the offset of the chunks, to mesh them at their world position
+  pub fn mesh_options(&self) -> MeshOptions {
+    MeshOptions { chunk_offset: self.offset, ..self.mesh_options }
   }
 
-  pub fn seamless_size(&self) -> u32 {
-    self.chunk_size - self.offset
+  /**
+    Voxels of the neighbour chunks one past each side of the chunk, for the
+    ambient occlusion of its border grids. Unloaded neighbours are air.
+  */
+  pub fn ao_border(&self, key: &[i64; 3]) -> AoBorder {
+    let seamless_size = self.seamless_size() as i64;
+    let start = [key[0] * seamless_size, key[1] * seamless_size, key[2] * seamless_size];
+    AoBorder::new(self.chunk_size, |x, y, z| {
+      self.get_voxel(&[start[0] + x, start[1] + y, start[2] + z])
+    })
   }
 
   /**
-    TODO: Deprecate later, in favor of using world coord instead of region coord
-          We just have to do coord conversion when it is needed in the future
+    Loaded chunks not containing the voxel, but reading it in ao_border()
+    one past their sides. They are meshed again after an edit of the voxel,
+    so the shading of their border stays the same as the neighbour's.
   */
+  pub fn ao_border_keys(&self, pos: &[i64; 3]) -> Vec<[i64; 3]> {
+    let seamless_size = self.seamless_size() as i64;
+    let chunk_size = self.chunk_size as i64;
+    let mut min = [0; 3];
+    let mut max = [0; 3];
+    for i in 0..3 {
+      min[i] = (pos[i] - chunk_size).div_euclid(seamless_size);
+      max[i] = (pos[i] + 1).div_euclid(seamless_size);
+    }
+
+    let mut keys = Vec::new();
+    for x in min[0]..=max[0] {
+      for y in min[1]..=max[1] {
+        for z in min[2]..=max[2] {
+          let key = [x, y, z];
+          let mut in_shell = true;
+          let mut inside = true;
+          for i in 0..3 {
+            let local = pos[i] - key[i] * seamless_size;
+            in_shell &= local >= -1 && local <= chunk_size;
+            inside &= local >= 0 && local < chunk_size;
+          }
+          if in_shell && !inside && self.get_chunk(&key).is_some() {
+            keys.push(key);
+          }
+        }
+      }
+    }
+    keys
+  }
+
   pub fn new_chunk(
     key: &[i64; 3], depth: u8, lod: usize, noise: OpenSimplex
+  ) -> Chunk {
+    ChunkManager::new_chunk_with_policy(key, depth, lod, noise, LodPolicy::default())
+  }
+
+  /**
+    Same as new_chunk(), with the policy used to downsample the octree
+    parents that octree.lod() returns
+  */
+  pub fn new_chunk_with_policy(
+    key: &[i64; 3], depth: u8, lod: usize, noise: OpenSimplex, policy: LodPolicy
+  ) -> Chunk {
+    let bounds = WorldBounds::default();
+    ChunkManager::new_chunk_in_bounds(key, depth, lod, noise, policy, &bounds)
+  }
+
+  /**
+    Same as new_chunk_with_policy(), voxels outside of the bounds are air.
+    Chunks entirely outside are returned empty without sampling the noise.
+  */
+  pub fn new_chunk_in_bounds(
+    key: &[i64; 3],
+    depth: u8,
+    lod: usize,
+    noise: OpenSimplex,
+    policy: LodPolicy,
+    bounds: &WorldBounds,
   ) -> Chunk {
     let size = 2_i32.pow(depth as u32) as u32;
     // if lod_level > depth {
     //   panic!("lod_level: {} cannot be higher than depth: {}", lod_level, depth);
     // }
     let seamless_size = size - 2;
-    let region_key = world_key_to_region_key(key, seamless_size);
-
-    let region_middle_pos = region_middle_pos(seamless_size) as i64;
-    let start_x = (region_key[0] * seamless_size) + 0;
-    let start_y = (region_key[1] * seamless_size) + 0;
-    let start_z = (region_key[2] * seamless_size) + 0;
 
     let new_octree = VoxelOctree::new(0, depth);
     let mut chunk = Chunk {
@@ -333,6 +466,11 @@ impl ChunkManager {
       is_default: true,
     };
 
+    if !bounds.contains_key(key, size, seamless_size) {
+      chunk.mode = ChunkMode::Air;
+      return chunk;
+    }
+
     let mut has_air = false;
     let mut has_value = false;
     let mut data = Vec::new();
@@ -342,15 +480,14 @@ impl ChunkManager {
     for octree_x in start..end {
       for octree_y in start..end {
         for octree_z in start..end {
-          let x = start_x + octree_x;
-          let y = start_y + octree_y;
-          let z = start_z + octree_z;
-          
-          let elevation = noise_elevation(&x, &z, &region_middle_pos, noise);
-          let mid_y = y as i64 - region_middle_pos;
+          let local = LocalVoxelPos([octree_x, octree_y, octree_z]);
+          let [x, y, z] = local.to_world(&ChunkKey(*key), seamless_size).0;
+
+          let elevation = noise_elevation(x, z, noise);
 
           /* Uncomment this later, testing for now */
-          let voxel = if mid_y < elevation { 1 } else { 0 };
+          let voxel = if y < elevation { 1 } else { 0 };
+          let voxel = bounds.voxel(&[x, y, z], voxel) as u32;
           // let voxel = if mid_y < 0 { 1 } else { 0 };
           data.push([octree_x, octree_y, octree_z, voxel]);
 
@@ -376,7 +513,7 @@ impl ChunkManager {
               has_air = true;
               // println!("Air {} {} {}", octree_x, octree_y, octree_z);
             }
-            if voxel == 1 {
+            if voxel != 0 {
               has_value = true;
               // println!("Voxel {} {} {}", octree_x, octree_y, octree_z);
             }
@@ -385,7 +522,9 @@ impl ChunkManager {
       }
     }
 
-    chunk.octree = VoxelOctree::new_from_3d_array(0, depth, &data, ParentValueType::Lod);
+    chunk.octree = VoxelOctree::new_from_3d_array(
+      0, depth, &data, ParentValueType::Downsample(policy)
+    );
     // chunk.mode = chunk_mode(&chunk.octree);
 
     /*
@@ -423,8 +562,19 @@ impl ChunkManager {
     self.chunks.get(key)
   }
 
+  /**
+    Same as get_chunk(), but counted in the cache stats as a hit or a miss
+    and marked as recently used
+  */
   pub fn get_chunk_mut(&mut self, key: &[i64; 3]) -> Option<&mut Chunk> {
     /* Later on, implement Spatial Partition or R-trees? */
+    match self.chunks.get(key) {
+      Some(chunk) => {
+        self.cache.hit();
+        self.cache.touch(key, chunk.memory_size(), !chunk.is_default);
+      }
+      None => self.cache.miss(),
+    }
     self.chunks.get_mut(key)
   }
 
@@ -433,10 +583,50 @@ impl ChunkManager {
     if c.is_some() {
       if !chunk.is_default {
         self.chunks.insert(key.clone(), chunk.clone());
+        self.notify_chunk(key, ChunkChangeKind::Edited);
       }
     } else {
       self.chunks.insert(key.clone(), chunk.clone());
+      self.notify_chunk(key, ChunkChangeKind::Generated);
+    }
+
+    if let Some(c) = self.chunks.get(key) {
+      self.cache.touch(key, c.memory_size(), !c.is_default);
     }
+    self.evict();
+  }
+
+  /**
+    Returns the loaded chunk, otherwise generates and keeps it.
+    Unlike get_chunk(), it is counted in the cache stats.
+  */
+  pub fn load_chunk(&mut self, key: &[i64; 3], lod: usize) -> Chunk {
+    if let Some(chunk) = self.get_chunk_mut(key) {
+      return chunk.clone();
+    }
+
+    let chunk = self.generate_chunk(key, lod);
+    if self.in_bounds(key) {
+      self.set_chunk(key, &chunk);
+    }
+    chunk
+  }
+
+  /// Generates the chunk with the settings of the manager without keeping it
+  pub fn generate_chunk(&self, key: &[i64; 3], lod: usize) -> Chunk {
+    ChunkManager::new_chunk_in_bounds(
+      key, self.depth as u8, lod, self.noise, self.lod_policy, &self.bounds
+    )
+  }
+
+  /// True if any voxel of the chunk is inside the bounds
+  pub fn in_bounds(&self, key: &[i64; 3]) -> bool {
+    self.bounds.contains_key(key, self.chunk_size, self.seamless_size())
+  }
+
+  /// Keys of the chunks inside the bounds
+  pub fn bounded_keys(&self, keys: &Vec<[i64; 3]>) -> Vec<[i64; 3]> {
+    self.bounds.filter_keys(keys, self.chunk_size, self.seamless_size())
   }
 
   pub fn remove_chunk(&mut self, key: &[i64; 3]) {
@@ -445,33 +635,119 @@ impl ChunkManager {
       let chunk = chunk_op.unwrap();
       if chunk.is_default {
         self.chunks.remove(key);
+        self.cache.remove(key);
+        self.notify_chunk(key, ChunkChangeKind::Removed);
       }
     }
   }
 
+  /// Removes every chunk including the modified ones
+  pub fn clear(&mut self) {
+    if !self.subscriptions.is_empty() {
+      let keys: Vec<[i64; 3]> = self.chunks.keys().cloned().collect();
+      for key in keys.iter() {
+        self.notify_chunk(key, ChunkChangeKind::Removed);
+      }
+    }
+    self.chunks.clear();
+    self.cache.clear();
+  }
+
   pub fn len(&self) -> usize {
     self.chunks.len()
   }
 
-  
+  pub fn set_budget(&mut self, budget: ChunkBudget) {
+    self.cache.budget = budget;
+    self.evict();
+  }
+
+  pub fn cache_stats(&self) -> ChunkCacheStats {
+    self.cache.stats()
+  }
+
+  /**
+    Keeps the chunk loaded while it is in use, e.g. meshed with a collider
+    around an observer, so get_voxel() doesn't fall back to air under it
+  */
+  pub fn hold_chunk(&mut self, key: &[i64; 3]) {
+    self.cache.hold(key);
+  }
+
+  /// Lets the chunk be evicted again, evicting at once if over the budget
+  pub fn release_chunk(&mut self, key: &[i64; 3]) {
+    self.cache.release(key);
+    self.evict();
+  }
+
+  /**
+    Removes the least recently used unmodified chunks until the budget is met.
+    Modified chunks are pinned instead, they can only be removed by clear().
+    Held chunks are skipped, subscribers get ChunkChangeKind::Evicted.
+  */
+  fn evict(&mut self) {
+    while self.cache.over_budget() {
+      let key = match self.cache.oldest() {
+        Some(k) => k,
+        None => break,
+      };
+
+      match self.chunks.get(&key) {
+        Some(chunk) if !chunk.is_default => self.cache.pin(&key),
+        Some(_) => {
+          self.chunks.remove(&key);
+          self.cache.remove(&key);
+          self.cache.evicted();
+          self.notify_chunk(&key, ChunkChangeKind::Evicted);
+        }
+        None => self.cache.remove(&key),
+      }
+    }
+  }
+
+  /**
+    Observes the chunks from min_key to max_key(inclusive). Changes made
+    through this manager are queued until take_changes(), except the
+    direct edits through get_chunk_mut() or the chunks field.
+  */
+  pub fn subscribe(&mut self, min_key: [i64; 3], max_key: [i64; 3]) -> SubscriptionId {
+    self.subscriptions.subscribe(min_key, max_key)
+  }
+
+  /// Moves the observed region, e.g. following the player
+  pub fn set_subscription_region(
+    &mut self, id: SubscriptionId, min_key: [i64; 3], max_key: [i64; 3]
+  ) -> bool {
+    self.subscriptions.set_region(id, min_key, max_key)
+  }
+
+  pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+    self.subscriptions.unsubscribe(id)
+  }
+
+  /// Changes since the last call in the order they first happened
+  pub fn take_changes(&mut self, id: SubscriptionId) -> Vec<ChunkChange> {
+    self.subscriptions.take_changes(id).unwrap_or_default()
+  }
+
+  pub(crate) fn notify(&mut self, key: &[i64; 3], kind: ChunkChangeKind, min: [u32; 3], max: [u32; 3]) {
+    if !self.subscriptions.is_empty() {
+      self.subscriptions.notify(&ChunkChange { key: *key, kind: kind, min: min, max: max });
+    }
+  }
+
+  fn notify_chunk(&mut self, key: &[i64; 3], kind: ChunkChangeKind) {
+    if !self.subscriptions.is_empty() {
+      self.subscriptions.notify(&ChunkChange::whole(*key, kind, self.chunk_size));
+    }
+  }
 
   pub fn get_adj_chunks(&mut self, key: [i64; 3]) -> Vec<Chunk> {
     let mut chunks = Vec::new();
 
-    let keys = adjacent_keys(&key, self.range as i64, true);
+    let keys = self.bounded_keys(&adjacent_keys(&key, self.range as i64, true));
     for key in keys.iter() {
-      let res = self.chunks.get(key);
-      if res.is_some() {
-        chunks.push(res.unwrap().clone());
-      }
-
-      if res.is_none() {
-        let c = ChunkManager::new_chunk(
-          key, self.depth as u8, 0, self.noise
-        );
-        chunks.push(c.clone());
-        self.chunks.insert(*key, c);
-      }
+      chunks.push(self.load_chunk(key, 0));
     }
 
     chunks
@@ -520,6 +796,51 @@ mod tests {
     Ok(())
   }
 
+  #[test]
+  fn test_apply_csg_across_chunks() -> Result<(), String> {
+    let mut chunk_manager = ChunkManager::default();
+    let mut expected = ChunkManager::default();
+
+    let mut data = Vec::new();
+    for x in 0..8 {
+      for y in 0..8 {
+        for z in 0..8 {
+          data.push([x, y, z, 5]);
+        }
+      }
+    }
+    let stamp = VoxelOctree::new_from_3d_array(0, 3, &data, ParentValueType::Lod);
+
+    let pos = [10, -4, 12];
+    let res = chunk_manager.apply_csg(&stamp, &pos, CsgOp::Replace).unwrap();
+    for x in 0..8 {
+      for y in 0..8 {
+        for z in 0..8 {
+          expected.set_voxel2(&[pos[0] + x, pos[1] + y, pos[2] + z], 5);
+        }
+      }
+    }
+
+    assert!(res.len() > 1);
+    for (key, chunk) in res.iter() {
+      assert!(!chunk.is_default);
+      assert_eq!(expected.get_chunk(key).unwrap().octree.to_dense(), chunk.octree.to_dense());
+    }
+
+    for x in 0..30 {
+      for y in -10..10 {
+        for z in 0..30 {
+          let p = [x, y, z];
+          assert_eq!(chunk_manager.get_voxel(&p), expected.get_voxel(&p), "at {:?}", p);
+        }
+      }
+    }
+
+    let res = chunk_manager.apply_csg(&stamp, &pos, CsgOp::Replace).unwrap();
+    assert_eq!(res.len(), 0);
+    Ok(())
+  }
+
   #[test]
   fn test_chunk_mode() -> Result<(), String> {
     let depth = 4;
@@ -567,6 +888,266 @@ mod tests {
 
     Ok(())
   }
+
+  #[test]
+  fn test_chunk_budget_count() -> Result<(), String> {
+    let mut manager = ChunkManager::default();
+    manager.set_budget(ChunkBudget::Count(8));
+
+    manager.set_voxel2(&[5, 5, 5], 3);
+    let modified = manager.get_chunk(&[0, 0, 0]).unwrap().clone();
+
+    for x in 0..10 {
+      manager.load_chunk(&[x, 50, 0], 0);
+    }
+    assert_eq!(manager.len(), 8);
+
+    // Modified chunk is pinned even though it is the least recently used
+    assert_eq!(manager.get_chunk(&[0, 0, 0]), Some(&modified));
+    assert_eq!(manager.get_voxel(&[5, 5, 5]), 3);
+
+    // Most recently used are kept
+    assert!(manager.get_chunk(&[9, 50, 0]).is_some());
+    assert!(manager.get_chunk(&[0, 50, 0]).is_none());
+
+    manager.load_chunk(&[9, 50, 0], 0);
+    let stats = manager.cache_stats();
+    assert_eq!(stats.misses, 11);
+    assert_eq!(stats.hits, 1);
+    assert_eq!(stats.evictions, 3);
+    assert_eq!(stats.pinned, 1);
+    assert_eq!(stats.len, 8);
+    Ok(())
+  }
+
+  #[test]
+  fn test_chunk_budget_bytes() -> Result<(), String> {
+    let mut manager = ChunkManager::default();
+    let keys = adjacent_keys(&[0, 0, 0], 1, true);
+    for key in keys.iter() {
+      manager.load_chunk(key, 0);
+    }
+
+    let total: usize = manager.chunks.values().map(|c| c.memory_size()).sum();
+    assert_eq!(manager.cache_stats().bytes, total);
+
+    let budget = total / 2;
+    manager.set_budget(ChunkBudget::Bytes(budget));
+    let stats = manager.cache_stats();
+    assert!(stats.bytes <= budget);
+    assert_eq!(stats.len, manager.len());
+    assert_eq!(stats.evictions as usize, keys.len() - manager.len());
+
+    // The first loaded are evicted first
+    assert!(manager.get_chunk(&keys[0]).is_none());
+    assert!(manager.get_chunk(&keys[keys.len() - 1]).is_some());
+
+    manager.clear();
+    assert_eq!(manager.cache_stats().bytes, 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_chunk_budget_held() -> Result<(), String> {
+    let mut manager = ChunkManager::default();
+    let id = manager.subscribe([0, 0, 0], [9, 0, 0]);
+    manager.set_budget(ChunkBudget::Count(2));
+    manager.hold_chunk(&[0, 0, 0]);
+    for x in 0..5 {
+      manager.load_chunk(&[x, 0, 0], 0);
+    }
+
+    // Held in use even though it is the least recently used
+    assert!(manager.get_chunk(&[0, 0, 0]).is_some());
+    assert!(manager.get_chunk(&[1, 0, 0]).is_none());
+    let evicted: Vec<[i64; 3]> = manager
+      .take_changes(id)
+      .into_iter()
+      .filter(|c| c.kind == ChunkChangeKind::Evicted)
+      .map(|c| c.key)
+      .collect();
+    assert_eq!(evicted, vec![[1, 0, 0], [2, 0, 0], [3, 0, 0]]);
+
+    // Released, it is the first evicted again
+    manager.release_chunk(&[0, 0, 0]);
+    manager.load_chunk(&[5, 0, 0], 0);
+    assert!(manager.get_chunk(&[0, 0, 0]).is_none());
+    assert!(manager.get_chunk(&[4, 0, 0]).is_some());
+    Ok(())
+  }
+
+  #[test]
+  fn test_subscription_changes() -> Result<(), String> {
+    let mut manager = ChunkManager::default();
+    manager.set_budget(ChunkBudget::Count(4));
+    let id = manager.subscribe([0, 0, 0], [0, 0, 0]);
+    let other = manager.subscribe([10, 0, 0], [20, 0, 0]);
+
+    manager.load_chunk(&[0, 0, 0], 0);
+    manager.load_chunk(&[5, 0, 0], 0);
+    let changes = manager.take_changes(id);
+    assert_eq!(changes, vec![ChunkChange::whole([0, 0, 0], ChunkChangeKind::Generated, 16)]);
+
+    // Edits on the borders are shared with [-1, 0, 0] and [1, 0, 0]
+    manager.set_voxel2(&[1, 2, 3], 1);
+    manager.set_voxel2(&[3, 5, 3], 1);
+    manager.set_voxel2(&[14, 2, 3], 1);
+    let changes = manager.take_changes(id);
+    let expected = ChunkChange {
+      key: [0, 0, 0], kind: ChunkChangeKind::Edited, min: [1, 2, 3], max: [14, 5, 3]
+    };
+    assert_eq!(changes, vec![expected]);
+
+    for x in 10..15 {
+      manager.load_chunk(&[x, 0, 0], 0);
+    }
+    let changes = manager.take_changes(other);
+    let evicted: Vec<[i64; 3]> = changes
+      .iter()
+      .filter(|c| c.kind == ChunkChangeKind::Evicted)
+      .map(|c| c.key)
+      .collect();
+    // The 3 modified chunks are pinned, only the last loaded fits the budget
+    assert_eq!(evicted, vec![[10, 0, 0], [11, 0, 0], [12, 0, 0], [13, 0, 0]]);
+    assert_eq!(changes.len(), 5);
+
+    let mut stamp = VoxelOctree::new(0, 2);
+    stamp.set_voxel(3, 3, 3, 3);
+    manager.apply_csg(&stamp, &[-2, 4, 12], CsgOp::Union).unwrap();
+    let changes = manager.take_changes(id);
+    let expected = ChunkChange {
+      key: [0, 0, 0], kind: ChunkChangeKind::Edited, min: [0, 4, 12], max: [1, 7, 15]
+    };
+    assert_eq!(changes, vec![expected]);
+
+    assert!(manager.set_subscription_region(id, [-1, 0, 0], [0, 0, 0]));
+    manager.clear();
+    let mut keys: Vec<[i64; 3]> = manager.take_changes(id).iter().map(|c| c.key).collect();
+    keys.sort();
+    assert_eq!(keys, vec![[-1, 0, 0], [0, 0, 0]]);
+
+    assert!(manager.unsubscribe(id));
+    assert!(!manager.unsubscribe(id));
+    assert_eq!(manager.take_changes(id), vec![]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_world_bounds() -> Result<(), String> {
+    let mut manager = ChunkManager::default();
+    manager.bounds = WorldBounds::default()
+      .with_xz([-20, -20], [19, 19])
+      .with_y(-16, 15)
+      .with_bedrock(9);
+
+    assert_eq!(manager.try_set_voxel(&[20, 0, 0], 1), Err(EditError::OutOfBounds([20, 0, 0])));
+    assert_eq!(manager.try_set_voxel(&[0, -16, 0], 0), Err(EditError::OutOfBounds([0, -16, 0])));
+    assert_eq!(manager.set_voxel2(&[0, 16, 0], 1).len(), 0);
+
+    let stamp = VoxelOctree::new(1, 2);
+    let res = manager.apply_csg(&stamp, &[17, 0, 0], CsgOp::Union);
+    assert_eq!(res, Err(EditError::OutOfBounds([17, 0, 0])));
+    assert!(manager.apply_csg(&stamp, &[16, 0, 0], CsgOp::Union).is_ok());
+    manager.clear();
+
+    // Key 2 starts at 28, key -3 ends at -27
+    let chunks = manager.get_adj_chunks([1, 0, -2]);
+    assert_eq!(chunks.len(), 2 * 3 * 2);
+    assert_eq!(manager.len(), chunks.len());
+
+    let chunk = manager.load_chunk(&[0, 2, 0], 0);
+    assert_eq!(chunk.mode, ChunkMode::Air);
+    assert!(manager.get_chunk(&[0, 2, 0]).is_none());
+
+    manager.load_chunk(&[0, -2, 0], 0);
+    manager.load_chunk(&[1, -2, 0], 0);
+    for x in 0..20 {
+      assert_eq!(manager.get_voxel(&[x, -16, 0]), 9);
+      assert_eq!(manager.get_voxel(&[x, -17, 0]), 0);
+    }
+    assert_eq!(manager.get_voxel(&[20, -16, 0]), 0);
+
+    assert!(manager.try_set_voxel(&[0, -15, 0], 0).is_ok());
+    assert_eq!(manager.get_voxel(&[0, -15, 0]), 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_ao_border_keys() -> Result<(), String> {
+    use crate::data::surface_nets::VoxelReuse;
+    use crate::data::voxel_octree::{VoxelMode, MeshData};
+
+    // Floor below y 6 and a wall up to world x 14, the seam of chunks 0 and 1
+    let mut manager = ChunkManager::default();
+    for key in [[0, 0, 0], [1, 0, 0]].iter() {
+      let chunk = Chunk { key: *key, is_default: false, ..Default::default() };
+      manager.set_chunk(key, &chunk);
+    }
+    for x in 2..28 {
+      for y in 2..12 {
+        for z in 2..12 {
+          if y < 6 || x <= 14 {
+            manager.set_voxel2(&[x, y, z], 1);
+          }
+        }
+      }
+    }
+
+    // 13 is only in chunk 0 and 16 only in chunk 1, both are read by the other one
+    assert_eq!(manager.ao_border_keys(&[13, 8, 8]), vec![[1, 0, 0]]);
+    assert_eq!(manager.ao_border_keys(&[16, 8, 8]), vec![[0, 0, 0]]);
+    assert!(manager.ao_border_keys(&[14, 8, 8]).is_empty());
+    assert!(manager.ao_border_keys(&[6, 8, 8]).is_empty());
+
+    let options = MeshOptions { ao: true, ..Default::default() };
+    let mesh = |manager: &ChunkManager, key: [i64; 3]| {
+      let chunk = manager.get_chunk(&key).unwrap();
+      chunk.octree.compute_mesh_with_border(
+        VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &manager.colors, 1.0, key, 0, &options,
+        Some(&manager.ao_border(&key)),
+      )
+    };
+    // Ambient occlusion of the vertices by world position
+    let seamless_size = manager.seamless_size() as f32;
+    let shading = |data: &MeshData| {
+      let x = data.key[0] as f32 * seamless_size;
+      data.positions
+        .iter()
+        .zip(data.ao.iter())
+        .map(|(p, ao)| ([p[0] + x, p[1], p[2]].map(|v| (v * 100.0).round() as i64), *ao))
+        .collect::<HashMap<[i64; 3], f32>>()
+    };
+    // Vertices both chunks have, the ones on the seam
+    let seam = |meshes: &Vec<MeshData>| {
+      let right = shading(&meshes[1]);
+      let mut seam: Vec<([i64; 3], f32, f32)> = shading(&meshes[0])
+        .into_iter()
+        .filter_map(|(p, ao)| right.get(&p).map(|other| (p, ao, *other)))
+        .collect();
+      seam.sort_by(|a, b| a.0.cmp(&b.0));
+      seam
+    };
+
+    let mut meshes = vec![mesh(&manager, [0, 0, 0]), mesh(&manager, [1, 0, 0])];
+    let before = seam(&meshes);
+    assert!(before.iter().all(|(_, left, right)| left == right));
+
+    // Meshing again the edited chunks and the ones reading the voxels
+    for pos in [[13, 6, 4], [16, 6, 9]].iter() {
+      let voxel = if manager.get_voxel(pos) == 0 { 1 } else { 0 };
+      let mut keys: Vec<[i64; 3]> = manager.set_voxel2(pos, voxel).iter().map(|(k, _)| *k).collect();
+      keys.extend(manager.ao_border_keys(pos));
+      for key in keys.iter() {
+        meshes[key[0] as usize] = mesh(&manager, *key);
+      }
+    }
+    let after = seam(&meshes);
+    assert_ne!(after, before);
+    for (pos, left, right) in after.iter() {
+      assert_eq!(left, right, "vertex at {:?}", pos);
+    }
+    Ok(())
+  }
 }
 
 
@@ -0,0 +1,343 @@
+use hashbrown::{HashMap, HashSet};
+
+/// Chunks past the bound of its ring before a key changes lod
+pub const LOD_HYSTERESIS: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LodTransition {
+  Load { key: [i64; 3], lod: usize },
+  /// To a finer lod, the observers came closer
+  Upgrade { key: [i64; 3], from: usize, to: usize },
+  Downgrade { key: [i64; 3], from: usize, to: usize },
+  Unload { key: [i64; 3], lod: usize },
+}
+
+impl LodTransition {
+  pub fn key(&self) -> [i64; 3] {
+    match *self {
+      LodTransition::Load { key, .. } => key,
+      LodTransition::Upgrade { key, .. } => key,
+      LodTransition::Downgrade { key, .. } => key,
+      LodTransition::Unload { key, .. } => key,
+    }
+  }
+
+  /// Lod the key is at after the transition, None if unloaded
+  pub fn lod(&self) -> Option<usize> {
+    match *self {
+      LodTransition::Load { lod, .. } => Some(lod),
+      LodTransition::Upgrade { to, .. } => Some(to),
+      LodTransition::Downgrade { to, .. } => Some(to),
+      LodTransition::Unload { .. } => None,
+    }
+  }
+}
+
+/// Key chunks are loaded around, with its own rings or the ones of the Clipmap
+#[derive(Clone, Debug, PartialEq)]
+pub struct LodObserver {
+  pub key: [i64; 3],
+  pub ranges: Option<Vec<u32>>,
+}
+
+impl LodObserver {
+  pub fn new(key: [i64; 3]) -> Self {
+    LodObserver { key: key, ranges: None }
+  }
+
+  pub fn with_ranges(key: [i64; 3], ranges: Vec<u32>) -> Self {
+    assert!(ranges.len() >= 2);
+    LodObserver { key: key, ranges: Some(ranges) }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Loaded {
+  lod: usize,
+  /// Observers that need the key
+  refs: u32,
+}
+
+/**
+  Rings of lods around the observers and the lod of each loaded key. Lod 0
+  has the keys within ranges[1] chunks on every axis, lod n > 0 the keys
+  within a distance of ranges[n + 1] chunks, same as Utils::in_range_by_lod().
+  The finest lod among the observers is used, and a key is loaded as long as
+  any observer needs it. A loaded key keeps its lod until it is `hysteresis`
+  chunks past the bounds of its ring, so the keys on the bounds don't
+  flicker as the observers move back and forth.
+*/
+#[derive(Clone, Debug)]
+pub struct Clipmap {
+  /// Rings of the observers without their own
+  pub ranges: Vec<u32>,
+  pub hysteresis: u32,
+  lods: HashMap<[i64; 3], Loaded>,
+}
+
+impl Clipmap {
+  pub fn new(ranges: Vec<u32>, hysteresis: u32) -> Self {
+    assert!(ranges.len() >= 2);
+    Clipmap {
+      ranges: ranges,
+      hysteresis: hysteresis,
+      lods: HashMap::new(),
+    }
+  }
+
+  /// Current lod of the key, None if not loaded
+  pub fn lod(&self, key: &[i64; 3]) -> Option<usize> {
+    self.lods.get(key).map(|l| l.lod)
+  }
+
+  /// Observers the key was needed by on the last update(), 0 if not loaded
+  pub fn refs(&self, key: &[i64; 3]) -> u32 {
+    self.lods.get(key).map_or(0, |l| l.refs)
+  }
+
+  /// Lod of the rings for the key without the hysteresis, None if out of range
+  pub fn desired_lod(&self, key: &[i64; 3], observers: &[LodObserver]) -> Option<usize> {
+    self.ring_lod(key, observers, 0)
+  }
+
+  pub fn keys(&self) -> impl Iterator<Item = &[i64; 3]> {
+    self.lods.keys()
+  }
+
+  pub fn len(&self) -> usize {
+    self.lods.len()
+  }
+
+  /// Forgets the key, it is loaded again by the next update() if in range
+  pub fn remove(&mut self, key: &[i64; 3]) {
+    self.lods.remove(key);
+  }
+
+  /**
+    Updates the lods for the observers, returns the transitions ordered by
+    key. Keys in range are loaded, the loaded keys past the hysteresis of
+    their ring change lod, and are unloaded once no observer needs them.
+  */
+  pub fn update(&mut self, observers: &[LodObserver]) -> Vec<LodTransition> {
+    let mut keys: HashSet<[i64; 3]> = self.lods.keys().cloned().collect();
+    for observer in observers.iter() {
+      let o = observer.key;
+      let r = *self.observer_ranges(observer).last().unwrap() as i64;
+      for x in -r..=r {
+        for y in -r..=r {
+          for z in -r..=r {
+            keys.insert([o[0] + x, o[1] + y, o[2] + z]);
+          }
+        }
+      }
+    }
+    let mut keys: Vec<[i64; 3]> = keys.into_iter().collect();
+    keys.sort();
+
+    let h = self.hysteresis as i64;
+    let mut transitions = Vec::new();
+    for key in keys.iter() {
+      let desired = self.ring_lod(key, observers, 0);
+      let current = match self.lods.get(key) {
+        Some(c) => c.lod,
+        None => {
+          if let Some(lod) = desired {
+            let refs = self.refs_by_ring(key, observers, 0);
+            self.lods.insert(*key, Loaded { lod: lod, refs: refs });
+            transitions.push(LodTransition::Load { key: *key, lod: lod });
+          }
+          continue;
+        }
+      };
+
+      // Observers keep the key until it is past their rings with the hysteresis
+      let refs = self.refs_by_ring(key, observers, h);
+      if refs == 0 {
+        self.lods.remove(key);
+        transitions.push(LodTransition::Unload { key: *key, lod: current });
+        continue;
+      }
+
+      // Lods with the ring bounds moved outward and inward by the hysteresis
+      let finer = self.ring_lod(key, observers, h).unwrap_or(usize::MAX);
+      let coarser = self.ring_lod(key, observers, -h).unwrap_or(usize::MAX);
+      let lod = match desired {
+        Some(lod) if current < finer || coarser < current => lod,
+        _ => current,
+      };
+      self.lods.insert(*key, Loaded { lod: lod, refs: refs });
+      if lod == current {
+        continue;
+      }
+      if lod < current {
+        transitions.push(LodTransition::Upgrade { key: *key, from: current, to: lod });
+      } else {
+        transitions.push(LodTransition::Downgrade { key: *key, from: current, to: lod });
+      }
+    }
+    transitions
+  }
+
+  fn observer_ranges<'a>(&'a self, observer: &'a LodObserver) -> &'a Vec<u32> {
+    observer.ranges.as_ref().unwrap_or(&self.ranges)
+  }
+
+  /// Finest lod of the key among the observers, None if out of range of all
+  fn ring_lod(&self, key: &[i64; 3], observers: &[LodObserver], shift: i64) -> Option<usize> {
+    observers
+      .iter()
+      .filter_map(|o| ring_lod(&offset(key, &o.key), self.observer_ranges(o), shift))
+      .min()
+  }
+
+  fn refs_by_ring(&self, key: &[i64; 3], observers: &[LodObserver], shift: i64) -> u32 {
+    observers
+      .iter()
+      .filter(|o| ring_lod(&offset(key, &o.key), self.observer_ranges(o), shift).is_some())
+      .count() as u32
+  }
+}
+
+fn offset(key: &[i64; 3], observer: &[i64; 3]) -> [i64; 3] {
+  [key[0] - observer[0], key[1] - observer[1], key[2] - observer[2]]
+}
+
+/// Lod of the offset from the observer with the ring bounds moved by `shift`
+fn ring_lod(d: &[i64; 3], ranges: &Vec<u32>, shift: i64) -> Option<usize> {
+  let bound = |i: usize| (ranges[i] as i64 + shift).max(0);
+  let tile_range = d.iter().map(|v| v.abs()).max().unwrap();
+  if tile_range <= bound(1) {
+    return Some(0);
+  }
+
+  let dist_sqr = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+  (1..ranges.len() - 1).find(|lod| {
+    let b = bound(lod + 1);
+    dist_sqr <= b * b
+  })
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn clipmap() -> Clipmap {
+    Clipmap::new(vec![0, 1, 3, 5, 7], 1)
+  }
+
+  fn at(keys: &[[i64; 3]]) -> Vec<LodObserver> {
+    keys.iter().map(|k| LodObserver::new(*k)).collect()
+  }
+
+  #[test]
+  fn test_clipmap_rings() -> Result<(), String> {
+    let clipmap = clipmap();
+    let observers = at(&[[0, 0, 0]]);
+    let expected = [
+      ([1, 1, -1], Some(0)),
+      ([2, 0, 0], Some(1)),
+      ([3, 0, 0], Some(1)),
+      ([4, 0, 0], Some(2)),
+      ([2, 2, 2], Some(2)),
+      ([7, 0, 0], Some(3)),
+      ([5, 5, 0], None),
+      ([8, 0, 0], None),
+    ];
+    for (key, lod) in expected.iter() {
+      assert_eq!(clipmap.desired_lod(key, &observers), *lod, "{:?}", key);
+    }
+
+    // The nearest observer decides
+    assert_eq!(clipmap.desired_lod(&[8, 0, 0], &at(&[[0, 0, 0], [9, 0, 0]])), Some(0));
+    assert_eq!(clipmap.desired_lod(&[0, 0, 0], &[]), None);
+    Ok(())
+  }
+
+  #[test]
+  fn test_clipmap_load_and_unload() -> Result<(), String> {
+    let mut clipmap = clipmap();
+    let transitions = clipmap.update(&at(&[[0, 0, 0]]));
+    assert!(transitions.iter().all(|t| matches!(t, LodTransition::Load { .. })));
+    assert_eq!(transitions.len(), clipmap.len());
+    assert_eq!(transitions.iter().filter(|t| t.lod() == Some(0)).count(), 27);
+    for t in transitions.iter() {
+      assert_eq!(t.lod(), clipmap.desired_lod(&t.key(), &at(&[[0, 0, 0]])));
+    }
+    assert!(clipmap.update(&at(&[[0, 0, 0]])).is_empty());
+
+    // Far away every key is unloaded and the new rings are loaded
+    let loaded = clipmap.len();
+    let transitions = clipmap.update(&at(&[[100, 0, 0]]));
+    let unloads = transitions.iter().filter(|t| matches!(t, LodTransition::Unload { .. })).count();
+    assert_eq!(unloads, loaded);
+    assert_eq!(clipmap.len(), loaded);
+    assert!(clipmap.keys().all(|k| k[0] > 90));
+    Ok(())
+  }
+
+  #[test]
+  fn test_clipmap_hysteresis() -> Result<(), String> {
+    let mut clipmap = clipmap();
+    let key = [4, 0, 0];
+    clipmap.update(&at(&[[0, 0, 0]]));
+    assert_eq!(clipmap.lod(&key), Some(2));
+
+    // One chunk into the lod 1 ring keeps lod 2
+    let transitions = clipmap.update(&at(&[[1, 0, 0]]));
+    assert!(transitions.iter().all(|t| t.key() != key));
+    assert_eq!(clipmap.lod(&key), Some(2));
+
+    let transitions = clipmap.update(&at(&[[2, 0, 0]]));
+    assert!(transitions.contains(&LodTransition::Upgrade { key: key, from: 2, to: 1 }));
+
+    // Moving back and forth over the bound doesn't change it again
+    for observer in [[1, 0, 0], [0, 0, 0], [1, 0, 0], [0, 0, 0]] {
+      clipmap.update(&at(&[observer]));
+      assert_eq!(clipmap.lod(&key), Some(1));
+    }
+    let transitions = clipmap.update(&at(&[[-1, 0, 0]]));
+    assert!(transitions.contains(&LodTransition::Downgrade { key: key, from: 1, to: 2 }));
+
+    // Keys just out of range stay loaded
+    let edge = [7, 0, 0];
+    clipmap.update(&at(&[[0, 0, 0]]));
+    assert_eq!(clipmap.lod(&edge), Some(3));
+    clipmap.update(&at(&[[-1, 0, 0]]));
+    assert_eq!(clipmap.lod(&edge), Some(3));
+    let transitions = clipmap.update(&at(&[[-2, 0, 0]]));
+    assert!(transitions.contains(&LodTransition::Unload { key: edge, lod: 3 }));
+    Ok(())
+  }
+
+  #[test]
+  fn test_clipmap_observers() -> Result<(), String> {
+    let mut clipmap = clipmap();
+    let player = LodObserver::new([0, 0, 0]);
+    let loader = LodObserver::with_ranges([20, 0, 0], vec![0, 1, 2]);
+    let shared = [6, 0, 0];
+    let far = LodObserver::with_ranges([6, 0, 0], vec![0, 1, 2]);
+
+    // Each observer loads its own rings
+    clipmap.update(&[player.clone(), loader.clone()]);
+    assert_eq!(clipmap.lod(&[20, 0, 0]), Some(0));
+    assert_eq!(clipmap.lod(&[22, 0, 0]), Some(1));
+    assert_eq!(clipmap.lod(&[23, 0, 0]), None);
+    assert_eq!(clipmap.refs(&[20, 0, 0]), 1);
+
+    // The finest lod wins and the key is kept while any observer needs it
+    clipmap.update(&[player.clone(), loader.clone(), far.clone()]);
+    assert_eq!(clipmap.lod(&shared), Some(0));
+    assert_eq!(clipmap.refs(&shared), 2);
+    clipmap.update(&[loader.clone(), far.clone()]);
+    assert_eq!(clipmap.refs(&shared), 1);
+    assert_eq!(clipmap.lod(&shared), Some(0));
+    assert_eq!(clipmap.lod(&[0, 0, 0]), None);
+
+    let transitions = clipmap.update(&[loader.clone()]);
+    assert!(transitions.contains(&LodTransition::Unload { key: shared, lod: 0 }));
+    assert_eq!(clipmap.refs(&shared), 0);
+    assert!(clipmap.keys().all(|k| clipmap.refs(k) == 1 && k[0] >= 17));
+    Ok(())
+  }
+}
@@ -0,0 +1,335 @@
+/*
+  Coordinate spaces used by the chunks:
+    WorldVoxelPos: One unit per voxel, the same for every chunk
+    ChunkKey:      Chunk index, a chunk owns seamless_size voxels per axis
+                   starting from key * seamless_size
+    LocalVoxelPos: Position inside the octree of a chunk, includes the
+                   overlapping border shared with the next chunk
+    WorldPosF32:   World position scaled by voxel_scale, voxels are on the
+                   grid points(voxel * voxel_scale)
+  Every conversion floors toward negative infinity, so -1 belongs to the
+  key -1 and not to the key 0.
+*/
+
+const GRID_EPSILON: f32 = 0.001;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub struct WorldVoxelPos(pub [i64; 3]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub struct ChunkKey(pub [i64; 3]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub struct LocalVoxelPos(pub [u32; 3]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct WorldPosF32(pub [f32; 3]);
+
+impl WorldVoxelPos {
+  /// Key of the chunk owning the voxel, ignoring the overlapping border
+  pub fn to_chunk_key(&self, seamless_size: u32) -> ChunkKey {
+    let s = seamless_size as i64;
+    ChunkKey([
+      self.0[0].div_euclid(s),
+      self.0[1].div_euclid(s),
+      self.0[2].div_euclid(s),
+    ])
+  }
+
+  /// Position relative to the chunk `key`, None if the chunk doesn't contain it
+  pub fn to_local(
+    &self, key: &ChunkKey, chunk_size: u32, seamless_size: u32
+  ) -> Option<LocalVoxelPos> {
+    let origin = key.origin(seamless_size);
+    let mut local = [0; 3];
+    for i in 0..3 {
+      let diff = self.0[i] - origin.0[i];
+      if diff < 0 || diff >= chunk_size as i64 {
+        return None;
+      }
+      local[i] = diff as u32;
+    }
+    Some(LocalVoxelPos(local))
+  }
+
+  /**
+    Returns every chunk containing the voxel, including the chunks where it
+    is part of the overlapping border. Keys are ordered by x, y then z.
+  */
+  pub fn chunk_coords(
+    &self, chunk_size: u32, seamless_size: u32
+  ) -> Vec<(ChunkKey, LocalVoxelPos)> {
+    let s = seamless_size as i64;
+    let mut min = [0; 3];
+    let mut max = [0; 3];
+    for i in 0..3 {
+      min[i] = (self.0[i] - chunk_size as i64).div_euclid(s) + 1;
+      max[i] = self.0[i].div_euclid(s);
+    }
+
+    let mut coords = Vec::new();
+    for x in min[0]..max[0] + 1 {
+      for y in min[1]..max[1] + 1 {
+        for z in min[2]..max[2] + 1 {
+          let key = ChunkKey([x, y, z]);
+          if let Some(local) = self.to_local(&key, chunk_size, seamless_size) {
+            coords.push((key, local));
+          }
+        }
+      }
+    }
+    coords
+  }
+
+  pub fn to_world_f32(&self, voxel_scale: f32) -> WorldPosF32 {
+    WorldPosF32([
+      self.0[0] as f32 * voxel_scale,
+      self.0[1] as f32 * voxel_scale,
+      self.0[2] as f32 * voxel_scale,
+    ])
+  }
+}
+
+impl ChunkKey {
+  /// World voxel position of the local [0, 0, 0] of the chunk
+  pub fn origin(&self, seamless_size: u32) -> WorldVoxelPos {
+    let s = seamless_size as i64;
+    WorldVoxelPos([self.0[0] * s, self.0[1] * s, self.0[2] * s])
+  }
+
+  pub fn to_world_f32(&self, seamless_size: u32, voxel_scale: f32) -> WorldPosF32 {
+    self.origin(seamless_size).to_world_f32(voxel_scale)
+  }
+}
+
+impl LocalVoxelPos {
+  pub fn to_world(&self, key: &ChunkKey, seamless_size: u32) -> WorldVoxelPos {
+    let origin = key.origin(seamless_size);
+    WorldVoxelPos([
+      origin.0[0] + self.0[0] as i64,
+      origin.0[1] + self.0[1] as i64,
+      origin.0[2] + self.0[2] as i64,
+    ])
+  }
+}
+
+impl WorldPosF32 {
+  /// Nearest voxel, rounded since voxels are on the grid points
+  pub fn to_voxel_pos(&self, voxel_scale: f32) -> WorldVoxelPos {
+    WorldVoxelPos([
+      (self.0[0] / voxel_scale).round() as i64,
+      (self.0[1] / voxel_scale).round() as i64,
+      (self.0[2] / voxel_scale).round() as i64,
+    ])
+  }
+
+  /**
+    Voxel truncated toward zero, the mapping the edit tools used before the
+    typed coordinates: positions in (-1, 1) all go to voxel 0
+  */
+  pub fn to_voxel_pos_truncated(&self, voxel_scale: f32) -> WorldVoxelPos {
+    WorldVoxelPos([
+      (self.0[0] / voxel_scale) as i64,
+      (self.0[1] / voxel_scale) as i64,
+      (self.0[2] / voxel_scale) as i64,
+    ])
+  }
+
+  /**
+    Key of the chunk the position is in. Floored per voxel first, positions
+    within float error of a grid point are snapped to it.
+  */
+  pub fn to_chunk_key(&self, seamless_size: u32, voxel_scale: f32) -> ChunkKey {
+    let floor = |v: f32| {
+      let v = v / voxel_scale;
+      let rounded = v.round();
+      if (v - rounded).abs() < GRID_EPSILON { rounded as i64 } else { v.floor() as i64 }
+    };
+    WorldVoxelPos([floor(self.0[0]), floor(self.0[1]), floor(self.0[2])])
+      .to_chunk_key(seamless_size)
+  }
+}
+
+impl From<[i64; 3]> for WorldVoxelPos {
+  fn from(pos: [i64; 3]) -> Self {
+    WorldVoxelPos(pos)
+  }
+}
+
+impl From<WorldVoxelPos> for [i64; 3] {
+  fn from(pos: WorldVoxelPos) -> Self {
+    pos.0
+  }
+}
+
+impl From<[i64; 3]> for ChunkKey {
+  fn from(key: [i64; 3]) -> Self {
+    ChunkKey(key)
+  }
+}
+
+impl From<ChunkKey> for [i64; 3] {
+  fn from(key: ChunkKey) -> Self {
+    key.0
+  }
+}
+
+impl From<[u32; 3]> for LocalVoxelPos {
+  fn from(pos: [u32; 3]) -> Self {
+    LocalVoxelPos(pos)
+  }
+}
+
+impl From<LocalVoxelPos> for [u32; 3] {
+  fn from(pos: LocalVoxelPos) -> Self {
+    pos.0
+  }
+}
+
+impl From<[f32; 3]> for WorldPosF32 {
+  fn from(pos: [f32; 3]) -> Self {
+    WorldPosF32(pos)
+  }
+}
+
+impl From<WorldPosF32> for [f32; 3] {
+  fn from(pos: WorldPosF32) -> Self {
+    pos.0
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::{potential_keys, has_local_coord, get_local_coord};
+
+  /// Deterministic positions for the property tests, including negatives
+  fn random_positions(count: usize, range: i64) -> Vec<[i64; 3]> {
+    let mut seed: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut next = || {
+      seed ^= seed << 13;
+      seed ^= seed >> 7;
+      seed ^= seed << 17;
+      (seed % (range as u64 * 2 + 1)) as i64 - range
+    };
+
+    let mut positions = Vec::with_capacity(count);
+    for _ in 0..count {
+      positions.push([next(), next(), next()]);
+    }
+    positions
+  }
+
+  #[test]
+  fn test_world_local_round_trip() -> Result<(), String> {
+    for depth in 3..6 {
+      let chunk_size = 2_u32.pow(depth);
+      let seamless_size = chunk_size - 2;
+      for p in random_positions(2000, 1000).iter() {
+        let pos = WorldVoxelPos(*p);
+        let key = pos.to_chunk_key(seamless_size);
+        let local = pos.to_local(&key, chunk_size, seamless_size).unwrap();
+        for i in 0..3 {
+          assert!(local.0[i] < seamless_size, "{:?} {:?}", pos, local);
+        }
+        assert_eq!(local.to_world(&key, seamless_size), pos);
+        assert_eq!(key.origin(seamless_size).to_chunk_key(seamless_size), key);
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_chunk_coords_round_trip() -> Result<(), String> {
+    let chunk_size = 16;
+    let seamless_size = 14;
+    for p in random_positions(2000, 500).iter() {
+      let pos = WorldVoxelPos(*p);
+      let coords = pos.chunk_coords(chunk_size, seamless_size);
+
+      let mut expected_len = 1;
+      for i in 0..3 {
+        if p[i].rem_euclid(seamless_size as i64) < (chunk_size - seamless_size) as i64 {
+          expected_len *= 2;
+        }
+      }
+      assert_eq!(coords.len(), expected_len, "{:?}", pos);
+
+      let owner = pos.to_chunk_key(seamless_size);
+      assert!(coords.iter().any(|(key, _)| *key == owner));
+      for (key, local) in coords.iter() {
+        assert_eq!(local.to_world(key, seamless_size), pos);
+      }
+
+      let legacy: Vec<_> = potential_keys(p, seamless_size)
+        .into_iter()
+        .filter(|key| has_local_coord(p, key, chunk_size, seamless_size as i64))
+        .map(|key| (ChunkKey(key), LocalVoxelPos(get_local_coord(p, &key, chunk_size))))
+        .collect();
+      assert_eq!(legacy, coords);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_world_f32_round_trip() -> Result<(), String> {
+    let seamless_size = 14;
+    let scales = [1.0, 0.5, 0.25, 0.125, 0.3];
+    for scale in scales.iter() {
+      for p in random_positions(2000, 2000).iter() {
+        let pos = WorldVoxelPos(*p);
+        let f = pos.to_world_f32(*scale);
+        assert_eq!(f.to_voxel_pos(*scale), pos, "scale {}", scale);
+        assert_eq!(f.to_chunk_key(seamless_size, *scale), pos.to_chunk_key(seamless_size));
+
+        // Anywhere inside the voxel cell stays in the same chunk
+        let inside = WorldPosF32([
+          f.0[0] + scale * 0.5,
+          f.0[1] + scale * 0.25,
+          f.0[2] + scale * 0.75,
+        ]);
+        assert_eq!(inside.to_chunk_key(seamless_size, *scale), pos.to_chunk_key(seamless_size));
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_voxel_pos_rounding() -> Result<(), String> {
+    let cases = [
+      ([0.4, -0.4, 2.5], [0, 0, 3], [0, 0, 2]),
+      ([-0.5, -1.5, 1.5], [-1, -2, 2], [0, -1, 1]),
+      ([-2.6, 2.6, -0.9], [-3, 3, -1], [-2, 2, 0]),
+    ];
+    for (pos, nearest, truncated) in cases.iter() {
+      let f = WorldPosF32(*pos);
+      assert_eq!(f.to_voxel_pos(1.0), WorldVoxelPos(*nearest), "{:?}", pos);
+      assert_eq!(f.to_voxel_pos_truncated(1.0), WorldVoxelPos(*truncated), "{:?}", pos);
+    }
+
+    // Scaled positions of the edit tools
+    let f = WorldPosF32([-0.75, 0.75, 1.25]);
+    assert_eq!(f.to_voxel_pos_truncated(0.5), WorldVoxelPos([-1, 1, 2]));
+    assert_eq!(f.to_voxel_pos(0.5), WorldVoxelPos([-2, 2, 3]));
+    Ok(())
+  }
+
+  #[test]
+  fn test_negative_boundaries() -> Result<(), String> {
+    let seamless_size = 14;
+    let keys = [
+      ([-15, -14, -1], [-2, -1, -1]),
+      ([0, 13, 14], [0, 0, 1]),
+      ([-28, -29, 27], [-2, -3, 1]),
+    ];
+    for (pos, key) in keys.iter() {
+      assert_eq!(WorldVoxelPos(*pos).to_chunk_key(seamless_size), ChunkKey(*key));
+    }
+
+    let key = ChunkKey([-1, 0, 0]);
+    assert_eq!(key.to_world_f32(seamless_size, 0.5), WorldPosF32([-7.0, 0.0, 0.0]));
+    assert_eq!(WorldPosF32([-0.1, 0.0, 6.9]).to_chunk_key(seamless_size, 0.5), ChunkKey([-1, 0, 0]));
+    Ok(())
+  }
+}
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+use hashbrown::{HashMap, HashSet};
+use super::chunk_manager::{ChunkManager, Chunk};
+use super::coords::WorldVoxelPos;
+use super::subscription::ChunkChangeKind;
+
+/// Voxels flooded from a seed before its island is taken as part of the terrain
+pub const ISLAND_MAX_VOXELS: usize = 4096;
+
+const NEIGHBOURS: [[i64; 3]; 6] = [
+  [1, 0, 0], [-1, 0, 0], [0, 1, 0], [0, -1, 0], [0, 0, 1], [0, 0, -1],
+];
+
+/**
+  What anchors the voxels. Besides these, the voxels on the min y of the
+  bounds and the ones touching an unloaded chunk are anchored, and so are
+  the islands bigger than max_voxels.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct IslandConfig {
+  pub max_voxels: usize,
+  /// Voxels at or below it are on the ground
+  pub ground_y: Option<i64>,
+  /// Voxel values anchoring everything they touch
+  pub anchors: Vec<u8>,
+}
+
+impl Default for IslandConfig {
+  fn default() -> Self {
+    IslandConfig {
+      max_voxels: ISLAND_MAX_VOXELS,
+      ground_y: None,
+      anchors: Vec::new(),
+    }
+  }
+}
+
+/// Solid voxels connected by their faces and to nothing anchored
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Island {
+  /// World voxel positions, sorted
+  pub positions: Vec<[i64; 3]>,
+  /// Count per material
+  pub materials: HashMap<u8, usize>,
+  pub min: [i64; 3],
+  pub max: [i64; 3],
+}
+
+impl Island {
+  pub fn len(&self) -> usize {
+    self.positions.len()
+  }
+}
+
+impl ChunkManager {
+  /**
+    Returns the islands left floating by an edit of the voxels in [min, max].
+    The solid voxels around the edit are flooded, an island is found when a
+    flood ends without reaching anything anchored.
+  */
+  pub fn find_islands(
+    &self,
+    min: &[i64; 3],
+    max: &[i64; 3],
+    config: &IslandConfig,
+  ) -> Vec<Island> {
+    let mut anchored = HashSet::new();
+    let mut floating = HashSet::new();
+    let mut islands = Vec::new();
+
+    for x in min[0] - 1..max[0] + 2 {
+      for y in min[1] - 1..max[1] + 2 {
+        for z in min[2] - 1..max[2] + 2 {
+          let seed = [x, y, z];
+          if anchored.contains(&seed) || floating.contains(&seed) {
+            continue;
+          }
+          if self.get_voxel_safe(&seed).unwrap_or(0) == 0 {
+            continue;
+          }
+
+          let (positions, is_anchored) = self.flood(&seed, &anchored, config);
+          if is_anchored {
+            anchored.extend(positions);
+            continue;
+          }
+          floating.extend(positions.iter().cloned());
+          islands.push(self.island(positions));
+        }
+      }
+    }
+    islands
+  }
+
+  /// Voxels connected to the seed, true if any of them is anchored
+  fn flood(
+    &self,
+    seed: &[i64; 3],
+    anchored: &HashSet<[i64; 3]>,
+    config: &IslandConfig,
+  ) -> (Vec<[i64; 3]>, bool) {
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    let mut positions = Vec::new();
+    visited.insert(*seed);
+    queue.push_back(*seed);
+
+    while let Some(pos) = queue.pop_front() {
+      let voxel = match self.get_voxel_safe(&pos) {
+        Some(v) => v,
+        None => return (positions, true),
+      };
+      let on_ground = config.ground_y.map_or(false, |y| pos[1] <= y)
+        || (self.bounds.min[1] != i64::MIN && pos[1] <= self.bounds.min[1]);
+      if on_ground || config.anchors.contains(&voxel) || anchored.contains(&pos) {
+        return (positions, true);
+      }
+      positions.push(pos);
+      if positions.len() > config.max_voxels {
+        return (positions, true);
+      }
+
+      for n in NEIGHBOURS.iter() {
+        let next = [pos[0] + n[0], pos[1] + n[1], pos[2] + n[2]];
+        if visited.contains(&next) {
+          continue;
+        }
+        visited.insert(next);
+        // Unloaded neighbours are kept to anchor the flood
+        if self.get_voxel_safe(&next).map_or(true, |v| v != 0) {
+          queue.push_back(next);
+        }
+      }
+    }
+    (positions, false)
+  }
+
+  fn island(&self, mut positions: Vec<[i64; 3]>) -> Island {
+    positions.sort();
+    let mut island = Island {
+      min: [i64::MAX; 3],
+      max: [i64::MIN; 3],
+      ..Default::default()
+    };
+    for pos in positions.iter() {
+      *island.materials.entry(self.get_voxel(pos)).or_insert(0) += 1;
+      for i in 0..3 {
+        island.min[i] = island.min[i].min(pos[i]);
+        island.max[i] = island.max[i].max(pos[i]);
+      }
+    }
+    island.positions = positions;
+    island
+  }
+
+  /**
+    Sets the voxels of the island to air, in every chunk containing them.
+    Returns the modified chunks.
+  */
+  pub fn remove_island(&mut self, island: &Island) -> Vec<([i64; 3], Chunk)> {
+    let chunk_size = self.chunk_size;
+    let seamless_size = self.seamless_size();
+    let mut keys = Vec::new();
+    for pos in island.positions.iter() {
+      let coords = WorldVoxelPos(*pos).chunk_coords(chunk_size, seamless_size);
+      for (key, local) in coords.iter() {
+        let chunk = match self.get_chunk_mut(&key.0) {
+          Some(c) => c,
+          None => continue,
+        };
+        chunk.octree.set_voxel(local.0[0], local.0[1], local.0[2], 0);
+        chunk.is_default = false;
+        self.cache.pin(&key.0);
+        self.notify(&key.0, ChunkChangeKind::Edited, local.0, local.0);
+        if !keys.contains(&key.0) {
+          keys.push(key.0);
+        }
+      }
+    }
+
+    keys
+      .iter()
+      .filter_map(|key| self.get_chunk(key).map(|c| (*key, c.clone())))
+      .collect()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::adjacent_keys;
+
+  const STONE: u8 = 1;
+  const ROOT: u8 = 9;
+
+  /// Loaded chunks around the origin with a floor at y 0 and a pillar with a slab on top
+  fn test_manager() -> ChunkManager {
+    let mut manager = ChunkManager::new(4, 1.0, 1, Vec::new());
+    for key in adjacent_keys(&[0, 0, 0], 1, true).iter() {
+      let mut chunk = manager.generate_chunk(key, 0);
+      chunk.octree = crate::data::voxel_octree::VoxelOctree::new(0, 4);
+      manager.set_chunk(key, &chunk);
+    }
+    for x in -4..4 {
+      for z in -4..4 {
+        manager.set_voxel2(&[x, 0, z], STONE);
+      }
+    }
+    for y in 1..6 {
+      manager.set_voxel2(&[0, y, 0], STONE);
+    }
+    for x in -1..2 {
+      for z in -1..2 {
+        manager.set_voxel2(&[x, 6, z], STONE);
+      }
+    }
+    manager
+  }
+
+  #[test]
+  fn test_find_islands() -> Result<(), String> {
+    let mut manager = test_manager();
+    let config = IslandConfig { ground_y: Some(0), ..Default::default() };
+    assert!(manager.find_islands(&[0, 3, 0], &[0, 3, 0], &config).is_empty());
+
+    // Cutting the pillar leaves its top and the slab floating
+    manager.set_voxel2(&[0, 3, 0], 0);
+    let islands = manager.find_islands(&[0, 3, 0], &[0, 3, 0], &config);
+    assert_eq!(islands.len(), 1);
+    let island = &islands[0];
+    assert_eq!(island.len(), 2 + 9);
+    assert_eq!((island.min, island.max), ([-1, 4, -1], [1, 6, 1]));
+    assert_eq!(island.materials.get(&STONE), Some(&11));
+
+    // Anchored by a voxel, a too small limit or the bounds
+    manager.set_voxel2(&[1, 6, 1], ROOT);
+    let rooted = IslandConfig { anchors: vec![ROOT], ..config.clone() };
+    assert!(manager.find_islands(&[0, 3, 0], &[0, 3, 0], &rooted).is_empty());
+    let small = IslandConfig { max_voxels: 5, ..config.clone() };
+    assert!(manager.find_islands(&[0, 3, 0], &[0, 3, 0], &small).is_empty());
+    assert!(manager.find_islands(&[0, 3, 0], &[0, 3, 0], &IslandConfig::default()).len() == 2);
+
+    let changed = manager.remove_island(&islands[0]);
+    assert!(!changed.is_empty());
+    assert_eq!(manager.get_voxel(&[0, 5, 0]), 0);
+    assert_eq!(manager.get_voxel(&[-1, 6, -1]), 0);
+    assert_eq!(manager.get_voxel(&[0, 2, 0]), STONE);
+    assert!(manager.find_islands(&[0, 3, 0], &[0, 3, 0], &config).is_empty());
+    Ok(())
+  }
+}
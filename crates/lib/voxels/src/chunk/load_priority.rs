@@ -0,0 +1,103 @@
+/**
+  Weights of the terms scoring a chunk to load, lower scores load first. The
+  score is the squared distance to the center in chunks, increased for the
+  chunks behind the camera, plus a term per lod so the coarse rings wait for
+  the near ones.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoadPriority {
+  /// Squared distance multiplied by 1 + facing for the chunks right behind the camera
+  pub facing: f32,
+  /// Added per lod, in squared chunks
+  pub lod: f32,
+}
+
+impl Default for LoadPriority {
+  fn default() -> Self {
+    LoadPriority {
+      facing: 2.0,
+      lod: 4.0,
+    }
+  }
+}
+
+impl LoadPriority {
+  /**
+    Score of the key for a center looking at `forward`, by distance alone if
+    the direction is unknown. The keys of the center and around it are never
+    behind.
+  */
+  pub fn score(
+    &self,
+    key: &[i64; 3],
+    lod: usize,
+    center: &[i64; 3],
+    forward: Option<[f32; 3]>,
+  ) -> u64 {
+    let d = [
+      (key[0] - center[0]) as f32,
+      (key[1] - center[1]) as f32,
+      (key[2] - center[2]) as f32,
+    ];
+    let dist_sqr = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+
+    let mut behind = 0.0;
+    if let Some(f) = forward {
+      let len = dist_sqr.sqrt() * (f[0] * f[0] + f[1] * f[1] + f[2] * f[2]).sqrt();
+      if dist_sqr > 3.0 && len > 0.0 {
+        let cos = (d[0] * f[0] + d[1] * f[1] + d[2] * f[2]) / len;
+        behind = (1.0 - cos) * 0.5;
+      }
+    }
+
+    let score = dist_sqr * (1.0 + self.facing * behind) + self.lod * lod as f32;
+    (score * 100.0).round() as u64
+  }
+
+  /// Lowest score among the centers, 0 without centers
+  pub fn min_score(
+    &self,
+    key: &[i64; 3],
+    lod: usize,
+    centers: &[([i64; 3], Option<[f32; 3]>)],
+  ) -> u64 {
+    centers
+      .iter()
+      .map(|(c, forward)| self.score(key, lod, c, *forward))
+      .min()
+      .unwrap_or(0)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_load_priority() -> Result<(), String> {
+    let priority = LoadPriority::default();
+    let center = [0, 0, 0];
+    let forward = Some([0.0, 0.0, -1.0]);
+    let score = |key: [i64; 3], lod| priority.score(&key, lod, &center, forward);
+
+    // Nearest first, the ones in view before the ones behind
+    assert!(score([0, 0, -2], 0) < score([0, 0, -3], 0));
+    assert!(score([0, 0, -3], 0) < score([3, 0, 0], 0));
+    assert!(score([3, 0, 0], 0) < score([0, 0, 3], 0));
+    assert!(score([0, 0, -3], 1) > score([0, 0, -3], 0));
+
+    // In view farther keys still come before the close ones behind
+    assert!(score([0, 0, -4], 0) < score([0, 0, 3], 0));
+    assert!(score([0, 0, -6], 0) > score([0, 0, 3], 0));
+
+    // Around the center the direction doesn't matter
+    assert_eq!(score([1, 1, 1], 0), score([-1, -1, -1], 0));
+    assert_eq!(priority.score(&[0, 0, 3], 0, &center, None), score([0, 0, -3], 0));
+
+    let centers = [([0, 0, 0], forward), ([10, 0, 0], None)];
+    assert_eq!(priority.min_score(&[9, 0, 0], 0, &centers), 100);
+    assert_eq!(priority.min_score(&[9, 0, 0], 0, &[]), 0);
+    Ok(())
+  }
+}
@@ -4,8 +4,25 @@ use noise::OpenSimplex;
 use num_traits::Pow;
 use crate::data::voxel_octree::VoxelOctree;
 use self::chunk_manager::*;
+use self::coords::WorldVoxelPos;
 
+pub mod border;
+pub mod bounds;
+pub mod cache;
+pub mod clipmap;
 pub mod chunk_manager;
+pub mod coords;
+pub mod islands;
+pub mod load_priority;
+pub mod prefetch;
+pub mod query;
+pub mod raycast;
+pub mod seams;
+pub mod store;
+pub mod subscription;
+
+/// Voxels shared with the next chunk on each axis
+pub const CHUNK_OFFSET: u32 = 2;
 
 
 pub fn is_adjacent(key1: &[i64; 3], key2: &[i64; 3]) -> bool {
@@ -575,32 +592,9 @@ pub fn region_pos_to_world_key(pos: &[u32; 3], seamless_size: u32) -> [i64; 3] {
   world_pos_to_key(&world_pos, seamless_size)
 }
 
+/** Same as WorldVoxelPos::to_chunk_key() */
 pub fn voxel_pos_to_key(pos: &[i64; 3], seamless_size: u32) -> [i64; 3] {
-  let seamless_size_i64 = seamless_size as i64;
-
-  let mut x = pos[0];
-  let mut y = pos[1];
-  let mut z = pos[2];
-
-  // Between -0.epsilon to -seamless_size..., it should be -1
-  if x < 0 {
-    x += 1;
-    x -= seamless_size_i64;
-  }
-  if y < 0 {
-    y += 1;
-    y -= seamless_size_i64;
-  }
-  if z < 0 {
-    z += 1;
-    z -= seamless_size_i64;
-  }
-
-  [
-    x / seamless_size_i64,
-    y / seamless_size_i64,
-    z / seamless_size_i64,
-  ]
+  WorldVoxelPos(*pos).to_chunk_key(seamless_size).0
 }
 
 
@@ -685,11 +679,11 @@ pub fn chunk_mode(octree: &VoxelOctree) -> ChunkMode {
   mode
 }
 
-fn noise_elevation(x: &u32, z: &u32, middle: &i64, noise: OpenSimplex) -> i64 {
+fn noise_elevation(x: i64, z: i64, noise: OpenSimplex) -> i64 {
   let frequency = 0.0125;
   let height_scale = 16.0;
-  let fx = (*x as i64 - middle) as f64 * frequency;
-  let fz = (*z as i64 - middle) as f64 * frequency;
+  let fx = x as f64 * frequency;
+  let fz = z as f64 * frequency;
   let noise = noise.get([fx, fz]);
   let elevation = (noise * height_scale) as i64;
   elevation
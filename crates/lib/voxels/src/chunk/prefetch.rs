@@ -0,0 +1,222 @@
+use hashbrown::HashSet;
+
+/// Added to the score of the prefetches, so they run after every load
+pub const PREFETCH_SCORE: u64 = 1 << 40;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrefetchConfig {
+  /// Seconds of the path ahead of the observer
+  pub lookahead: f32,
+  /// Chunks per second below which nothing is prefetched
+  pub min_speed: f32,
+  /// Chunks around the path on every axis
+  pub radius: i64,
+  pub max_keys: usize,
+  /// Cosine of the turn that cancels the prefetches
+  pub turn_cos: f32,
+}
+
+impl Default for PrefetchConfig {
+  fn default() -> Self {
+    PrefetchConfig {
+      lookahead: 2.0,
+      min_speed: 1.0,
+      radius: 1,
+      max_keys: 128,
+      turn_cos: 0.9,
+    }
+  }
+}
+
+/**
+  Keys along the path of an observer at the key moving at `velocity`, in
+  chunks per second, ordered from the nearest on the path. Empty below the
+  min speed.
+*/
+pub fn predicted_keys(
+  key: &[i64; 3],
+  velocity: &[f32; 3],
+  config: &PrefetchConfig,
+) -> Vec<[i64; 3]> {
+  let speed = length(velocity);
+  if speed < config.min_speed || speed == 0.0 {
+    return Vec::new();
+  }
+  let dir = [velocity[0] / speed, velocity[1] / speed, velocity[2] / speed];
+  let dist = speed * config.lookahead;
+  let r = config.radius;
+
+  let mut seen = HashSet::new();
+  let mut keys = Vec::new();
+  let steps = (dist * 2.0).ceil() as i64;
+  for step in 0..=steps {
+    let t = (step as f32 * 0.5).min(dist);
+    let p = [
+      (key[0] as f32 + 0.5 + dir[0] * t).floor() as i64,
+      (key[1] as f32 + 0.5 + dir[1] * t).floor() as i64,
+      (key[2] as f32 + 0.5 + dir[2] * t).floor() as i64,
+    ];
+    for x in -r..=r {
+      for y in -r..=r {
+        for z in -r..=r {
+          let k = [p[0] + x, p[1] + y, p[2] + z];
+          if seen.insert(k) {
+            keys.push(k);
+            if keys.len() >= config.max_keys {
+              return keys;
+            }
+          }
+        }
+      }
+    }
+  }
+  keys
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PrefetchUpdate {
+  /// New keys on the path, nearest first
+  pub added: Vec<[i64; 3]>,
+  /// Keys to stop prefetching
+  pub cancelled: Vec<[i64; 3]>,
+}
+
+/**
+  Keys prefetched for an observer by its velocity. Moving on the same
+  direction keeps the previous keys still ahead, turning by more than
+  config.turn_cos or slowing down below config.min_speed cancels the keys
+  off the new path.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct Prefetch {
+  pub config: PrefetchConfig,
+  direction: Option<[f32; 3]>,
+  keys: HashSet<[i64; 3]>,
+}
+
+impl Prefetch {
+  pub fn new(config: PrefetchConfig) -> Self {
+    Prefetch { config: config, ..Default::default() }
+  }
+
+  pub fn update(&mut self, key: &[i64; 3], velocity: &[f32; 3]) -> PrefetchUpdate {
+    let path = predicted_keys(key, velocity, &self.config);
+    let speed = length(velocity);
+    let direction = if path.is_empty() {
+      None
+    } else {
+      Some([velocity[0] / speed, velocity[1] / speed, velocity[2] / speed])
+    };
+    let turned = match (self.direction, direction) {
+      (Some(a), Some(b)) => dot(&a, &b) < self.config.turn_cos,
+      (Some(_), None) => true,
+      _ => false,
+    };
+
+    let new_keys: HashSet<[i64; 3]> = path.iter().cloned().collect();
+    let mut update = PrefetchUpdate::default();
+    for k in self.keys.iter() {
+      if new_keys.contains(k) {
+        continue;
+      }
+      // Going on the same way, only the keys left behind are dropped
+      let behind = match direction {
+        Some(d) => {
+          let offset = [(k[0] - key[0]) as f32, (k[1] - key[1]) as f32, (k[2] - key[2]) as f32];
+          dot(&offset, &d) < -(self.config.radius as f32)
+        }
+        None => true,
+      };
+      if turned || behind {
+        update.cancelled.push(*k);
+      }
+    }
+    for k in update.cancelled.iter() {
+      self.keys.remove(k);
+    }
+    update.cancelled.sort();
+
+    for k in path.into_iter() {
+      if self.keys.insert(k) {
+        update.added.push(k);
+      }
+    }
+    self.direction = direction;
+    update
+  }
+
+  pub fn contains(&self, key: &[i64; 3]) -> bool {
+    self.keys.contains(key)
+  }
+
+  pub fn keys(&self) -> impl Iterator<Item = &[i64; 3]> {
+    self.keys.iter()
+  }
+
+  /// Forgets the keys and the direction, returns the keys to cancel
+  pub fn clear(&mut self) -> Vec<[i64; 3]> {
+    self.direction = None;
+    let mut keys: Vec<[i64; 3]> = self.keys.drain().collect();
+    keys.sort();
+    keys
+  }
+}
+
+fn length(v: &[f32; 3]) -> f32 {
+  dot(v, v).sqrt()
+}
+
+fn dot(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+  a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_predicted_keys() -> Result<(), String> {
+    let config = PrefetchConfig { radius: 0, ..Default::default() };
+    assert!(predicted_keys(&[0, 0, 0], &[0.5, 0.0, 0.0], &config).is_empty());
+
+    // 4 chunks per second for 2 seconds
+    let keys = predicted_keys(&[0, 0, 0], &[4.0, 0.0, 0.0], &config);
+    let expected: Vec<[i64; 3]> = (0..=8).map(|x| [x, 0, 0]).collect();
+    assert_eq!(keys, expected);
+
+    let keys = predicted_keys(&[0, 0, 0], &[-4.0, 0.0, 4.0], &PrefetchConfig::default());
+    assert!(keys.contains(&[-5, 0, 5]) && keys.contains(&[-1, 1, 1]));
+    assert!(keys.iter().all(|k| k[0] <= 1 && k[2] >= -1));
+
+    let few = PrefetchConfig { max_keys: 10, ..Default::default() };
+    assert_eq!(predicted_keys(&[0, 0, 0], &[0.0, 0.0, 10.0], &few).len(), 10);
+    Ok(())
+  }
+
+  #[test]
+  fn test_prefetch_cancels_on_turn() -> Result<(), String> {
+    let mut prefetch = Prefetch::new(PrefetchConfig { radius: 0, ..Default::default() });
+    let update = prefetch.update(&[0, 0, 0], &[4.0, 0.0, 0.0]);
+    assert_eq!(update.added.len(), 9);
+    assert!(update.cancelled.is_empty());
+
+    // Further on the same way, the keys behind are dropped and the ones ahead kept
+    let update = prefetch.update(&[3, 0, 0], &[2.0, 0.0, 0.0]);
+    assert_eq!(update.cancelled, vec![[0, 0, 0], [1, 0, 0], [2, 0, 0]]);
+    assert!(update.added.is_empty());
+    assert!(prefetch.contains(&[8, 0, 0]));
+
+    // Turning cancels every key off the new path
+    let update = prefetch.update(&[3, 0, 0], &[0.0, 0.0, 4.0]);
+    assert_eq!(update.cancelled.len(), 5);
+    assert_eq!(update.added.len(), 8);
+    assert!(prefetch.keys().all(|k| k[0] == 3 && k[2] >= 0));
+
+    // Stopping cancels the rest
+    let update = prefetch.update(&[3, 0, 0], &[0.0, 0.0, 0.0]);
+    assert_eq!(update.cancelled.len(), 9);
+    assert_eq!(prefetch.keys().count(), 0);
+    Ok(())
+  }
+}
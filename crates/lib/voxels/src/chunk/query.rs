@@ -0,0 +1,256 @@
+use hashbrown::HashMap;
+use super::chunk_manager::ChunkManager;
+use super::coords::{ChunkKey, WorldVoxelPos};
+
+/**
+  Region in world units(scaled by voxel_scale). A voxel is inside if its
+  grid point(voxel * voxel_scale) is inside.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoxelShape {
+  Box { min: [f32; 3], max: [f32; 3] },
+  Sphere { center: [f32; 3], radius: f32 },
+  Capsule { start: [f32; 3], end: [f32; 3], radius: f32 },
+  /// Axes are the orthonormal local x, y and z directions of the box
+  OrientedBox { center: [f32; 3], half_extents: [f32; 3], axes: [[f32; 3]; 3] },
+}
+
+impl VoxelShape {
+  pub fn contains(&self, p: [f32; 3]) -> bool {
+    match self {
+      VoxelShape::Box { min, max } => {
+        (0..3).all(|i| p[i] >= min[i] && p[i] <= max[i])
+      }
+      VoxelShape::Sphere { center, radius } => {
+        dist_sqr(p, *center) <= radius * radius
+      }
+      VoxelShape::Capsule { start, end, radius } => {
+        let ab = sub(*end, *start);
+        let ap = sub(p, *start);
+        let len_sqr = dot(ab, ab);
+        let t = if len_sqr > 0.0 { (dot(ap, ab) / len_sqr).clamp(0.0, 1.0) } else { 0.0 };
+        let closest = [start[0] + ab[0] * t, start[1] + ab[1] * t, start[2] + ab[2] * t];
+        dist_sqr(p, closest) <= radius * radius
+      }
+      VoxelShape::OrientedBox { center, half_extents, axes } => {
+        let d = sub(p, *center);
+        (0..3).all(|i| dot(d, axes[i]).abs() <= half_extents[i])
+      }
+    }
+  }
+
+  /// Axis aligned bounds in world units
+  pub fn bounds(&self) -> ([f32; 3], [f32; 3]) {
+    match self {
+      VoxelShape::Box { min, max } => (*min, *max),
+      VoxelShape::Sphere { center, radius } => {
+        (add_scalar(*center, -radius), add_scalar(*center, *radius))
+      }
+      VoxelShape::Capsule { start, end, radius } => {
+        let mut min = [0.0; 3];
+        let mut max = [0.0; 3];
+        for i in 0..3 {
+          min[i] = start[i].min(end[i]) - radius;
+          max[i] = start[i].max(end[i]) + radius;
+        }
+        (min, max)
+      }
+      VoxelShape::OrientedBox { center, half_extents, axes } => {
+        let mut min = [0.0; 3];
+        let mut max = [0.0; 3];
+        for i in 0..3 {
+          let extent: f32 = (0..3).map(|a| (axes[a][i] * half_extents[a]).abs()).sum();
+          min[i] = center[i] - extent;
+          max[i] = center[i] + extent;
+        }
+        (min, max)
+      }
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VoxelQuery {
+  /// Non-air voxels inside the shape
+  pub positions: Vec<[i64; 3]>,
+  /// Count per material, air excluded
+  pub materials: HashMap<u8, usize>,
+  /// Every loaded voxel inside the shape, including air
+  pub count: usize,
+  /// Volume of the non-air voxels in world units
+  pub solid_volume: f32,
+}
+
+impl ChunkManager {
+  /**
+    Returns the voxels inside the shape with the count per material.
+    Unloaded chunks are skipped, each voxel is read once from the chunk
+    owning it(not from the overlapping border of the adjacent chunk).
+  */
+  pub fn query(&self, shape: &VoxelShape) -> VoxelQuery {
+    let mut query = VoxelQuery::default();
+    let scale = self.voxel_scale;
+    let seamless_size = self.seamless_size();
+
+    let (min_f, max_f) = shape.bounds();
+    let mut min = [0; 3];
+    let mut max = [0; 3];
+    for i in 0..3 {
+      min[i] = (min_f[i] / scale).ceil() as i64;
+      max[i] = (max_f[i] / scale).floor() as i64;
+      if min[i] > max[i] {
+        return query;
+      }
+    }
+
+    let min_key = WorldVoxelPos(min).to_chunk_key(seamless_size).0;
+    let max_key = WorldVoxelPos(max).to_chunk_key(seamless_size).0;
+    for kx in min_key[0]..max_key[0] + 1 {
+      for ky in min_key[1]..max_key[1] + 1 {
+        for kz in min_key[2]..max_key[2] + 1 {
+          let key = ChunkKey([kx, ky, kz]);
+          let chunk = match self.get_chunk(&key.0) {
+            Some(c) => c,
+            None => continue,
+          };
+
+          let origin = key.origin(seamless_size).0;
+          let mut start = [0; 3];
+          let mut end = [0; 3];
+          for i in 0..3 {
+            start[i] = min[i].max(origin[i]);
+            end[i] = max[i].min(origin[i] + seamless_size as i64 - 1);
+          }
+
+          for x in start[0]..end[0] + 1 {
+            for y in start[1]..end[1] + 1 {
+              for z in start[2]..end[2] + 1 {
+                let p = [x as f32 * scale, y as f32 * scale, z as f32 * scale];
+                if !shape.contains(p) {
+                  continue;
+                }
+
+                query.count += 1;
+                let voxel = chunk.octree.get_voxel(
+                  (x - origin[0]) as u32, (y - origin[1]) as u32, (z - origin[2]) as u32
+                );
+                if voxel > 0 {
+                  query.positions.push([x, y, z]);
+                  *query.materials.entry(voxel).or_insert(0) += 1;
+                }
+              }
+            }
+          }
+        }
+      }
+    }
+
+    query.solid_volume = query.positions.len() as f32 * scale * scale * scale;
+    query
+  }
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+  [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+  a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn dist_sqr(a: [f32; 3], b: [f32; 3]) -> f32 {
+  let d = sub(a, b);
+  dot(d, d)
+}
+
+fn add_scalar(a: [f32; 3], v: f32) -> [f32; 3] {
+  [a[0] + v, a[1] + v, a[2] + v]
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::adjacent_keys;
+
+  fn test_manager(voxel_scale: f32) -> ChunkManager {
+    let mut manager = ChunkManager::new(4, voxel_scale, 1, Vec::new());
+    for key in adjacent_keys(&[0, 0, 0], 2, true).iter() {
+      manager.load_chunk(key, 0);
+    }
+    for x in -5..5 {
+      for z in -5..5 {
+        manager.set_voxel2(&[x, 3, z], 7);
+      }
+    }
+    manager
+  }
+
+  fn brute_force(manager: &ChunkManager, shape: &VoxelShape) -> (Vec<[i64; 3]>, usize) {
+    let scale = manager.voxel_scale;
+    let mut positions = Vec::new();
+    let mut count = 0;
+    for x in -40..40 {
+      for y in -40..40 {
+        for z in -40..40 {
+          let p = [x as f32 * scale, y as f32 * scale, z as f32 * scale];
+          if !shape.contains(p) {
+            continue;
+          }
+          count += 1;
+          if manager.get_voxel(&[x, y, z]) > 0 {
+            positions.push([x, y, z]);
+          }
+        }
+      }
+    }
+    (positions, count)
+  }
+
+  #[test]
+  fn test_query_shapes_match_brute_force() -> Result<(), String> {
+    let c = std::f32::consts::FRAC_1_SQRT_2;
+    let shapes = [
+      VoxelShape::Box { min: [-6.5, -3.0, -2.0], max: [4.0, 5.5, 9.0] },
+      VoxelShape::Sphere { center: [1.0, 2.0, -1.5], radius: 7.5 },
+      VoxelShape::Capsule { start: [-8.0, 0.0, 0.0], end: [6.0, 4.0, 3.0], radius: 3.0 },
+      VoxelShape::OrientedBox {
+        center: [0.5, 1.0, 0.0],
+        half_extents: [8.0, 3.0, 2.0],
+        axes: [[c, 0.0, c], [0.0, 1.0, 0.0], [-c, 0.0, c]],
+      },
+    ];
+
+    for scale in [1.0, 0.5].iter() {
+      let manager = test_manager(*scale);
+      for shape in shapes.iter() {
+        let query = manager.query(shape);
+        let (mut expected, count) = brute_force(&manager, shape);
+
+        let mut positions = query.positions.clone();
+        positions.sort();
+        expected.sort();
+        assert_eq!(positions, expected, "{:?}", shape);
+        assert_eq!(query.count, count, "{:?}", shape);
+
+        let total: usize = query.materials.values().sum();
+        assert_eq!(total, positions.len());
+        assert_eq!(query.solid_volume, total as f32 * scale * scale * scale);
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_query_material_census() -> Result<(), String> {
+    let manager = test_manager(1.0);
+    let query = manager.query(&VoxelShape::Box { min: [-5.0, 3.0, -5.0], max: [4.0, 3.0, 4.0] });
+    assert_eq!(query.count, 100);
+    assert_eq!(query.materials.get(&7), Some(&100));
+    assert_eq!(query.materials.len(), 1);
+
+    let empty = manager.query(&VoxelShape::Sphere { center: [500.0, 0.0, 0.0], radius: 2.0 });
+    assert_eq!(empty, VoxelQuery::default());
+    Ok(())
+  }
+}
@@ -0,0 +1,251 @@
+use super::chunk_manager::ChunkManager;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxelHit {
+  /// World voxel position of the hit voxel
+  pub pos: [i64; 3],
+  /// Normal of the face entered by the ray, zero if the ray started inside a voxel
+  pub normal: [i64; 3],
+  pub voxel: u8,
+  /// Air voxel before the hit, same as pos if the ray started inside a voxel
+  pub prev: [i64; 3],
+  /// Distance from the origin in world units
+  pub distance: f32,
+}
+
+impl ChunkManager {
+  /**
+    Traverses the voxel grid along the ray(Amanatides-Woo) and returns the
+    first non-air voxel within max_dist. Origin and max_dist are in world
+    units(scaled by voxel_scale), the voxels are on the grid points so each
+    voxel covers half a voxel_scale around it. Unloaded chunks are air, so an
+    infinite max_dist stops past the loaded chunks. Returns None for NaN or
+    infinite origin and direction.
+  */
+  pub fn raycast(&self, origin: [f32; 3], dir: [f32; 3], max_dist: f32) -> Option<VoxelHit> {
+    let len = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+    if len == 0.0 || !len.is_finite() || origin.iter().any(|v| !v.is_finite()) {
+      return None;
+    }
+    if max_dist.is_nan() || max_dist < 0.0 {
+      return None;
+    }
+
+    let scale = self.voxel_scale;
+    let max_t = if max_dist.is_finite() {
+      max_dist / scale
+    } else {
+      self.loaded_reach(&origin)?
+    };
+
+    let mut pos = [0; 3];
+    let mut step = [0; 3];
+    let mut t_max = [f32::MAX; 3];
+    let mut t_delta = [f32::MAX; 3];
+    for i in 0..3 {
+      let d = dir[i] / len;
+      let p = origin[i] / scale + 0.5;
+      pos[i] = p.floor() as i64;
+
+      if d > 0.0 {
+        step[i] = 1;
+        t_max[i] = (pos[i] as f32 + 1.0 - p) / d;
+        t_delta[i] = 1.0 / d;
+      } else if d < 0.0 {
+        step[i] = -1;
+        t_max[i] = (p - pos[i] as f32) / -d;
+        t_delta[i] = 1.0 / -d;
+      }
+    }
+
+    let mut prev = pos;
+    let mut normal = [0; 3];
+    let mut t = 0.0;
+    loop {
+      let voxel = self.get_voxel(&pos);
+      if voxel > 0 {
+        return Some(VoxelHit {
+          pos: pos,
+          normal: normal,
+          voxel: voxel,
+          prev: prev,
+          distance: t * scale,
+        });
+      }
+
+      let mut axis = 0;
+      if t_max[1] < t_max[axis] {
+        axis = 1;
+      }
+      if t_max[2] < t_max[axis] {
+        axis = 2;
+      }
+
+      t = t_max[axis];
+      if t > max_t {
+        return None;
+      }
+
+      prev = pos;
+      pos[axis] += step[axis];
+      t_max[axis] += t_delta[axis];
+      normal = [0; 3];
+      normal[axis] = -step[axis];
+    }
+  }
+
+  /// Distance in voxels from the origin to the farthest corner of the loaded chunks
+  fn loaded_reach(&self, origin: &[f32; 3]) -> Option<f32> {
+    let mut keys = self.chunks.keys();
+    let first = *keys.next()?;
+    let mut min_key = first;
+    let mut max_key = first;
+    for key in keys {
+      for i in 0..3 {
+        min_key[i] = min_key[i].min(key[i]);
+        max_key[i] = max_key[i].max(key[i]);
+      }
+    }
+
+    let seamless_size = self.seamless_size() as f32;
+    let mut reach = 0.0;
+    for i in 0..3 {
+      let p = origin[i] / self.voxel_scale;
+      let min = min_key[i] as f32 * seamless_size;
+      let max = max_key[i] as f32 * seamless_size + self.chunk_size as f32;
+      let d = (p - min).abs().max((p - max).abs());
+      reach += d * d;
+    }
+    Some(reach.sqrt() + 1.0)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{chunk::{adjacent_keys, chunk_manager::Chunk}, data::voxel_octree::VoxelOctree};
+
+  /// Floor at y == -1 with a pillar of voxel 2 at [3, 0..4, -5], air elsewhere
+  fn test_manager(voxel_scale: f32) -> ChunkManager {
+    let mut manager = ChunkManager::new(4, voxel_scale, 1, Vec::new());
+    for key in adjacent_keys(&[0, 0, 0], 3, true).iter() {
+      let chunk = Chunk {
+        key: *key,
+        octree: VoxelOctree::new(0, manager.depth as u8),
+        is_default: false,
+        ..Default::default()
+      };
+      manager.set_chunk(key, &chunk);
+    }
+
+    for x in -10..10 {
+      for z in -10..10 {
+        manager.set_voxel2(&[x, -1, z], 1);
+      }
+    }
+    for y in 0..4 {
+      manager.set_voxel2(&[3, y, -5], 2);
+    }
+    manager
+  }
+
+  /// Samples the ray with small steps, the first solid voxel found
+  fn march(manager: &ChunkManager, origin: [f32; 3], dir: [f32; 3], max_dist: f32) -> Option<[i64; 3]> {
+    let scale = manager.voxel_scale;
+    let len = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+    let steps = (max_dist / scale * 200.0) as i64;
+    for s in 0..steps {
+      let t = s as f32 * max_dist / steps as f32;
+      let mut pos = [0; 3];
+      for i in 0..3 {
+        pos[i] = ((origin[i] + dir[i] / len * t) / scale + 0.5).floor() as i64;
+      }
+      if manager.get_voxel(&pos) > 0 {
+        return Some(pos);
+      }
+    }
+    None
+  }
+
+  #[test]
+  fn test_raycast_down() -> Result<(), String> {
+    let manager = test_manager(1.0);
+    let hit = manager.raycast([0.0, 5.0, 0.0], [0.0, -1.0, 0.0], 20.0).unwrap();
+    assert_eq!(hit.pos, [0, -1, 0]);
+    assert_eq!(hit.prev, [0, 0, 0]);
+    assert_eq!(hit.normal, [0, 1, 0]);
+    assert_eq!(hit.voxel, 1);
+    assert!((hit.distance - 5.5).abs() < 0.001);
+
+    assert!(manager.raycast([0.0, 5.0, 0.0], [0.0, -1.0, 0.0], 5.0).is_none());
+    assert!(manager.raycast([0.0, 5.0, 0.0], [0.0, 1.0, 0.0], 20.0).is_none());
+    assert!(manager.raycast([0.0, 5.0, 0.0], [0.0, 0.0, 0.0], 20.0).is_none());
+    Ok(())
+  }
+
+  #[test]
+  fn test_raycast_unbounded_and_invalid() -> Result<(), String> {
+    let manager = test_manager(0.5);
+    let hit = manager.raycast([0.0, 5.0, 0.0], [0.0, -1.0, 0.0], f32::INFINITY).unwrap();
+    assert_eq!(hit.pos, [0, -1, 0]);
+
+    // Misses end past the loaded chunks
+    assert!(manager.raycast([0.0, 5.0, 0.0], [0.0, 1.0, 0.0], f32::INFINITY).is_none());
+    assert!(manager.raycast([900.0, 5.0, 0.0], [1.0, 0.2, 0.0], f32::INFINITY).is_none());
+    assert!(ChunkManager::default().raycast([0.0; 3], [0.0, -1.0, 0.0], f32::INFINITY).is_none());
+
+    assert!(manager.raycast([f32::NAN, 5.0, 0.0], [0.0, -1.0, 0.0], 20.0).is_none());
+    assert!(manager.raycast([0.0, f32::INFINITY, 0.0], [0.0, -1.0, 0.0], 20.0).is_none());
+    assert!(manager.raycast([0.0, 5.0, 0.0], [f32::NAN, -1.0, 0.0], 20.0).is_none());
+    assert!(manager.raycast([0.0, 5.0, 0.0], [0.0, -1.0, 0.0], f32::NAN).is_none());
+    assert!(manager.raycast([0.0, 5.0, 0.0], [0.0, -1.0, 0.0], -1.0).is_none());
+    Ok(())
+  }
+
+  #[test]
+  fn test_raycast_side_and_inside() -> Result<(), String> {
+    let manager = test_manager(1.0);
+    let hit = manager.raycast([-2.0, 2.0, -5.0], [1.0, 0.0, 0.0], 20.0).unwrap();
+    assert_eq!(hit.pos, [3, 2, -5]);
+    assert_eq!(hit.normal, [-1, 0, 0]);
+    assert_eq!(hit.prev, [2, 2, -5]);
+    assert_eq!(hit.voxel, 2);
+
+    let hit = manager.raycast([3.0, 1.0, -5.0], [1.0, 0.0, 0.0], 20.0).unwrap();
+    assert_eq!(hit.pos, [3, 1, -5]);
+    assert_eq!(hit.normal, [0, 0, 0]);
+    assert_eq!(hit.prev, hit.pos);
+    assert_eq!(hit.distance, 0.0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_raycast_matches_marching() -> Result<(), String> {
+    let dirs = [
+      [0.3, -1.0, 0.2],
+      [-0.7, -0.4, 0.5],
+      [1.0, -0.25, -1.0],
+      [-0.2, -0.9, -0.8],
+    ];
+    for scale in [1.0, 0.5, 0.25].iter() {
+      let manager = test_manager(*scale);
+      let origin = [-1.3 * scale, 2.6 * scale, 0.4 * scale];
+      let max_dist = 30.0 * scale;
+      for dir in dirs.iter() {
+        let hit = manager.raycast(origin, *dir, max_dist).map(|h| h.pos);
+        assert_eq!(hit, march(&manager, origin, *dir, max_dist), "scale {} dir {:?}", scale, dir);
+
+        if let Some(h) = manager.raycast(origin, *dir, max_dist) {
+          let diff: i64 = (0..3).map(|i| (h.pos[i] - h.prev[i]).abs()).sum();
+          assert_eq!(diff, 1);
+          assert_eq!(manager.get_voxel(&h.prev), 0);
+          for i in 0..3 {
+            assert_eq!(h.prev[i], h.pos[i] + h.normal[i]);
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,112 @@
+use hashbrown::{HashMap, HashSet};
+
+/// Offsets of the neighbouring chunks in the side order of add_skirts()
+pub const SIDE_OFFSETS: [[i64; 3]; 6] = [
+  [-1, 0, 0], [1, 0, 0],
+  [0, -1, 0], [0, 1, 0],
+  [0, 0, -1], [0, 0, 1],
+];
+
+/**
+  Lod each chunk is meshed at, to find the sides that need a skirt against a
+  neighbour of another lod and the neighbours to stitch again when it changes.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct LodSeams {
+  lods: HashMap<[i64; 3], usize>,
+}
+
+impl LodSeams {
+  pub fn lod(&self, key: &[i64; 3]) -> Option<usize> {
+    self.lods.get(key).cloned()
+  }
+
+  /**
+    Sets the lod of the keys. Returns the other meshed chunks whose seam sides
+    changed, their meshes have to be computed again.
+  */
+  pub fn set_lods(&mut self, keys: &Vec<[i64; 3]>, lod: usize) -> Vec<[i64; 3]> {
+    let set: HashSet<[i64; 3]> = keys.iter().cloned().collect();
+    let mut neighbours = Vec::new();
+    for key in keys.iter() {
+      for offset in SIDE_OFFSETS.iter() {
+        let n = [key[0] + offset[0], key[1] + offset[1], key[2] + offset[2]];
+        if !set.contains(&n) && self.lods.contains_key(&n) && !neighbours.contains(&n) {
+          neighbours.push(n);
+        }
+      }
+    }
+
+    let before: Vec<[bool; 6]> = neighbours
+      .iter()
+      .map(|n| self.sides(n, self.lods[n]))
+      .collect();
+    for key in keys.iter() {
+      self.lods.insert(*key, lod);
+    }
+
+    neighbours
+      .iter()
+      .zip(before.iter())
+      .filter(|(n, sides)| self.sides(n, self.lods[*n]) != **sides)
+      .map(|(n, _)| *n)
+      .collect()
+  }
+
+  pub fn remove(&mut self, key: &[i64; 3]) {
+    self.lods.remove(key);
+  }
+
+  /// Sides with a meshed neighbour at a different lod than `lod`
+  pub fn sides(&self, key: &[i64; 3], lod: usize) -> [bool; 6] {
+    let mut sides = [false; 6];
+    for (i, offset) in SIDE_OFFSETS.iter().enumerate() {
+      let n = [key[0] + offset[0], key[1] + offset[1], key[2] + offset[2]];
+      sides[i] = self.lods.get(&n).map_or(false, |l| *l != lod);
+    }
+    sides
+  }
+
+  /// Highest lod among the chunk and its neighbours, skirts have to reach that deep
+  pub fn max_lod(&self, key: &[i64; 3], lod: usize) -> usize {
+    SIDE_OFFSETS
+      .iter()
+      .filter_map(|o| self.lods.get(&[key[0] + o[0], key[1] + o[1], key[2] + o[2]]))
+      .fold(lod, |max, l| max.max(*l))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_lod_seams() -> Result<(), String> {
+    let mut seams = LodSeams::default();
+    let ring0 = vec![[0, 0, 0], [1, 0, 0]];
+    assert!(seams.set_lods(&ring0, 0).is_empty());
+    assert_eq!(seams.sides(&[0, 0, 0], 0), [false; 6]);
+
+    // [1, 0, 0] is stitched again against the new lod 1 neighbour
+    assert_eq!(seams.set_lods(&vec![[2, 0, 0], [1, 1, 0]], 1), vec![[1, 0, 0]]);
+    assert_eq!(seams.sides(&[1, 0, 0], 0), [false, true, false, true, false, false]);
+    assert_eq!(seams.sides(&[2, 0, 0], 1), [true, false, false, false, false, false]);
+    assert_eq!(seams.max_lod(&[1, 0, 0], 0), 1);
+    assert_eq!(seams.max_lod(&[0, 0, 0], 0), 0);
+
+    // Same lod again, nothing changes
+    assert!(seams.set_lods(&vec![[2, 0, 0]], 1).is_empty());
+
+    // [2, 0, 0] becomes lod 0, [1, 0, 0] has no seam on +x anymore
+    let mut changed = seams.set_lods(&vec![[2, 0, 0]], 0);
+    changed.sort();
+    assert_eq!(changed, vec![[1, 0, 0]]);
+    assert_eq!(seams.sides(&[1, 0, 0], 0), [false, false, false, true, false, false]);
+
+    seams.remove(&[1, 1, 0]);
+    assert_eq!(seams.lod(&[1, 1, 0]), None);
+    assert_eq!(seams.sides(&[1, 0, 0], 0), [false; 6]);
+    Ok(())
+  }
+}
@@ -0,0 +1,230 @@
+use noise::OpenSimplex;
+use crate::data::voxel_octree::LodPolicy;
+use crate::utils::grid_hashmap::ShardedGridHashMap;
+use super::bounds::WorldBounds;
+use super::chunk_manager::{Chunk, ChunkManager};
+use super::coords::{WorldVoxelPos, ChunkKey, LocalVoxelPos};
+
+/**
+  Thread-safe chunk storage with the same generation settings as the
+  ChunkManager it was created from. Share it with Arc between generation,
+  meshing and edit tasks instead of cloning the whole ChunkManager, e.g. the
+  region of ChunkManager::chunk_store() for the edit previews. The manager
+  keeps its own map, as its callers borrow the chunks instead of copying them.
+*/
+pub struct ChunkStore {
+  chunks: ShardedGridHashMap<Chunk, [i64; 3]>,
+  pub depth: u32,
+  pub chunk_size: u32,
+  pub offset: u32,
+  pub noise: OpenSimplex,
+  pub lod_policy: LodPolicy,
+  pub bounds: WorldBounds,
+}
+
+impl ChunkStore {
+  /// Empty store using the settings of the manager
+  pub fn new(manager: &ChunkManager) -> Self {
+    ChunkStore {
+      chunks: ShardedGridHashMap::default(),
+      depth: manager.depth,
+      chunk_size: manager.chunk_size,
+      offset: manager.offset,
+      noise: manager.noise,
+      lod_policy: manager.lod_policy,
+      bounds: manager.bounds,
+    }
+  }
+
+  pub fn seamless_size(&self) -> u32 {
+    self.chunk_size - self.offset
+  }
+
+  pub fn get_chunk(&self, key: &[i64; 3]) -> Option<Chunk> {
+    self.chunks.get(key)
+  }
+
+  /// Calls f with the chunk without cloning it
+  pub fn read_chunk<R>(&self, key: &[i64; 3], f: impl FnOnce(&Chunk) -> R) -> Option<R> {
+    self.chunks.read(key, f)
+  }
+
+  /// Same rule as ChunkManager::set_chunk(), default chunks don't replace loaded ones
+  pub fn set_chunk(&self, key: &[i64; 3], chunk: &Chunk) {
+    if chunk.is_default && self.chunks.contains_key(key) {
+      return;
+    }
+    self.chunks.insert(*key, chunk.clone());
+  }
+
+  pub fn remove_chunk(&self, key: &[i64; 3]) -> Option<Chunk> {
+    self.chunks.remove(key)
+  }
+
+  /**
+    Returns the stored chunk, otherwise generates it without blocking other
+    chunks. Chunks outside of the bounds are returned without being stored.
+  */
+  pub fn load_chunk(&self, key: &[i64; 3], lod: usize) -> Chunk {
+    let generate = || {
+      ChunkManager::new_chunk_in_bounds(
+        key, self.depth as u8, lod, self.noise, self.lod_policy, &self.bounds
+      )
+    };
+    if !self.bounds.contains_key(key, self.chunk_size, self.seamless_size()) {
+      return generate();
+    }
+    self.chunks.get_or_insert_with(*key, generate)
+  }
+
+  /// Returns 0 if the chunk is not loaded containing the coordinate
+  pub fn get_voxel(&self, pos: &[i64; 3]) -> u8 {
+    let seamless_size = self.seamless_size();
+    let pos = WorldVoxelPos(*pos);
+    let key = pos.to_chunk_key(seamless_size);
+    let local = match pos.to_local(&key, self.chunk_size, seamless_size) {
+      Some(l) => l.0,
+      None => return 0,
+    };
+
+    self
+      .chunks
+      .read(&key.0, |c| c.octree.get_voxel(local[0], local[1], local[2]))
+      .unwrap_or(0)
+  }
+
+  /**
+    Same as ChunkManager::set_voxel2(), missing chunks are generated first.
+    Returns the keys of the modified chunks, empty if outside of the bounds.
+  */
+  pub fn set_voxel(&self, pos: &[i64; 3], voxel: u8) -> Vec<[i64; 3]> {
+    let mut keys = Vec::new();
+    if !self.bounds.is_editable(pos) {
+      return keys;
+    }
+    let coords = WorldVoxelPos(*pos).chunk_coords(self.chunk_size, self.seamless_size());
+    for (ChunkKey(key), LocalVoxelPos(local)) in coords.iter() {
+      self.load_chunk(key, 0);
+      self.chunks.write(key, |chunk| {
+        chunk.octree.set_voxel(local[0], local[1], local[2], voxel);
+        chunk.is_default = false;
+      });
+      keys.push(*key);
+    }
+    keys
+  }
+
+  pub fn len(&self) -> usize {
+    self.chunks.len()
+  }
+
+  pub fn keys(&self) -> Vec<[i64; 3]> {
+    self.chunks.keys()
+  }
+
+  /// Copies the chunks back to a manager with ChunkManager::set_chunk()
+  pub fn copy_to_manager(&self, manager: &mut ChunkManager) {
+    self.chunks.for_each(|key, chunk| manager.set_chunk(key, chunk));
+  }
+}
+
+
+impl ChunkManager {
+  /**
+    Store with a copy of the loaded chunks containing the voxels from min to
+    max(inclusive), for edits on a region without cloning the whole manager
+  */
+  pub fn chunk_store(&self, min: &[i64; 3], max: &[i64; 3]) -> ChunkStore {
+    let store = ChunkStore::new(self);
+    let seamless_size = self.seamless_size();
+    let mut min_key = WorldVoxelPos(*min).to_chunk_key(seamless_size).0;
+    for (key, _) in WorldVoxelPos(*min).chunk_coords(self.chunk_size, seamless_size).iter() {
+      for i in 0..3 {
+        min_key[i] = min_key[i].min(key.0[i]);
+      }
+    }
+    let max_key = WorldVoxelPos(*max).to_chunk_key(seamless_size).0;
+
+    for x in min_key[0]..=max_key[0] {
+      for y in min_key[1]..=max_key[1] {
+        for z in min_key[2]..=max_key[2] {
+          let key = [x, y, z];
+          if let Some(chunk) = self.chunks.get(&key) {
+            store.chunks.insert(key, chunk.clone());
+          }
+        }
+      }
+    }
+    store
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_store_parallel_edits_match_manager() -> Result<(), String> {
+    let mut manager = ChunkManager::default();
+    let store = ChunkStore::new(&manager);
+
+    let edits: Vec<([i64; 3], u8)> = (-20..20)
+      .flat_map(|x| (-3..3).map(move |y| ([x, y, x / 2 - y], ((x + 30) % 5 + 1) as u8)))
+      .collect();
+
+    std::thread::scope(|scope| {
+      for part in edits.chunks(40) {
+        let store = &store;
+        scope.spawn(move || {
+          for (pos, voxel) in part.iter() {
+            store.set_voxel(pos, *voxel);
+          }
+        });
+      }
+    });
+
+    for (pos, voxel) in edits.iter() {
+      manager.set_voxel2(pos, *voxel);
+    }
+
+    assert_eq!(store.len(), manager.len());
+    for key in store.keys().iter() {
+      let chunk = store.get_chunk(key).unwrap();
+      let expected = manager.get_chunk(key).unwrap();
+      assert_eq!(chunk.octree.to_dense(), expected.octree.to_dense(), "key {:?}", key);
+      assert_eq!(chunk.is_default, expected.is_default);
+    }
+    for (pos, voxel) in edits.iter() {
+      assert_eq!(store.get_voxel(pos), *voxel);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_chunk_store_region() -> Result<(), String> {
+    let mut manager = ChunkManager::default();
+    manager.set_voxel2(&[1, 2, 3], 9);
+    manager.set_voxel2(&[40, 2, 3], 5);
+
+    // Only the chunks containing the region are copied
+    let store = manager.chunk_store(&[0, 0, 0], &[3, 3, 3]);
+    assert_eq!(store.get_voxel(&[1, 2, 3]), 9);
+    assert_eq!(store.get_voxel(&[40, 2, 3]), 0);
+    let keys = store.keys();
+    assert!(keys.contains(&[0, 0, 0]));
+    assert!(keys.iter().all(|k| k.iter().all(|v| *v == 0 || *v == -1)), "{:?}", keys);
+
+    // Default chunk doesn't replace the modified one
+    let default = ChunkManager::new_chunk(&[0, 0, 0], 4, 0, manager.noise);
+    store.set_chunk(&[0, 0, 0], &default);
+    assert_eq!(store.get_voxel(&[1, 2, 3]), 9);
+
+    store.set_voxel(&[-1, 0, 0], 4);
+    let mut other = ChunkManager::default();
+    store.copy_to_manager(&mut other);
+    assert_eq!(other.get_voxel(&[-1, 0, 0]), 4);
+    assert_eq!(other.get_voxel(&[1, 2, 3]), 9);
+    Ok(())
+  }
+}
@@ -0,0 +1,189 @@
+use hashbrown::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkChangeKind {
+  /// New chunk was created, either generated or set
+  Generated,
+  /// Voxels of a loaded chunk changed
+  Edited,
+  /// Unmodified chunk removed by the cache budget
+  Evicted,
+  /// Chunk removed by remove_chunk() or clear()
+  Removed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkChange {
+  pub key: [i64; 3],
+  pub kind: ChunkChangeKind,
+  /// Inclusive local voxel bounds of the change, the whole chunk unless Edited
+  pub min: [u32; 3],
+  pub max: [u32; 3],
+}
+
+impl ChunkChange {
+  pub fn whole(key: [i64; 3], kind: ChunkChangeKind, chunk_size: u32) -> Self {
+    let m = chunk_size - 1;
+    ChunkChange { key: key, kind: kind, min: [0; 3], max: [m, m, m] }
+  }
+
+  /**
+    Merges a later change of the same key into this one. A chunk edited
+    after being removed is loaded again, so it becomes Generated with the
+    whole chunk bounds for the subscribers that dropped it.
+  */
+  fn merge(&mut self, other: &ChunkChange) {
+    let removed = self.kind == ChunkChangeKind::Evicted || self.kind == ChunkChangeKind::Removed;
+    if other.kind != ChunkChangeKind::Edited {
+      *self = *other;
+    } else if removed {
+      self.kind = ChunkChangeKind::Generated;
+    } else {
+      for i in 0..3 {
+        self.min[i] = self.min[i].min(other.min[i]);
+        self.max[i] = self.max[i].max(other.max[i]);
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(pub u32);
+
+/**
+  Observed region(inclusive chunk keys) with the changes not taken yet.
+  Changes of the same key are merged until taken.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct SubscribeData {
+  pub min_key: [i64; 3],
+  pub max_key: [i64; 3],
+  changes: Vec<ChunkChange>,
+  indices: HashMap<[i64; 3], usize>,
+}
+
+impl SubscribeData {
+  pub fn contains(&self, key: &[i64; 3]) -> bool {
+    (0..3).all(|i| key[i] >= self.min_key[i] && key[i] <= self.max_key[i])
+  }
+
+  fn push(&mut self, change: &ChunkChange) {
+    match self.indices.get(&change.key) {
+      Some(index) => self.changes[*index].merge(change),
+      None => {
+        self.indices.insert(change.key, self.changes.len());
+        self.changes.push(*change);
+      }
+    }
+  }
+
+  fn take(&mut self) -> Vec<ChunkChange> {
+    self.indices.clear();
+    std::mem::take(&mut self.changes)
+  }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChunkSubscriptions {
+  next_id: u32,
+  subscriptions: HashMap<SubscriptionId, SubscribeData>,
+}
+
+impl ChunkSubscriptions {
+  pub fn subscribe(&mut self, min_key: [i64; 3], max_key: [i64; 3]) -> SubscriptionId {
+    let id = SubscriptionId(self.next_id);
+    self.next_id += 1;
+    self.subscriptions.insert(id, SubscribeData {
+      min_key: min_key,
+      max_key: max_key,
+      ..Default::default()
+    });
+    id
+  }
+
+  /// Moves the observed region, pending changes are kept
+  pub fn set_region(&mut self, id: SubscriptionId, min_key: [i64; 3], max_key: [i64; 3]) -> bool {
+    match self.subscriptions.get_mut(&id) {
+      Some(data) => {
+        data.min_key = min_key;
+        data.max_key = max_key;
+        true
+      }
+      None => false,
+    }
+  }
+
+  pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+    self.subscriptions.remove(&id).is_some()
+  }
+
+  /// Returns the changes since the last call, None if not subscribed
+  pub fn take_changes(&mut self, id: SubscriptionId) -> Option<Vec<ChunkChange>> {
+    self.subscriptions.get_mut(&id).map(|data| data.take())
+  }
+
+  pub fn notify(&mut self, change: &ChunkChange) {
+    for data in self.subscriptions.values_mut() {
+      if data.contains(&change.key) {
+        data.push(change);
+      }
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.subscriptions.is_empty()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_changes_are_merged_per_key() -> Result<(), String> {
+    let mut subs = ChunkSubscriptions::default();
+    let id = subs.subscribe([-1, -1, -1], [1, 1, 1]);
+    let edit = |min: [u32; 3], max: [u32; 3]| ChunkChange {
+      key: [0, 0, 0], kind: ChunkChangeKind::Edited, min: min, max: max
+    };
+
+    subs.notify(&edit([2, 3, 4], [2, 3, 4]));
+    subs.notify(&edit([5, 1, 4], [6, 1, 4]));
+    subs.notify(&ChunkChange::whole([5, 0, 0], ChunkChangeKind::Generated, 16));
+    assert_eq!(subs.take_changes(id), Some(vec![edit([2, 1, 4], [6, 3, 4])]));
+    assert_eq!(subs.take_changes(id), Some(vec![]));
+
+    subs.notify(&ChunkChange::whole([0, 0, 0], ChunkChangeKind::Generated, 16));
+    subs.notify(&edit([1, 1, 1], [1, 1, 1]));
+    let changes = subs.take_changes(id).unwrap();
+    assert_eq!(changes, vec![ChunkChange::whole([0, 0, 0], ChunkChangeKind::Generated, 16)]);
+
+    assert!(subs.unsubscribe(id));
+    assert_eq!(subs.take_changes(id), None);
+    Ok(())
+  }
+
+  #[test]
+  fn test_edit_after_removal_is_generated() -> Result<(), String> {
+    let mut subs = ChunkSubscriptions::default();
+    let id = subs.subscribe([-1, -1, -1], [1, 1, 1]);
+    let edit = ChunkChange { key: [0, 0, 0], kind: ChunkChangeKind::Edited, min: [1, 1, 1], max: [2, 2, 2] };
+    let generated = ChunkChange::whole([0, 0, 0], ChunkChangeKind::Generated, 16);
+
+    for kind in [ChunkChangeKind::Evicted, ChunkChangeKind::Removed].iter() {
+      subs.notify(&ChunkChange::whole([0, 0, 0], *kind, 16));
+      subs.notify(&edit);
+      subs.notify(&edit);
+      assert_eq!(subs.take_changes(id), Some(vec![generated]));
+    }
+
+    // Removed again after the edit stays removed
+    subs.notify(&ChunkChange::whole([0, 0, 0], ChunkChangeKind::Evicted, 16));
+    subs.notify(&edit);
+    subs.notify(&ChunkChange::whole([0, 0, 0], ChunkChangeKind::Removed, 16));
+    let changes = subs.take_changes(id).unwrap();
+    assert_eq!(changes, vec![ChunkChange::whole([0, 0, 0], ChunkChangeKind::Removed, 16)]);
+    Ok(())
+  }
+}
@@ -0,0 +1,378 @@
+use crate::utils::coord_to_index;
+use super::surface_nets::{VoxelReuse, Layout, AoBorder, fill_voxels, mesh_cells};
+use super::voxel_octree::{VoxelOctree, MeshData, MeshOptions};
+
+/**
+  Surface nets mesher of one chunk that keeps its voxels, grids and faces per
+  block of grids. Edits only mark the blocks around the changed voxels dirty,
+  update() meshes those blocks again and mesh_data() patches them together.
+*/
+pub struct BlockMesher {
+  pub key: [i64; 3],
+  pub lod: usize,
+  pub colors: Vec<[f32; 3]>,
+  block_size: u32,
+  /// Blocks per axis
+  blocks_len: u32,
+  size: u32,
+  voxel_reuse: VoxelReuse,
+  layout: Layout,
+  blocks: Vec<MeshData>,
+  dirty: Vec<bool>,
+  /// Blocks meshed by the last update()
+  updated: Vec<usize>,
+}
+
+impl BlockMesher {
+  pub fn new(
+    octree: &VoxelOctree,
+    colors: &Vec<[f32; 3]>,
+    scale: f32,
+    key: [i64; 3],
+    lod: usize,
+    options: &MeshOptions,
+    block_size: u32,
+  ) -> Self {
+    BlockMesher::new_with_border(octree, colors, scale, key, lod, options, block_size, None)
+  }
+
+  /// Same as new(), with the neighbour voxels for the ambient occlusion of the border grids
+  pub fn new_with_border(
+    octree: &VoxelOctree,
+    colors: &Vec<[f32; 3]>,
+    scale: f32,
+    key: [i64; 3],
+    lod: usize,
+    options: &MeshOptions,
+    block_size: u32,
+    ao_border: Option<AoBorder>,
+  ) -> Self {
+    let size = octree.get_size();
+    let blocks_len = (size - 1 + block_size - 1) / block_size;
+    let count = blocks_len.pow(3) as usize;
+
+    let mut voxel_reuse = VoxelReuse::new(octree.get_depth() as u32, 3);
+    fill_voxels(octree, &mut voxel_reuse, &options.kinds);
+
+    let mut layout = Layout::for_chunk(size, key, options, scale);
+    layout.ao_border = ao_border;
+
+    let mut mesher = BlockMesher {
+      key: key,
+      lod: lod,
+      colors: colors.clone(),
+      block_size: block_size,
+      blocks_len: blocks_len,
+      size: size,
+      voxel_reuse: voxel_reuse,
+      layout: layout,
+      blocks: vec![MeshData::default(); count],
+      dirty: vec![true; count],
+      updated: Vec::new(),
+    };
+    mesher.update();
+    mesher
+  }
+
+  /**
+    Neighbour voxels for the ambient occlusion of the border grids, marks the
+    blocks on the chunk border dirty when they changed
+  */
+  pub fn set_ao_border(&mut self, ao_border: AoBorder) {
+    if self.layout.ao_border.as_ref() == Some(&ao_border) {
+      return;
+    }
+    self.layout.ao_border = Some(ao_border);
+    if !self.layout.options.ao {
+      return;
+    }
+    let last = self.size - 2;
+    for axis in 0..3 {
+      let mut max = [last; 3];
+      max[axis] = 0;
+      self.mark_dirty([0; 3], max);
+      let mut min = [0; 3];
+      min[axis] = last;
+      self.mark_dirty(min, [last; 3]);
+    }
+  }
+
+  /// Voxels per axis
+  pub fn size(&self) -> u32 {
+    self.size
+  }
+
+  pub fn options(&self) -> &MeshOptions {
+    &self.layout.options
+  }
+
+  pub fn scale(&self) -> f32 {
+    self.layout.scale
+  }
+
+  pub fn get_voxel(&self, x: u32, y: u32, z: u32) -> u8 {
+    self.voxel_reuse.voxels[coord_to_index(x, y, z, 0, self.size)]
+  }
+
+  /**
+    Sets the voxel and marks the blocks using it dirty, false if unchanged.
+    Voxels that are not opaque are stored as air.
+  */
+  pub fn set_voxel(&mut self, x: u32, y: u32, z: u32, voxel: u8) -> bool {
+    let voxel = self.layout.options.kinds.opaque_voxel(voxel);
+    let index = coord_to_index(x, y, z, 0, self.size);
+    if self.voxel_reuse.voxels[index] == voxel {
+      return false;
+    }
+    self.voxel_reuse.voxels[index] = voxel;
+
+    // Grids x - 1 and x have the voxel as corner, the faces of x + 1 use grid x.
+    // Ambient occlusion of the grids x - 2 and x + 1 samples it too.
+    let margin = if self.layout.options.ao { 2 } else { 1 };
+    let last = self.size - 2;
+    self.mark_dirty(
+      [x.max(margin) - margin, y.max(margin) - margin, z.max(margin) - margin],
+      [(x + margin).min(last), (y + margin).min(last), (z + margin).min(last)],
+    );
+    true
+  }
+
+  /// Copies the voxels that differ from the octree, returns how many changed
+  pub fn sync(&mut self, octree: &VoxelOctree) -> usize {
+    let voxels = octree.to_dense();
+    let mut changed = 0;
+    for x in 0..self.size {
+      for y in 0..self.size {
+        for z in 0..self.size {
+          let voxel = voxels[coord_to_index(x, y, z, 0, self.size)];
+          if self.set_voxel(x, y, z, voxel) {
+            changed += 1;
+          }
+        }
+      }
+    }
+    changed
+  }
+
+  /**
+    Same as sync(), only the voxels in the inclusive bounds are compared, as
+    the ChunkChange of an edit gives them
+  */
+  pub fn sync_region(&mut self, octree: &VoxelOctree, min: [u32; 3], max: [u32; 3]) -> usize {
+    let last = self.size - 1;
+    let mut changed = 0;
+    for x in min[0]..=max[0].min(last) {
+      for y in min[1]..=max[1].min(last) {
+        for z in min[2]..=max[2].min(last) {
+          if self.set_voxel(x, y, z, octree.get_voxel(x, y, z)) {
+            changed += 1;
+          }
+        }
+      }
+    }
+    changed
+  }
+
+  /// Marks the blocks overlapping the inclusive grid range
+  pub fn mark_dirty(&mut self, min: [u32; 3], max: [u32; 3]) {
+    let b = self.block_size;
+    for bx in min[0] / b..=max[0] / b {
+      for by in min[1] / b..=max[1] / b {
+        for bz in min[2] / b..=max[2] / b {
+          let index = self.block_index(bx, by, bz);
+          self.dirty[index] = true;
+        }
+      }
+    }
+  }
+
+  pub fn is_dirty(&self) -> bool {
+    self.dirty.iter().any(|d| *d)
+  }
+
+  /// Meshes the dirty blocks again, returns how many were meshed
+  pub fn update(&mut self) -> usize {
+    let end = self.size - 1;
+    let b = self.block_size;
+    let mut count = 0;
+    self.updated.clear();
+    for bx in 0..self.blocks_len {
+      for by in 0..self.blocks_len {
+        for bz in 0..self.blocks_len {
+          let index = self.block_index(bx, by, bz);
+          if !self.dirty[index] {
+            continue;
+          }
+
+          let min = [bx * b, by * b, bz * b];
+          let max = [(min[0] + b).min(end), (min[1] + b).min(end), (min[2] + b).min(end)];
+          let mut data = MeshData::default();
+          mesh_cells(&mut data, &mut self.layout, &mut self.voxel_reuse, &self.colors, min, max);
+          self.blocks[index] = data;
+          self.dirty[index] = false;
+          self.updated.push(index);
+          count += 1;
+        }
+      }
+    }
+    count
+  }
+
+  /// Indices of the blocks meshed by the last update(), to patch what is built from them
+  pub fn updated_blocks(&self) -> &Vec<usize> {
+    &self.updated
+  }
+
+  /// Mesh of each block, mesh_data() appends them in this order
+  pub fn blocks(&self) -> &Vec<MeshData> {
+    &self.blocks
+  }
+
+  /// Mesh of the whole chunk, with the blocks appended in order
+  pub fn mesh_data(&self) -> MeshData {
+    let mut data = MeshData::default();
+    data.key = self.key;
+    data.lod = self.lod;
+    for block in self.blocks.iter() {
+      let offset = data.positions.len() as u32;
+      data.indices.extend(block.indices.iter().map(|i| i + offset));
+      data.positions.extend_from_slice(&block.positions);
+      data.normals.extend_from_slice(&block.normals);
+      data.uvs.extend_from_slice(&block.uvs);
+      data.weights.extend_from_slice(&block.weights);
+      data.colors.extend_from_slice(&block.colors);
+      data.ao.extend_from_slice(&block.ao);
+      data.types_1.extend_from_slice(&block.types_1);
+    }
+    data
+  }
+
+  fn block_index(&self, bx: u32, by: u32, bz: u32) -> usize {
+    coord_to_index(bx, by, bz, 0, self.blocks_len)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::voxel_octree::VoxelMode;
+
+  fn terrain(depth: u8) -> VoxelOctree {
+    let mut octree = VoxelOctree::new(0, depth);
+    let size = octree.get_size();
+    for x in 0..size {
+      for z in 0..size {
+        let height = 8 + ((x as f32 * 0.4).sin() * 3.0 + (z as f32 * 0.3).cos() * 3.0) as u32;
+        for y in 0..height {
+          octree.set_voxel(x, y, z, 1 + (x + z) as u8 % 3);
+        }
+      }
+    }
+    octree
+  }
+
+  /// Triangles with their vertex attributes, sorted as the order of the faces differs
+  fn triangles(data: &MeshData) -> Vec<Vec<i64>> {
+    let mut triangles: Vec<Vec<i64>> = data.indices
+      .chunks_exact(3)
+      .map(|t| {
+        t.iter()
+          .flat_map(|i| {
+            let i = *i as usize;
+            let p = data.positions[i];
+            let n = data.normals[i];
+            let c = data.colors[i];
+            let ao = data.ao.get(i).cloned().unwrap_or(1.0);
+            [p[0], p[1], p[2], n[0], n[1], n[2], c[0], c[1], c[2], ao]
+          })
+          .map(|v| (v * 1000.0).round() as i64)
+          .collect()
+      })
+      .collect();
+    triangles.sort();
+    triangles
+  }
+
+  fn full_mesh(octree: &VoxelOctree, options: &MeshOptions) -> MeshData {
+    let colors = vec![[0.2, 0.4, 0.6], [0.8, 0.1, 0.3], [0.5, 0.5, 0.1]];
+    octree.compute_mesh_with_options(
+      VoxelMode::SurfaceNets,
+      &mut VoxelReuse::new(octree.get_depth() as u32, 3),
+      &colors, 1.0, [0, 0, 0], 0, options
+    )
+  }
+
+  fn mesher(octree: &VoxelOctree, options: &MeshOptions) -> BlockMesher {
+    let colors = vec![[0.2, 0.4, 0.6], [0.8, 0.1, 0.3], [0.5, 0.5, 0.1]];
+    BlockMesher::new(octree, &colors, 1.0, [0, 0, 0], 0, options, 8)
+  }
+
+  #[test]
+  fn test_block_mesher_matches_full_mesh() -> Result<(), String> {
+    let options = MeshOptions { ao: true, ..Default::default() };
+    let mut octree = terrain(5);
+    let mut mesher = mesher(&octree, &options);
+    assert!(!mesher.is_dirty());
+    assert_eq!(triangles(&mesher.mesh_data()), triangles(&full_mesh(&octree, &options)));
+
+    // Edits on a block corner, in the middle and on the chunk border,
+    // with the number of blocks meshed again out of 64
+    let edits = [
+      ([8, 7, 8, 0], 8), ([9, 7, 12, 0], 4), ([15, 12, 16, 2], 4),
+      ([0, 5, 3, 0], 1), ([31, 10, 31, 1], 1), ([20, 10, 20, 3], 1),
+    ];
+    for ([x, y, z, voxel], blocks) in edits.iter() {
+      octree.set_voxel(*x, *y, *z, *voxel as u8);
+      assert!(mesher.set_voxel(*x, *y, *z, *voxel as u8));
+      assert_eq!(mesher.update(), *blocks);
+      assert_eq!(
+        triangles(&mesher.mesh_data()),
+        triangles(&full_mesh(&octree, &options)),
+        "after edit {:?}", [x, y, z]
+      );
+    }
+    assert!(!mesher.set_voxel(20, 10, 20, 3));
+    assert_eq!(mesher.update(), 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_block_mesher_sync() -> Result<(), String> {
+    let options = MeshOptions::default();
+    let mut octree = terrain(4);
+    let mut mesher = mesher(&octree, &options);
+
+    octree.set_voxel(8, 7, 8, 0);
+    octree.set_voxel(3, 2, 3, 0);
+    assert_eq!(mesher.sync(&octree), 2);
+    assert!(mesher.is_dirty());
+    assert_eq!(mesher.update(), 8);
+    assert_eq!(triangles(&mesher.mesh_data()), triangles(&full_mesh(&octree, &options)));
+    assert_eq!(mesher.sync(&octree), 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_block_mesher_sync_region() -> Result<(), String> {
+    let options = MeshOptions::default();
+    let mut octree = terrain(4);
+    let mut mesher = mesher(&octree, &options);
+    assert_eq!(mesher.updated_blocks().len(), 8);
+
+    octree.set_voxel(3, 2, 3, 0);
+    octree.set_voxel(4, 3, 2, 0);
+    octree.set_voxel(12, 5, 12, 0);
+    // Only the edited bounds are compared
+    assert_eq!(mesher.sync_region(&octree, [2, 1, 2], [5, 4, 4]), 2);
+    assert_eq!(mesher.update(), 1);
+    assert_eq!(mesher.updated_blocks(), &vec![0]);
+    assert!(!mesher.blocks()[0].positions.is_empty());
+
+    assert_eq!(mesher.sync_region(&octree, [12, 5, 12], [12, 5, 12]), 1);
+    mesher.update();
+    assert_eq!(triangles(&mesher.mesh_data()), triangles(&full_mesh(&octree, &options)));
+    assert_eq!(mesher.sync(&octree), 0);
+    Ok(())
+  }
+}
@@ -0,0 +1,396 @@
+use super::voxel_octree::{VoxelOctree, UniformNode, LodPolicy};
+
+/**
+  Boolean operation applied between a target and a stamp.
+  Only the footprint of the stamp is affected, voxels outside of it are
+  left as they are for every operation.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsgOp {
+  /// Fills the air of the target with the solid voxels of the stamp
+  Union,
+  /// Removes the voxels of the target where the stamp is solid
+  Subtract,
+  /// Keeps the voxels of the target only where the stamp is solid
+  Intersect,
+  /// Overwrites the target with the solid voxels of the stamp
+  Replace,
+}
+
+impl CsgOp {
+  /// Returns the resulting voxel from target value `a` and stamp value `b`
+  pub fn apply(&self, a: u8, b: u8) -> u8 {
+    match self {
+      CsgOp::Union => if a > 0 { a } else { b },
+      CsgOp::Subtract => if b > 0 { 0 } else { a },
+      CsgOp::Intersect => if b > 0 { a } else { 0 },
+      CsgOp::Replace => if b > 0 { b } else { a },
+    }
+  }
+
+  /// True if a whole stamp node with value `b` leaves the target untouched
+  fn skips(&self, b: u8) -> bool {
+    match self {
+      CsgOp::Union | CsgOp::Subtract | CsgOp::Replace => b == 0,
+      CsgOp::Intersect => b > 0,
+    }
+  }
+
+  /// Value of a whole stamp node with value `b` when it doesn't depend on the target
+  fn fill(&self, b: u8) -> Option<u8> {
+    match self {
+      CsgOp::Union => None,
+      CsgOp::Subtract | CsgOp::Intersect => Some(0),
+      CsgOp::Replace => Some(b),
+    }
+  }
+}
+
+impl VoxelOctree {
+  /**
+    Applies `op` with `stamp` placed at `offset`(local coords of this octree,
+    can be negative or beyond the size). Both octrees are walked by node:
+    uniform nodes of the stamp that can't change anything are skipped, the
+    ones that don't depend on the target replace whole target nodes and the
+    target is only split where the values differ. The parents of a changed
+    octree are recalculated with `policy`.
+    Returns true if any voxel changed.
+  */
+  pub fn apply_csg(
+    &mut self, stamp: &VoxelOctree, offset: [i64; 3], op: CsgOp, policy: LodPolicy
+  ) -> bool {
+    let size = self.get_size();
+    let nodes: Vec<(UniformNode, [u32; 3], [u32; 3])> = stamp
+      .uniform_nodes()
+      .into_iter()
+      .filter(|node| !op.skips(node.value))
+      .filter_map(|node| {
+        clip_node(&node, offset, size).map(|(min, max)| (node, min, max))
+      })
+      .collect();
+
+    if nodes.len() == 0 {
+      return false;
+    }
+
+    let mut root = CsgNode::from_octree(self);
+    let mut changed = false;
+    for (node, min, max) in nodes.iter() {
+      let stamp = StampRegion { min: *min, max: *max, value: node.value, op: op };
+      changed |= root.apply(&stamp, [0, 0, 0], size);
+    }
+
+    if changed {
+      root.collapse(policy);
+      *self = root.to_octree(self.get_depth());
+    }
+    changed
+  }
+}
+
+/// Part of a stamp node inside the target, [min, max) in target coords
+struct StampRegion {
+  min: [u32; 3],
+  max: [u32; 3],
+  value: u8,
+  op: CsgOp,
+}
+
+/// Octree node unpacked from the byte layout so it can be split and merged
+#[derive(Clone, Debug, PartialEq)]
+enum CsgNode {
+  Leaf(u8),
+  /// Lod value and children ordered by their descriptor bit
+  Branch(u8, Box<[CsgNode; 8]>),
+}
+
+impl CsgNode {
+  fn from_octree(octree: &VoxelOctree) -> Self {
+    let mut root = CsgNode::Leaf(0);
+    for node in octree.uniform_nodes().iter() {
+      root.insert(node, [0, 0, 0], octree.get_size());
+    }
+    root.collapse(LodPolicy::Majority);
+    root
+  }
+
+  fn split(value: u8) -> Self {
+    CsgNode::Branch(value, Box::new([
+      CsgNode::Leaf(value), CsgNode::Leaf(value), CsgNode::Leaf(value), CsgNode::Leaf(value),
+      CsgNode::Leaf(value), CsgNode::Leaf(value), CsgNode::Leaf(value), CsgNode::Leaf(value),
+    ]))
+  }
+
+  fn value(&self) -> u8 {
+    match self {
+      CsgNode::Leaf(value) | CsgNode::Branch(value, _) => *value,
+    }
+  }
+
+  fn insert(&mut self, node: &UniformNode, origin: [u32; 3], size: u32) {
+    if node.size == size {
+      *self = CsgNode::Leaf(node.value);
+      return;
+    }
+    if let CsgNode::Leaf(value) = self {
+      *self = CsgNode::split(*value);
+    }
+    if let CsgNode::Branch(_, children) = self {
+      let half = size / 2;
+      let mut bit = 0;
+      let mut child_origin = origin;
+      for i in 0..3 {
+        if node.origin[i] >= origin[i] + half {
+          bit += 1 << i;
+          child_origin[i] += half;
+        }
+      }
+      children[bit].insert(node, child_origin, half);
+    }
+  }
+
+  /// Applies the stamp on the node at `origin`, true if a voxel changed
+  fn apply(&mut self, stamp: &StampRegion, origin: [u32; 3], size: u32) -> bool {
+    let mut covered = true;
+    for i in 0..3 {
+      if stamp.max[i] <= origin[i] || origin[i] + size <= stamp.min[i] {
+        return false;
+      }
+      covered &= stamp.min[i] <= origin[i] && origin[i] + size <= stamp.max[i];
+    }
+
+    let fill = stamp.op.fill(stamp.value);
+    match self {
+      CsgNode::Leaf(current) => {
+        let value = fill.unwrap_or(stamp.op.apply(*current, stamp.value));
+        if value == *current {
+          return false;
+        }
+        if covered {
+          *self = CsgNode::Leaf(value);
+          return true;
+        }
+        *self = CsgNode::split(*current);
+      }
+      CsgNode::Branch(..) => {
+        if let (true, Some(value)) = (covered, fill) {
+          *self = CsgNode::Leaf(value);
+          return true;
+        }
+      }
+    }
+
+    let mut changed = false;
+    if let CsgNode::Branch(_, children) = self {
+      let half = size / 2;
+      for (bit, child) in children.iter_mut().enumerate() {
+        let child_origin = [
+          origin[0] + (bit as u32 & 1) * half,
+          origin[1] + ((bit as u32 >> 1) & 1) * half,
+          origin[2] + ((bit as u32 >> 2) & 1) * half,
+        ];
+        changed |= child.apply(stamp, child_origin, half);
+      }
+    }
+    changed
+  }
+
+  /// Merges the branches with uniform children and sets the lod values
+  fn collapse(&mut self, policy: LodPolicy) {
+    let children = match self {
+      CsgNode::Leaf(_) => return,
+      CsgNode::Branch(_, children) => children,
+    };
+    for child in children.iter_mut() {
+      child.collapse(policy);
+    }
+
+    let first = &children[0];
+    let uniform = children.iter().all(|c| matches!(c, CsgNode::Leaf(_)) && c == first);
+    if uniform {
+      *self = CsgNode::Leaf(first.value());
+      return;
+    }
+    let values: Vec<u8> = children.iter().map(|c| c.value()).collect();
+    let value = policy.parent_value(&values);
+    if let CsgNode::Branch(lod, _) = self {
+      *lod = value;
+    }
+  }
+
+  /**
+    Packs the nodes layer by layer, children equal to the default value of
+    their parent are left out like new_from_3d_array() does
+  */
+  fn to_octree(&self, depth: u8) -> VoxelOctree {
+    let mut data = vec![depth];
+    let mut layer = vec![self];
+    for _ in 0..depth {
+      let mut defaults = Vec::with_capacity(layer.len());
+      let mut descriptors = Vec::with_capacity(layer.len());
+      let mut next = Vec::new();
+      for node in layer.iter() {
+        let mut descriptor = 0;
+        if let CsgNode::Branch(default, children) = node {
+          for (bit, child) in children.iter().enumerate() {
+            if *child != CsgNode::Leaf(*default) {
+              descriptor |= 1 << bit;
+              next.push(child);
+            }
+          }
+        }
+        defaults.push(node.value());
+        descriptors.push(descriptor);
+      }
+      data.extend(defaults);
+      data.extend(descriptors);
+      layer = next;
+    }
+    data.extend(layer.iter().map(|node| node.value()));
+
+    VoxelOctree::new_from_bytes(data)
+  }
+}
+
+/**
+  Returns the [min, max) range of the node inside the target octree,
+  None if the node is completely outside
+*/
+fn clip_node(
+  node: &UniformNode,
+  offset: [i64; 3],
+  size: u32,
+) -> Option<([u32; 3], [u32; 3])> {
+  let mut min = [0; 3];
+  let mut max = [0; 3];
+  for i in 0..3 {
+    let start = offset[i] + node.origin[i] as i64;
+    let end = start + node.size as i64;
+    let start = start.max(0);
+    let end = end.min(size as i64);
+    if start >= end {
+      return None;
+    }
+    min[i] = start as u32;
+    max[i] = end as u32;
+  }
+  Some((min, max))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::voxel_octree::ParentValueType;
+
+  fn dense_octree(depth: u8, value: impl Fn(u32, u32, u32) -> u8) -> VoxelOctree {
+    let size = 2_u32.pow(depth as u32);
+    let mut data = Vec::new();
+    for x in 0..size {
+      for y in 0..size {
+        for z in 0..size {
+          data.push([x, y, z, value(x, y, z) as u32]);
+        }
+      }
+    }
+    VoxelOctree::new_from_3d_array(0, depth, &data, ParentValueType::Lod)
+  }
+
+  #[test]
+  fn test_uniform_nodes_cover_octree() -> Result<(), String> {
+    let octree = dense_octree(4, |x, y, z| if y < 5 { 1 } else if x == z { 3 } else { 0 });
+    let nodes = octree.uniform_nodes();
+
+    let volume: u32 = nodes.iter().map(|n| n.size.pow(3)).sum();
+    assert_eq!(volume, octree.get_size().pow(3));
+
+    for node in nodes.iter() {
+      let o = node.origin;
+      for x in o[0]..o[0] + node.size {
+        for y in o[1]..o[1] + node.size {
+          for z in o[2]..o[2] + node.size {
+            assert_eq!(octree.get_voxel(x, y, z), node.value, "at {} {} {}", x, y, z);
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_csg_ops_match_per_voxel() -> Result<(), String> {
+    let ops = [CsgOp::Union, CsgOp::Subtract, CsgOp::Intersect, CsgOp::Replace];
+    let offset = [5, -3, 2];
+    for op in ops.iter() {
+      let target = dense_octree(4, |_x, y, z| if y < 8 { 1 + (z % 2) as u8 } else { 0 });
+      let stamp = dense_octree(3, |x, _y, _z| if x < 4 { 7 } else { 0 });
+
+      let mut result = target.clone();
+      result.apply_csg(&stamp, offset, *op, LodPolicy::Majority);
+
+      let size = target.get_size() as i64;
+      let stamp_size = stamp.get_size() as i64;
+      for x in 0..size {
+        for y in 0..size {
+          for z in 0..size {
+            let a = target.get_voxel(x as u32, y as u32, z as u32);
+            let s = [x - offset[0], y - offset[1], z - offset[2]];
+            let inside = s.iter().all(|v| *v >= 0 && *v < stamp_size);
+            let expected = if inside {
+              op.apply(a, stamp.get_voxel(s[0] as u32, s[1] as u32, s[2] as u32))
+            } else {
+              a
+            };
+            let value = result.get_voxel(x as u32, y as u32, z as u32);
+            assert_eq!(value, expected, "{:?} at {} {} {}", op, x, y, z);
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_csg_outside_bounds_unchanged() -> Result<(), String> {
+    let mut target = dense_octree(4, |_x, y, _z| if y < 8 { 1 } else { 0 });
+    let stamp = dense_octree(3, |_x, _y, _z| 2);
+    let before = target.clone();
+
+    assert!(!target.apply_csg(&stamp, [16, 0, 0], CsgOp::Replace, LodPolicy::Majority));
+    assert!(!target.apply_csg(&stamp, [-8, 0, 0], CsgOp::Subtract, LodPolicy::Majority));
+    assert_eq!(target, before);
+
+    // Air stamp never changes anything on union
+    let air = VoxelOctree::new(0, 3);
+    assert!(!target.apply_csg(&air, [0, 0, 0], CsgOp::Union, LodPolicy::Majority));
+    Ok(())
+  }
+
+  #[test]
+  fn test_csg_keeps_uniform_nodes_collapsed() -> Result<(), String> {
+    let solid = VoxelOctree::new(2, 3);
+    let mut target = VoxelOctree::new(0, 4);
+    assert!(target.apply_csg(&solid, [8, 0, 8], CsgOp::Union, LodPolicy::Majority));
+    let nodes = target.uniform_nodes();
+    assert_eq!(nodes.len(), 8);
+    assert!(nodes.contains(&UniformNode { origin: [8, 0, 8], size: 8, value: 2 }));
+    assert_eq!(target.data.len(), 5);
+    assert_eq!(target.get_voxel(12, 3, 9), 2);
+    assert_eq!(target.get_voxel(3, 3, 9), 0);
+
+    // Only the nodes on the way to the stamp are split
+    let mut target = VoxelOctree::new(1, 4);
+    let small = VoxelOctree::new(1, 1);
+    assert!(target.apply_csg(&small, [2, 2, 2], CsgOp::Subtract, LodPolicy::Majority));
+    assert_eq!(target.uniform_nodes().len(), 7 * 3 + 1);
+    assert_eq!(target.get_voxel(3, 3, 3), 0);
+    assert_eq!(target.get_voxel(4, 3, 3), 1);
+
+    // Removing everything leaves a single node
+    let mut target = VoxelOctree::new(1, 4);
+    assert!(target.apply_csg(&VoxelOctree::new(1, 4), [0, 0, 0], CsgOp::Subtract, LodPolicy::Majority));
+    assert!(target.is_empty());
+    assert_eq!(target.uniform_nodes().len(), 1);
+    Ok(())
+  }
+}
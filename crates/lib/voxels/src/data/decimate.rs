@@ -0,0 +1,430 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use hashbrown::{HashMap, HashSet};
+use super::voxel_octree::MeshData;
+
+/// Minimum cosine between a triangle normal before and after a collapse
+const MIN_NORMAL_DOT: f64 = 0.2;
+
+/**
+  Simplifies the mesh by collapsing edges with the lowest quadric error first,
+  until the error would exceed `max_error` (squared distance in voxels).
+  Vertices are welded by position, a point is moved onto one of its
+  neighbours so each corner keeps its own attributes. Points on the outermost
+  grids of the chunk and on open edges never move, the borders stay the same
+  as the neighbouring chunks.
+*/
+pub fn decimate(data: &mut MeshData, max_error: f32, chunk_size: u32, scale: f32) {
+  if data.indices.is_empty() {
+    return;
+  }
+  let mut mesh = Points::new(data, chunk_size, scale);
+  mesh.collapse_all(max_error as f64);
+
+  // Corners move to the position of the point their point collapsed into
+  let mut indices = Vec::new();
+  for (t, corners) in mesh.corners.iter().enumerate() {
+    if mesh.alive[t] {
+      indices.extend_from_slice(corners);
+    }
+  }
+  let positions: Vec<[f32; 3]> = (0..data.positions.len())
+    .map(|v| data.positions[mesh.vertex_of[mesh.find(mesh.point_of[v])]])
+    .collect();
+  data.positions = positions;
+  data.indices = indices;
+  compact(data);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate {
+  cost: f64,
+  from: usize,
+  to: usize,
+  versions: (u32, u32),
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+  fn cmp(&self, other: &Self) -> Ordering {
+    // Lowest cost first out of the max heap
+    other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+  }
+}
+
+impl PartialOrd for Candidate {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+/// Welded vertices of the mesh and the triangles between them
+struct Points {
+  /// Position in voxels
+  pos: Vec<[f64; 3]>,
+  quadrics: Vec<[f64; 10]>,
+  locked: Vec<bool>,
+  /// Point each removed point collapsed into
+  merged: Vec<Option<usize>>,
+  versions: Vec<u32>,
+  tris: Vec<[usize; 3]>,
+  alive: Vec<bool>,
+  point_tris: Vec<Vec<usize>>,
+  /// Vertex indices of each triangle, kept for the output
+  corners: Vec<[u32; 3]>,
+  point_of: Vec<usize>,
+  /// First vertex of each point
+  vertex_of: Vec<usize>,
+}
+
+impl Points {
+  fn new(data: &MeshData, chunk_size: u32, scale: f32) -> Self {
+    let mut ids = HashMap::new();
+    let mut pos = Vec::new();
+    let mut vertex_of = Vec::new();
+    let mut point_of = Vec::with_capacity(data.positions.len());
+    for (v, p) in data.positions.iter().enumerate() {
+      let key = [
+        (p[0] / scale * 256.0).round() as i64,
+        (p[1] / scale * 256.0).round() as i64,
+        (p[2] / scale * 256.0).round() as i64,
+      ];
+      let id = *ids.entry(key).or_insert_with(|| {
+        pos.push([(p[0] / scale) as f64, (p[1] / scale) as f64, (p[2] / scale) as f64]);
+        vertex_of.push(v);
+        pos.len() - 1
+      });
+      point_of.push(id);
+    }
+
+    let last = (chunk_size - 2) as f64;
+    let mut locked: Vec<bool> = pos
+      .iter()
+      .map(|p| p.iter().any(|c| *c < 1.0 || *c > last))
+      .collect();
+
+    let mut tris = Vec::new();
+    let mut corners = Vec::new();
+    let mut point_tris = vec![Vec::new(); pos.len()];
+    let mut quadrics = vec![[0.0; 10]; pos.len()];
+    let mut edge_counts = HashMap::new();
+    for c in data.indices.chunks_exact(3) {
+      let t = [point_of[c[0] as usize], point_of[c[1] as usize], point_of[c[2] as usize]];
+      if t[0] == t[1] || t[1] == t[2] || t[0] == t[2] {
+        continue;
+      }
+
+      let q = plane_quadric(&pos[t[0]], &pos[t[1]], &pos[t[2]]);
+      for i in 0..3 {
+        add_quadric(&mut quadrics[t[i]], &q);
+        point_tris[t[i]].push(tris.len());
+        let e = edge_key(t[i], t[(i + 1) % 3]);
+        *edge_counts.entry(e).or_insert(0) += 1;
+      }
+      tris.push(t);
+      corners.push([c[0], c[1], c[2]]);
+    }
+
+    for ((a, b), count) in edge_counts.iter() {
+      if *count != 2 {
+        locked[*a] = true;
+        locked[*b] = true;
+      }
+    }
+
+    let len = pos.len();
+    let tri_len = tris.len();
+    Points {
+      pos: pos,
+      quadrics: quadrics,
+      locked: locked,
+      merged: vec![None; len],
+      versions: vec![0; len],
+      tris: tris,
+      alive: vec![true; tri_len],
+      point_tris: point_tris,
+      corners: corners,
+      point_of: point_of,
+      vertex_of: vertex_of,
+    }
+  }
+
+  fn find(&self, mut p: usize) -> usize {
+    while let Some(next) = self.merged[p] {
+      p = next;
+    }
+    p
+  }
+
+  fn neighbours(&self, p: usize) -> HashSet<usize> {
+    let mut set = HashSet::new();
+    for t in self.point_tris[p].iter().filter(|t| self.alive[**t]) {
+      for q in self.tris[*t].iter().filter(|q| **q != p) {
+        set.insert(*q);
+      }
+    }
+    set
+  }
+
+  /// Cheapest direction to collapse the edge, None if both points are locked
+  fn candidate(&self, a: usize, b: usize) -> Option<Candidate> {
+    let mut q = self.quadrics[a];
+    add_quadric(&mut q, &self.quadrics[b]);
+    let versions = (self.versions[a], self.versions[b]);
+    let a_to_b = Candidate { cost: quadric_error(&q, &self.pos[b]), from: a, to: b, versions: versions };
+    let b_to_a = Candidate { cost: quadric_error(&q, &self.pos[a]), from: b, to: a, versions: (versions.1, versions.0) };
+    match (self.locked[a], self.locked[b]) {
+      (true, true) => None,
+      (true, false) => Some(b_to_a),
+      (false, true) => Some(a_to_b),
+      (false, false) => Some(if a_to_b.cost <= b_to_a.cost { a_to_b } else { b_to_a }),
+    }
+  }
+
+  fn collapse_all(&mut self, max_error: f64) {
+    let mut heap = BinaryHeap::new();
+    for p in 0..self.pos.len() {
+      for n in self.neighbours(p) {
+        if p < n {
+          heap.extend(self.candidate(p, n));
+        }
+      }
+    }
+
+    while let Some(c) = heap.pop() {
+      if c.cost > max_error {
+        break;
+      }
+      let stale = self.merged[c.from].is_some()
+        || self.merged[c.to].is_some()
+        || (self.versions[c.from], self.versions[c.to]) != c.versions;
+      if stale || !self.can_collapse(c.from, c.to) {
+        continue;
+      }
+
+      // Only the quadric of `to` changed, edges elsewhere are checked again when popped
+      self.collapse(c.from, c.to);
+      for n in self.neighbours(c.to) {
+        heap.extend(self.candidate(c.to, n));
+      }
+    }
+  }
+
+  /// Keeps the mesh manifold and no triangle around `from` flips
+  fn can_collapse(&self, from: usize, to: usize) -> bool {
+    let shared = self.point_tris[from]
+      .iter()
+      .filter(|t| self.alive[**t] && self.tris[**t].contains(&to))
+      .count();
+    let common = self.neighbours(from).intersection(&self.neighbours(to)).count();
+    if shared != 2 || common != 2 {
+      return false;
+    }
+
+    for t in self.point_tris[from].iter().filter(|t| self.alive[**t]) {
+      let tri = self.tris[*t];
+      if tri.contains(&to) {
+        continue;
+      }
+      let before = normal(&self.pos[tri[0]], &self.pos[tri[1]], &self.pos[tri[2]]);
+      let moved: Vec<[f64; 3]> = tri
+        .iter()
+        .map(|p| if *p == from { self.pos[to] } else { self.pos[*p] })
+        .collect();
+      let after = normal(&moved[0], &moved[1], &moved[2]);
+      if dot(&before, &after) < MIN_NORMAL_DOT {
+        return false;
+      }
+    }
+    true
+  }
+
+  fn collapse(&mut self, from: usize, to: usize) {
+    let tris = std::mem::take(&mut self.point_tris[from]);
+    for t in tris.into_iter() {
+      if !self.alive[t] {
+        continue;
+      }
+      if self.tris[t].contains(&to) {
+        self.alive[t] = false;
+        continue;
+      }
+      for p in self.tris[t].iter_mut() {
+        if *p == from {
+          *p = to;
+        }
+      }
+      self.point_tris[to].push(t);
+    }
+
+    let q = self.quadrics[from];
+    add_quadric(&mut self.quadrics[to], &q);
+    self.merged[from] = Some(to);
+    self.versions[to] += 1;
+  }
+}
+
+/// Removes the vertices no triangle uses anymore
+fn compact(data: &mut MeshData) {
+  let len = data.positions.len();
+  let mut remap = vec![u32::MAX; len];
+  let mut used = Vec::new();
+  for i in data.indices.iter_mut() {
+    if remap[*i as usize] == u32::MAX {
+      remap[*i as usize] = used.len() as u32;
+      used.push(*i as usize);
+    }
+    *i = remap[*i as usize];
+  }
+
+  fn keep<T: Copy>(values: &mut Vec<T>, used: &Vec<usize>, len: usize) {
+    if values.len() == len {
+      *values = used.iter().map(|i| values[*i]).collect();
+    }
+  }
+  keep(&mut data.positions, &used, len);
+  keep(&mut data.normals, &used, len);
+  keep(&mut data.colors, &used, len);
+  keep(&mut data.uvs, &used, len);
+  keep(&mut data.weights, &used, len);
+  keep(&mut data.types_1, &used, len);
+  keep(&mut data.ao, &used, len);
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+  if a < b { (a, b) } else { (b, a) }
+}
+
+fn sub(a: &[f64; 3], b: &[f64; 3]) -> [f64; 3] {
+  [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+  a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Unit normal of the triangle, zero if degenerate
+fn normal(a: &[f64; 3], b: &[f64; 3], c: &[f64; 3]) -> [f64; 3] {
+  let (u, v) = (sub(b, a), sub(c, a));
+  let n = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+  let len = dot(&n, &n).sqrt();
+  if len == 0.0 {
+    return [0.0; 3];
+  }
+  [n[0] / len, n[1] / len, n[2] / len]
+}
+
+/// Upper triangle of the 4x4 quadric of the plane through the triangle
+fn plane_quadric(a: &[f64; 3], b: &[f64; 3], c: &[f64; 3]) -> [f64; 10] {
+  let n = normal(a, b, c);
+  let d = -dot(&n, a);
+  [
+    n[0] * n[0], n[0] * n[1], n[0] * n[2], n[0] * d,
+    n[1] * n[1], n[1] * n[2], n[1] * d,
+    n[2] * n[2], n[2] * d,
+    d * d,
+  ]
+}
+
+fn add_quadric(q: &mut [f64; 10], other: &[f64; 10]) {
+  for i in 0..10 {
+    q[i] += other[i];
+  }
+}
+
+/// Sum of the squared distances of `p` to the planes of the quadric
+fn quadric_error(q: &[f64; 10], p: &[f64; 3]) -> f64 {
+  let (x, y, z) = (p[0], p[1], p[2]);
+  q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+    + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+    + q[7] * z * z + 2.0 * q[8] * z
+    + q[9]
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::voxel_octree::{VoxelOctree, VoxelMode, MeshOptions};
+  use crate::data::surface_nets::VoxelReuse;
+
+  fn mesh(octree: &VoxelOctree) -> MeshData {
+    let options = MeshOptions { ao: true, ..Default::default() };
+    octree.compute_mesh_with_options(
+      VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &vec![[1.0; 3]; 4], 0.5, [0, 0, 0], 0, &options
+    )
+  }
+
+  fn border_positions(data: &MeshData) -> Vec<[i64; 3]> {
+    let mut positions: Vec<[i64; 3]> = data.positions
+      .iter()
+      .filter(|p| p.iter().any(|c| *c / 0.5 < 1.0 || *c / 0.5 > 14.0))
+      .map(|p| [(p[0] * 256.0) as i64, (p[1] * 256.0) as i64, (p[2] * 256.0) as i64])
+      .collect();
+    positions.sort();
+    positions.dedup();
+    positions
+  }
+
+  #[test]
+  fn test_decimate_keeps_borders() -> Result<(), String> {
+    // Flat floor and a hill
+    let mut octree = VoxelOctree::new(0, 4);
+    for x in 0..16 {
+      for z in 0..16 {
+        let hill = (4.0 - ((x as f32 - 8.0).powi(2) + (z as f32 - 8.0).powi(2)).sqrt()).max(0.0);
+        for y in 0..5 + hill as u32 {
+          octree.set_voxel(x, y, z, 1);
+        }
+      }
+    }
+    let full = mesh(&octree);
+
+    let mut data = full.clone();
+    decimate(&mut data, 0.01, 16, 0.5);
+    assert!(data.indices.len() < full.indices.len() * 2 / 3);
+    assert_eq!(border_positions(&data), border_positions(&full));
+    assert_eq!(data.normals.len(), data.positions.len());
+    assert_eq!(data.colors.len(), data.positions.len());
+    assert_eq!(data.ao.len(), data.positions.len());
+    assert!(data.indices.iter().all(|i| (*i as usize) < data.positions.len()));
+
+    // Points only move onto other points of the mesh
+    for p in data.positions.iter() {
+      assert!(full.positions.contains(p), "{:?}", p);
+    }
+
+    // Higher error, fewer triangles
+    let mut coarse = full.clone();
+    decimate(&mut coarse, 1.0, 16, 0.5);
+    assert!(coarse.indices.len() < full.indices.len() / 3);
+    assert_eq!(border_positions(&coarse), border_positions(&full));
+    Ok(())
+  }
+
+  #[test]
+  fn test_decimate_by_lod() -> Result<(), String> {
+    let mut octree = VoxelOctree::new(0, 4);
+    for x in 0..16 {
+      for z in 0..16 {
+        for y in 0..6 {
+          octree.set_voxel(x, y, z, 1);
+        }
+      }
+    }
+    let mut options = MeshOptions::default();
+    options.lod_max_errors[1] = 0.01;
+    let mesh_lod = |lod: usize| {
+      octree.compute_mesh_with_options(
+        VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &vec![[1.0; 3]; 4], 1.0, [0, 0, 0], lod, &options
+      )
+    };
+
+    let full = mesh_lod(0);
+    assert_eq!(mesh_lod(2).indices.len(), full.indices.len());
+    let decimated = mesh_lod(1);
+    assert!(decimated.indices.len() < full.indices.len() / 4);
+    Ok(())
+  }
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use hashbrown::HashMap;
+use crate::utils::FnvHasher;
+use super::surface_nets::VoxelReuse;
+use super::voxel_octree::{VoxelOctree, VoxelMode, MeshData, MeshOptions};
+use super::quantized_mesh::QuantizedMesh;
+
+/// Meshes kept by default
+pub const MESH_CACHE_LEN: usize = 256;
+
+/**
+  Everything the mesh depends on. The chunk key is only part of it when the
+  uvs are in world coords, otherwise chunks with the same voxels share the
+  mesh.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MeshCacheKey {
+  /// VoxelOctree::content_hash()
+  pub hash: u64,
+  /// Size of the octree
+  pub size: u32,
+  pub lod: usize,
+  pub mode: u8,
+  /// Bits of the voxel scale
+  pub scale: u32,
+  pub palette: u64,
+  pub options: u64,
+  pub key: Option<[i64; 3]>,
+}
+
+impl MeshCacheKey {
+  pub fn new(
+    octree: &VoxelOctree,
+    mode: VoxelMode,
+    colors: &Vec<[f32; 3]>,
+    scale: f32,
+    key: [i64; 3],
+    lod: usize,
+    options: &MeshOptions,
+  ) -> Self {
+    MeshCacheKey::from_voxels(octree.get_size(), &octree.to_dense(), mode, colors, scale, key, lod, options)
+  }
+
+  /// Key of the dense voxels of VoxelOctree::to_dense(), to check the cached ones with
+  pub fn from_voxels(
+    size: u32,
+    voxels: &[u8],
+    mode: VoxelMode,
+    colors: &Vec<[f32; 3]>,
+    scale: f32,
+    key: [i64; 3],
+    lod: usize,
+    options: &MeshOptions,
+  ) -> Self {
+    let mut hasher = FnvHasher::default();
+    hasher.write_u32(size);
+    hasher.write(voxels);
+    MeshCacheKey {
+      hash: hasher.finish(),
+      size: size,
+      lod: lod,
+      mode: mode as u8,
+      scale: scale.to_bits(),
+      palette: palette_version(colors),
+      options: options_hash(options),
+      key: options.uv_scale.map(|_| key),
+    }
+  }
+}
+
+/// Changes with any color of the palette
+pub fn palette_version(colors: &Vec<[f32; 3]>) -> u64 {
+  let mut hasher = FnvHasher::default();
+  for c in colors.iter() {
+    for v in c.iter() {
+      hasher.write_u32(v.to_bits());
+    }
+  }
+  hasher.finish()
+}
+
+fn options_hash(options: &MeshOptions) -> u64 {
+  let mut hasher = FnvHasher::default();
+  hasher.write_u8(options.ao as u8);
+  hasher.write_u8(options.materials as u8);
+  hasher.write_u32(options.uv_scale.map_or(u32::MAX, |s| s.to_bits()));
+  hasher.write_u32(options.chunk_offset);
+  for e in options.lod_max_errors.iter() {
+    hasher.write_u32(e.to_bits());
+  }
+  for voxel in 0..=255 {
+    hasher.write_u8(options.kinds.get(voxel) as u8);
+  }
+  hasher.finish()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct MeshCacheStats {
+  pub hits: u64,
+  pub misses: u64,
+  pub len: usize,
+}
+
+#[derive(Clone, Debug)]
+struct MeshCacheEntry {
+  tick: u64,
+  /// Dense voxels the mesh was computed from, in case two of them have the same hash
+  voxels: Vec<u8>,
+  mesh: QuantizedMesh,
+}
+
+/**
+  Least recently used meshes by MeshCacheKey, so reloading, undoing or
+  streaming back chunks with the same voxels skips the meshing. The cached
+  meshes are quantized and without skirts, as these depend on the
+  neighbouring chunks.
+*/
+#[derive(Clone, Debug)]
+pub struct MeshCache {
+  pub capacity: usize,
+  tick: u64,
+  order: BTreeMap<u64, MeshCacheKey>,
+  entries: HashMap<MeshCacheKey, MeshCacheEntry>,
+  hits: u64,
+  misses: u64,
+}
+
+impl Default for MeshCache {
+  fn default() -> Self {
+    MeshCache::new(MESH_CACHE_LEN)
+  }
+}
+
+impl MeshCache {
+  pub fn new(capacity: usize) -> Self {
+    MeshCache {
+      capacity: capacity,
+      tick: 0,
+      order: BTreeMap::new(),
+      entries: HashMap::new(),
+      hits: 0,
+      misses: 0,
+    }
+  }
+
+  /**
+    Decoded copy of the cached mesh with the key of the chunk, marked as
+    recently used. Only if it was computed from the same `voxels`.
+  */
+  pub fn get(&mut self, cache_key: &MeshCacheKey, voxels: &[u8], key: [i64; 3]) -> Option<MeshData> {
+    self.tick += 1;
+    let entry = match self.entries.get_mut(cache_key) {
+      Some(entry) if entry.voxels == voxels => entry,
+      _ => {
+        self.misses += 1;
+        return None;
+      }
+    };
+    self.order.remove(&entry.tick);
+    entry.tick = self.tick;
+    self.order.insert(self.tick, *cache_key);
+    self.hits += 1;
+    Some(decode(&entry.mesh, key))
+  }
+
+  /**
+    Adds the mesh, evicting the least recently used ones beyond the capacity.
+    Returns it as get() does, so the meshes are the same with or without a hit.
+  */
+  pub fn insert(&mut self, cache_key: MeshCacheKey, voxels: Vec<u8>, data: &MeshData) -> MeshData {
+    self.tick += 1;
+    if let Some(entry) = self.entries.get(&cache_key) {
+      self.order.remove(&entry.tick);
+    }
+    let scale = f32::from_bits(cache_key.scale);
+    let mesh = QuantizedMesh::new(data, cache_key.size, scale);
+    let decoded = decode(&mesh, data.key);
+    self.order.insert(self.tick, cache_key);
+    self.entries.insert(cache_key, MeshCacheEntry { tick: self.tick, voxels: voxels, mesh: mesh });
+
+    while self.entries.len() > self.capacity {
+      let (_, oldest) = match self.order.pop_first() {
+        Some(o) => o,
+        None => break,
+      };
+      self.entries.remove(&oldest);
+    }
+    decoded
+  }
+
+  /// The cached mesh or the computed one, added to the cache
+  pub fn compute_mesh(
+    &mut self,
+    octree: &VoxelOctree,
+    mode: VoxelMode,
+    voxel_reuse: &mut VoxelReuse,
+    colors: &Vec<[f32; 3]>,
+    scale: f32,
+    key: [i64; 3],
+    lod: usize,
+    options: &MeshOptions,
+  ) -> MeshData {
+    let voxels = octree.to_dense();
+    let cache_key = MeshCacheKey::from_voxels(
+      octree.get_size(), &voxels, mode, colors, scale, key, lod, options
+    );
+    if let Some(data) = self.get(&cache_key, &voxels, key) {
+      return data;
+    }
+    let data = octree.compute_mesh_with_options(mode, voxel_reuse, colors, scale, key, lod, options);
+    self.insert(cache_key, voxels, &data)
+  }
+
+  /// Forgets every mesh, the stats are kept
+  pub fn clear(&mut self) {
+    self.order.clear();
+    self.entries.clear();
+  }
+
+  pub fn stats(&self) -> MeshCacheStats {
+    MeshCacheStats {
+      hits: self.hits,
+      misses: self.misses,
+      len: self.entries.len(),
+    }
+  }
+}
+
+/// Mesh with the key of the chunk
+fn decode(mesh: &QuantizedMesh, key: [i64; 3]) -> MeshData {
+  let mut data = mesh.to_mesh_data();
+  data.key = key;
+  if let Some(transparent) = data.transparent.as_mut() {
+    transparent.key = key;
+  }
+  data
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ground(height: u32) -> VoxelOctree {
+    let mut octree = VoxelOctree::new(0, 4);
+    for x in 0..16 {
+      for z in 0..16 {
+        for y in 0..height {
+          octree.set_voxel(x, y, z, 1);
+        }
+      }
+    }
+    octree
+  }
+
+  #[test]
+  fn test_content_hash() -> Result<(), String> {
+    let mut octree = ground(6);
+    let hash = octree.content_hash();
+    assert_eq!(hash, ground(6).content_hash());
+    assert_ne!(hash, ground(7).content_hash());
+
+    // Undoing an edit gives the same hash, even if the nodes stay split
+    octree.set_voxel(3, 10, 3, 2);
+    assert_ne!(octree.content_hash(), hash);
+    octree.set_voxel(3, 10, 3, 0);
+    assert_eq!(octree.content_hash(), hash);
+    Ok(())
+  }
+
+  #[test]
+  fn test_mesh_cache_shares_meshes() -> Result<(), String> {
+    let colors = vec![[0.5; 3], [0.2; 3]];
+    let options = MeshOptions { ao: true, ..Default::default() };
+    let mut voxel_reuse = VoxelReuse::new(4, 3);
+    let mut cache = MeshCache::new(2);
+    let mut mesh = |cache: &mut MeshCache, octree: &VoxelOctree, key, lod, options: &MeshOptions| {
+      cache.compute_mesh(octree, VoxelMode::SurfaceNets, &mut voxel_reuse, &colors, 1.0, key, lod, options)
+    };
+
+    // Flat ground of another chunk is the same mesh with its own key
+    let first = mesh(&mut cache, &ground(6), [0, 0, 0], 0, &options);
+    let second = mesh(&mut cache, &ground(6), [3, 0, 1], 0, &options);
+    assert_eq!(second.key, [3, 0, 1]);
+    assert_eq!(second.positions, first.positions);
+    assert_eq!(cache.stats(), MeshCacheStats { hits: 1, misses: 1, len: 1 });
+
+    // Lod, options and world uvs are part of the key
+    mesh(&mut cache, &ground(6), [0, 0, 0], 1, &options);
+    mesh(&mut cache, &ground(6), [0, 0, 0], 0, &MeshOptions::default());
+    let uvs = MeshOptions { uv_scale: Some(0.5), ..options };
+    let uv_mesh = mesh(&mut cache, &ground(6), [0, 0, 0], 0, &uvs);
+    let other = mesh(&mut cache, &ground(6), [1, 0, 0], 0, &uvs);
+    assert_ne!(other.uvs, uv_mesh.uvs);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.len), (1, 5, 2));
+
+    // The oldest meshes were evicted
+    mesh(&mut cache, &ground(6), [0, 0, 0], 0, &options);
+    assert_eq!(cache.stats().misses, 6);
+    Ok(())
+  }
+
+  #[test]
+  fn test_mesh_cache_checks_voxels() -> Result<(), String> {
+    let colors = vec![[0.5; 3]];
+    let options = MeshOptions::default();
+    let mut cache = MeshCache::default();
+    let octree = ground(6);
+    let data = cache.compute_mesh(
+      &octree, VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &colors, 1.0, [0, 0, 0], 0, &options
+    );
+    assert!(!data.positions.is_empty());
+
+    // Other voxels with the same hash are a miss
+    let cache_key = MeshCacheKey::new(&octree, VoxelMode::SurfaceNets, &colors, 1.0, [0, 0, 0], 0, &options);
+    assert!(cache.get(&cache_key, &ground(7).to_dense(), [0, 0, 0]).is_none());
+    let cached = cache.get(&cache_key, &octree.to_dense(), [0, 0, 0]);
+    assert_eq!(cached, Some(data));
+    assert_eq!(cache.stats(), MeshCacheStats { hits: 1, misses: 2, len: 1 });
+    Ok(())
+  }
+}
@@ -1,4 +1,11 @@
+pub mod block_mesher;
+pub mod csg;
+pub mod decimate;
+pub mod mesh_cache;
+pub mod quantized_mesh;
+pub mod skirts;
 pub mod surface_nets;
+pub mod transparent;
 pub mod voxel_octree;
 
 
@@ -0,0 +1,312 @@
+use hashbrown::HashMap;
+use serde::{Serialize, Deserialize};
+use super::voxel_octree::MeshData;
+
+/// Position steps per chunk size, a power of two so the chunk origins stay on the grid
+pub const POSITION_STEPS: f32 = (1 << 14) as f32;
+
+/// u16 values when they all fit, otherwise u32
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PackedIndices {
+  U16(Vec<u16>),
+  U32(Vec<u32>),
+}
+
+impl PackedIndices {
+  pub fn new(values: &[u32]) -> Self {
+    if values.iter().all(|v| *v <= u16::MAX as u32) {
+      return PackedIndices::U16(values.iter().map(|v| *v as u16).collect());
+    }
+    PackedIndices::U32(values.to_vec())
+  }
+
+  pub fn to_u32(&self) -> Vec<u32> {
+    match self {
+      PackedIndices::U16(values) => values.iter().map(|v| *v as u32).collect(),
+      PackedIndices::U32(values) => values.clone(),
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    match self {
+      PackedIndices::U16(values) => values.len(),
+      PackedIndices::U32(values) => values.len(),
+    }
+  }
+
+  fn memory_size(&self) -> usize {
+    match self {
+      PackedIndices::U16(values) => values.len() * 2,
+      PackedIndices::U32(values) => values.len() * 4,
+    }
+  }
+}
+
+impl Default for PackedIndices {
+  fn default() -> Self {
+    PackedIndices::U16(Vec::new())
+  }
+}
+
+/**
+  Compact MeshData for caches and worker messages. Positions are u16 steps
+  on a grid fixed to the chunk, so the shared border vertices of neighbouring
+  chunks decode to the same values. Normals are octahedral encoded and
+  colors are indices into the palette of the mesh. Ambient occlusion, weights
+  and materials take a byte per value, uvs are kept as they are.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct QuantizedMesh {
+  pub key: [i64; 3],
+  pub lod: usize,
+  pub min: [f32; 3],
+  /// Size of a position step per axis
+  pub step: [f32; 3],
+  pub positions: Vec<[u16; 3]>,
+  pub normals: Vec<[i16; 2]>,
+  pub indices: PackedIndices,
+  pub palette: Vec<[f32; 3]>,
+  pub colors: PackedIndices,
+  pub uvs: Vec<[f32; 2]>,
+  pub weights: Vec<[u8; 4]>,
+  pub ao: Vec<u8>,
+  pub types_1: Vec<[u8; 4]>,
+  pub transparent: Option<Box<QuantizedMesh>>,
+}
+
+impl QuantizedMesh {
+  /**
+    Positions from one chunk size below the chunk origin up to three sizes
+    above it, room for the skirts and the transparent meshes
+  */
+  pub fn new(data: &MeshData, chunk_size: u32, scale: f32) -> Self {
+    let size = chunk_size as f32 * scale;
+    let min = [-size; 3];
+    let step = [size / POSITION_STEPS; 3];
+    let positions = data.positions
+      .iter()
+      .map(|p| {
+        let mut q = [0; 3];
+        for i in 0..3 {
+          q[i] = ((p[i] - min[i]) / step[i]).round().clamp(0.0, u16::MAX as f32) as u16;
+        }
+        q
+      })
+      .collect();
+
+    let mut palette = Vec::new();
+    let mut palette_ids = HashMap::new();
+    let colors: Vec<u32> = data.colors
+      .iter()
+      .map(|c| {
+        let bits = [c[0].to_bits(), c[1].to_bits(), c[2].to_bits()];
+        *palette_ids.entry(bits).or_insert_with(|| {
+          palette.push(*c);
+          palette.len() as u32 - 1
+        })
+      })
+      .collect();
+
+    QuantizedMesh {
+      key: data.key,
+      lod: data.lod,
+      min: min,
+      step: step,
+      positions: positions,
+      normals: data.normals.iter().map(|n| encode_normal(n)).collect(),
+      indices: PackedIndices::new(&data.indices),
+      palette: palette,
+      colors: PackedIndices::new(&colors),
+      uvs: data.uvs.clone(),
+      weights: data.weights.iter().map(|w| w.map(|v| unit_to_u8(v))).collect(),
+      ao: data.ao.iter().map(|a| unit_to_u8(*a)).collect(),
+      types_1: data.types_1.iter().map(|t| t.map(|v| v as u8)).collect(),
+      transparent: data.transparent.as_ref().map(|t| Box::new(QuantizedMesh::new(t, chunk_size, scale))),
+    }
+  }
+
+  pub fn to_mesh_data(&self) -> MeshData {
+    let positions = self.positions
+      .iter()
+      .map(|q| {
+        [
+          self.min[0] + q[0] as f32 * self.step[0],
+          self.min[1] + q[1] as f32 * self.step[1],
+          self.min[2] + q[2] as f32 * self.step[2],
+        ]
+      })
+      .collect();
+
+    MeshData {
+      key: self.key,
+      lod: self.lod,
+      positions: positions,
+      normals: self.normals.iter().map(|n| decode_normal(n)).collect(),
+      uvs: self.uvs.clone(),
+      indices: self.indices.to_u32(),
+      weights: self.weights.iter().map(|w| w.map(|v| v as f32 / 255.0)).collect(),
+      colors: self.colors.to_u32().iter().map(|i| self.palette[*i as usize]).collect(),
+      ao: self.ao.iter().map(|a| *a as f32 / 255.0).collect(),
+      types_1: self.types_1.iter().map(|t| t.map(|v| v as u32)).collect(),
+      transparent: self.transparent.as_ref().map(|t| Box::new(t.to_mesh_data())),
+    }
+  }
+
+  /// Estimated bytes of the vertex and index data
+  pub fn memory_size(&self) -> usize {
+    self.positions.len() * 6
+      + self.normals.len() * 4
+      + self.indices.memory_size()
+      + self.palette.len() * 12
+      + self.colors.memory_size()
+      + self.uvs.len() * 8
+      + self.weights.len() * 4
+      + self.ao.len()
+      + self.types_1.len() * 4
+      + self.transparent.as_ref().map_or(0, |t| t.memory_size())
+  }
+}
+
+fn unit_to_u8(v: f32) -> u8 {
+  (v.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn sign(v: f32) -> f32 {
+  if v < 0.0 { -1.0 } else { 1.0 }
+}
+
+/// Octahedral encoding of the direction, zero normals become +z
+fn encode_normal(n: &[f32; 3]) -> [i16; 2] {
+  let l1 = n[0].abs() + n[1].abs() + n[2].abs();
+  if l1 == 0.0 {
+    return [0, 0];
+  }
+  let (mut x, mut y) = (n[0] / l1, n[1] / l1);
+  if n[2] < 0.0 {
+    let (ox, oy) = (x, y);
+    x = (1.0 - oy.abs()) * sign(ox);
+    y = (1.0 - ox.abs()) * sign(oy);
+  }
+  [(x * i16::MAX as f32).round() as i16, (y * i16::MAX as f32).round() as i16]
+}
+
+fn decode_normal(e: &[i16; 2]) -> [f32; 3] {
+  let mut x = (e[0] as f32 / i16::MAX as f32).clamp(-1.0, 1.0);
+  let mut y = (e[1] as f32 / i16::MAX as f32).clamp(-1.0, 1.0);
+  let z = 1.0 - x.abs() - y.abs();
+  if z < 0.0 {
+    let (ox, oy) = (x, y);
+    x = (1.0 - oy.abs()) * sign(ox);
+    y = (1.0 - ox.abs()) * sign(oy);
+  }
+  let len = (x * x + y * y + z * z).sqrt();
+  [x / len, y / len, z / len]
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::voxel_octree::{VoxelOctree, VoxelMode, MeshOptions};
+  use crate::data::surface_nets::VoxelReuse;
+  use crate::data::skirts::add_skirts;
+
+  fn normalized(n: &[f32; 3]) -> [f32; 3] {
+    let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+    [n[0] / len, n[1] / len, n[2] / len]
+  }
+
+  #[test]
+  fn test_quantized_mesh_round_trip() -> Result<(), String> {
+    let mut octree = VoxelOctree::new(0, 5);
+    for x in 0..32 {
+      for z in 0..32 {
+        let height = 12 + ((x as f32 * 0.4).sin() * 4.0 + (z as f32 * 0.3).cos() * 4.0) as u32;
+        for y in 0..height {
+          octree.set_voxel(x, y, z, 1 + (x + z) as u8 % 3);
+        }
+      }
+    }
+    let options = MeshOptions { ao: true, materials: true, uv_scale: Some(0.25), ..Default::default() };
+    let colors = vec![[0.2, 0.4, 0.6], [0.8, 0.1, 0.3], [0.5, 0.5, 0.1]];
+    let mut data = octree.compute_mesh_with_options(
+      VoxelMode::SurfaceNets, &mut VoxelReuse::new(5, 3), &colors, 0.5, [1, 0, -2], 1, &options
+    );
+    add_skirts(&mut data, &[true; 6], 32, 0.5, 1.0);
+
+    let quantized = QuantizedMesh::new(&data, 32, 0.5);
+    assert!(matches!(quantized.indices, PackedIndices::U16(_)));
+    // Averaged colors of the 3 materials around each grid
+    assert!(quantized.palette.len() < 256);
+    let decoded = quantized.to_mesh_data();
+
+    assert_eq!((decoded.key, decoded.lod), (data.key, data.lod));
+    assert_eq!(decoded.indices, data.indices);
+    assert_eq!(decoded.colors, data.colors);
+    assert_eq!(decoded.uvs, data.uvs);
+    assert_eq!(decoded.types_1, data.types_1);
+    for i in 0..data.positions.len() {
+      for a in 0..3 {
+        assert!((decoded.positions[i][a] - data.positions[i][a]).abs() < 0.001);
+      }
+      let (n0, n1) = (normalized(&data.normals[i]), decoded.normals[i]);
+      assert!(n0[0] * n1[0] + n0[1] * n1[1] + n0[2] * n1[2] > 0.9999, "{:?} {:?}", n0, n1);
+      assert!((decoded.ao[i] - data.ao[i]).abs() < 0.003);
+      for w in 0..4 {
+        assert!((decoded.weights[i][w] - data.weights[i][w]).abs() < 0.003);
+      }
+    }
+
+    // Bytes of the same attributes stored as f32 and u32
+    let full_size = data.positions.len() * (12 + 12 + 12 + 8 + 16 + 4 + 16) + data.indices.len() * 4;
+    assert!(quantized.memory_size() * 5 < full_size * 2, "{} {}", quantized.memory_size(), full_size);
+
+    // Encoding again keeps the same values
+    assert_eq!(QuantizedMesh::new(&decoded, 32, 0.5).to_mesh_data(), decoded);
+    Ok(())
+  }
+
+  #[test]
+  fn test_quantized_mesh_edge_cases() -> Result<(), String> {
+    let empty = QuantizedMesh::new(&MeshData::default(), 16, 1.0);
+    assert_eq!(empty.to_mesh_data(), MeshData::default());
+
+    let mut data = MeshData::default();
+    data.positions = vec![[1.0, 2.0, 3.0]; 70000];
+    data.normals = vec![[0.0, 0.0, -1.0]; 70000];
+    data.colors = vec![[0.5; 3]; 70000];
+    data.indices = vec![0, 1, 69999];
+    let quantized = QuantizedMesh::new(&data, 16, 1.0);
+    assert!(matches!(quantized.indices, PackedIndices::U32(_)));
+    assert_eq!(quantized.palette.len(), 1);
+
+    let decoded = quantized.to_mesh_data();
+    assert_eq!(decoded.positions, data.positions);
+    assert_eq!(decoded.normals, data.normals);
+    assert_eq!(decoded.indices, data.indices);
+    Ok(())
+  }
+
+  #[test]
+  fn test_quantized_mesh_shared_border() -> Result<(), String> {
+    // Same world position on the last grids of a chunk and the first of the next one
+    let (size, scale) = (16, 0.5);
+    let seamless = 14.0 * scale;
+    let mut a = MeshData::default();
+    a.positions = vec![[seamless + 0.123457, 3.21, 0.0], [1.0, 2.0, 3.0]];
+    let mut b = MeshData::default();
+    b.positions = vec![[0.123457, 3.21, 0.0], [6.5, 0.1, 0.2]];
+
+    let a = QuantizedMesh::new(&a, size, scale).to_mesh_data();
+    let b = QuantizedMesh::new(&b, size, scale).to_mesh_data();
+    assert_eq!(a.positions[0][0], b.positions[0][0] + seamless);
+    assert_eq!(a.positions[0][1], b.positions[0][1]);
+
+    // Skirts below the chunk still decode
+    let mut skirt = MeshData::default();
+    skirt.positions = vec![[0.0, -2.5, 7.0]];
+    let decoded = QuantizedMesh::new(&skirt, size, scale).to_mesh_data();
+    assert!((decoded.positions[0][1] + 2.5).abs() < 0.001);
+    Ok(())
+  }
+}
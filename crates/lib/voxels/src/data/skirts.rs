@@ -0,0 +1,173 @@
+use hashbrown::HashMap;
+use super::voxel_octree::MeshData;
+
+/**
+  Adds skirts hanging from the open edges of the mesh on the given sides
+  (-x, +x, -y, +y, -z, +z), covering the cracks against a neighbour meshed at
+  a different lod. Skirts go `depth` against the normals into the terrain and
+  copy the attributes of the edge vertices.
+*/
+pub fn add_skirts(
+  data: &mut MeshData,
+  sides: &[bool; 6],
+  chunk_size: u32,
+  scale: f32,
+  depth: f32,
+) {
+  if !sides.iter().any(|s| *s) {
+    return;
+  }
+
+  let quantize = |p: &[f32; 3]| {
+    [
+      (p[0] / scale * 256.0).round() as i64,
+      (p[1] / scale * 256.0).round() as i64,
+      (p[2] / scale * 256.0).round() as i64,
+    ]
+  };
+
+  // Edges used by a single triangle are the open edges of the mesh
+  let mut edge_counts = HashMap::new();
+  let triangles: Vec<[u32; 3]> = data.indices
+    .chunks_exact(3)
+    .map(|t| [t[0], t[1], t[2]])
+    .collect();
+  for t in triangles.iter() {
+    for i in 0..3 {
+      let a = quantize(&data.positions[t[i] as usize]);
+      let b = quantize(&data.positions[t[(i + 1) % 3] as usize]);
+      let key = if a < b { (a, b) } else { (b, a) };
+      *edge_counts.entry(key).or_insert(0) += 1;
+    }
+  }
+
+  for t in triangles.iter() {
+    for i in 0..3 {
+      let (a, b) = (t[i] as usize, t[(i + 1) % 3] as usize);
+      let qa = quantize(&data.positions[a]);
+      let qb = quantize(&data.positions[b]);
+      let key = if qa < qb { (qa, qb) } else { (qb, qa) };
+      if edge_counts[&key] != 1 {
+        continue;
+      }
+
+      let side = edge_side(&data.positions[a], &data.positions[b], chunk_size, scale);
+      if side.map_or(true, |s| !sides[s]) {
+        continue;
+      }
+
+      let a_low = lowered(&data.positions[a], &data.normals[a], depth);
+      let b_low = lowered(&data.positions[b], &data.normals[b], depth);
+      let a_top = copy_vertex(data, a, data.positions[a]);
+      let b_top = copy_vertex(data, b, data.positions[b]);
+      let a_bottom = copy_vertex(data, a, a_low);
+      let b_bottom = copy_vertex(data, b, b_low);
+
+      // Reversed edge, facing the same way as the triangle it hangs from
+      data.indices.extend([b_top, a_top, a_bottom, b_top, a_bottom, b_bottom]);
+    }
+  }
+}
+
+/// Side of the chunk both vertices are on, the outermost grids of the mesh
+fn edge_side(a: &[f32; 3], b: &[f32; 3], chunk_size: u32, scale: f32) -> Option<usize> {
+  let last = (chunk_size - 2) as f32;
+  for axis in 0..3 {
+    let (la, lb) = (a[axis] / scale, b[axis] / scale);
+    if la < 1.0 && lb < 1.0 {
+      return Some(axis * 2);
+    }
+    if la > last && lb > last {
+      return Some(axis * 2 + 1);
+    }
+  }
+  None
+}
+
+fn lowered(pos: &[f32; 3], normal: &[f32; 3], depth: f32) -> [f32; 3] {
+  let len = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+  let dir = if len > 0.0 {
+    [normal[0] / len, normal[1] / len, normal[2] / len]
+  } else {
+    [0.0, 1.0, 0.0]
+  };
+  [pos[0] - dir[0] * depth, pos[1] - dir[1] * depth, pos[2] - dir[2] * depth]
+}
+
+/// Pushes a copy of the vertex at `pos` with every attribute the mesh has
+fn copy_vertex(data: &mut MeshData, index: usize, pos: [f32; 3]) -> u32 {
+  let len = data.positions.len();
+  data.positions.push(pos);
+  data.normals.push(data.normals[index]);
+  if data.colors.len() == len {
+    data.colors.push(data.colors[index]);
+  }
+  if data.uvs.len() == len {
+    data.uvs.push(data.uvs[index]);
+  }
+  if data.weights.len() == len {
+    data.weights.push(data.weights[index]);
+  }
+  if data.types_1.len() == len {
+    data.types_1.push(data.types_1[index]);
+  }
+  if data.ao.len() == len {
+    data.ao.push(data.ao[index]);
+  }
+  len as u32
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::voxel_octree::{VoxelOctree, VoxelMode, MeshOptions};
+  use crate::data::surface_nets::VoxelReuse;
+
+  fn floor_mesh() -> MeshData {
+    let mut octree = VoxelOctree::new(0, 4);
+    for x in 0..16 {
+      for y in 0..6 {
+        for z in 0..16 {
+          octree.set_voxel(x, y, z, 1);
+        }
+      }
+    }
+    let options = MeshOptions { ao: true, ..Default::default() };
+    octree.compute_mesh_with_options(
+      VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &vec![[1.0; 3]; 4], 1.0, [0, 0, 0], 0, &options
+    )
+  }
+
+  #[test]
+  fn test_skirts_on_seam_sides() -> Result<(), String> {
+    let mesh = floor_mesh();
+
+    let mut data = mesh.clone();
+    add_skirts(&mut data, &[false; 6], 16, 1.0, 2.0);
+    assert_eq!(data, mesh);
+
+    // The floor is open on -x, +x, -z and +z, only -x gets a skirt
+    let mut data = mesh.clone();
+    add_skirts(&mut data, &[true, false, false, false, false, false], 16, 1.0, 2.0);
+    let start = mesh.positions.len();
+    assert!(data.positions.len() > start);
+    assert_eq!(data.ao.len(), data.positions.len());
+    assert_eq!(data.colors.len(), data.positions.len());
+    assert_eq!((data.positions.len() - start) % 4, 0);
+    assert_eq!(data.indices.len() - mesh.indices.len(), (data.positions.len() - start) / 4 * 6);
+
+    // Floor vertices are at y 5.5, the skirt hangs 2 voxels below
+    for pos in data.positions[start..].iter() {
+      assert!(pos[0] < 1.0, "{:?}", pos);
+      assert!(pos[1] == 5.5 || pos[1] == 3.5, "{:?}", pos);
+    }
+
+    // Same number of open edges on each of the 4 open sides
+    let x_quads = (data.positions.len() - start) / 4;
+    let mut data = mesh.clone();
+    add_skirts(&mut data, &[true; 6], 16, 1.0, 2.0);
+    assert_eq!((data.positions.len() - start) / 4, x_quads * 4);
+    Ok(())
+  }
+}
@@ -1,6 +1,7 @@
 use parry3d::math::Point;
 use crate::utils::{coord_to_index, get_len_by_size};
 use super::voxel_octree::*;
+use super::transparent::MaterialKinds;
 use crate::data::CUBE_EDGES;
 
 const _CURRENT: [i8; 3] = [0, 0, 0];
@@ -13,6 +14,9 @@ const _BACK_DOWN: [i8; 3] = [0, -1, -1];
 const _RIGHT_DOWN: [i8; 3] = [-1,-1, 0];
 const _RIGHT_BACK: [i8; 3] = [-1, 0,-1];
 
+/// Darkest ambient occlusion is 1.0 - AO_STRENGTH
+pub const AO_STRENGTH: f32 = 0.6;
+
 
 #[derive(Clone)]
 pub struct GridPosition {
@@ -68,15 +72,62 @@ struct Grid {
   // types: [u32; 4],
   types: [u32; 8],
   voxel_count: u8,
+  ao: f32,
+}
+
+/**
+  Voxels one past each side of a chunk, read from its neighbours. With them the
+  ambient occlusion of the grids on the chunk border samples as wide as the
+  other grids, so the vertices shared by two chunks shade the same.
+*/
+#[derive(Clone, Default, PartialEq)]
+pub struct AoBorder {
+  /// Voxels per axis of the chunk, the border is one more on each side
+  size: u32,
+  voxels: Vec<u8>,
+}
+
+impl AoBorder {
+  /// `get` returns the voxel of the local position, from -1 to size on each axis
+  pub fn new(size: u32, get: impl Fn(i64, i64, i64) -> u8) -> Self {
+    let padded = size + 2;
+    let mut voxels = vec![0; padded.pow(3) as usize];
+    let last = size as i64;
+    for x in -1..=last {
+      for y in -1..=last {
+        for z in -1..=last {
+          let inside = [x, y, z].iter().all(|c| *c >= 0 && *c < last);
+          if !inside {
+            let index = coord_to_index((x + 1) as u32, (y + 1) as u32, (z + 1) as u32, 0, padded);
+            voxels[index] = get(x, y, z);
+          }
+        }
+      }
+    }
+    AoBorder { size: size, voxels: voxels }
+  }
+
+  /// Voxel at the local position, 0 inside the chunk
+  pub fn get(&self, x: i64, y: i64, z: i64) -> u8 {
+    let padded = self.size + 2;
+    let index = coord_to_index((x + 1) as u32, (y + 1) as u32, (z + 1) as u32, 0, padded);
+    self.voxels[index]
+  }
 }
 
-struct Layout {
+pub(crate) struct Layout {
   grids: Vec<Grid>,
   size: u32,
+  pub options: MeshOptions,
+  /// Neighbour voxels for the ambient occlusion of the border grids
+  pub ao_border: Option<AoBorder>,
+  /// World voxel position of the chunk origin
+  origin: [f32; 3],
+  pub scale: f32,
 }
 
 impl Layout {
-  pub fn new(size: u32) -> Self {
+  pub fn new(size: u32, options: &MeshOptions, origin: [f32; 3], scale: f32) -> Self {
     let mut grids = Vec::new();
     let len = get_len_by_size(size, 3);
     for _ in 0..len {
@@ -85,8 +136,26 @@ impl Layout {
     Self {
       grids: grids,
       size: size,
+      options: *options,
+      ao_border: None,
+      origin: origin,
+      scale: scale,
     }
   }
+
+  /**
+    Layout for the grids of a chunk octree of `size` voxels per axis, sharing
+    options.chunk_offset voxels with the next chunk
+  */
+  pub fn for_chunk(size: u32, key: [i64; 3], options: &MeshOptions, scale: f32) -> Self {
+    let seamless_size = (size - options.chunk_offset) as f32;
+    let origin = [
+      key[0] as f32 * seamless_size,
+      key[1] as f32 * seamless_size,
+      key[2] as f32 * seamless_size,
+    ];
+    Layout::new(size - 1, options, origin, scale)
+  }
 }
 
 pub fn get_surface_nets(
@@ -97,41 +166,95 @@ pub fn get_surface_nets(
   key: [i64; 3],
   lod: usize,
 ) -> MeshData {
+  get_surface_nets_with_options(
+    octree, voxel_reuse, colors, scale, key, lod, &MeshOptions::default()
+  )
+}
+
+pub fn get_surface_nets_with_options(
+  octree: &VoxelOctree, 
+  voxel_reuse: &mut VoxelReuse,
+  colors: &Vec<[f32; 3]>,
+  scale: f32,
+  key: [i64; 3],
+  lod: usize,
+  options: &MeshOptions,
+) -> MeshData {
+  get_surface_nets_with_border(octree, voxel_reuse, colors, scale, key, lod, options, None)
+}
+
+/// Same as get_surface_nets_with_options(), with the neighbour voxels for the ambient occlusion
+pub fn get_surface_nets_with_border(
+  octree: &VoxelOctree, 
+  voxel_reuse: &mut VoxelReuse,
+  colors: &Vec<[f32; 3]>,
+  scale: f32,
+  key: [i64; 3],
+  lod: usize,
+  options: &MeshOptions,
+  ao_border: Option<&AoBorder>,
+) -> MeshData {
+  fill_voxels(octree, voxel_reuse, &options.kinds);
+
+  let mut data = MeshData::default();
+  data.key = key;
+  data.lod = lod;
+  // data.lod = 0;
+
+  // Checking for each grid
+  let end = octree.get_size() - 1;
+  let mut layout = Layout::for_chunk(octree.get_size(), key, options, scale);
+  layout.ao_border = ao_border.cloned();
+  mesh_cells(&mut data, &mut layout, voxel_reuse, colors, [0; 3], [end; 3]);
+  data
+}
+
+/// Copies the voxels of the octree, the ones that are not opaque become air
+pub(crate) fn fill_voxels(octree: &VoxelOctree, voxel_reuse: &mut VoxelReuse, kinds: &MaterialKinds) {
   let voxel_start = 0;
   let voxel_end = octree.get_size();
   for x in voxel_start..voxel_end {
     for y in voxel_start..voxel_end {
       for z in voxel_start..voxel_end {
-        let voxel = octree.get_voxel(x, y, z);
+        let voxel = kinds.opaque_voxel(octree.get_voxel(x, y, z));
 
         let index = coord_to_index(x, y, z, voxel_start, voxel_end);
         voxel_reuse.voxels[index] = voxel;
       }
     }
   }
+}
 
-  let mut data = MeshData::default();
-  data.key = key;
-  data.lod = lod;
-  // data.lod = 0;
-
-  // Checking for each grid
-  let start = 0;
-  let end = octree.get_size() - 1;
-  let mut layout = Layout::new(end);
-
-  for x in start..end {
-    for y in start..end {
-      for z in start..end {
-        init_grid(&mut layout, voxel_reuse, x, y, z, scale);
-        detect_face_x(&mut data, &mut layout, voxel_reuse, x, y, z, colors);
-        detect_face_y(&mut data, &mut layout, voxel_reuse, x, y, z, colors);
-        detect_face_z(&mut data, &mut layout, voxel_reuse, x, y, z, colors);
+/**
+  Appends the faces of the grids in [min, max) to `data`. The grids one below
+  `min` are computed again too, as the faces are shared with them.
+*/
+pub(crate) fn mesh_cells(
+  data: &mut MeshData,
+  layout: &mut Layout,
+  voxel_reuse: &mut VoxelReuse,
+  colors: &Vec<[f32; 3]>,
+  min: [u32; 3],
+  max: [u32; 3],
+) {
+  let scale = layout.scale;
+  for x in min[0].max(1) - 1..max[0] {
+    for y in min[1].max(1) - 1..max[1] {
+      for z in min[2].max(1) - 1..max[2] {
+        init_grid(layout, voxel_reuse, x, y, z, scale);
       }
     }
   }
 
-  data
+  for x in min[0]..max[0] {
+    for y in min[1]..max[1] {
+      for z in min[2]..max[2] {
+        detect_face_x(data, layout, voxel_reuse, x, y, z, colors);
+        detect_face_y(data, layout, voxel_reuse, x, y, z, colors);
+        detect_face_z(data, layout, voxel_reuse, x, y, z, colors);
+      }
+    }
+  }
 }
 
 
@@ -182,6 +305,8 @@ fn init_grid(
   }
 
   let grid_index = coord_to_index(x, y, z, 0, layout.size);
+  // Grids are computed again after edits
+  layout.grids[grid_index] = Grid::default();
   if voxel_count > 0 && voxel_count < 8 {
     let mut count = 0;
     let mut sum = [0.0, 0.0, 0.0];
@@ -213,6 +338,9 @@ fn init_grid(
 
     layout.grids[grid_index].pos = avg_pos;
     layout.grids[grid_index].normal = [normal_x, normal_y, normal_z];
+    if layout.options.ao {
+      layout.grids[grid_index].ao = get_ao(layout, voxel_reuse, x, y, z);
+    }
   }
 
   
@@ -279,70 +407,24 @@ fn detect_face_x(
 
     let start = 0;
     if face_left && x != start {
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_010.pos.unwrap());
-      data.normals.push(grid_010.normal);
-      data.colors.push(color_010);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_011.pos.unwrap());
-      data.normals.push(grid_011.normal);
-      data.colors.push(color_011);
-
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_011.pos.unwrap());
-      data.normals.push(grid_011.normal);
-      data.colors.push(color_011);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 0);
+      push_vertex(data, layout, grid_010, color_010, &voxels, 0);
+      push_vertex(data, layout, grid_011, color_011, &voxels, 0);
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_001.pos.unwrap());
-      data.normals.push(grid_001.normal);
-      data.colors.push(color_001);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 0);
+      push_vertex(data, layout, grid_011, color_011, &voxels, 0);
+      push_vertex(data, layout, grid_001, color_001, &voxels, 0);
     }
 
     let end_index = voxel_reuse.size - 1;
     if face_right && x != end_index {
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 0);
+      push_vertex(data, layout, grid_011, color_011, &voxels, 0);
+      push_vertex(data, layout, grid_010, color_010, &voxels, 0);
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_011.pos.unwrap());
-      data.normals.push(grid_011.normal);
-      data.colors.push(color_011);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_010.pos.unwrap());
-      data.normals.push(grid_010.normal);
-      data.colors.push(color_010);
-
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_001.pos.unwrap());
-      data.normals.push(grid_001.normal);
-      data.colors.push(color_001);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_011.pos.unwrap());
-      data.normals.push(grid_011.normal);
-      data.colors.push(color_011);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 0);
+      push_vertex(data, layout, grid_001, color_001, &voxels, 0);
+      push_vertex(data, layout, grid_011, color_011, &voxels, 0);
     }
   }
 }
@@ -403,72 +485,24 @@ fn detect_face_y(
 
     let start = 0;
     if face_up && y != start {
+      push_vertex(data, layout, grid_000, color_000, &voxels, 1);
+      push_vertex(data, layout, grid_101, color_101, &voxels, 1);
+      push_vertex(data, layout, grid_100, color_100, &voxels, 1);
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-      
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_101.pos.unwrap());
-      data.normals.push(grid_101.normal);
-      data.colors.push(color_101);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_100.pos.unwrap());
-      data.normals.push(grid_100.normal);
-      data.colors.push(color_100);
-
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_001.pos.unwrap());
-      data.normals.push(grid_001.normal);
-      data.colors.push(color_001);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_101.pos.unwrap());
-      data.normals.push(grid_101.normal);
-      data.colors.push(color_101);
-
+      push_vertex(data, layout, grid_000, color_000, &voxels, 1);
+      push_vertex(data, layout, grid_001, color_001, &voxels, 1);
+      push_vertex(data, layout, grid_101, color_101, &voxels, 1);
     }
 
     let end_index = voxel_reuse.size - 1;
     if face_down && y != end_index {
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 1);
+      push_vertex(data, layout, grid_100, color_100, &voxels, 1);
+      push_vertex(data, layout, grid_101, color_101, &voxels, 1);
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_100.pos.unwrap());
-      data.normals.push(grid_100.normal);
-      data.colors.push(color_100);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_101.pos.unwrap());
-      data.normals.push(grid_101.normal);
-      data.colors.push(color_101);
-
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_101.pos.unwrap());
-      data.normals.push(grid_101.normal);
-      data.colors.push(color_101);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_001.pos.unwrap());
-      data.normals.push(grid_001.normal);
-      data.colors.push(color_001);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 1);
+      push_vertex(data, layout, grid_101, color_101, &voxels, 1);
+      push_vertex(data, layout, grid_001, color_001, &voxels, 1);
     }
   }
 }
@@ -529,72 +563,123 @@ fn detect_face_z(
 
     let start = 0;
     if face_front && z != start {
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-      
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_110.pos.unwrap());
-      data.normals.push(grid_110.normal);
-      data.colors.push(color_110);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 2);
+      push_vertex(data, layout, grid_110, color_110, &voxels, 2);
+      push_vertex(data, layout, grid_010, color_010, &voxels, 2);
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_010.pos.unwrap());
-      data.normals.push(grid_010.normal);
-      data.colors.push(color_010);
-
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_100.pos.unwrap());
-      data.normals.push(grid_100.normal);
-      data.colors.push(color_100);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_110.pos.unwrap());
-      data.normals.push(grid_110.normal);
-      data.colors.push(color_110);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 2);
+      push_vertex(data, layout, grid_100, color_100, &voxels, 2);
+      push_vertex(data, layout, grid_110, color_110, &voxels, 2);
     }
 
     let end_index = voxel_reuse.size - 1;
     if face_back && z != end_index {
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
-
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_010.pos.unwrap());
-      data.normals.push(grid_010.normal);
-      data.colors.push(color_010);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 2);
+      push_vertex(data, layout, grid_010, color_010, &voxels, 2);
+      push_vertex(data, layout, grid_110, color_110, &voxels, 2);
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_110.pos.unwrap());
-      data.normals.push(grid_110.normal);
-      data.colors.push(color_110);
+      push_vertex(data, layout, grid_000, color_000, &voxels, 2);
+      push_vertex(data, layout, grid_110, color_110, &voxels, 2);
+      push_vertex(data, layout, grid_100, color_100, &voxels, 2);
+    }
+  }
+}
 
+/// `voxels` are the materials of the quad and `axis` the axis the quad is facing
+fn push_vertex(
+  data: &mut MeshData,
+  layout: &Layout,
+  grid: &Grid,
+  color: [f32; 3],
+  voxels: &[u32; 4],
+  axis: usize,
+) {
+  let pos = grid.pos.unwrap();
+  data.indices.push(data.positions.len() as u32);
+  data.positions.push(pos);
+  data.normals.push(grid.normal);
+  data.colors.push(color);
+  if layout.options.ao {
+    data.ao.push(grid.ao);
+  }
+  if layout.options.materials {
+    data.types_1.push(*voxels);
+    data.weights.push(get_weights(voxels, grid));
+  }
+  if let Some(uv_scale) = layout.options.uv_scale {
+    let world = [
+      pos[0] / layout.scale + layout.origin[0],
+      pos[1] / layout.scale + layout.origin[1],
+      pos[2] / layout.scale + layout.origin[2],
+    ];
+    // Planar projection on the plane of the quad
+    let uv = match axis {
+      0 => [world[2], world[1]],
+      1 => [world[0], world[2]],
+      _ => [world[0], world[1]],
+    };
+    data.uvs.push([uv[0] * uv_scale, uv[1] * uv_scale]);
+  }
+}
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_000.pos.unwrap());
-      data.normals.push(grid_000.normal);
-      data.colors.push(color_000);
+/// Share of each material of the quad among the solid corners of the grid
+fn get_weights(voxels: &[u32; 4], grid: &Grid) -> [f32; 4] {
+  let mut weights = [0.0; 4];
+  let mut total = 0.0;
+  for voxel in grid.types[..grid.voxel_count as usize].iter() {
+    for i in 0..4 {
+      if voxels[i] != 0 && voxels[i] == *voxel {
+        weights[i] += 1.0;
+        total += 1.0;
+      }
+    }
+  }
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_110.pos.unwrap());
-      data.normals.push(grid_110.normal);
-      data.colors.push(color_110);
+  if total == 0.0 {
+    return [1.0, 0.0, 0.0, 0.0];
+  }
+  for w in weights.iter_mut() {
+    *w /= total;
+  }
+  weights
+}
 
-      data.indices.push(data.positions.len() as u32);
-      data.positions.push(grid_100.pos.unwrap());
-      data.normals.push(grid_100.normal);
-      data.colors.push(color_100);
+/**
+  Occlusion from the share of solid voxels in the 4 wide block around the grid,
+  1.0 when at most half is solid(flat or convex surface) down to
+  1.0 - AO_STRENGTH. The block of the grids on the chunk border reaches into
+  the neighbours through the layout's ao_border, without it those grids only
+  sample their own voxels on that axis.
+*/
+fn get_ao(layout: &Layout, voxel_reuse: &VoxelReuse, x: u32, y: u32, z: u32) -> f32 {
+  let size = voxel_reuse.size as i64;
+  let border = layout.ao_border.as_ref();
+  let range = |c: u32| {
+    let c = c as i64;
+    if border.is_some() || (c > 0 && c < size - 2) { c - 1..c + 3 } else { c..c + 2 }
+  };
+
+  let mut solid = 0;
+  let mut total = 0;
+  for vx in range(x) {
+    for vy in range(y) {
+      for vz in range(z) {
+        let inside = [vx, vy, vz].iter().all(|c| *c >= 0 && *c < size);
+        let voxel = if inside {
+          voxel_reuse.voxels[coord_to_index(vx as u32, vy as u32, vz as u32, 0, voxel_reuse.size)]
+        } else {
+          layout.options.kinds.opaque_voxel(border.map_or(0, |b| b.get(vx, vy, vz)))
+        };
+        if voxel > 0 {
+          solid += 1;
+        }
+        total += 1;
+      }
     }
   }
+
+  let occlusion = ((solid as f32 / total as f32 - 0.5) * 2.0).max(0.0);
+  1.0 - AO_STRENGTH * occlusion
 }
 
 fn get_color(
@@ -620,57 +705,32 @@ fn get_color(
   color
 }
 
-// FIXME: Remove later
+/**
+  Up to 4 materials of the quad, the most common among the solid corners of
+  its grids first. Shared by the 4 vertices so the weights blend the same
+  materials across the quad, unused slots are 0.
+*/
 fn get_vertices_voxels(
   grid_0: &Grid,
-  _grid_1: &Grid,
-  _grid_2: &Grid,
-  _grid_3: &Grid,
+  grid_1: &Grid,
+  grid_2: &Grid,
+  grid_3: &Grid,
 ) -> [u32; 4] {
-  let all_voxels = [0; 4];
-  let mut _index = 0;
-  for _i in 0..grid_0.types.len() {
-    // let type0 = grid_0.types[i];
-    // let type1 = grid_1.types[i];
-    // let type2 = grid_2.types[i];
-    // let type3 = grid_3.types[i];
-
-
-
-    // if !all_voxels.contains(&type0) {
-    //   // println!("index {} {:?}", index, all_voxels);
-    //   all_voxels[index] = type0;
-    //   index += 1;
-    //   if index == 4 {
-    //     break;
-    //   }
-    // }
-    // if !all_voxels.contains(&type1) {
-    //   // println!("index {} {:?}", index, all_voxels);
-    //   all_voxels[index] = type1;
-    //   index += 1;
-    //   if index == 4 {
-    //     break;
-    //   }
-    // }
-    // if !all_voxels.contains(&type2) {
-    //   // println!("index {} {:?}", index, all_voxels);
-    //   all_voxels[index] = type2;
-    //   index += 1;
-    //   if index == 4 {
-    //     break;
-    //   }
-    // }
-    // if !all_voxels.contains(&type3) {
-    //   // println!("index {} {:?}", index, all_voxels);
-    //   all_voxels[index] = type3;
-    //   index += 1;
-    //   if index == 4 {
-    //     break;
-    //   }
-    // }
+  let mut counts: Vec<(u32, u32)> = Vec::new();
+  for grid in [grid_0, grid_1, grid_2, grid_3].iter() {
+    for voxel in grid.types[..grid.voxel_count as usize].iter() {
+      match counts.iter_mut().find(|(v, _)| v == voxel) {
+        Some((_, count)) => *count += 1,
+        None => counts.push((*voxel, 1)),
+      }
+    }
+  }
+  counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+  let mut all_voxels = [0; 4];
+  for (index, (voxel, _)) in counts.iter().take(4).enumerate() {
+    all_voxels[index] = *voxel;
   }
-  // modify_to_texture_indices(&mut all_voxels);
   all_voxels
 }
 
@@ -906,6 +966,259 @@ mod tests {
 
 
 
+  #[test]
+  fn test_ambient_occlusion() -> Result<(), String> {
+    // Floor below y 6 with a wall below x 7, the inner corner is occluded
+    let mut octree = VoxelOctree::new(0, 4);
+    for x in 0..16 {
+      for y in 0..12 {
+        for z in 0..16 {
+          if y < 6 || x < 7 {
+            octree.set_voxel(x, y, z, 1);
+          }
+        }
+      }
+    }
+
+    let colors = vec![[1.0, 1.0, 1.0]; 4];
+    let data = octree.compute_mesh(
+      VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &colors, 1.0, [0, 0, 0], 0
+    );
+    assert!(data.ao.is_empty());
+
+    let options = MeshOptions { ao: true, ..Default::default() };
+    let data = octree.compute_mesh_with_options(
+      VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &colors, 1.0, [0, 0, 0], 0, &options
+    );
+    assert!(data.positions.len() > 0);
+    assert_eq!(data.ao.len(), data.positions.len());
+
+    let mut occluded = 0;
+    for (pos, ao) in data.positions.iter().zip(data.ao.iter()) {
+      assert!(*ao >= 1.0 - AO_STRENGTH && *ao <= 1.0);
+      if pos[0] > 9.0 {
+        assert_eq!(*ao, 1.0, "open floor at {:?}", pos);
+      }
+      if pos[0] > 6.0 && pos[0] < 8.0 && pos[1] > 5.0 && pos[1] < 7.0 {
+        assert!(*ao < 1.0, "corner at {:?}", pos);
+        occluded += 1;
+      }
+    }
+    assert!(occluded > 0);
+
+    let shaded = data.shaded_colors();
+    for (color, ao) in shaded.iter().zip(data.ao.iter()) {
+      assert_eq!(color, &[*ao, *ao, *ao]);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_ambient_occlusion_across_chunks() -> Result<(), String> {
+    use crate::chunk::chunk_manager::{ChunkManager, Chunk};
+    use hashbrown::HashMap;
+
+    let mut manager = ChunkManager::default();
+    for key in [[0, 0, 0], [1, 0, 0]].iter() {
+      let chunk = Chunk { key: *key, is_default: false, ..Default::default() };
+      manager.set_chunk(key, &chunk);
+    }
+
+    // The wall face is on world x 14..15, shared by both chunks
+    for x in 2..28 {
+      for y in 2..12 {
+        for z in 2..12 {
+          if y < 6 || x <= 14 {
+            manager.set_voxel2(&[x, y, z], 1);
+          }
+        }
+      }
+    }
+
+    let options = MeshOptions { ao: true, ..Default::default() };
+    let seamless_size = manager.seamless_size() as f32;
+    let mut meshes = Vec::new();
+    for key in [[0, 0, 0], [1, 0, 0]].iter() {
+      let chunk = manager.get_chunk(key).unwrap();
+      let data = chunk.octree.compute_mesh_with_options(
+        VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &manager.colors, 1.0, *key, 0, &options
+      );
+      let vertices: Vec<([i64; 3], f32)> = data.positions
+        .iter()
+        .zip(data.ao.iter())
+        .map(|(p, ao)| {
+          let world = [p[0] + key[0] as f32 * seamless_size, p[1], p[2]];
+          (world.map(|v| (v * 100.0).round() as i64), *ao)
+        })
+        .collect();
+      meshes.push(vertices);
+    }
+
+    let left: HashMap<[i64; 3], f32> = meshes[0].iter().cloned().collect();
+    let mut matched = 0;
+    for (pos, ao) in meshes[1].iter() {
+      if let Some(other) = left.get(pos) {
+        assert_eq!(other, ao, "vertex at {:?}", pos);
+        if *ao < 1.0 {
+          matched += 1;
+        }
+      }
+    }
+    assert!(matched > 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_ambient_occlusion_seam_kernel() -> Result<(), String> {
+    use crate::chunk::chunk_manager::{ChunkManager, Chunk};
+    use hashbrown::HashMap;
+
+    // Floor below y 6 with a wall and steps ending at the seam of world x 14..15
+    let solid = |x: i64, y: i64, z: i64| {
+      y < 6 || x <= 14 || (y < 8 && x <= 16 && z < 7)
+    };
+    let manager = |shift: i64| {
+      let mut manager = ChunkManager::default();
+      for key in [[0, 0, 0], [1, 0, 0]].iter() {
+        let chunk = Chunk { key: *key, is_default: false, ..Default::default() };
+        manager.set_chunk(key, &chunk);
+      }
+      for x in 2..28 {
+        for y in 2..12 {
+          for z in 2..12 {
+            if solid(x, y, z) {
+              manager.set_voxel2(&[x - shift, y, z], 1);
+            }
+          }
+        }
+      }
+      manager
+    };
+
+    let options = MeshOptions { ao: true, ..Default::default() };
+    let vertices = |manager: &ChunkManager, key: [i64; 3], shift: i64| {
+      let chunk = manager.get_chunk(&key).unwrap();
+      let border = manager.ao_border(&key);
+      let data = chunk.octree.compute_mesh_with_border(
+        VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &manager.colors, 1.0, key, 0, &options,
+        Some(&border),
+      );
+      let seamless_size = manager.seamless_size() as f32;
+      data.positions
+        .iter()
+        .zip(data.ao.iter())
+        .map(|(p, ao)| {
+          let world = [p[0] + (key[0] as f32 * seamless_size) + shift as f32, p[1], p[2]];
+          (world.map(|v| (v * 100.0).round() as i64), *ao)
+        })
+        .collect::<HashMap<[i64; 3], f32>>()
+    };
+
+    // Both chunks shade the seam vertices the same
+    let seam = manager(0);
+    let left = vertices(&seam, [0, 0, 0], 0);
+    let right = vertices(&seam, [1, 0, 0], 0);
+    // Shifted by 7 the seam is inside chunk 0, meshed with the interior kernel
+    let shifted = manager(7);
+    let interior = vertices(&shifted, [0, 0, 0], 7);
+
+    let mut matched = 0;
+    for (pos, ao) in right.iter() {
+      if let Some(other) = left.get(pos) {
+        assert_eq!(other, ao, "vertex at {:?}", pos);
+        assert_eq!(interior.get(pos), Some(ao), "vertex at {:?}", pos);
+        if *ao < 1.0 {
+          matched += 1;
+        }
+      }
+    }
+    assert!(matched > 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_material_weights_and_uvs() -> Result<(), String> {
+    // Floor below y 6, material 1 below x 8 and material 2 from x 8
+    let mut octree = VoxelOctree::new(0, 4);
+    for x in 0..16 {
+      for y in 0..6 {
+        for z in 0..16 {
+          octree.set_voxel(x, y, z, if x < 8 { 1 } else { 2 });
+        }
+      }
+    }
+
+    let colors = vec![[1.0, 1.0, 1.0]; 4];
+    let options = MeshOptions { materials: true, uv_scale: Some(0.5), ..Default::default() };
+    let data = octree.compute_mesh_with_options(
+      VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &colors, 1.0, [1, 0, 2], 0, &options
+    );
+    assert!(data.positions.len() > 0);
+    assert_eq!(data.types_1.len(), data.positions.len());
+    assert_eq!(data.weights.len(), data.positions.len());
+    assert_eq!(data.uvs.len(), data.positions.len());
+    assert!(data.ao.is_empty());
+
+    let mut blended = 0;
+    for i in 0..data.positions.len() {
+      let (pos, types, weights) = (data.positions[i], data.types_1[i], data.weights[i]);
+      let sum: f32 = weights.iter().sum();
+      assert!((sum - 1.0).abs() < 0.0001, "weights {:?}", weights);
+      for slot in 0..4 {
+        if types[slot] == 0 {
+          assert_eq!(weights[slot], 0.0);
+        }
+      }
+
+      let weight = |voxel: u32| {
+        types.iter().position(|t| *t == voxel).map_or(0.0, |slot| weights[slot])
+      };
+      if pos[0] < 6.0 {
+        assert_eq!(weight(1), 1.0, "at {:?}", pos);
+      }
+      if pos[0] > 9.0 {
+        assert_eq!(weight(2), 1.0, "at {:?}", pos);
+      }
+      if weight(1) > 0.0 && weight(2) > 0.0 {
+        blended += 1;
+      }
+
+      // Every quad faces y, key [1, 0, 2] starts at world voxel [14, 0, 28]
+      assert_eq!(data.uvs[i], [(pos[0] + 14.0) * 0.5, (pos[2] + 28.0) * 0.5]);
+    }
+    assert!(blended > 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_uvs_with_manager_offset() -> Result<(), String> {
+    use crate::chunk::chunk_manager::ChunkManager;
+
+    let mut manager = ChunkManager::default();
+    manager.offset = 4;
+    manager.mesh_options.uv_scale = Some(1.0);
+    let options = manager.mesh_options();
+    assert_eq!(options.chunk_offset, 4);
+
+    let mut octree = VoxelOctree::new(0, 4);
+    for x in 0..16 {
+      for y in 0..6 {
+        for z in 0..16 {
+          octree.set_voxel(x, y, z, 1);
+        }
+      }
+    }
+    let data = octree.compute_mesh_with_options(
+      VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &manager.colors, 1.0, [1, 0, 2], 0, &options
+    );
+    assert!(data.positions.len() > 0);
+    for (pos, uv) in data.positions.iter().zip(data.uvs.iter()) {
+      // Key [1, 0, 2] starts at world voxel [12, 0, 24] with 12 seamless voxels
+      assert_eq!(*uv, [pos[0] + 12.0, pos[2] + 24.0]);
+    }
+    Ok(())
+  }
+
   fn load_vecu32(path: &str) -> Vec<u32> {
     let data = fs::read_to_string(path).expect("Unable to read file");
     let vec: Vec<u32> = match ron::from_str(&data) {
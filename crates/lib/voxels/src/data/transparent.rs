@@ -0,0 +1,325 @@
+use serde::{Serialize, Deserialize};
+use crate::utils::coord_to_index;
+use super::voxel_octree::{VoxelOctree, MeshData, MeshOptions};
+
+/// Top of the liquid voxels below air, under the voxel top so the surface is flat
+pub const LIQUID_SURFACE: f32 = 0.375;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default, Clone, Copy)]
+pub enum MaterialKind {
+  #[default]
+  Opaque,
+  Transparent,
+  Liquid,
+}
+
+/// Kind of each voxel value, opaque unless set
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default, Clone, Copy)]
+pub struct MaterialKinds {
+  transparent: [u64; 4],
+  liquid: [u64; 4],
+}
+
+impl MaterialKinds {
+  pub fn with(mut self, voxel: u8, kind: MaterialKind) -> Self {
+    self.set(voxel, kind);
+    self
+  }
+
+  pub fn set(&mut self, voxel: u8, kind: MaterialKind) {
+    let (i, bit) = (voxel as usize / 64, 1 << (voxel % 64));
+    self.transparent[i] &= !bit;
+    self.liquid[i] &= !bit;
+    match kind {
+      MaterialKind::Opaque => {}
+      MaterialKind::Transparent => self.transparent[i] |= bit,
+      MaterialKind::Liquid => self.liquid[i] |= bit,
+    }
+  }
+
+  pub fn get(&self, voxel: u8) -> MaterialKind {
+    let (i, bit) = (voxel as usize / 64, 1 << (voxel % 64));
+    if self.transparent[i] & bit != 0 {
+      return MaterialKind::Transparent;
+    }
+    if self.liquid[i] & bit != 0 {
+      return MaterialKind::Liquid;
+    }
+    MaterialKind::Opaque
+  }
+
+  /// Solid voxel meshed by surface nets
+  pub fn is_opaque(&self, voxel: u8) -> bool {
+    voxel != 0 && self.get(voxel) == MaterialKind::Opaque
+  }
+
+  /// Voxel as seen by surface nets, the transparent and liquid ones are air
+  pub fn opaque_voxel(&self, voxel: u8) -> u8 {
+    if self.is_opaque(voxel) { voxel } else { 0 }
+  }
+
+  pub fn all_opaque(&self) -> bool {
+    self.transparent == [0; 4] && self.liquid == [0; 4]
+  }
+}
+
+/**
+  Faces of the transparent and liquid voxels as blocks, without the faces
+  against opaque voxels or the same voxel. Liquid voxels below a different
+  voxel have their top at LIQUID_SURFACE. The chunk meshes the voxels in
+  [1, size - 1) so the faces are not repeated by its neighbours. types_1 of
+  each vertex is the voxel of its face.
+*/
+pub fn get_transparent_mesh(
+  octree: &VoxelOctree,
+  colors: &Vec<[f32; 3]>,
+  scale: f32,
+  key: [i64; 3],
+  lod: usize,
+  options: &MeshOptions,
+) -> MeshData {
+  let mut data = MeshData::default();
+  data.key = key;
+  data.lod = lod;
+  let kinds = &options.kinds;
+  if kinds.all_opaque() {
+    return data;
+  }
+
+  let size = octree.get_size();
+  let voxels = octree.to_dense();
+  let get = |x: u32, y: u32, z: u32| voxels[coord_to_index(x, y, z, 0, size)];
+  let seamless = (size - options.chunk_offset) as i64;
+  let origin = [
+    (key[0] * seamless) as f32, (key[1] * seamless) as f32, (key[2] * seamless) as f32
+  ];
+
+  for x in 1..size - 1 {
+    for y in 1..size - 1 {
+      for z in 1..size - 1 {
+        let voxel = get(x, y, z);
+        let kind = kinds.get(voxel);
+        if voxel == 0 || kind == MaterialKind::Opaque {
+          continue;
+        }
+
+        let above = get(x, y + 1, z);
+        let mut top = 0.5;
+        if kind == MaterialKind::Liquid && above != voxel {
+          top = LIQUID_SURFACE;
+        }
+
+        let pos = [x, y, z];
+        for axis in 0..3 {
+          for dir in [-1i32, 1] {
+            let mut n = pos;
+            n[axis] = (n[axis] as i32 + dir) as u32;
+            let neighbour = get(n[0], n[1], n[2]);
+            if neighbour == voxel || kinds.is_opaque(neighbour) {
+              continue;
+            }
+
+            let face = Face { pos: pos, axis: axis, dir: dir, top: top, voxel: voxel };
+            push_face(&mut data, &face, colors, scale, &origin, options);
+          }
+        }
+      }
+    }
+  }
+  data
+}
+
+struct Face {
+  pos: [u32; 3],
+  axis: usize,
+  dir: i32,
+  /// Height of the top of the block above the voxel center
+  top: f32,
+  voxel: u8,
+}
+
+fn push_face(
+  data: &mut MeshData,
+  face: &Face,
+  colors: &Vec<[f32; 3]>,
+  scale: f32,
+  origin: &[f32; 3],
+  options: &MeshOptions,
+) {
+  let (axis, u, v) = (face.axis, (face.axis + 1) % 3, (face.axis + 2) % 3);
+  let mut normal = [0.0; 3];
+  normal[axis] = face.dir as f32;
+
+  // u x v points to +axis, the corners are reversed for the faces to -axis
+  let mut corners = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+  if face.dir < 0 {
+    corners.reverse();
+  }
+
+  let start = data.positions.len() as u32;
+  let color = colors[face.voxel as usize - 1];
+  for c in corners.iter() {
+    let mut offset = [0.0; 3];
+    offset[axis] = if face.dir > 0 { 0.5 } else { -0.5 };
+    offset[u] = c[0] - 0.5;
+    offset[v] = c[1] - 0.5;
+    if offset[1] > 0.0 {
+      offset[1] = face.top;
+    }
+
+    let local = [
+      face.pos[0] as f32 + offset[0],
+      face.pos[1] as f32 + offset[1],
+      face.pos[2] as f32 + offset[2],
+    ];
+    data.positions.push([local[0] * scale, local[1] * scale, local[2] * scale]);
+    data.normals.push(normal);
+    data.colors.push(color);
+    data.types_1.push([face.voxel as u32, 0, 0, 0]);
+    if options.materials {
+      data.weights.push([1.0, 0.0, 0.0, 0.0]);
+    }
+    if let Some(uv_scale) = options.uv_scale {
+      let world = [local[0] + origin[0], local[1] + origin[1], local[2] + origin[2]];
+      data.uvs.push([world[u] * uv_scale, world[v] * uv_scale]);
+    }
+  }
+  data.indices.extend([start, start + 1, start + 2, start, start + 2, start + 3]);
+}
+
+/**
+  Positions and indices for the collider of the chunk, the opaque mesh and
+  the transparent faces that are not liquid.
+*/
+pub fn collider_mesh(data: &MeshData, kinds: &MaterialKinds) -> (Vec<[f32; 3]>, Vec<u32>) {
+  let mut positions = data.positions.clone();
+  let mut indices = data.indices.clone();
+  let transparent = match &data.transparent {
+    Some(t) => t,
+    None => return (positions, indices),
+  };
+
+  let offset = positions.len() as u32;
+  positions.extend_from_slice(&transparent.positions);
+  for t in transparent.indices.chunks_exact(3) {
+    let voxel = transparent.types_1[t[0] as usize][0] as u8;
+    if kinds.get(voxel) != MaterialKind::Liquid {
+      indices.extend(t.iter().map(|i| i + offset));
+    }
+  }
+  (positions, indices)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::voxel_octree::VoxelMode;
+  use crate::data::surface_nets::VoxelReuse;
+
+  const STONE: u8 = 1;
+  const WATER: u8 = 2;
+  const GLASS: u8 = 3;
+
+  fn options() -> MeshOptions {
+    let kinds = MaterialKinds::default()
+      .with(WATER, MaterialKind::Liquid)
+      .with(GLASS, MaterialKind::Transparent);
+    MeshOptions { kinds: kinds, ..Default::default() }
+  }
+
+  /// Stone floor below y 4 with a pool of water at y 4 and 5 in x and z 4..8, a glass block on the side
+  fn pool() -> VoxelOctree {
+    let mut octree = VoxelOctree::new(0, 4);
+    for x in 0..16 {
+      for z in 0..16 {
+        for y in 0..6 {
+          octree.set_voxel(x, y, z, STONE);
+        }
+      }
+    }
+    for x in 4..8 {
+      for z in 4..8 {
+        for y in 4..6 {
+          octree.set_voxel(x, y, z, WATER);
+        }
+      }
+    }
+    octree.set_voxel(10, 6, 10, GLASS);
+    octree
+  }
+
+  fn cross(a: &[f32; 3], b: &[f32; 3], c: &[f32; 3]) -> [f32; 3] {
+    let (u, v) = ([b[0] - a[0], b[1] - a[1], b[2] - a[2]], [c[0] - a[0], c[1] - a[1], c[2] - a[2]]);
+    [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]]
+  }
+
+  #[test]
+  fn test_material_kinds() -> Result<(), String> {
+    let kinds = options().kinds;
+    assert_eq!(kinds.get(STONE), MaterialKind::Opaque);
+    assert_eq!(kinds.get(WATER), MaterialKind::Liquid);
+    assert_eq!(kinds.get(GLASS), MaterialKind::Transparent);
+    assert!(kinds.is_opaque(STONE) && !kinds.is_opaque(WATER) && !kinds.is_opaque(0));
+    assert!(!kinds.all_opaque());
+    assert!(kinds.with(WATER, MaterialKind::Opaque).with(GLASS, MaterialKind::Opaque).all_opaque());
+    assert_eq!(MaterialKinds::default().with(200, MaterialKind::Liquid).get(200), MaterialKind::Liquid);
+    Ok(())
+  }
+
+  #[test]
+  fn test_transparent_mesh() -> Result<(), String> {
+    let octree = pool();
+    let colors = vec![[0.5; 3], [0.0, 0.2, 0.8], [0.9; 3]];
+    let data = get_transparent_mesh(&octree, &colors, 1.0, [0, 0, 0], 0, &options());
+
+    // Water only has the flat top, the glass block all but the face on the floor
+    let water: Vec<usize> = (0..data.positions.len()).filter(|i| data.types_1[*i][0] == WATER as u32).collect();
+    assert_eq!(water.len(), 16 * 4);
+    for i in water.iter() {
+      assert_eq!(data.positions[*i][1], 5.0 + LIQUID_SURFACE);
+      assert_eq!(data.normals[*i], [0.0, 1.0, 0.0]);
+      assert_eq!(data.colors[*i], colors[1]);
+    }
+    let glass = data.positions.len() - water.len();
+    assert_eq!(glass, 5 * 4);
+
+    // Triangles face along their normals
+    for t in data.indices.chunks_exact(3) {
+      let (a, b, c) = (t[0] as usize, t[1] as usize, t[2] as usize);
+      let n = cross(&data.positions[a], &data.positions[b], &data.positions[c]);
+      let normal = data.normals[a];
+      assert!(n[0] * normal[0] + n[1] * normal[1] + n[2] * normal[2] > 0.0);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_opaque_mesh_without_transparent_voxels() -> Result<(), String> {
+    let octree = pool();
+    let colors = vec![[0.5; 3], [0.0, 0.2, 0.8], [0.9; 3]];
+    let mesh = |options: &MeshOptions| {
+      octree.compute_mesh_with_options(
+        VoxelMode::SurfaceNets, &mut VoxelReuse::new(4, 3), &colors, 1.0, [0, 0, 0], 0, options
+      )
+    };
+
+    // All opaque, the water is part of the floor and there is no transparent mesh
+    let all_opaque = mesh(&MeshOptions::default());
+    assert!(all_opaque.transparent.is_none());
+
+    // The floor has a hole below the water and the glass block is not meshed
+    let data = mesh(&options());
+    assert!(data.indices.len() > all_opaque.indices.len());
+    assert!(data.positions.iter().any(|p| p[1] < 4.0 && p[0] > 4.0 && p[0] < 7.0));
+    assert!(data.positions.iter().all(|p| p[1] < 6.0));
+    let transparent = data.transparent.as_ref().unwrap();
+    assert_eq!(transparent.positions.len(), (16 + 5) * 4);
+
+    // Water has no collider
+    let (positions, indices) = collider_mesh(&data, &options().kinds);
+    assert_eq!(positions.len(), data.positions.len() + transparent.positions.len());
+    assert_eq!(indices.len(), data.indices.len() + 5 * 6);
+    Ok(())
+  }
+}
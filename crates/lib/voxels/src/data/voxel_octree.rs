@@ -1,14 +1,64 @@
-use crate::utils::get_length;
+use std::hash::Hasher;
+use crate::utils::{get_length, get_len_by_size, coord_to_index, FnvHasher};
 use super::surface_nets::*;
+use super::decimate::decimate;
+use super::transparent::{MaterialKinds, get_transparent_mesh};
 use serde::{Serialize, Deserialize};
+use crate::chunk::CHUNK_OFFSET;
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum ParentValueType {
+  /// Same as Downsample(LodPolicy::Majority)
   Lod,
+  Downsample(LodPolicy),
   FillEmptyChildrenWithDefaultValue,
   DefaultValue
 }
 
+/**
+  How the value of a parent node is picked from its children,
+  which is what lod() returns for the levels above the leaves
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum LodPolicy {
+  /// Most frequent value including air
+  #[default]
+  Majority,
+  /// Most frequent material if any child is solid, keeps thin features
+  SolidWins,
+  /// Air if any child is air, otherwise the most frequent material
+  AirWins,
+  /**
+    Solid if any child is on the solid/air boundary, using the most frequent
+    material of the solids facing air so thin walls and overhangs are kept
+    and the color of surfaces doesn't shift to the material beneath
+  */
+  SurfacePreserving,
+}
+
+impl LodPolicy {
+  pub fn parent_value(&self, values: &[u8]) -> u8 {
+    let solids: Vec<u8> = values.iter().filter(|v| **v > 0).cloned().collect();
+    match self {
+      LodPolicy::Majority => most_occurrence(values),
+      LodPolicy::SolidWins => most_occurrence(&solids),
+      LodPolicy::AirWins => {
+        if solids.len() < values.len() {
+          return 0;
+        }
+        most_occurrence(values)
+      }
+      LodPolicy::SurfacePreserving => {
+        let boundary = boundary_solids(values);
+        if boundary.len() == 0 {
+          return most_occurrence(&solids);
+        }
+        most_occurrence(&boundary)
+      }
+    }
+  }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum VoxelMode {
   Cube,
@@ -34,10 +84,84 @@ pub struct MeshData {
   pub normals: Vec<[f32; 3]>,
   pub uvs: Vec<[f32; 2]>,
   pub indices: Vec<u32>,
+  /// Blend weights of the types_1 materials, empty unless MeshOptions::materials
   pub weights: Vec<[f32; 4]>,
   pub colors: Vec<[f32; 3]>,
+  /// Per vertex ambient occlusion(1.0 unoccluded), empty unless MeshOptions::ao
+  #[serde(default)]
+  pub ao: Vec<f32>,
+  /// Up to 4 voxel materials per vertex, 0 for unused
+  #[serde(default)]
+  pub types_1: Vec<[u32; 4]>,
+  /// Faces of the transparent and liquid voxels, None if there are none
+  #[serde(default)]
+  pub transparent: Option<Box<MeshData>>,
 }
 
+impl MeshData {
+  /// No opaque nor transparent faces
+  pub fn is_empty(&self) -> bool {
+    self.positions.is_empty() && self.transparent.is_none()
+  }
+
+  /// Colors multiplied by the ambient occlusion if computed
+  pub fn shaded_colors(&self) -> Vec<[f32; 3]> {
+    if self.ao.len() != self.colors.len() {
+      return self.colors.clone();
+    }
+    self.colors
+      .iter()
+      .zip(self.ao.iter())
+      .map(|(c, ao)| [c[0] * ao, c[1] * ao, c[2] * ao])
+      .collect()
+  }
+}
+
+/// Optional per vertex terms computed with the mesh
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct MeshOptions {
+  pub ao: bool,
+  /// Material ids(types_1) and blend weights for texture splatting
+  pub materials: bool,
+  /// Planar uvs in world voxel units times the scale, projected on the plane of each quad
+  pub uv_scale: Option<f32>,
+  /**
+    Max error of the mesh decimation per lod, in squared voxels. 0 keeps the
+    full mesh, lod 0 is never decimated so it matches the voxels for edits.
+  */
+  pub lod_max_errors: [f32; 8],
+  /// Voxels meshed by the transparent pass instead of surface nets
+  pub kinds: MaterialKinds,
+  /// Voxels shared with the next chunk per axis, ChunkManager::mesh_options() sets its offset
+  #[serde(default = "default_chunk_offset")]
+  pub chunk_offset: u32,
+}
+
+fn default_chunk_offset() -> u32 {
+  CHUNK_OFFSET
+}
+
+impl Default for MeshOptions {
+  fn default() -> Self {
+    MeshOptions {
+      ao: false,
+      materials: false,
+      uv_scale: None,
+      lod_max_errors: [0.0; 8],
+      kinds: MaterialKinds::default(),
+      chunk_offset: CHUNK_OFFSET,
+    }
+  }
+}
+
+
+/** Cube of voxels sharing the same value, origin is in local octree coords */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct UniformNode {
+  pub origin: [u32; 3],
+  pub size: u32,
+  pub value: u8,
+}
 
 #[derive(Default, Clone, Debug)]
 struct Node {
@@ -401,6 +525,28 @@ impl VoxelOctree {
     self.size
   }
 
+  /**
+    Hash of the size and voxels, octrees with the same voxels have the same
+    hash even if their nodes are split differently.
+  */
+  pub fn content_hash(&self) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write_u32(self.size);
+    hasher.write(&self.to_dense());
+    hasher.finish()
+  }
+
+  /// Estimated heap memory used by the octree in bytes
+  pub fn heap_size(&self) -> usize {
+    let usize_len = std::mem::size_of::<usize>();
+    let mappings: usize = self.layer_mappings.iter().map(|m| m.capacity() * usize_len).sum();
+    self.data.capacity()
+      + self.layers.capacity() * usize_len
+      + self.layer_mappings.capacity() * std::mem::size_of::<Vec<usize>>()
+      + mappings
+      + self.layer_section_cache.capacity() * std::mem::size_of::<(usize, usize)>()
+  }
+
   pub fn compute_mesh(
     &self, mode: VoxelMode, 
     voxel_reuse: &mut VoxelReuse,
@@ -409,17 +555,76 @@ impl VoxelOctree {
     key: [i64; 3],
     lod: usize,
   ) -> MeshData {
-    match mode {
-      VoxelMode::SurfaceNets => get_surface_nets(
+    self.compute_mesh_with_options(
+      mode, voxel_reuse, colors, scale, key, lod, &MeshOptions::default()
+    )
+  }
+
+  pub fn compute_mesh_with_options(
+    &self, mode: VoxelMode, 
+    voxel_reuse: &mut VoxelReuse,
+    colors: &Vec<[f32; 3]>,
+    scale: f32,
+    key: [i64; 3],
+    lod: usize,
+    options: &MeshOptions,
+  ) -> MeshData {
+    self.compute_mesh_with_border(mode, voxel_reuse, colors, scale, key, lod, options, None)
+  }
+
+  /**
+    Same as compute_mesh_with_options(), the ambient occlusion of the grids on
+    the chunk border samples the neighbour voxels of `ao_border`
+  */
+  pub fn compute_mesh_with_border(
+    &self, mode: VoxelMode, 
+    voxel_reuse: &mut VoxelReuse,
+    colors: &Vec<[f32; 3]>,
+    scale: f32,
+    key: [i64; 3],
+    lod: usize,
+    options: &MeshOptions,
+    ao_border: Option<&AoBorder>,
+  ) -> MeshData {
+    let mut data = match mode {
+      VoxelMode::SurfaceNets => get_surface_nets_with_border(
         self, 
         voxel_reuse, 
         colors, 
         scale,
         key,
-        lod
+        lod,
+        options,
+        ao_border,
       ),
       _ => panic!("VoxelMode {:?} implementation not existing yet", mode),
+    };
+
+    let max_error = options.lod_max_errors.get(lod).cloned().unwrap_or(0.0);
+    if lod > 0 && max_error > 0.0 {
+      decimate(&mut data, max_error, self.get_size(), scale);
     }
+    data.transparent = self.compute_transparent_mesh(colors, scale, key, lod, options);
+    data
+  }
+
+  /// Mesh of the transparent and liquid voxels, None if it has no faces
+  pub fn compute_transparent_mesh(
+    &self,
+    colors: &Vec<[f32; 3]>,
+    scale: f32,
+    key: [i64; 3],
+    lod: usize,
+    options: &MeshOptions,
+  ) -> Option<Box<MeshData>> {
+    if options.kinds.all_opaque() {
+      return None;
+    }
+    let data = get_transparent_mesh(self, colors, scale, key, lod, options);
+    if data.indices.is_empty() {
+      return None;
+    }
+    Some(Box::new(data))
   }
 
   pub fn is_empty(&self) -> bool {
@@ -433,7 +638,8 @@ impl VoxelOctree {
     if level > self.get_depth() as usize {
       panic!("level can't be greater depth {}", level);
     }
-    if level == self.get_depth() as usize {
+    // Collapsed octrees have no nodes below some layers
+    if level == self.get_depth() as usize || level + 1 >= self.layers.len() {
       return self.data.clone();
     }
 
@@ -442,6 +648,86 @@ impl VoxelOctree {
     self.data[0..last_index].to_vec()
   }
 
+  /**
+    Returns the octree as regions of uniform value instead of single voxels.
+    Nodes without children are reported whole, so a mostly empty octree
+    only yields a handful of entries.
+  */
+  pub fn uniform_nodes(&self) -> Vec<UniformNode> {
+    let mut nodes = Vec::new();
+    self.collect_uniform_nodes(0, 0, [0, 0, 0], self.size, &mut nodes);
+    nodes
+  }
+
+  /**
+    Expands the octree into a flat array indexed by coord_to_index()
+  */
+  pub fn to_dense(&self) -> Vec<u8> {
+    let size = self.size;
+    let mut voxels = vec![0; get_len_by_size(size, 3)];
+    for node in self.uniform_nodes().iter() {
+      let o = node.origin;
+      for x in o[0]..o[0] + node.size {
+        for y in o[1]..o[1] + node.size {
+          for z in o[2]..o[2] + node.size {
+            voxels[coord_to_index(x, y, z, 0, size)] = node.value;
+          }
+        }
+      }
+    }
+    voxels
+  }
+
+  fn collect_uniform_nodes(
+    &self,
+    layer: usize,
+    local_index: usize,
+    origin: [u32; 3],
+    size: u32,
+    nodes: &mut Vec<UniformNode>,
+  ) {
+    let (layer_start, layer_size) = self.get_layer_section(layer);
+    let descriptor_index = layer_start + layer_size / 2 + local_index;
+
+    // Sliced by lod(), the default value covers the whole node
+    if descriptor_index >= self.data.len() {
+      let value = self.data[layer_start + local_index];
+      nodes.push(UniformNode { origin, size, value });
+      return;
+    }
+
+    if layer == self.get_depth() as usize {
+      let value = self.data[descriptor_index];
+      nodes.push(UniformNode { origin, size, value });
+      return;
+    }
+
+    let descriptor = self.data[descriptor_index];
+    let default_value = self.data[layer_start + local_index];
+    if descriptor == 0 {
+      nodes.push(UniformNode { origin, size, value: default_value });
+      return;
+    }
+    let half = size / 2;
+    for bit in 0..8 {
+      let child_origin = [
+        origin[0] + (bit & 1) * half,
+        origin[1] + ((bit >> 1) & 1) * half,
+        origin[2] + ((bit >> 2) & 1) * half,
+      ];
+
+      let branch = 1 << bit;
+      if descriptor & branch != branch {
+        nodes.push(UniformNode { origin: child_origin, size: half, value: default_value });
+        continue;
+      }
+
+      let child_index = self.layer_mappings[layer][local_index]
+        + branch_index_reverse(descriptor, branch);
+      self.collect_uniform_nodes(layer + 1, child_index, child_origin, half, nodes);
+    }
+  }
+
   /**
    * start_index, layer_size
    */
@@ -695,13 +981,11 @@ fn has_most_occurrence_value(default_value: u8, values: &Vec<u8>, mode: ParentVa
   let mut highest = 0;
   let mut value = 0;
   if mode == ParentValueType::Lod {
-    for val in values.iter() {
-      let occurrences = values.iter().filter(|x| **x == *val).count();
-      if occurrences > highest {
-        highest = occurrences;
-        value = *val;
-      }
-    }
+    value = most_occurrence(values);
+  }
+
+  if let ParentValueType::Downsample(policy) = mode {
+    value = policy.parent_value(values);
   }
 
   if mode == ParentValueType::FillEmptyChildrenWithDefaultValue {
@@ -728,6 +1012,35 @@ fn has_most_occurrence_value(default_value: u8, values: &Vec<u8>, mode: ParentVa
   value
 }
 
+/**
+  Solid children with an air child next to them, children are in descriptor
+  bit order. Empty unless all 8 children are given.
+*/
+fn boundary_solids(values: &[u8]) -> Vec<u8> {
+  if values.len() != 8 {
+    return Vec::new();
+  }
+  (0..8)
+    .filter(|bit| values[*bit] > 0)
+    .filter(|bit| [1, 2, 4].iter().any(|axis| values[bit ^ axis] == 0))
+    .map(|bit| values[bit])
+    .collect()
+}
+
+/** Returns the first most frequent value, 0 if there are no values */
+fn most_occurrence(values: &[u8]) -> u8 {
+  let mut highest = 0;
+  let mut value = 0;
+  for val in values.iter() {
+    let occurrences = values.iter().filter(|x| **x == *val).count();
+    if occurrences > highest {
+      highest = occurrences;
+      value = *val;
+    }
+  }
+  value
+}
+
 /** Convert node key Vec<u8> into u32 for faster HashMap key access */
 fn get_num_key(key: &Vec<u8>) -> usize {
   if key.len() == 0 {
@@ -1655,4 +1968,230 @@ mod tests {
     }
     Ok(())
   }
+
+
+  /** First most frequent value of the cell, 0 without values */
+  fn reference_most_frequent(values: &[u8]) -> u8 {
+    let mut counts = [0; 256];
+    for v in values.iter() {
+      counts[*v as usize] += 1;
+    }
+    let (mut best, mut best_count) = (0, 0);
+    for v in values.iter() {
+      if counts[*v as usize] > best_count {
+        best = *v;
+        best_count = counts[*v as usize];
+      }
+    }
+    best
+  }
+
+  /**
+    Reference downsample done on plain arrays without LodPolicy::parent_value(),
+    every policy written out on the 2x2x2 cells of the dense grid
+  */
+  fn reference_downsample(voxels: &Vec<u8>, size: u32, policy: LodPolicy) -> Vec<u8> {
+    let half = size / 2;
+    let voxel = |x: u32, y: u32, z: u32| voxels[coord_to_index(x, y, z, 0, size)];
+    let mut res = vec![0; (half * half * half) as usize];
+    for x in 0..half {
+      for y in 0..half {
+        for z in 0..half {
+          // Children in the same order as the octree, for the same ties
+          let mut cells = Vec::new();
+          for bit in 0..8 {
+            cells.push([x * 2 + (bit & 1), y * 2 + ((bit >> 1) & 1), z * 2 + ((bit >> 2) & 1)]);
+          }
+          let values: Vec<u8> = cells.iter().map(|c| voxel(c[0], c[1], c[2])).collect();
+          let solids: Vec<u8> = values.iter().filter(|v| **v > 0).cloned().collect();
+
+          let value = match policy {
+            LodPolicy::Majority => reference_most_frequent(&values),
+            LodPolicy::SolidWins => reference_most_frequent(&solids),
+            LodPolicy::AirWins => {
+              if values.contains(&0) { 0 } else { reference_most_frequent(&values) }
+            }
+            LodPolicy::SurfacePreserving => {
+              // Solids with air next to them along an axis, inside the cell
+              let boundary: Vec<u8> = cells
+                .iter()
+                .filter(|c| voxel(c[0], c[1], c[2]) > 0)
+                .filter(|c| {
+                  voxel(c[0] ^ 1, c[1], c[2]) == 0
+                    || voxel(c[0], c[1] ^ 1, c[2]) == 0
+                    || voxel(c[0], c[1], c[2] ^ 1) == 0
+                })
+                .map(|c| voxel(c[0], c[1], c[2]))
+                .collect();
+              if boundary.is_empty() {
+                reference_most_frequent(&solids)
+              } else {
+                reference_most_frequent(&boundary)
+              }
+            }
+          };
+          res[coord_to_index(x, y, z, 0, half)] = value;
+        }
+      }
+    }
+    res
+  }
+
+  fn assert_lod_matches_reference(policy: LodPolicy) {
+    let depth = 4;
+    let size = 2_u32.pow(depth as u32);
+
+    // Terrain with a 1 voxel thick pillar and a different material on top
+    let mut voxels = vec![0; (size * size * size) as usize];
+    let mut data = Vec::new();
+    for x in 0..size {
+      for y in 0..size {
+        for z in 0..size {
+          let mut value = 0;
+          if y < 5 + (x + z) % 3 {
+            value = if y > 4 { 2 } else { 1 };
+          }
+          if x == 9 && z == 6 && y < 13 {
+            value = 3;
+          }
+          voxels[coord_to_index(x, y, z, 0, size)] = value;
+          data.push([x, y, z, value as u32]);
+        }
+      }
+    }
+    let octree = VoxelOctree::new_from_3d_array(
+      0, depth, &data, ParentValueType::Downsample(policy)
+    );
+
+    let mut reference = voxels.clone();
+    let mut reference_size = size;
+    for level in (0..depth as usize).rev() {
+      reference = reference_downsample(&reference, reference_size, policy);
+      reference_size /= 2;
+
+      let lod = VoxelOctree::new_from_bytes(octree.lod(level));
+      let cell = size / reference_size;
+      for x in 0..size {
+        for y in 0..size {
+          for z in 0..size {
+            let index = coord_to_index(x / cell, y / cell, z / cell, 0, reference_size);
+            assert_eq!(
+              lod.get_voxel(x, y, z),
+              reference[index],
+              "{:?} level {} at {} {} {}", policy, level, x, y, z
+            );
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_lod_policy_majority() -> Result<(), String> {
+    assert_lod_matches_reference(LodPolicy::Majority);
+    Ok(())
+  }
+
+  #[test]
+  fn test_lod_policy_solid_wins() -> Result<(), String> {
+    assert_lod_matches_reference(LodPolicy::SolidWins);
+
+    // Thin pillar survives down to the 2x2x2 level
+    assert_eq!(LodPolicy::SolidWins.parent_value(&[0, 0, 0, 0, 0, 0, 0, 3]), 3);
+    Ok(())
+  }
+
+  #[test]
+  fn test_lod_policy_air_wins() -> Result<(), String> {
+    assert_lod_matches_reference(LodPolicy::AirWins);
+    assert_eq!(LodPolicy::AirWins.parent_value(&[1, 1, 1, 1, 1, 1, 1, 0]), 0);
+    Ok(())
+  }
+
+  #[test]
+  fn test_lod_policy_surface_preserving() -> Result<(), String> {
+    assert_lod_matches_reference(LodPolicy::SurfacePreserving);
+
+    // Boundary stays solid, keeping the material of the solids
+    assert_eq!(LodPolicy::SurfacePreserving.parent_value(&[0, 0, 0, 0, 2, 2, 1, 2]), 2);
+    assert_eq!(LodPolicy::SurfacePreserving.parent_value(&[0, 0, 0, 0, 0, 2, 1, 2]), 2);
+
+    // Grass facing the air wins over the dirt beneath
+    let grass_on_dirt = [1, 1, 2, 2, 1, 1, 2, 0];
+    assert_eq!(LodPolicy::SurfacePreserving.parent_value(&grass_on_dirt), 2);
+    assert_eq!(LodPolicy::SolidWins.parent_value(&grass_on_dirt), 1);
+    Ok(())
+  }
+
+  #[test]
+  fn test_lod_policy_surface_preserving_materials() -> Result<(), String> {
+    let depth = 3;
+    let size = 2_u32.pow(depth as u32);
+    let mut data = Vec::new();
+    for x in 0..size {
+      for y in 0..size {
+        for z in 0..size {
+          // Dirt under a grass layer with holes, dirt is the most frequent solid of the cells
+          let value = match y {
+            0..=2 => 1,
+            3 if x % 2 == 0 || z % 2 == 0 => 2,
+            _ => 0,
+          };
+          data.push([x, y, z, value]);
+        }
+      }
+    }
+
+    let solid = VoxelOctree::new_from_3d_array(
+      0, depth, &data, ParentValueType::Downsample(LodPolicy::SolidWins)
+    );
+    let surface = VoxelOctree::new_from_3d_array(
+      0, depth, &data, ParentValueType::Downsample(LodPolicy::SurfacePreserving)
+    );
+
+    // Same occupancy, but the surface keeps the grass
+    let solid_lod = VoxelOctree::new_from_bytes(solid.lod(depth as usize - 1));
+    let surface_lod = VoxelOctree::new_from_bytes(surface.lod(depth as usize - 1));
+    for x in 0..size {
+      for z in 0..size {
+        assert_eq!(solid_lod.get_voxel(x, 3, z), 1);
+        assert_eq!(surface_lod.get_voxel(x, 3, z), 2);
+        assert_eq!(solid_lod.get_voxel(x, 5, z), surface_lod.get_voxel(x, 5, z));
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_lod_policy_surface_preserving_thin_wall() -> Result<(), String> {
+    let depth = 3;
+    let size = 2_u32.pow(depth as u32);
+    let mut data = Vec::new();
+    for x in 0..size {
+      for y in 0..size {
+        for z in 0..size {
+          // One voxel thick wall and an overhang on top of it
+          let wall = x == 3 || (y == 7 && x < 6);
+          data.push([x, y, z, wall as u32]);
+        }
+      }
+    }
+
+    let majority = VoxelOctree::new_from_3d_array(
+      0, depth, &data, ParentValueType::Downsample(LodPolicy::Majority)
+    );
+    let surface = VoxelOctree::new_from_3d_array(
+      0, depth, &data, ParentValueType::Downsample(LodPolicy::SurfacePreserving)
+    );
+
+    let lod = VoxelOctree::new_from_bytes(majority.lod(depth as usize - 1));
+    assert_eq!(lod.get_voxel(3, 2, 2), 0);
+    assert_eq!(lod.get_voxel(1, 7, 1), 0);
+    for level in 0..depth as usize {
+      let lod = VoxelOctree::new_from_bytes(surface.lod(level));
+      assert_eq!(lod.get_voxel(3, 2, 2), 1, "wall at level {}", level);
+      assert_eq!(lod.get_voxel(1, 7, 1), 1, "overhang at level {}", level);
+    }
+    Ok(())
+  }
 }
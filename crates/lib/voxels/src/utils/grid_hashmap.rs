@@ -1,79 +1,231 @@
+use std::hash::Hash;
+use std::sync::RwLock;
 use hashbrown::HashMap;
 
-use crate::{chunk::world_pos_to_key};
-
 /*
-  TODO
-    Create a 3-dimensional grid to distribute access to HashMaps
-  Implementation:
-    Need to have grid size
-    Setup unit test
-    Setup benchmark
-    Make this generic?
+  3-dimensional grid to distribute access to HashMaps.
+  Keys close to each other are kept in the same grid cell(size per axis),
+  so ShardedGridHashMap locks nearby keys together.
 */
 
-pub struct GridHashMap<V> {
-  maps: HashMap<[i64; 3], HashMap<[i64; 4], V>>,
+pub trait GridKey: Copy + Eq + Hash {
+  /// Position used to find the grid cell of the key
+  fn grid_pos(&self) -> [i64; 3];
+}
+
+impl GridKey for [i64; 3] {
+  fn grid_pos(&self) -> [i64; 3] {
+    *self
+  }
+}
+
+impl GridKey for [i64; 4] {
+  fn grid_pos(&self) -> [i64; 3] {
+    [self[0], self[1], self[2]]
+  }
+}
+
+pub fn get_grid_cell<K: GridKey>(key: &K, size: u32) -> [i64; 3] {
+  let pos = key.grid_pos();
+  let s = size as i64;
+  [pos[0].div_euclid(s), pos[1].div_euclid(s), pos[2].div_euclid(s)]
+}
+
+#[derive(Clone, Debug)]
+pub struct GridHashMap<V, K: GridKey = [i64; 4]> {
+  maps: HashMap<[i64; 3], HashMap<K, V>>,
   pub size: u32,
 }
 
-impl<V> GridHashMap<V> {
-  pub fn get(&self, key: &[i64; 4]) -> Option<&V> {
-    let key3 = [key[0], key[1], key[2]];
-    let grid = world_pos_to_key(&key3, self.size);
-    let map_op = self.maps.get(&grid);
-    if map_op.is_none() {
-      return None;
-    }
+impl<V, K: GridKey> GridHashMap<V, K> {
+  pub fn new(size: u32) -> Self {
+    GridHashMap { maps: HashMap::new(), size: size }
+  }
+
+  pub fn get(&self, key: &K) -> Option<&V> {
+    let grid = get_grid_cell(key, self.size);
+    self.maps.get(&grid)?.get(key)
+  }
 
-    let local_key = get_local_key(&key3, self.size);
-    let local_key4 = [local_key[0], local_key[1], local_key[2], key[3]];
-    map_op.unwrap().get(&local_key4)
+  pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+    let grid = get_grid_cell(key, self.size);
+    self.maps.get_mut(&grid)?.get_mut(key)
   }
 
-  pub fn insert(&mut self, key: [i64; 4], value: V) {
-    let key3 = [key[0], key[1], key[2]];
-    let grid = world_pos_to_key(&key3, self.size);
-    let map_op = self.maps.get(&grid);
+  pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+    let grid = get_grid_cell(&key, self.size);
+    self.maps.entry(grid).or_insert_with(HashMap::new).insert(key, value)
+  }
 
-    let local_key = get_local_key(&key3, self.size);
-    let local_key4 = [local_key[0], local_key[1], local_key[2], key[3]];
-    if map_op.is_none() {
-      let mut new_map = HashMap::new();
-      new_map.insert(local_key4, value);
-      self.maps.insert(grid, new_map);
-    } else {
-      self.maps.get_mut(&grid).unwrap().insert(local_key4, value);
+  pub fn remove(&mut self, key: &K) -> Option<V> {
+    let grid = get_grid_cell(key, self.size);
+    let map = self.maps.get_mut(&grid)?;
+    let value = map.remove(key);
+    if map.is_empty() {
+      self.maps.remove(&grid);
     }
+    value
   }
 
-  pub fn contains_key(&self, key: &[i64; 4]) -> bool {
+  pub fn contains_key(&self, key: &K) -> bool {
     self.get(key).is_some()
   }
 
   pub fn len(&self) -> usize {
-    // self.maps.len()
-    let mut len = 0;
-    for (_key, val) in self.maps.iter() {
-      len += val.len();
-    }
-    len
+    self.maps.values().map(|m| m.len()).sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.maps.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.maps.clear();
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+    self.maps.values().flat_map(|m| m.iter())
+  }
+
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+    self.maps.values_mut().flat_map(|m| m.iter_mut())
+  }
+
+  pub fn keys(&self) -> impl Iterator<Item = &K> {
+    self.iter().map(|(k, _)| k)
+  }
+
+  pub fn values(&self) -> impl Iterator<Item = &V> {
+    self.iter().map(|(_, v)| v)
   }
 }
 
-impl<V> Default for GridHashMap<V> {
+impl<V, K: GridKey> Default for GridHashMap<V, K> {
   fn default() -> Self {
-    GridHashMap {
-      maps: HashMap::<[i64; 3], HashMap<[i64; 4], V>>::new(),
-      size: 32,
+    GridHashMap::new(32)
+  }
+}
+
+/**
+  Thread-safe GridHashMap, the grid cells are distributed to shards each
+  with its own RwLock. Reads of different shards never wait for each other,
+  values are accessed through closures or cloned since the lock can't
+  outlive the call.
+*/
+#[derive(Debug)]
+pub struct ShardedGridHashMap<V, K: GridKey = [i64; 4]> {
+  shards: Vec<RwLock<GridHashMap<V, K>>>,
+  pub size: u32,
+}
+
+impl<V, K: GridKey> ShardedGridHashMap<V, K> {
+  pub fn new(shard_count: usize, size: u32) -> Self {
+    let shard_count = shard_count.max(1);
+    let mut shards = Vec::with_capacity(shard_count);
+    for _ in 0..shard_count {
+      shards.push(RwLock::new(GridHashMap::new(size)));
+    }
+    ShardedGridHashMap { shards: shards, size: size }
+  }
+
+  fn shard(&self, key: &K) -> &RwLock<GridHashMap<V, K>> {
+    let grid = get_grid_cell(key, self.size);
+    let hash = (grid[0].wrapping_mul(73856093))
+      ^ (grid[1].wrapping_mul(19349663))
+      ^ (grid[2].wrapping_mul(83492791));
+    &self.shards[hash.rem_euclid(self.shards.len() as i64) as usize]
+  }
+
+  /// Calls f with the value while the shard is read locked
+  pub fn read<R>(&self, key: &K, f: impl FnOnce(&V) -> R) -> Option<R> {
+    let shard = self.shard(key).read().unwrap();
+    shard.get(key).map(f)
+  }
+
+  /// Calls f with the value while the shard is write locked
+  pub fn write<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+    let mut shard = self.shard(key).write().unwrap();
+    shard.get_mut(key).map(f)
+  }
+
+  pub fn insert(&self, key: K, value: V) -> Option<V> {
+    self.shard(&key).write().unwrap().insert(key, value)
+  }
+
+  pub fn remove(&self, key: &K) -> Option<V> {
+    self.shard(key).write().unwrap().remove(key)
+  }
+
+  pub fn contains_key(&self, key: &K) -> bool {
+    self.shard(key).read().unwrap().contains_key(key)
+  }
+
+  pub fn len(&self) -> usize {
+    self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.shards.iter().all(|s| s.read().unwrap().is_empty())
+  }
+
+  pub fn clear(&self) {
+    for shard in self.shards.iter() {
+      shard.write().unwrap().clear();
+    }
+  }
+
+  /// Calls f for every value, one shard is locked at a time
+  pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+    for shard in self.shards.iter() {
+      for (k, v) in shard.read().unwrap().iter() {
+        f(k, v);
+      }
+    }
+  }
+
+  pub fn keys(&self) -> Vec<K> {
+    let mut keys = Vec::new();
+    self.for_each(|k, _| keys.push(*k));
+    keys
+  }
+}
+
+impl<V: Clone, K: GridKey> ShardedGridHashMap<V, K> {
+  pub fn get(&self, key: &K) -> Option<V> {
+    self.read(key, |v| v.clone())
+  }
+
+  /**
+    Returns the value, otherwise inserts the one created by f. f is called
+    without holding the lock, so if another thread inserted the key in the
+    meantime its value is kept and returned instead.
+  */
+  pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> V {
+    if let Some(v) = self.get(&key) {
+      return v;
     }
+
+    let value = f();
+    let mut shard = self.shard(&key).write().unwrap();
+    if let Some(v) = shard.get(&key) {
+      return v.clone();
+    }
+    shard.insert(key, value.clone());
+    value
+  }
+}
+
+impl<V, K: GridKey> Default for ShardedGridHashMap<V, K> {
+  fn default() -> Self {
+    ShardedGridHashMap::new(16, 8)
   }
 }
 
 /**
  Needed for negative key to identify local key
+ Position of the key inside its grid cell
 */
-fn get_local_key(&key: &[i64; 3], size: u32) -> [i64; 3] {
+pub fn get_local_key(&key: &[i64; 3], size: u32) -> [i64; 3] {
   let sizei64 = size as i64;
   let rel_x = key[0] % sizei64;
   let rel_y = key[1] % sizei64;
@@ -95,6 +247,7 @@ fn get_local_key(&key: &[i64; 3], size: u32) -> [i64; 3] {
   [local_x, local_y, local_z]
 }
 
+
 #[cfg(test)]
 mod tests {
   use crate::chunk::chunk_manager::Chunk;
@@ -166,8 +319,60 @@ use super::*;
     Ok(())
   }
 
-  /*
-    TODO
-      Add more unit tests later
-  */
+  #[test]
+  fn test_remove_and_iter() -> Result<(), String> {
+    let mut grid_hashmap = GridHashMap::<u32, [i64; 3]>::new(4);
+    for x in -10..10 {
+      grid_hashmap.insert([x, -x, x * 3], x as u32);
+    }
+    assert_eq!(grid_hashmap.len(), 20);
+    assert_eq!(grid_hashmap.get(&[-4, 4, -12]), Some(&(-4_i64 as u32)));
+
+    assert_eq!(grid_hashmap.remove(&[-4, 4, -12]), Some(-4_i64 as u32));
+    assert_eq!(grid_hashmap.remove(&[-4, 4, -12]), None);
+    assert_eq!(grid_hashmap.len(), 19);
+
+    let mut keys: Vec<_> = grid_hashmap.keys().cloned().collect();
+    keys.sort();
+    assert_eq!(keys.len(), 19);
+    assert!(!keys.contains(&[-4, 4, -12]));
+
+    for x in -10..10 {
+      grid_hashmap.remove(&[x, -x, x * 3]);
+    }
+    assert!(grid_hashmap.is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn test_sharded_parallel_access() -> Result<(), String> {
+    let map = ShardedGridHashMap::<i64, [i64; 3]>::new(8, 4);
+    std::thread::scope(|scope| {
+      for t in 0..4_i64 {
+        let map = &map;
+        scope.spawn(move || {
+          for x in -50..50 {
+            map.insert([x, t, -x], x * t);
+          }
+          for x in -50..50 {
+            map.write(&[x, t, -x], |v| *v += 1);
+          }
+        });
+      }
+    });
+
+    assert_eq!(map.len(), 400);
+    for t in 0..4_i64 {
+      for x in -50..50 {
+        assert_eq!(map.get(&[x, t, -x]), Some(x * t + 1));
+      }
+    }
+
+    let value = map.get_or_insert_with([0, 0, 0], || 100);
+    assert_eq!(value, 1);
+    assert_eq!(map.get_or_insert_with([0, 0, 1000], || 100), 100);
+    assert_eq!(map.remove(&[0, 0, 1000]), Some(100));
+    assert_eq!(map.keys().len(), 400);
+    Ok(())
+  }
 }
@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use hashbrown::HashMap;
+
+/// Job taken out of the queue, finish() it with its id when done
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job<K, J> {
+  pub key: K,
+  pub id: u64,
+  pub job: J,
+}
+
+#[derive(Clone, Debug)]
+struct Pending<J> {
+  priority: u64,
+  id: u64,
+  job: J,
+}
+
+/**
+  Queue of jobs keyed by chunk, lowest priority value first. A key has at most
+  one pending job, pushing again replaces it. At most max_in_flight jobs are
+  running, the rest wait in the queue where they can still be cancelled or
+  reprioritized. A key is not started again while its previous job runs, so
+  its results arrive in order.
+*/
+#[derive(Clone, Debug)]
+pub struct JobQueue<K: Clone + Eq + Hash, J> {
+  order: BTreeMap<(u64, u64), K>,
+  pending: HashMap<K, Pending<J>>,
+  in_flight: HashMap<K, u64>,
+  next_id: u64,
+  pub max_in_flight: usize,
+}
+
+impl<K: Clone + Eq + Hash, J> JobQueue<K, J> {
+  pub fn new(max_in_flight: usize) -> Self {
+    JobQueue {
+      order: BTreeMap::new(),
+      pending: HashMap::new(),
+      in_flight: HashMap::new(),
+      next_id: 0,
+      max_in_flight: max_in_flight,
+    }
+  }
+
+  /// Queues the job, replacing the pending job of the key
+  pub fn push(&mut self, key: K, priority: u64, job: J) {
+    if let Some(p) = self.pending.remove(&key) {
+      self.order.remove(&(p.priority, p.id));
+    }
+    let id = self.next_id;
+    self.next_id += 1;
+    self.order.insert((priority, id), key.clone());
+    self.pending.insert(key, Pending { priority: priority, id: id, job: job });
+  }
+
+  /// Next job to run, None if the queue is empty or max_in_flight jobs are running
+  pub fn pop(&mut self) -> Option<Job<K, J>> {
+    let order_key = self.next()?;
+    let key = self.order.remove(&order_key).unwrap();
+    let p = self.pending.remove(&key).unwrap();
+    self.in_flight.insert(key.clone(), p.id);
+    Some(Job { key: key, id: p.id, job: p.job })
+  }
+
+  /// Job pop() would return next
+  pub fn peek(&self) -> Option<(&K, &J)> {
+    let key = &self.order[&self.next()?];
+    Some((key, &self.pending[key].job))
+  }
+
+  fn next(&self) -> Option<(u64, u64)> {
+    if self.in_flight.len() >= self.max_in_flight {
+      return None;
+    }
+    self.order
+      .iter()
+      .find(|(_, key)| !self.in_flight.contains_key(*key))
+      .map(|(o, _)| *o)
+  }
+
+  /// False if the job was cancelled meanwhile, its result should be dropped
+  pub fn finish(&mut self, key: &K, id: u64) -> bool {
+    if self.in_flight.get(key) != Some(&id) {
+      return false;
+    }
+    self.in_flight.remove(key);
+    true
+  }
+
+  /// Removes the pending and running job of the key, true if there was any
+  pub fn cancel(&mut self, key: &K) -> bool {
+    let mut cancelled = self.in_flight.remove(key).is_some();
+    if let Some(p) = self.pending.remove(key) {
+      self.order.remove(&(p.priority, p.id));
+      cancelled = true;
+    }
+    cancelled
+  }
+
+  /// Sets the priority of every pending job, None cancels it
+  pub fn reprioritize<F: FnMut(&K, &J) -> Option<u64>>(&mut self, mut f: F) {
+    let order = std::mem::take(&mut self.order);
+    for (_, key) in order.into_iter() {
+      let p = self.pending.get_mut(&key).unwrap();
+      match f(&key, &p.job) {
+        Some(priority) => {
+          p.priority = priority;
+          self.order.insert((priority, p.id), key);
+        }
+        None => {
+          self.pending.remove(&key);
+        }
+      }
+    }
+  }
+
+  /// Cancels the running jobs `f` returns false for, returns their keys
+  pub fn retain_in_flight<F: FnMut(&K) -> bool>(&mut self, mut f: F) -> Vec<K> {
+    let cancelled: Vec<K> = self.in_flight.keys().filter(|k| !f(k)).cloned().collect();
+    for key in cancelled.iter() {
+      self.in_flight.remove(key);
+    }
+    cancelled
+  }
+
+  pub fn pending_len(&self) -> usize {
+    self.pending.len()
+  }
+
+  pub fn in_flight_len(&self) -> usize {
+    self.in_flight.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pending.is_empty() && self.in_flight.is_empty()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pop_keys(queue: &mut JobQueue<i64, &'static str>) -> Vec<i64> {
+    let mut keys = Vec::new();
+    while let Some(j) = queue.pop() {
+      keys.push(j.key);
+    }
+    keys
+  }
+
+  #[test]
+  fn test_job_queue_priority_and_backpressure() -> Result<(), String> {
+    let mut queue = JobQueue::new(2);
+    queue.push(1, 5, "a");
+    queue.push(2, 1, "b");
+    queue.push(3, 3, "c");
+    queue.push(4, 3, "d");
+
+    // Only 2 jobs run at once
+    assert_eq!(queue.peek(), Some((&2, &"b")));
+    let b = queue.pop().unwrap();
+    assert_eq!(b, Job { key: 2, id: 1, job: "b" });
+    let c = queue.pop().unwrap();
+    assert_eq!(c.key, 3);
+    assert!(queue.pop().is_none());
+    assert!(queue.peek().is_none());
+    assert_eq!(queue.in_flight_len(), 2);
+    assert_eq!(queue.pending_len(), 2);
+
+    assert!(queue.finish(&b.key, b.id));
+    assert!(!queue.finish(&b.key, b.id));
+    assert_eq!(pop_keys(&mut queue), vec![4]);
+    assert!(queue.finish(&3, c.id));
+    assert!(queue.finish(&4, 3));
+    assert_eq!(pop_keys(&mut queue), vec![1]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_job_queue_replace_and_cancel() -> Result<(), String> {
+    let mut queue = JobQueue::new(4);
+    queue.push(1, 5, "old");
+    queue.push(1, 2, "new");
+    queue.push(2, 3, "b");
+    assert_eq!(queue.pending_len(), 2);
+
+    let job = queue.pop().unwrap();
+    assert_eq!((job.key, job.job), (1, "new"));
+
+    // The key waits for its running job before starting again
+    queue.push(1, 0, "again");
+    assert_eq!(pop_keys(&mut queue), vec![2]);
+    assert!(queue.finish(&1, job.id));
+    let again = queue.pop().unwrap();
+    assert_eq!(again.job, "again");
+
+    // Cancelled running job drops its result
+    assert!(queue.cancel(&1));
+    assert!(!queue.finish(&1, again.id));
+    assert!(!queue.cancel(&1));
+    assert!(queue.finish(&2, 2));
+    assert!(queue.is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn test_job_queue_reprioritize() -> Result<(), String> {
+    let mut queue = JobQueue::new(1);
+    for key in 0..6 {
+      queue.push(key, key as u64, "job");
+    }
+    let running = queue.pop().unwrap();
+
+    // Reverse the order and cancel the odd keys
+    queue.reprioritize(|key, _| if key % 2 == 1 { None } else { Some(10 - *key as u64) });
+    assert_eq!(queue.pending_len(), 2);
+    assert_eq!(queue.retain_in_flight(|key| *key != running.key), vec![0]);
+    assert!(!queue.finish(&running.key, running.id));
+
+    let mut keys = Vec::new();
+    while let Some(j) = queue.pop() {
+      keys.push(j.key);
+      queue.finish(&j.key, j.id);
+    }
+    assert_eq!(keys, vec![4, 2]);
+    Ok(())
+  }
+}
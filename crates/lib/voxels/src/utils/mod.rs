@@ -1,5 +1,7 @@
 pub mod grid_hashmap;
+pub mod job_queue;
 use crate::chunk::voxel_pos_to_key;
+use crate::chunk::coords::{WorldVoxelPos, WorldPosF32, ChunkKey};
 use crate::data::voxel_octree::VoxelOctree;
 
 pub struct Utils;
@@ -51,10 +53,9 @@ impl Utils {
 
 
 
+/** Same as WorldPosF32::to_chunk_key() with voxel_scale of 1.0 */
 pub fn posf32_to_world_key(pos: &[f32; 3], seamless_size: u32) -> [i64; 3] {
-  let posi64 = [pos[0] as i64, pos[1] as i64, pos[2] as i64];
-  // world_pos_to_key(&posi64, seamless_size)
-  voxel_pos_to_key(&posi64, seamless_size)
+  WorldPosF32(*pos).to_chunk_key(seamless_size, 1.0).0
 }
 
 
@@ -97,30 +98,17 @@ pub fn get_length(depth: u8) -> usize {
 
 
 
+/** Same as WorldVoxelPos::chunk_coords() */
 pub fn get_chunk_coords(
   pos: &[i64; 3], 
   chunk_size: u32, 
   seamless_size: u32
 ) -> Vec<ChunkCoordinate> {
-  let mut coords = Vec::new();
-
-  let keys = &potential_keys(pos, seamless_size);
-  for key in keys.iter() {
-    // println!("key {:?}", key);
-    if has_local_coord(pos, key, chunk_size, seamless_size as i64) {
-      let local = get_local_coord(pos, key, chunk_size);
-
-      let coord = ChunkCoordinate {
-        key: key.clone(),
-        local: local
-      };
-      coords.push(coord);
-      // println!("get_chunk_coords key {:?} coord {:?}", key, local);
-    }
-
-  }
-
-  coords
+  WorldVoxelPos(*pos)
+    .chunk_coords(chunk_size, seamless_size)
+    .into_iter()
+    .map(|(key, local)| ChunkCoordinate { key: key.0, local: local.0 })
+    .collect()
 }
 
 pub fn get_chunk_coords2(
@@ -289,12 +277,9 @@ pub fn world_pos_to_octree_key(pos: &[i64; 3], seamless_size: u32) -> [i64; 3] {
 }
 
 
+/** Same as ChunkKey::to_world_f32() with voxel_scale of 1.0 */
 pub fn key_to_world_coord_f32(key: &[i64; 3], seamless_size: u32) -> [f32; 3] {
-  [
-    (key[0] * seamless_size as i64) as f32,
-    (key[1] * seamless_size as i64) as f32,
-    (key[2] * seamless_size as i64) as f32,
-  ]
+  ChunkKey(*key).to_world_f32(seamless_size, 1.0).0
 }
 
 pub struct OctreeCoord {
@@ -303,6 +288,33 @@ pub struct OctreeCoord {
 }
 
 
+/**
+  FNV-1a hasher, unlike the std hasher it gives the same hashes on every run
+  and platform.
+*/
+#[derive(Clone, Copy, Debug)]
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+  fn default() -> Self {
+    FnvHasher(0xcbf29ce484222325)
+  }
+}
+
+impl std::hash::Hasher for FnvHasher {
+  fn write(&mut self, bytes: &[u8]) {
+    for b in bytes.iter() {
+      self.0 ^= *b as u64;
+      self.0 = self.0.wrapping_mul(0x100000001b3);
+    }
+  }
+
+  fn finish(&self) -> u64 {
+    self.0
+  }
+}
+
+
 #[cfg(test)]
 mod tests {
   use super::*;